@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Host build of the kernel modules that don't touch hardware, so their unit tests can run with
+//! `cargo test -p ktest`. The kernel crate itself only builds for x86_64-unknown-none.
+//!
+//! Modules are included straight from the kernel's source tree, so they may only use `core` and
+//! `alloc`: no other crates, and no `crate::` paths into the rest of the kernel. Whatever they
+//! define for the kernel's use is unused here.
+
+#![feature(allocator_api)]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../src/drivers/ahci/ata.rs"]
+mod ata;
+
+#[path = "../../src/drivers/ahci/hotplug.rs"]
+mod hotplug;
+
+#[path = "../../src/drivers/ahci/layout.rs"]
+mod layout;
+
+#[path = "../../src/drivers/ahci/pool.rs"]
+mod pool;
+
+#[path = "../../src/drivers/ahci/slots.rs"]
+mod slots;
+
+#[path = "../../src/drivers/acpi_frames.rs"]
+mod acpi_frames;
+
+#[path = "../../src/drivers/acpi_sleep.rs"]
+mod acpi_sleep;
+
+#[path = "../../src/drivers/acpi_user.rs"]
+mod acpi_user;
+
+#[path = "../../src/drivers/pci_bar.rs"]
+mod pci_bar;
+
+#[path = "../../src/drivers/pci_ids.rs"]
+mod pci_ids;
+
+#[path = "../../src/drivers/pci_scan.rs"]
+mod pci_scan;
+
+#[path = "../../src/common/bitmap.rs"]
+mod bitmap;
+
+#[path = "../../src/arch/x86_64/irq_chains.rs"]
+mod irq_chains;
+
+#[path = "../../src/arch/x86_64/irq_counters.rs"]
+mod irq_counters;
+
+#[path = "../../src/process/run_queue.rs"]
+mod run_queue;
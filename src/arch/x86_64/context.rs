@@ -0,0 +1,85 @@
+//! Switching between the kernel stacks of tasks
+
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+/// Where a task that isn't running left off: its stack pointer, with the callee-saved
+/// registers and the address it continues at on top of the stack
+#[derive(Debug)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+}
+
+// Saves the callee-saved registers on the current stack and its pointer to `*from`, then
+// continues on the stack `to` points to. Everything else is saved by the caller, and the
+// kernel is built without SSE.
+global_asm!(
+    r#"
+    .section .text.context_switch, "ax"
+    .global context_switch
+context_switch:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, (%rdi)
+
+    mov %rsi, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn context_switch(from: *mut u64, to: u64);
+}
+
+/// Callee-saved registers `context_switch` keeps on the stack
+const SAVED_REGISTERS: usize = 6;
+
+impl Context {
+    /// Context of the code running on a CPU's own stack, filled in once it switches away
+    pub const fn empty() -> Self {
+        Self { rsp: 0 }
+    }
+
+    /// Context of a task that hasn't run yet, which starts at `entry` on the stack ending at
+    /// `stack_top`
+    pub fn new(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> Self {
+        let top = stack_top.align_down(16u64).as_u64() as *mut u64;
+
+        unsafe {
+            // `entry` sees the stack like after a call, with a return address it never uses
+            let frame = top.sub(SAVED_REGISTERS + 2);
+
+            for register in 0..SAVED_REGISTERS {
+                frame.add(register).write(0);
+            }
+
+            frame.add(SAVED_REGISTERS).write(entry as usize as u64);
+            frame.add(SAVED_REGISTERS + 1).write(0);
+
+            Self { rsp: frame as u64 }
+        }
+    }
+
+    /// Saves the executing context to `from` and continues `to`, returning once something
+    /// switches back to `from`
+    ///
+    /// # Safety
+    /// Interrupts have to be disabled, and `to` has to be a context that was switched away
+    /// from or made by [`Context::new`], and is not `from`.
+    pub unsafe fn switch(from: *mut Context, to: *const Context) {
+        context_switch(&mut (*from).rsp, (*to).rsp);
+    }
+}
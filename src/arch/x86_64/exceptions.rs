@@ -1,12 +1,16 @@
 use {
+    crate::{map_page, FRAME_ALLOCATOR},
+    alloc::{boxed::Box, vec},
+    core::sync::atomic::{AtomicU64, Ordering},
     lazy_static::lazy_static,
     x86_64::{
         instructions::{
             segmentation::{Segment, CS, DS, ES, FS, GS},
-            tables::load_tss,
+            tables::{load_tss, sgdt},
         },
         structures::{
             gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+            paging::{FrameAllocator, PageTableFlags, Size4KiB},
             tss::TaskStateSegment,
         },
         VirtAddr,
@@ -14,114 +18,156 @@ use {
 };
 
 pub struct Selectors {
-    code: SegmentSelector,
-    ds: SegmentSelector,
-    es: SegmentSelector,
-    fs: SegmentSelector,
-    gs: SegmentSelector,
+    pub(crate) code: SegmentSelector,
+    pub(crate) ds: SegmentSelector,
+    /// SYSRET expects user data right below user code, see `syscall::init()`
+    pub(crate) user_data: SegmentSelector,
+    pub(crate) user_code: SegmentSelector,
     tss: SegmentSelector,
 }
 
 pub const DOUBLE_FAULT_STACK_INDEX: u16 = 0;
 pub const PAGE_FAULT_STACK_INDEX: u16 = 1;
 pub const INVALID_TSS_STACK_INDEX: u16 = 2;
-pub const DIV_ERR_STACK_INDEX: u16 = 3;
-pub const SIGBUS_STACK_INDEX: u16 = 4;
+/// Division errors run on the regular stack, NMIs can interrupt any code so they need their own
+pub const NMI_STACK_INDEX: u16 = 3;
+/// Segment-not-present faults run on the regular stack, machine checks can hit anywhere
+pub const MACHINE_CHECK_STACK_INDEX: u16 = 4;
 pub const SIGSEGV_STACK_INDEX: u16 = 5;
 pub const GPF_STACK_INDEX: u16 = 6;
 
+/// Size of the stack every CPU enters the kernel on from user mode
+const KERNEL_STACK_SIZE: usize = 4096 * 16;
+
+/// Where the interrupt stacks of every CPU are mapped, in the same PML4 entry as the heap
+const IST_STACKS_BASE: u64 = 0x2040_0000_0000;
+/// Size of every interrupt stack
+const IST_STACK_SIZE: u64 = 4096 * 5;
+/// An interrupt stack and the unmapped guard page below it
+const IST_SLOT_SIZE: u64 = IST_STACK_SIZE + 4096;
+
+/// Interrupt stacks handed out so far, each TSS takes [`IST_STACK_COUNT`] in a row
+static IST_SLOTS: AtomicU64 = AtomicU64::new(0);
+
 lazy_static! {
     pub static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
-
-            let begin = VirtAddr::from_ptr(unsafe { &STACK });
-
-            begin + LEN
-        };
-        tss.interrupt_stack_table[PAGE_FAULT_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
+        tss.privilege_stack_table[0] = {
+            static mut STACK: [u8; KERNEL_STACK_SIZE] = [0; KERNEL_STACK_SIZE];
 
             let begin = VirtAddr::from_ptr(unsafe { &STACK });
 
-            begin + LEN
+            begin + KERNEL_STACK_SIZE
         };
-        tss.interrupt_stack_table[INVALID_TSS_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
+        alloc_ist_stacks(&mut tss);
 
-            let begin = VirtAddr::from_ptr(unsafe { &STACK });
-
-            begin + LEN
-        };
-        tss.interrupt_stack_table[DIV_ERR_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
+        tss
+    };
+    pub static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&TSS);
+}
 
-            let begin = VirtAddr::from_ptr(unsafe { &STACK });
+/// Number of interrupt stacks set up in every TSS
+const IST_STACK_COUNT: usize = 7;
+
+/// Maps fresh interrupt stacks for every slot of `tss`, each above a guard page that is left
+/// unmapped, so overflowing one faults instead of running into whatever lies below
+fn alloc_ist_stacks(tss: &mut TaskStateSegment) {
+    let first = IST_SLOTS.fetch_add(IST_STACK_COUNT as u64, Ordering::SeqCst);
+
+    for (i, stack) in tss.interrupt_stack_table.iter_mut().enumerate() {
+        let bottom = IST_STACKS_BASE + (first + i as u64) * IST_SLOT_SIZE + 4096;
+
+        for page in (bottom..bottom + IST_STACK_SIZE).step_by(4096) {
+            let frame = FRAME_ALLOCATOR
+                .get()
+                .unwrap()
+                .write()
+                .allocate_frame()
+                .expect("Out of memory for interrupt stacks");
+
+            map_page!(
+                frame.start_address().as_u64(),
+                page,
+                Size4KiB,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+            );
+        }
+
+        *stack = VirtAddr::new(bottom + IST_STACK_SIZE);
+    }
+}
 
-            begin + LEN
-        };
-        tss.interrupt_stack_table[SIGBUS_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
+/// The index of the interrupt stack whose guard page `addr` lies in, if any
+pub fn ist_guard_page(addr: VirtAddr) -> Option<u16> {
+    let offset = addr.as_u64().checked_sub(IST_STACKS_BASE)?;
+    let slot = offset / IST_SLOT_SIZE;
 
-            let begin = VirtAddr::from_ptr(unsafe { &STACK });
+    (slot < IST_SLOTS.load(Ordering::SeqCst) && offset % IST_SLOT_SIZE < 4096)
+        .then_some((slot % IST_STACK_COUNT as u64) as u16)
+}
 
-            begin + LEN
-        };
-        tss.interrupt_stack_table[SIGSEGV_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let ds = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code,
+            ds,
+            user_data,
+            user_code,
+            tss,
+        },
+    )
+}
 
-            let begin = VirtAddr::from_ptr(unsafe { &STACK });
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code);
+        DS::set_reg(gdt.1.ds);
+        ES::set_reg(gdt.1.ds);
+        FS::set_reg(gdt.1.ds);
+        GS::set_reg(gdt.1.ds);
+        load_tss(gdt.1.tss);
+    }
+}
 
-            begin + LEN
-        };
-        tss.interrupt_stack_table[GPF_STACK_INDEX as usize] = {
-            const LEN: usize = 4096 * 5;
-            static mut STACK: [u8; LEN] = [0; LEN];
+/// Loads the GDT again after the CPU lost it, e.g. when waking up from S3. The TSS descriptor
+/// in memory still has its busy bit set from the first load, which `ltr` faults on, so it is
+/// cleared first.
+pub fn reload() {
+    let gdtr = sgdt();
+    let descriptor = gdtr.base.as_u64() + GDT.1.tss.index() as u64 * 8;
 
-            let begin = VirtAddr::from_ptr(unsafe { &STACK });
+    // Byte 5 holds the type, busy (0b1011) becomes available (0b1001)
+    unsafe { *((descriptor + 5) as *mut u8) &= !0b10 };
 
-            begin + LEN
-        };
-        tss
-    };
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code = gdt.add_entry(Descriptor::kernel_code_segment());
-        let ds = gdt.add_entry(Descriptor::kernel_data_segment());
-        let es = gdt.add_entry(Descriptor::kernel_data_segment());
-        let fs = gdt.add_entry(Descriptor::kernel_data_segment());
-        let gs = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code,
-                ds,
-                es,
-                fs,
-                gs,
-                tss,
-            },
-        )
-    };
+    init();
 }
 
 /// GDT initializer
 pub fn init() {
-    GDT.0.load();
-    unsafe {
-        CS::set_reg(GDT.1.code);
-        DS::set_reg(GDT.1.ds);
-        ES::set_reg(GDT.1.es);
-        FS::set_reg(GDT.1.fs);
-        GS::set_reg(GDT.1.gs);
-        load_tss(GDT.1.tss);
-    }
+    load(&GDT);
+}
+
+/// Loads a GDT and TSS of its own on an application processor. Loading a TSS marks its
+/// descriptor busy, so it can't be shared, and every CPU needs interrupt stacks of its own
+/// anyway. Returns the top of the stack the CPU enters the kernel on from user mode.
+pub fn init_ap() -> VirtAddr {
+    let mut tss = TaskStateSegment::new();
+
+    let memory = vec![0u8; KERNEL_STACK_SIZE].leak();
+    let kernel_stack = VirtAddr::from_ptr(memory.as_ptr()) + KERNEL_STACK_SIZE;
+    tss.privilege_stack_table[0] = kernel_stack;
+    alloc_ist_stacks(&mut tss);
+
+    let tss = Box::leak(Box::new(tss));
+    load(Box::leak(Box::new(build_gdt(tss))));
+
+    kernel_stack
 }
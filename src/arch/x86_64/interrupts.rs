@@ -1,28 +1,39 @@
-use core::sync::atomic::AtomicU32;
+use core::{
+    arch::global_asm,
+    ptr,
+    sync::atomic::{AtomicU8, AtomicUsize},
+};
+
+use alloc::{alloc::Global, vec::Vec};
 
+use bit_field::BitField;
 use log::warn;
 use raw_cpuid::{CpuId, Hypervisor};
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 use x86_64::{
     instructions::interrupts,
-    registers::{
-        read_rip,
-        rflags::{self, RFlags},
-        segmentation::{Segment, CS},
-    },
+    registers::rflags::{self, RFlags},
     structures::{
         gdt::SegmentSelector,
         idt::{Entry, InterruptStackFrameValue, SelectorErrorCode},
-        paging::{PageTableFlags, Size4KiB},
     },
-    PrivilegeLevel,
+    PrivilegeLevel, VirtAddr,
 };
 
+pub use super::irq_chains::IrqHandler;
+pub use super::irq_counters::{CpuIrqStats, IrqCounters};
+
+use super::irq_chains::{irq_stub_offset, IrqChains, IrqRegistration};
+
 use crate::{
-    ahci::{get_ahci, get_hba, HbaPortIS},
-    apic_impl::{get_active_lapic, get_lapic_ids},
-    map_page,
-    process::{signal::Signal, State, PTABLE, PTABLE_IDX},
+    ahci::{get_ahci, HbaPortIS, PowerPolicy},
+    apic_impl::{get_active_lapic, read_esr, send_ipi, LapicError},
+    common::bitmap::Bitmap,
+    cralloc::vm::{self, LazyFaultError},
+    pci_impl::check_aer,
+    percpu,
+    process::{sched, signal::Signal},
+    serial::emergency_print,
 };
 
 use {
@@ -35,12 +46,11 @@ use {
     },
 };
 
-/// Initializes the IDT
+/// Loads the IDT on the executing CPU. IDTR points straight at the table behind the lock, so
+/// handlers registered later take effect on every CPU.
 pub fn init() {
-    lazy_static! {
-        static ref IDT_CLONE: InterruptDescriptorTable = IDT.read().clone();
-    }
-    IDT_CLONE.load();
+    // The table lives in a static, so it outlives IDTR pointing at it
+    unsafe { IDT.read().load_unsafe() };
 }
 
 pub fn current_privilege_level(frame: InterruptStackFrameValue) -> PrivilegeLevel {
@@ -52,11 +62,6 @@ pub fn current_privilege_level(frame: InterruptStackFrameValue) -> PrivilegeLeve
 
 pub const QEMU_STATUS_FAIL: u32 = 0x11;
 
-pub static INTA_IRQ: AtomicU64 = AtomicU64::new(0);
-pub static INTB_IRQ: AtomicU64 = AtomicU64::new(0);
-pub static INTC_IRQ: AtomicU64 = AtomicU64::new(0);
-pub static INTD_IRQ: AtomicU64 = AtomicU64::new(0);
-
 lazy_static! {
     pub static ref IDT: RwLock<InterruptDescriptorTable> = {
         let mut idt = InterruptDescriptorTable::new();
@@ -68,15 +73,20 @@ lazy_static! {
             idt.page_fault
                 .set_handler_fn(page_fault)
                 .set_stack_index(super::exceptions::PAGE_FAULT_STACK_INDEX);
-            idt.divide_error
-                .set_handler_fn(sigfpe)
-                .set_stack_index(super::exceptions::DIV_ERR_STACK_INDEX);
+            idt.divide_error.set_handler_fn(sigfpe);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi)
+                .set_stack_index(super::exceptions::NMI_STACK_INDEX);
             idt.invalid_tss
                 .set_handler_fn(invalid_tss)
                 .set_stack_index(super::exceptions::INVALID_TSS_STACK_INDEX);
-            idt.segment_not_present
-                .set_handler_fn(sigbus)
-                .set_stack_index(super::exceptions::SIGBUS_STACK_INDEX);
+            idt.segment_not_present.set_handler_fn(sigbus);
+
+            // The entry is typed for a handler that never returns, but corrected errors are
+            // resumed from
+            idt.machine_check
+                .set_handler_addr(VirtAddr::new(machine_check as usize as u64))
+                .set_stack_index(super::exceptions::MACHINE_CHECK_STACK_INDEX);
             idt.stack_segment_fault
                 .set_handler_fn(sigsegv)
                 .set_stack_index(super::exceptions::SIGSEGV_STACK_INDEX);
@@ -85,7 +95,10 @@ lazy_static! {
                 .set_stack_index(super::exceptions::GPF_STACK_INDEX);
         }
 
-        idt.breakpoint.set_handler_fn(breakpoint);
+        // User code may use int3 too
+        idt.breakpoint
+            .set_handler_fn(breakpoint)
+            .set_privilege_level(PrivilegeLevel::Ring3);
         idt.bound_range_exceeded
             .set_handler_fn(bound_range_exceeded);
         idt.invalid_opcode.set_handler_fn(invalid_op);
@@ -93,24 +106,66 @@ lazy_static! {
         idt[IrqIndex::Timer as usize].set_handler_fn(timer);
         idt[IrqIndex::LapicErr as usize].set_handler_fn(lapic_err);
         idt[IrqIndex::Spurious as usize].set_handler_fn(spurious);
-        idt[INTA_IRQ.load(Ordering::SeqCst) as usize].set_handler_fn(pin_inta);
-        idt[INTB_IRQ.load(Ordering::SeqCst) as usize].set_handler_fn(pin_intb);
-        idt[INTC_IRQ.load(Ordering::SeqCst) as usize].set_handler_fn(pin_intc);
-        idt[INTD_IRQ.load(Ordering::SeqCst) as usize].set_handler_fn(pin_intd);
-        idt[0x80].set_handler_fn(syscall);
-
-        // Vector 100 = IPI_WAKE handler as task scheduler
-        // performance is the obvious reason why I'm doing this
-        idt[132].set_handler_fn(task_sched);
-        idt[139].set_handler_fn(pci);
+        idt[IrqIndex::IpiTlb as usize].set_handler_fn(tlb_shootdown);
+        idt[IrqIndex::IpiWake as usize].set_handler_fn(wake);
+
+        // User mode may call it, see the `syscall` module
+        unsafe {
+            idt[0x80]
+                .set_handler_addr(super::syscall::int80_entry())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+
         idt[0x82].set_handler_fn(spurious);
-        idt[151].set_handler_fn(ahci);
+
+        // Chained at boot, see `IRQ_CHAINS`
+        for vector in [139, 151] {
+            unsafe { idt[vector].set_handler_addr(irq_stub(vector as u8)) };
+        }
+
         RwLock::new(idt)
     };
 }
 
 pub static TICK_COUNT: AtomicU64 = AtomicU64::new(0);
-pub static ACTIVE_LAPIC_ID: AtomicU32 = AtomicU32::new(0);
+
+/// What the NMI handler does once it dumped the state of the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NmiPolicy {
+    /// Return to the interrupted code, e.g. for a watchdog that fires periodically
+    Resume = 0,
+    Panic = 1,
+}
+
+static NMI_POLICY: AtomicU8 = AtomicU8::new(NmiPolicy::Panic as u8);
+
+/// NMIs taken on any CPU
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Number of `TICK_COUNT` values kept in `NMI_TICKS`
+const NMI_TICK_HISTORY: usize = 4;
+/// `TICK_COUNT` as seen by the last few NMIs, to tell whether the timer still runs
+static NMI_TICKS: [AtomicU64; NMI_TICK_HISTORY] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; NMI_TICK_HISTORY]
+};
+
+pub fn set_nmi_policy(policy: NmiPolicy) {
+    NMI_POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+/// Called on breakpoints hit in kernel mode, e.g. by a debugger stub. Returns whether it took
+/// care of the breakpoint; if not, the handler logs it and carries on.
+pub type DebugHook = fn(&mut InterruptStackFrame) -> bool;
+
+/// The attached `DebugHook` as an address, 0 if there is none. Kept lock-free so the
+/// breakpoint handler can't deadlock on it.
+static DEBUG_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Attaches `hook` to kernel-mode breakpoints, or detaches the current one
+pub fn set_debug_hook(hook: Option<DebugHook>) {
+    DEBUG_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::SeqCst);
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -124,100 +179,156 @@ pub enum IrqIndex {
     Spurious = 0xff,  // 255
 }
 
-extern "x86-interrupt" fn timer(_frame: InterruptStackFrame) {
-    TICK_COUNT.fetch_add(1, Ordering::Relaxed);
-    unsafe { get_active_lapic().end_of_interrupt() };
+extern "x86-interrupt" fn machine_check(frame: InterruptStackFrame) {
+    crate::mce::machine_check_service(&frame);
 }
 
-extern "x86-interrupt" fn spurious(_frame: InterruptStackFrame) {
-    debug!("Received spurious interrupt");
-    unsafe { get_active_lapic().end_of_interrupt() };
+/// Dumps the interrupted state to the serial port, since the interrupted code may hold the
+/// logger's locks, then resumes or panics according to [`NmiPolicy`]
+extern "x86-interrupt" fn nmi(frame: InterruptStackFrame) {
+    let count = NMI_COUNT.fetch_add(1, Ordering::SeqCst);
+    let rip = frame.instruction_pointer.as_u64();
+
+    NMI_TICKS[count as usize % NMI_TICK_HISTORY]
+        .store(TICK_COUNT.load(Ordering::Relaxed), Ordering::Relaxed);
+
+    emergency_print(format_args!(
+        "\nNMI #{} at RIP {:#x}, RSP {:#x}\n",
+        count + 1,
+        rip,
+        frame.stack_pointer.as_u64()
+    ));
+
+    match percpu::try_current() {
+        Some(cpu) => emergency_print(format_args!(
+            "CPU {} (local APIC ID {}), task {:?}\n",
+            cpu.cpu_id(),
+            cpu.lapic_id(),
+            cpu.current_task()
+        )),
+        None => emergency_print(format_args!("CPU without per-CPU data\n")),
+    }
+
+    emergency_print(format_args!("TICK_COUNT at the last NMIs, newest first:"));
+
+    for i in 0..=count.min(NMI_TICK_HISTORY as u64 - 1) {
+        let ticks = &NMI_TICKS[(count - i) as usize % NMI_TICK_HISTORY];
+        emergency_print(format_args!(" {}", ticks.load(Ordering::Relaxed)));
+    }
+
+    emergency_print(format_args!("\n"));
+
+    if NMI_POLICY.load(Ordering::SeqCst) == NmiPolicy::Panic as u8 {
+        panic!("Non-maskable interrupt at {:#x}", rip);
+    }
 }
 
-extern "x86-interrupt" fn lapic_err(_frame: InterruptStackFrame) {
-    error!("Local APIC error; check the status for details");
-    unsafe { get_active_lapic().end_of_interrupt() };
+// The handlers below run with interrupts disabled, as the interrupt gates leave them, and
+// signal the end of the interrupt as soon as they no longer depend on it being in service.
+// Edge-triggered vectors like these don't fire again before that, while the next one can
+// already be taken once the handler returns. None of them enables interrupts again, and
+// the switch to another task is left for their very end, after anything they counted.
+//
+// Exceptions never signal the end of an interrupt: they don't come from the local APIC, so
+// the EOI would retire whatever unrelated interrupt is in service instead.
+
+/// Counts the tick and preempts the current task once its time slice ran out. A CPU running
+/// no task looks for one on every tick.
+extern "x86-interrupt" fn timer(_frame: InterruptStackFrame) {
+    let cpu = percpu::current();
+
+    let preempt = {
+        let _entry = cpu.irq().enter(IrqIndex::Timer as u8);
+        unsafe { get_active_lapic().end_of_interrupt() };
+
+        crate::time::timer_service();
+
+        cpu.consume_tick() || cpu.current_task().is_none()
+    };
+
+    // A preempted task continues here once it's switched back to
+    if preempt {
+        sched::schedule();
+    }
 }
 
-/// Round-robin preemptive scheduler
-///
-/// Uses an IPI instead of the timer or the loop at the end of maink for optimization reasons:
-/// an IPI can send itself to every CPU on the system, making it possible to evenly distribute all that power
-extern "x86-interrupt" fn task_sched(_: InterruptStackFrame) {
-    // use index of an atomic to ensure that only one process is being run at a time
-    if !(PTABLE.read().is_empty()) {
-        if PTABLE.read().len() == 1 {
-            // always runnable as only one process exists
-            (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-                .write()
-                .set_state(State::Runnable);
-
-            (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-                .write()
-                .run()
-                .unwrap(); // TODO: handle error cases using file descriptors
-        } else {
-            // need to preempt previous process in the table
-            (PTABLE.read())[&(PTABLE_IDX.load(Ordering::SeqCst) - 1)]
-                .write()
-                .set_state(State::Blocked);
-
-            (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-                .write()
-                .set_state(State::Runnable);
-
-            (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-                .write()
-                .run()
-                .unwrap(); // TODO: handle error cases using file descriptors
-        }
+/// Asks the CPU to pick its next task right away, e.g. because one woke up
+extern "x86-interrupt" fn wake(_frame: InterruptStackFrame) {
+    {
+        let _entry = percpu::current().irq().enter(IrqIndex::IpiWake as u8);
+        unsafe { get_active_lapic().end_of_interrupt() };
+    }
 
-        if PTABLE_IDX.load(Ordering::SeqCst) < (PTABLE.read().len()) {
-            // ensure that the next CPU core runs the next process when it receives this interrupt
-            PTABLE_IDX.fetch_add(1, Ordering::SeqCst);
-        } else {
-            // we've reached the end of PTABLE, so time to reset this
-            PTABLE_IDX.store(0, Ordering::SeqCst);
-        }
+    sched::schedule();
+}
+
+/// Makes the CPU with the local APIC ID `lapic_id` reschedule now instead of at the end of its
+/// current time slice. The executing CPU isn't sent an IPI, it reschedules on its next tick.
+pub fn kick(lapic_id: u32) {
+    let cpu = percpu::current();
+
+    if cpu.lapic_id() == lapic_id {
+        cpu.refill_slice(0);
+        return;
     }
 
-    if ACTIVE_LAPIC_ID.load(Ordering::SeqCst) == 0 {
-        // initialize with first LAPIC ID
-        ACTIVE_LAPIC_ID.store(get_lapic_ids().next().unwrap(), Ordering::SeqCst);
+    send_ipi(IrqIndex::IpiWake as u8, lapic_id);
+}
 
-        // get the ball rolling
-        unsafe { get_active_lapic().send_ipi(100, get_lapic_ids().cycle().nth(1).unwrap()) };
-    } else {
-        // need to store this in a variable in order to ensure that `.next()` matches the correct core ID
-        let mut lapic_iter = get_lapic_ids().cycle();
+extern "x86-interrupt" fn tlb_shootdown(_frame: InterruptStackFrame) {
+    let _entry = percpu::current().irq().enter(IrqIndex::IpiTlb as u8);
+    unsafe { get_active_lapic().end_of_interrupt() };
 
-        if lapic_iter.any(|id| id == ACTIVE_LAPIC_ID.load(Ordering::SeqCst)) {
-            // find the next LAPIC ID after the current one
-            // Note that because we're using `.cycle` this will never be None
-            let id = lapic_iter.next().unwrap();
+    crate::tlb::shootdown_service();
+}
 
-            // update active LAPIC ID to match the next one
-            ACTIVE_LAPIC_ID.store(id, Ordering::SeqCst);
+/// The local APIC doesn't mark spurious interrupts in service, so they get no EOI
+extern "x86-interrupt" fn spurious(_frame: InterruptStackFrame) {
+    let irq = percpu::current().irq();
+    let _entry = irq.enter(IrqIndex::Spurious as u8);
+    irq.count_spurious();
 
-            // send the very IPI that this handler handles to the next available CPU core on the system
-            unsafe { get_active_lapic().send_ipi(100, ACTIVE_LAPIC_ID.load(Ordering::SeqCst)) };
-        } else {
-            unreachable!()
-        }
+    debug!("Received spurious interrupt");
+}
+
+extern "x86-interrupt" fn lapic_err(_frame: InterruptStackFrame) {
+    let _entry = percpu::current().irq().enter(IrqIndex::LapicErr as u8);
+    let errors = read_esr();
+    unsafe { get_active_lapic().end_of_interrupt() };
+
+    let cpu = percpu::current();
+    let occurrences = cpu.count_lapic_errors(errors);
+
+    error!(
+        "Local APIC error on local APIC ID {}: {:?}",
+        cpu.lapic_id(),
+        errors
+    );
+
+    // A bad vector in an IPI is almost always what keeps coming back
+    if errors.intersects(LapicError::SEND_ILLEGAL_VECTOR | LapicError::RECEIVE_ILLEGAL_VECTOR)
+        && occurrences > 1
+    {
+        error!(
+            "Local APIC: illegal vector error #{}, the last IPI sent from here used vector {}",
+            occurrences,
+            cpu.last_ipi_vector()
+        );
     }
+}
 
-    unsafe {
-        get_active_lapic().end_of_interrupt();
-    };
+/// Sends `signal` to the process running on this CPU
+fn kill_current(signal: Signal) {
+    if let Some(process) = percpu::current_process() {
+        process.write().kill(signal);
+    }
 }
 
 extern "x86-interrupt" fn bound_range_exceeded(frame: InterruptStackFrame) {
     if let PrivilegeLevel::Ring0 = current_privilege_level(*frame) {
         panic!("Bound range exceeded\nStack frame: {:#?}", frame);
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGFPE);
+        kill_current(Signal::SIGFPE);
     }
 }
 
@@ -231,9 +342,7 @@ extern "x86-interrupt" fn invalid_op(frame: InterruptStackFrame) {
             frame
         );
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGILL);
+        kill_current(Signal::SIGILL);
     }
 }
 
@@ -241,26 +350,55 @@ extern "x86-interrupt" fn navail(frame: InterruptStackFrame) {
     if let PrivilegeLevel::Ring0 = current_privilege_level(*frame) {
         panic!("Device not available\nStack frame: {:#?}", frame);
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGSYS);
+        kill_current(Signal::SIGSYS);
     }
 }
 
-extern "x86-interrupt" fn breakpoint(_frame: InterruptStackFrame) {
-    debug!("Reached breakpoint; waiting for debugger to give the all-clear");
-    loop {
-        let int3_ip = read_rip().as_u64();
+extern "x86-interrupt" fn breakpoint(mut frame: InterruptStackFrame) {
+    if let PrivilegeLevel::Ring3 = current_privilege_level(*frame) {
+        kill_current(Signal::SIGTRAP);
+        return;
+    }
 
-        // 0xcc is the INT3 opcode
-        if unsafe { *(int3_ip as *const u8) != 0xcc } {
-            debug!("Resuming");
-            break;
+    let hook = DEBUG_HOOK.load(Ordering::SeqCst);
+
+    if hook != 0 {
+        let hook = unsafe { core::mem::transmute::<usize, DebugHook>(hook) };
+
+        if hook(&mut frame) {
+            return;
         }
     }
+
+    // #BP is a trap, RIP is already past the instruction
+    let rip = frame.instruction_pointer;
+    let int3 = rip - 1u64;
+
+    match vm::peek_byte(int3) {
+        // 0xcc is the INT3 opcode
+        Some(0xcc) => warn!(
+            "Breakpoint at {:#x}, continuing\n{:#?}",
+            int3.as_u64(),
+            frame
+        ),
+        _ => warn!(
+            "Breakpoint exception before {:#x}, continuing\n{:#?}",
+            rip.as_u64(),
+            frame
+        ),
+    }
 }
 
 extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _code: u64) -> ! {
+    // A page fault while pushing the frame of a page fault onto an overflowed stack ends up
+    // here instead
+    if let Some(index) = super::exceptions::ist_guard_page(Cr2::read()) {
+        panic!(
+            "kernel stack overflow on IST {}\nBacktrace: {:#?}",
+            index, frame
+        );
+    }
+
     panic!(
         "Double fault at address {:#x}\nBacktrace: {:#?}",
         frame.instruction_pointer.as_u64(),
@@ -269,35 +407,52 @@ extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _code: u64) -
 }
 
 extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, code: PageFaultErrorCode) {
-    if code.is_empty() {
-        // Create and map the nonexistent page and try again
-        let virt = Cr2::read().as_u64();
-        let phys = Cr2::read().as_u64();
-
-        map_page!(
-            phys,
-            virt,
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
+    let addr = Cr2::read();
+
+    if let Some(index) = super::exceptions::ist_guard_page(addr) {
+        panic!(
+            "kernel stack overflow on IST {}\nBacktrace: {:#?}",
+            index, frame
         );
+    }
+
+    // Only non-present pages in a lazy region may be backed on demand
+    let lazy = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        Err(LazyFaultError::NotLazy)
+    } else {
+        vm::map_lazy(addr, code.contains(PageFaultErrorCode::USER_MODE))
+    };
+
+    let Err(error) = lazy else {
+        return;
+    };
+
+    if let PrivilegeLevel::Ring0 = current_privilege_level(*frame) {
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "present"
+        } else {
+            "non-present"
+        };
 
-        unsafe { frame.iretq() }
-    } else if let PrivilegeLevel::Ring0 = CS::get_reg().rpl() {
-        // kernel mode
         panic!(
-            "Page fault: Attempt to access address {:#x} returned a {:#?} error\n Backtrace: {:#?}",
-            Cr2::read(),
+            "Page fault in kernel mode: {} {} page at {:#x} ({})\nError code: {:#?}\nBacktrace: {:#?}",
+            access,
+            page,
+            addr.as_u64(),
+            error,
             code,
             frame
         );
     } else {
-        // user mode
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGSEGV);
+        kill_current(Signal::SIGSEGV);
     }
 }
 
@@ -305,9 +460,7 @@ extern "x86-interrupt" fn sigfpe(frame: InterruptStackFrame) {
     if let PrivilegeLevel::Ring0 = current_privilege_level(*frame) {
         panic!("Attempt to divide by zero\nBacktrace: {:#?}", frame);
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGFPE);
+        kill_current(Signal::SIGFPE);
     }
 }
 extern "x86-interrupt" fn invalid_tss(frame: InterruptStackFrame, code: u64) {
@@ -348,9 +501,7 @@ extern "x86-interrupt" fn sigbus(frame: InterruptStackFrame, code: u64) {
             frame
         );
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGBUS);
+        kill_current(Signal::SIGBUS);
     }
 }
 
@@ -383,9 +534,7 @@ extern "x86-interrupt" fn sigsegv(frame: InterruptStackFrame, code: u64) {
             );
         }
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGSEGV);
+        kill_current(Signal::SIGSEGV);
     }
 }
 
@@ -431,69 +580,88 @@ extern "x86-interrupt" fn general_protection(frame: InterruptStackFrame, code: u
             )
         }
     } else {
-        (PTABLE.read())[&PTABLE_IDX.load(Ordering::SeqCst)]
-            .write()
-            .kill(Signal::SIGABRT);
+        kill_current(Signal::SIGABRT);
     }
 }
 
-pub extern "x86-interrupt" fn pin_inta(_frame: InterruptStackFrame) {
-    info!("Received IntA interrupt");
-    unsafe { get_active_lapic().end_of_interrupt() };
-}
-
-pub extern "x86-interrupt" fn pin_intb(_frame: InterruptStackFrame) {
-    info!("Received IntB interrupt");
-    unsafe { get_active_lapic().end_of_interrupt() };
-}
-
-pub extern "x86-interrupt" fn pin_intc(_frame: InterruptStackFrame) {
-    info!("Received IntC interrupt");
-    unsafe { get_active_lapic().end_of_interrupt() };
+/// Logs interrupts on the vector reserved for PCI devices
+pub fn pci(vector: u8, _: *mut ()) {
+    debug!("Received PCI interrupt on vector {}", vector);
 }
 
-pub extern "x86-interrupt" fn pin_intd(_frame: InterruptStackFrame) {
-    info!("Received IntD interrupt");
-    unsafe { get_active_lapic().end_of_interrupt() };
-}
+pub fn ahci(vector: u8, _: *mut ()) {
+    info!("Received AHCI interrupt on vector {}", vector);
 
-pub extern "x86-interrupt" fn pci(frame: InterruptStackFrame) {
-    debug!("Received PCI interrupt: {:#?}", &frame);
-    unsafe { get_active_lapic().end_of_interrupt() };
+    ahci_service();
 }
 
-pub extern "x86-interrupt" fn ahci(frame: InterruptStackFrame) {
-    info!("Received AHCI interrupt: {:#?}", &frame);
-
+/// Services every AHCI controller's pending interrupts, without signalling the end of the
+/// interrupt, so it can also run on a shared INTx line.
+///
+/// The driver and port locks keep interrupts disabled while held, so they can't be held by
+/// the code this interrupted. Hotplug events are only acknowledged here and handled by
+/// `ahci::process_hotplug()`, as probing a port takes far too long for an interrupt handler.
+/// Failed ports only get their command engine restarted here, a COMRESET is left to
+/// `ahci::process_resets()`.
+pub fn ahci_service() {
     // Source: https://wiki.osdev.org/AHCI#IRQ_handler
 
-    // Read and write back global HBA interrupt status
-    let status = get_hba().interrupt_status.get();
-    get_hba().interrupt_status.set(status);
-
-    // Read and write back port interrupt status
-    for port in get_ahci().write().ports.as_mut().iter_mut().flatten() {
-        let port_status = port.inner.write().hba_port().is.get();
-
-        // Check error bit and debug if set
-        if port_status.contains(HbaPortIS::HBDS) {
-            warn!("AHCI: Host bus data error");
-        } else if port_status.contains(HbaPortIS::HBFS) {
-            warn!("AHCI: Host bus file error");
-        } else if port_status.contains(HbaPortIS::TFES) {
-            warn!("AHCI: Task file error");
-        } else if port_status.contains(HbaPortIS::CPDS) {
-            warn!("AHCI: Cold port detected");
+    // Controllers may share the interrupt line, so check all of them
+    for driver in (0..).map_while(get_ahci) {
+        let ahci = driver.read();
+        let hba = ahci.hba_mem();
+
+        // Read and write back global HBA interrupt status
+        let status = hba.interrupt_status.get();
+        hba.interrupt_status.set(status);
+
+        // Read and write back port interrupt status
+        for i in (0..32).filter(|i| status.get_bit(*i)) {
+            let port_status = hba.port_mut(i).is.get();
+            let mut hotplug = HbaPortIS::PCS | HbaPortIS::PRCS;
+
+            if let Some(port) = ahci.ports[i].as_ref() {
+                // Check error bit and debug if set
+                if port_status.contains(HbaPortIS::HBDS) {
+                    warn!("AHCI: Host bus data error");
+                    check_aer(driver.bdf());
+                } else if port_status.contains(HbaPortIS::HBFS) {
+                    warn!("AHCI: Host bus file error");
+                    check_aer(driver.bdf());
+                } else if port_status.contains(HbaPortIS::TFES) {
+                    warn!("AHCI: Task file error");
+                } else if port_status.contains(HbaPortIS::CPDS) {
+                    warn!("AHCI: Cold port detected");
+                }
+
+                // D2H register FISes complete regular commands, Set Device Bits FISes complete NCQ
+                // ones and PIO reads end with a PIO Setup FIS
+                if port_status.intersects(
+                    HbaPortIS::DHRS | HbaPortIS::SDBS | HbaPortIS::PSS | HbaPortIS::TFES,
+                ) {
+                    port.inner.write().complete_commands();
+                }
+
+                // Link power state transitions change PhyRdy too, so only trust PCS then
+                if port.power_policy() == PowerPolicy::PowerSave {
+                    hotplug.remove(HbaPortIS::PRCS);
+
+                    if port_status.contains(HbaPortIS::PRCS) {
+                        hba.port_mut(i).clear_phy_ready_change();
+                    }
+                }
+            }
+
+            // A device was plugged in or pulled out. PxIS.PCS stays set until PxSERR.DIAG.X
+            // is cleared, which would raise the interrupt again right away.
+            if port_status.intersects(hotplug) {
+                hba.port_mut(i).serr.set(u32::MAX);
+                driver.queue_hotplug(i);
+            }
+
+            hba.port_mut(i).is.set(port_status);
         }
-
-        port.inner.write().hba_port().is.set(port_status);
     }
-
-    unsafe { get_active_lapic().end_of_interrupt() };
-}
-
-pub extern "x86-interrupt" fn syscall(_: InterruptStackFrame) {
-    todo!("Syscall handler");
 }
 
 #[inline(always)]
@@ -511,19 +679,276 @@ pub(crate) unsafe fn enable_interrupts() {
     interrupts::enable();
 }
 
-/// Function that searches for an open entry in the IDT to map a new handler to
-pub fn irqalloc() -> u8 {
-    for i in 32..=255 {
-        if IDT.read()[i] == Entry::missing() {
-            return i as u8;
+/// Vectors with handlers installed at boot, on top of the exceptions and the vectors below 48
+const FIXED_VECTORS: [u8; 11] = [
+    IrqIndex::Timer as u8,
+    IrqIndex::LapicErr as u8,
+    IrqIndex::IpiWake as u8,
+    IrqIndex::IpiTlb as u8,
+    IrqIndex::IpiSwitch as u8,
+    IrqIndex::IpiPit as u8,
+    IrqIndex::Spurious as u8,
+    0x80,
+    0x82,
+    139,
+    151,
+];
+
+lazy_static! {
+    /// Allocated vectors, with everything that isn't free for drivers reserved up front
+    static ref VECTORS: Mutex<Bitmap<Global>> = {
+        let mut vectors = Bitmap::new(256);
+
+        for vector in 0..48 {
+            vectors.set(vector, true);
+        }
+
+        for vector in FIXED_VECTORS {
+            vectors.set(vector as usize, true);
         }
+
+        Mutex::new(vectors)
+    };
+}
+
+/// Allocates a free vector to map a new handler to
+pub fn irqalloc() -> Option<u8> {
+    irqalloc_contiguous(1)
+}
+
+/// Allocates `count` free vectors in a row starting at a multiple of `count`, as needed for
+/// multiple MSI messages. Returns the first one. `count` has to be a power of two.
+pub fn irqalloc_contiguous(count: usize) -> Option<u8> {
+    let count = count.max(1);
+    let mut vectors = VECTORS.lock();
+
+    let first = vectors.find_unset_aligned(count)?;
+
+    for vector in first..first + count {
+        vectors.set(vector, true);
     }
-    // we've exhausted all entries at this point
-    panic!("Out of IRQs!")
+
+    Some(first as u8)
+}
+
+/// Returns `vector` to the free vectors and removes its handlers
+pub fn irqfree(vector: u8) {
+    let mut vectors = VECTORS.lock();
+
+    if vector < 48 || FIXED_VECTORS.contains(&vector) {
+        warn!("Refusing to free reserved vector {}", vector);
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        IRQ_CHAINS.write().clear(vector);
+        IDT.write()[vector as usize] = Entry::missing();
+        init();
+    });
+
+    vectors.set(vector as usize, false);
+}
+
+// One stub per vector from 32 up, each pushing its vector and jumping to the common entry.
+// The common entry saves the registers the System V ABI lets `irq_dispatch` clobber; the
+// kernel is built without SSE, so those are only general-purpose ones.
+global_asm!(
+    r#"
+    .section .text.irq_stubs, "ax"
+    .balign 16
+    .global irq_stubs
+irq_stubs:
+    irq_vector = 32
+    .rept 224
+    .balign 16
+    pushq $irq_vector
+    jmp irq_common
+    irq_vector = irq_vector + 1
+    .endr
+
+irq_common:
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+
+    # The CPU aligns the stack before the 5 words of the frame, so with the vector and the
+    # 9 registers it is off by one word
+    mov 72(%rsp), %rdi
+    sub $8, %rsp
+    cld
+    call irq_dispatch
+    add $8, %rsp
+
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+
+    # Drop the vector
+    add $8, %rsp
+    iretq
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static irq_stubs: u8;
+}
+
+/// Address of the stub that dispatches `vector`
+fn irq_stub(vector: u8) -> VirtAddr {
+    let base = unsafe { &irq_stubs as *const u8 as u64 };
+    VirtAddr::new(base + irq_stub_offset(vector))
+}
+
+lazy_static! {
+    /// Handlers of every vector, called in the order they were registered
+    static ref IRQ_CHAINS: RwLock<IrqChains> = {
+        let mut chains = IrqChains::new();
+
+        chains.register(
+            139,
+            IrqRegistration {
+                handler: pci,
+                ctx: ptr::null_mut(),
+                name: Some("pci"),
+            },
+        );
+        chains.register(
+            151,
+            IrqRegistration {
+                handler: ahci,
+                ctx: ptr::null_mut(),
+                name: Some("ahci"),
+            },
+        );
+
+        RwLock::new(chains)
+    };
 }
 
-/// Indexes a new handler at a new IDT entry created by `irqalloc()` fn
-pub fn register_handler(irq: u8, handler: extern "x86-interrupt" fn(InterruptStackFrame)) {
-    IDT.write()[irq as usize].set_handler_fn(handler);
-    init();
+/// Common entry of every vector's stub: calls the handlers registered for `vector`, then
+/// signals the end of the interrupt. Level-triggered lines assert again right after the EOI
+/// unless the handlers silenced their devices, so it has to come last. Handlers must leave
+/// interrupts disabled.
+#[no_mangle]
+extern "C" fn irq_dispatch(vector: u64) {
+    let vector = vector as u8;
+    let _entry = percpu::current().irq().enter(vector);
+
+    // Handlers can't register or unregister handlers themselves, which would wait for this
+    // lock. Everyone else takes the write lock with interrupts off, so it can't be held here.
+    for registration in IRQ_CHAINS.read().chain(vector) {
+        (registration.handler)(vector, registration.ctx);
+
+        debug_assert!(
+            !is_enabled(),
+            "handler {:?} of vector {} enabled interrupts",
+            registration.name,
+            vector
+        );
+    }
+
+    unsafe { get_active_lapic().end_of_interrupt() };
+}
+
+/// Adds `handler` to the handlers of `vector`, to be called with `ctx` on every interrupt on
+/// it. Usually `vector` comes from [`irqalloc`]; registering on a vector another device
+/// already uses shares it. `name` labels the handler in [`log_stats`].
+pub fn register_irq(vector: u8, handler: IrqHandler, ctx: *mut (), name: Option<&'static str>) {
+    // An interrupt must not see the entry half written, nor take the read lock on this CPU
+    // while the write lock is held
+    interrupts::without_interrupts(|| {
+        IRQ_CHAINS
+            .write()
+            .register(vector, IrqRegistration { handler, ctx, name });
+
+        unsafe { IDT.write()[vector as usize].set_handler_addr(irq_stub(vector)) };
+        init();
+    });
+}
+
+/// Removes `handler` with the context `ctx` from the handlers of `vector`. The IDT entry goes
+/// away with the last handler, the vector stays allocated until [`irqfree`].
+pub fn unregister_irq(vector: u8, handler: IrqHandler, ctx: *mut ()) {
+    interrupts::without_interrupts(|| {
+        if IRQ_CHAINS.write().unregister(vector, handler, ctx) {
+            IDT.write()[vector as usize] = Entry::missing();
+            init();
+        }
+    });
+}
+
+/// The interrupt counters of every CPU
+pub fn stats() -> Vec<CpuIrqStats> {
+    percpu::all()
+        .map(|cpu| cpu.irq().snapshot(cpu.cpu_id()))
+        .collect()
+}
+
+/// What the vectors with a handler of their own in the IDT are used for
+fn fixed_vector_name(vector: u8) -> Option<&'static str> {
+    match vector {
+        0..=31 => Some("exception"),
+        v if v == IrqIndex::Timer as u8 => Some("timer"),
+        v if v == IrqIndex::LapicErr as u8 => Some("local APIC error"),
+        v if v == IrqIndex::IpiTlb as u8 => Some("TLB shootdown"),
+        v if v == IrqIndex::Spurious as u8 => Some("spurious"),
+        v if v == IrqIndex::IpiWake as u8 => Some("reschedule"),
+        0x80 => Some("syscall"),
+        _ => None,
+    }
+}
+
+/// Logs every vector that was taken so far, summed up and per CPU, with the names of its
+/// handlers
+pub fn log_stats() {
+    let stats = stats();
+
+    for vector in 0..=255u8 {
+        let total = stats
+            .iter()
+            .map(|cpu| cpu.counts[vector as usize])
+            .sum::<u64>();
+
+        if total == 0 {
+            continue;
+        }
+
+        let handlers = IRQ_CHAINS
+            .read()
+            .chain(vector)
+            .iter()
+            .map(|registration| registration.name.unwrap_or("unnamed"))
+            .chain(fixed_vector_name(vector))
+            .collect::<Vec<_>>();
+
+        let per_cpu = stats
+            .iter()
+            .map(|cpu| cpu.counts[vector as usize])
+            .collect::<Vec<_>>();
+
+        info!(
+            "Vector {:3}: {} interrupts ({:?} per CPU), handlers {:?}",
+            vector, total, per_cpu, handlers
+        );
+    }
+
+    for cpu in &stats {
+        info!(
+            "CPU {}: {} spurious interrupts, interrupt handlers nested up to {} deep",
+            cpu.cpu_id, cpu.spurious, cpu.max_depth
+        );
+    }
 }
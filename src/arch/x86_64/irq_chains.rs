@@ -0,0 +1,174 @@
+//! Handlers registered per vector and the stubs dispatching to them
+
+use alloc::{vec, vec::Vec};
+
+/// Called for every interrupt on a vector it was registered for, with the vector and the
+/// context passed to [`register_irq`]. Vectors can be shared, so it has to check whether its
+/// device actually raised the interrupt. The dispatcher signals the end of the interrupt.
+///
+/// [`register_irq`]: super::interrupts::register_irq
+pub type IrqHandler = fn(u8, *mut ());
+
+/// Distance between two stubs in `irq_stubs`
+pub(crate) const IRQ_STUB_SIZE: u64 = 16;
+/// First vector past the exceptions, and the first one with a stub
+pub(crate) const FIRST_IRQ_VECTOR: u8 = 32;
+
+/// Offset of the stub that dispatches `vector` from the first one
+pub(crate) fn irq_stub_offset(vector: u8) -> u64 {
+    assert!(vector >= FIRST_IRQ_VECTOR, "vector {} has no stub", vector);
+
+    (vector - FIRST_IRQ_VECTOR) as u64 * IRQ_STUB_SIZE
+}
+
+/// A handler and the context it was registered with
+#[derive(Clone, Copy)]
+pub(crate) struct IrqRegistration {
+    pub handler: IrqHandler,
+    pub ctx: *mut (),
+    /// Shown by `log_stats`
+    pub name: Option<&'static str>,
+}
+
+// The context is only ever handed back to the handler that brought it
+unsafe impl Send for IrqRegistration {}
+unsafe impl Sync for IrqRegistration {}
+
+impl IrqRegistration {
+    fn is(&self, handler: IrqHandler, ctx: *mut ()) -> bool {
+        self.handler as usize == handler as usize && self.ctx == ctx
+    }
+}
+
+/// Handlers of every vector, called in the order they were registered
+pub(crate) struct IrqChains(Vec<Vec<IrqRegistration>>);
+
+impl IrqChains {
+    pub(crate) fn new() -> Self {
+        Self(vec![Vec::new(); 256])
+    }
+
+    /// Handlers of `vector`
+    pub(crate) fn chain(&self, vector: u8) -> &[IrqRegistration] {
+        &self.0[vector as usize]
+    }
+
+    pub(crate) fn register(&mut self, vector: u8, registration: IrqRegistration) {
+        self.0[vector as usize].push(registration);
+    }
+
+    /// Removes `handler` with the context `ctx` from the handlers of `vector`. Returns
+    /// whether that was the last one, so the vector's IDT entry has to go.
+    pub(crate) fn unregister(&mut self, vector: u8, handler: IrqHandler, ctx: *mut ()) -> bool {
+        let chain = &mut self.0[vector as usize];
+
+        chain.retain(|registration| !registration.is(handler, ctx));
+        chain.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self, vector: u8) {
+        self.0[vector as usize].clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        ptr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Counts its calls in the `AtomicUsize` behind `ctx`
+    fn count(_vector: u8, ctx: *mut ()) {
+        unsafe { &*(ctx as *const AtomicUsize) }.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn ctx(counter: &AtomicUsize) -> *mut () {
+        counter as *const AtomicUsize as *mut ()
+    }
+
+    fn registration(handler: IrqHandler, ctx: *mut ()) -> IrqRegistration {
+        IrqRegistration {
+            handler,
+            ctx,
+            name: None,
+        }
+    }
+
+    /// What `irq_dispatch` does with an interrupt on `vector`
+    fn fire(chains: &IrqChains, vector: u8) {
+        for registration in chains.chain(vector) {
+            (registration.handler)(vector, registration.ctx);
+        }
+    }
+
+    #[test]
+    fn handlers_registered_after_boot_run() {
+        let mut chains = IrqChains::new();
+        let counter = AtomicUsize::new(0);
+
+        fire(&chains, 0x41);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+
+        chains.register(0x41, registration(count, ctx(&counter)));
+
+        fire(&chains, 0x41);
+        fire(&chains, 0x41);
+        fire(&chains, 0x42);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn shared_vectors_run_every_handler() {
+        let mut chains = IrqChains::new();
+        let (first, second) = (AtomicUsize::new(0), AtomicUsize::new(0));
+
+        chains.register(0x50, registration(count, ctx(&first)));
+        chains.register(0x50, registration(count, ctx(&second)));
+
+        fire(&chains, 0x50);
+        assert_eq!(first.load(Ordering::Relaxed), 1);
+        assert_eq!(second.load(Ordering::Relaxed), 1);
+
+        // Only the handler with the same context goes away
+        assert!(!chains.unregister(0x50, count, ctx(&first)));
+
+        fire(&chains, 0x50);
+        assert_eq!(first.load(Ordering::Relaxed), 1);
+        assert_eq!(second.load(Ordering::Relaxed), 2);
+
+        assert!(chains.unregister(0x50, count, ctx(&second)));
+        assert!(chains.chain(0x50).is_empty());
+    }
+
+    #[test]
+    fn unregistering_unknown_handlers_keeps_the_others() {
+        fn other(_: u8, _: *mut ()) {}
+
+        let mut chains = IrqChains::new();
+        let counter = AtomicUsize::new(0);
+
+        chains.register(0x60, registration(count, ctx(&counter)));
+
+        assert!(!chains.unregister(0x60, other, ctx(&counter)));
+        assert!(!chains.unregister(0x60, count, ptr::null_mut()));
+        assert_eq!(chains.chain(0x60).len(), 1);
+
+        chains.clear(0x60);
+        assert!(chains.chain(0x60).is_empty());
+    }
+
+    #[test]
+    fn every_vector_past_the_exceptions_has_a_stub() {
+        assert_eq!(irq_stub_offset(FIRST_IRQ_VECTOR), 0);
+        assert_eq!(irq_stub_offset(139), (139 - 32) * IRQ_STUB_SIZE);
+        assert_eq!(irq_stub_offset(255), 223 * IRQ_STUB_SIZE);
+    }
+
+    #[test]
+    #[should_panic]
+    fn exceptions_have_no_stub() {
+        irq_stub_offset(31);
+    }
+}
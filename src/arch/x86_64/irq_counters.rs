@@ -0,0 +1,138 @@
+//! Per-CPU interrupt counters
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Interrupts one CPU took, kept in its per-CPU block
+#[derive(Debug)]
+pub struct IrqCounters {
+    /// Interrupts taken, by vector
+    counts: [AtomicU64; 256],
+    /// Spurious interrupts taken
+    spurious: AtomicU64,
+    /// Interrupt handlers currently running on the CPU
+    depth: AtomicU32,
+    /// Deepest the handlers ever nested
+    max_depth: AtomicU32,
+}
+
+impl IrqCounters {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; 256],
+            spurious: AtomicU64::new(0),
+            depth: AtomicU32::new(0),
+            max_depth: AtomicU32::new(0),
+        }
+    }
+
+    /// Counts an interrupt on `vector`. The handler counts as running until the returned
+    /// guard is dropped.
+    pub(crate) fn enter(&self, vector: u8) -> IrqEntry<'_> {
+        self.counts[vector as usize].fetch_add(1, Ordering::Relaxed);
+
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+
+        IrqEntry(self)
+    }
+
+    pub(crate) fn count_spurious(&self) {
+        self.spurious.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, cpu_id: usize) -> CpuIrqStats {
+        CpuIrqStats {
+            cpu_id,
+            counts: core::array::from_fn(|vector| self.counts[vector].load(Ordering::Relaxed)),
+            spurious: self.spurious.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A handler running, see [`IrqCounters::enter`]
+pub(crate) struct IrqEntry<'a>(&'a IrqCounters);
+
+impl Drop for IrqEntry<'_> {
+    fn drop(&mut self) {
+        self.0.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Interrupt counters of one CPU at the time of [`stats`]
+///
+/// [`stats`]: super::interrupts::stats
+#[derive(Debug, Clone)]
+pub struct CpuIrqStats {
+    pub cpu_id: usize,
+    /// Interrupts taken, by vector
+    pub counts: [u64; 256],
+    pub spurious: u64,
+    /// Deepest the interrupt handlers nested
+    pub max_depth: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMER: u8 = 0xFE;
+    const AHCI: u8 = 151;
+    const SPURIOUS: u8 = 0xFF;
+
+    #[test]
+    fn timer_ticks_are_taken_while_ahci_interrupts_are_in_service() {
+        let irq = IrqCounters::new();
+
+        for _ in 0..100 {
+            let _ahci = irq.enter(AHCI);
+
+            // The AHCI handler sent its EOI, so the timer gets through on top of it
+            for _ in 0..10 {
+                let _timer = irq.enter(TIMER);
+            }
+        }
+
+        let stats = irq.snapshot(0);
+
+        assert_eq!(stats.counts[AHCI as usize], 100);
+        assert_eq!(stats.counts[TIMER as usize], 1000);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(irq.depth.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn handlers_count_as_running_until_they_return() {
+        let irq = IrqCounters::new();
+
+        let first = irq.enter(TIMER);
+        let second = irq.enter(AHCI);
+        assert_eq!(irq.depth.load(Ordering::Relaxed), 2);
+
+        drop(second);
+        drop(first);
+        assert_eq!(irq.depth.load(Ordering::Relaxed), 0);
+
+        // Handlers taken one after another don't nest
+        drop(irq.enter(TIMER));
+        drop(irq.enter(TIMER));
+        assert_eq!(irq.snapshot(0).max_depth, 2);
+    }
+
+    #[test]
+    fn spurious_interrupts_are_counted_once_each() {
+        let irq = IrqCounters::new();
+
+        for _ in 0..3 {
+            let _entry = irq.enter(SPURIOUS);
+            irq.count_spurious();
+        }
+
+        let stats = irq.snapshot(1);
+
+        assert_eq!(stats.cpu_id, 1);
+        assert_eq!(stats.spurious, 3);
+        assert_eq!(stats.counts[SPURIOUS as usize], 3);
+        assert_eq!(stats.counts.iter().sum::<u64>(), 3);
+    }
+}
@@ -0,0 +1,255 @@
+//! Machine-check architecture
+//!
+//! Hardware errors are reported through banks of MSRs, IA32_MCG_CAP says how many. Errors the
+//! hardware corrected are cleared and logged, anything else is decoded into the panic message.
+
+use core::fmt;
+
+use bit_field::BitField;
+use raw_cpuid::CpuId;
+use x86_64::{
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::serial::emergency_print;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+
+/// IA32_MCG_CAP: the number of banks
+const MCG_CAP_COUNT: core::ops::Range<usize> = 0..8;
+/// IA32_MCG_CAP: IA32_MCG_CTL is present
+const MCG_CAP_CTL_P: usize = 8;
+
+/// IA32_MCG_STATUS: execution can restart at the saved RIP
+const MCG_STATUS_RIPV: usize = 0;
+/// IA32_MCG_STATUS: the saved RIP is where the error happened
+const MCG_STATUS_EIPV: usize = 1;
+
+/// IA32_MCi_STATUS: the bank holds an error
+const STATUS_VAL: usize = 63;
+/// IA32_MCi_STATUS: another error came in while the bank was still full
+const STATUS_OVER: usize = 62;
+/// IA32_MCi_STATUS: the hardware didn't correct the error
+const STATUS_UC: usize = 61;
+/// IA32_MCi_STATUS: IA32_MCi_ADDR holds the address the error happened at
+const STATUS_ADDRV: usize = 58;
+/// IA32_MCi_STATUS: the processor context may be corrupt
+const STATUS_PCC: usize = 57;
+
+/// Banks beyond this many are ignored, no CPU implements that many
+const MAX_BANKS: usize = 32;
+
+fn bank_ctl(bank: usize) -> Msr {
+    Msr::new(0x400 + 4 * bank as u32)
+}
+
+fn bank_status(bank: usize) -> Msr {
+    Msr::new(0x401 + 4 * bank as u32)
+}
+
+fn bank_addr(bank: usize) -> Msr {
+    Msr::new(0x402 + 4 * bank as u32)
+}
+
+fn bank_count() -> usize {
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    (cap.get_bits(MCG_CAP_COUNT) as usize).min(MAX_BANKS)
+}
+
+/// Names the class of a compound or simple MCA error code, following section 16.9 of the
+/// Intel SDM, volume 3B
+fn describe_error_code(code: u16) -> &'static str {
+    // Bit 12 only says whether corrected errors were filtered
+    let code = code & !(1 << 12);
+
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified error",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error",
+        0x0004 => "FRC error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer error",
+        _ if code & 0xFFFC == 0x000C => "generic cache hierarchy error",
+        _ if code & 0xFFF0 == 0x0010 => "TLB error",
+        _ if code & 0xFF80 == 0x0080 => "memory controller error",
+        _ if code & 0xFF00 == 0x0100 => "cache hierarchy error",
+        _ if code & 0xF800 == 0x0800 => "bus or interconnect error",
+        _ if code & 0xFC00 == 0x0400 => "internal unclassified error",
+        _ => "unknown error",
+    }
+}
+
+/// Cache level named by the LL field of compound error codes
+fn describe_level(code: u16) -> &'static str {
+    match code & 0b11 {
+        0 => "L0",
+        1 => "L1",
+        2 => "L2",
+        _ => "generic level",
+    }
+}
+
+/// The error logged in one bank
+#[derive(Debug, Clone, Copy)]
+struct BankError {
+    bank: usize,
+    status: u64,
+    addr: Option<u64>,
+}
+
+impl BankError {
+    fn read(bank: usize) -> Option<Self> {
+        let status = unsafe { bank_status(bank).read() };
+
+        if !status.get_bit(STATUS_VAL) {
+            return None;
+        }
+
+        Some(Self {
+            bank,
+            status,
+            addr: status
+                .get_bit(STATUS_ADDRV)
+                .then(|| unsafe { bank_addr(bank).read() }),
+        })
+    }
+
+    fn uncorrected(&self) -> bool {
+        self.status.get_bit(STATUS_UC) || self.status.get_bit(STATUS_PCC)
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.status.get_bits(0..16) as u16;
+
+        write!(
+            f,
+            "bank {}: {} ({}, code {:#06x}, status {:#018x})",
+            self.bank,
+            describe_error_code(code),
+            describe_level(code),
+            code,
+            self.status
+        )?;
+
+        if self.status.get_bit(STATUS_UC) {
+            write!(f, ", uncorrected")?;
+        }
+
+        if self.status.get_bit(STATUS_PCC) {
+            write!(f, ", processor context corrupt")?;
+        }
+
+        if self.status.get_bit(STATUS_OVER) {
+            write!(f, ", overflowed")?;
+        }
+
+        if let Some(addr) = self.addr {
+            write!(f, ", address {:#x}", addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Every error the banks held when the exception came in, formatted into the panic message
+/// without allocating
+struct MachineCheck {
+    mcg_status: u64,
+    errors: [Option<BankError>; MAX_BANKS],
+}
+
+impl fmt::Display for MachineCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IA32_MCG_STATUS {:#x} (restartable: {}, at the error: {})",
+            self.mcg_status,
+            self.mcg_status.get_bit(MCG_STATUS_RIPV),
+            self.mcg_status.get_bit(MCG_STATUS_EIPV)
+        )?;
+
+        for error in self.errors.iter().flatten() {
+            write!(f, "\n  {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Enables machine checks on the executing CPU, reporting every error class every bank knows
+/// of. Errors left over from before the reset are logged and cleared first.
+pub fn init() {
+    let has_mca = CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_mce() && info.has_mca());
+
+    if !has_mca {
+        return;
+    }
+
+    unsafe {
+        let cap = Msr::new(IA32_MCG_CAP).read();
+
+        if cap.get_bit(MCG_CAP_CTL_P) {
+            Msr::new(IA32_MCG_CTL).write(u64::MAX);
+        }
+
+        for bank in 0..bank_count() {
+            if let Some(error) = BankError::read(bank) {
+                emergency_print(format_args!("MCE: left over from before boot: {}\n", error));
+            }
+
+            bank_ctl(bank).write(u64::MAX);
+            bank_status(bank).write(0);
+        }
+
+        Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+    }
+}
+
+/// Handles a machine-check exception. Returns if only corrected errors were logged and the
+/// interrupted code can be restarted, panics with the decoded banks otherwise.
+pub(crate) fn machine_check_service(frame: &InterruptStackFrame) {
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let mut check = MachineCheck {
+        mcg_status,
+        errors: [None; MAX_BANKS],
+    };
+
+    for bank in 0..bank_count() {
+        check.errors[bank] = BankError::read(bank);
+    }
+
+    let fatal = !mcg_status.get_bit(MCG_STATUS_RIPV)
+        || check.errors.iter().flatten().any(BankError::uncorrected);
+
+    if fatal {
+        panic!(
+            "Machine check at {:#x}: {}",
+            frame.instruction_pointer.as_u64(),
+            check
+        );
+    }
+
+    // The logger's locks may be held by the interrupted code
+    emergency_print(format_args!("MCE: corrected errors: {}\n", check));
+
+    unsafe {
+        for error in check.errors.iter().flatten() {
+            bank_status(error.bank).write(0);
+        }
+
+        // Clears MCIP, another machine check while it is set shuts the CPU down
+        Msr::new(IA32_MCG_STATUS).write(0);
+    }
+}
@@ -1,3 +1,11 @@
+pub mod context;
 pub mod exceptions;
 pub mod interrupts;
+mod irq_chains;
+mod irq_counters;
+pub mod mce;
+pub mod percpu;
+pub mod smp;
 pub mod syscall;
+pub mod tlb;
+pub mod wakeup;
@@ -0,0 +1,262 @@
+//! Per-CPU data
+//!
+//! Every CPU gets a block of its own, whose address goes into IA32_GS_BASE when the CPU is
+//! set up. The block starts with a pointer to itself, so [`current`] finds it with a single
+//! load from `gs:0`. Loading a selector into GS clears the base, so [`init`] has to run after
+//! the GDT is loaded.
+//!
+//! While a CPU runs user code, the block's address waits in IA32_KERNEL_GS_BASE instead, the
+//! kernel entry points get it back with `swapgs`.
+
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use raw_cpuid::CpuId;
+use spin::{Mutex, RwLock};
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::GsBase, VirtAddr,
+};
+
+use super::{context::Context, interrupts::IrqCounters, tlb::Mailbox};
+use crate::{
+    apic_impl::LapicError,
+    process::{sched::Task, Process, RunQueue},
+};
+
+/// Value of `current_task` while the CPU runs no process
+const NO_TASK: usize = usize::MAX;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, has to stay the first field
+    this: *const PerCpu,
+    /// Top of the stack system calls run on, `syscall_entry` reads it from `gs:8`
+    kernel_stack: VirtAddr,
+    /// Stack pointer of the caller while a system call runs, `syscall_entry` keeps it at `gs:16`
+    user_rsp: AtomicU64,
+    cpu_id: usize,
+    lapic_id: u32,
+    /// Key of the process this CPU runs in its run queue
+    current_task: AtomicUsize,
+    /// Processes this CPU schedules, only taken with interrupts disabled
+    run_queue: Mutex<RunQueue<Box<Task>>>,
+    /// Where the code on the CPU's own stack left off while a task runs
+    own_context: UnsafeCell<Context>,
+    /// Timer interrupts taken
+    ticks: AtomicU64,
+    /// TSC value of the next tick, if the timer runs in TSC-deadline mode
+    next_tick: AtomicU64,
+    /// Ticks the current task may still run before it is preempted
+    slice_left: AtomicU64,
+    /// Processes switched to
+    context_switches: AtomicU64,
+    /// TLB flushes other CPUs asked for
+    tlb: Mailbox,
+    /// Errors the local APIC reported, by bit of its error status register
+    lapic_errors: [AtomicU64; 8],
+    /// Vector of the last IPI this CPU sent
+    last_ipi_vector: AtomicU8,
+    /// Interrupts taken, see `interrupts::stats()`
+    irq: IrqCounters,
+}
+
+// Everything but the pointer to itself and the CPU's own context is atomic, locked or never
+// changes. The context is only touched by the CPU itself, with interrupts disabled.
+unsafe impl Sync for PerCpu {}
+unsafe impl Send for PerCpu {}
+
+/// The block of every CPU that ran `init()`, by CPU index
+static CPUS: RwLock<Vec<&'static PerCpu>> = RwLock::new(Vec::new());
+
+impl PerCpu {
+    /// Index of the CPU, 0 for the BSP and counting up in the order the APs came online
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
+    pub fn lapic_id(&self) -> u32 {
+        self.lapic_id
+    }
+
+    /// Key of the process running on this CPU in its run queue
+    pub fn current_task(&self) -> Option<usize> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            task => Some(task),
+        }
+    }
+
+    pub fn set_current_task(&self, task: Option<usize>) {
+        self.current_task
+            .store(task.unwrap_or(NO_TASK), Ordering::Relaxed);
+    }
+
+    /// Runs `f` on the processes this CPU schedules, with interrupts disabled so the timer
+    /// can't schedule from under it
+    pub(crate) fn with_run_queue<R>(&self, f: impl FnOnce(&mut RunQueue<Box<Task>>) -> R) -> R {
+        without_interrupts(|| f(&mut self.run_queue.lock()))
+    }
+
+    /// Where the code on the CPU's own stack, like the main loop, left off while a task runs
+    pub(crate) fn own_context(&self) -> *mut Context {
+        self.own_context.get()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn count_tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn next_tick(&self) -> u64 {
+        self.next_tick.load(Ordering::Relaxed)
+    }
+
+    pub fn set_next_tick(&self, tsc: u64) {
+        self.next_tick.store(tsc, Ordering::Relaxed);
+    }
+
+    /// Gives the task about to run `ticks` timer ticks
+    pub fn refill_slice(&self, ticks: u64) {
+        self.slice_left.store(ticks, Ordering::Relaxed);
+    }
+
+    /// Takes a tick off the current task's slice. Returns whether the slice ran out.
+    pub fn consume_tick(&self) -> bool {
+        self.slice_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(1))
+            })
+            .unwrap_or(0)
+            <= 1
+    }
+
+    pub fn context_switches(&self) -> u64 {
+        self.context_switches.load(Ordering::Relaxed)
+    }
+
+    pub fn count_context_switch(&self) {
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tlb(&self) -> &Mailbox {
+        &self.tlb
+    }
+
+    /// Counts each error in `errors`. Returns how often the most frequent of them occurred
+    /// on this CPU so far.
+    pub fn count_lapic_errors(&self, errors: LapicError) -> u64 {
+        (0..8)
+            .filter(|&bit| errors.bits() & (1 << bit) != 0)
+            .map(|bit| self.lapic_errors[bit].fetch_add(1, Ordering::Relaxed) + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// How often each error occurred on this CPU
+    pub fn lapic_errors(&self) -> impl Iterator<Item = (LapicError, u64)> + '_ {
+        self.lapic_errors.iter().enumerate().map(|(bit, count)| {
+            (
+                LapicError::from_bits_truncate(1 << bit),
+                count.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    pub fn last_ipi_vector(&self) -> u8 {
+        self.last_ipi_vector.load(Ordering::Relaxed)
+    }
+
+    pub fn note_ipi_sent(&self, vector: u8) {
+        self.last_ipi_vector.store(vector, Ordering::Relaxed);
+    }
+
+    pub fn irq(&self) -> &IrqCounters {
+        &self.irq
+    }
+}
+
+/// Local APIC ID of the executing CPU as CPUID reports it, which works before the local APIC
+/// is mapped
+fn cpuid_lapic_id() -> u32 {
+    let cpuid = CpuId::new();
+
+    cpuid
+        .get_extended_topology_info()
+        .and_then(|mut levels| levels.next())
+        .map(|level| level.x2apic_id())
+        .or_else(|| {
+            cpuid
+                .get_feature_info()
+                .map(|info| info.initial_local_apic_id() as u32)
+        })
+        .unwrap_or(0)
+}
+
+/// Allocates the block of the executing CPU and points GS at it. `kernel_stack` is the top of
+/// the stack the TSS switches to when entering the kernel from user mode.
+pub fn init(cpu_id: usize, kernel_stack: VirtAddr) {
+    let block = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        // `syscall` leaves the stack as it is, unlike interrupts
+        kernel_stack: kernel_stack.align_down(16u64),
+        user_rsp: AtomicU64::new(0),
+        cpu_id,
+        lapic_id: cpuid_lapic_id(),
+        current_task: AtomicUsize::new(NO_TASK),
+        run_queue: Mutex::new(RunQueue::new()),
+        own_context: UnsafeCell::new(Context::empty()),
+        ticks: AtomicU64::new(0),
+        next_tick: AtomicU64::new(0),
+        slice_left: AtomicU64::new(0),
+        context_switches: AtomicU64::new(0),
+        tlb: Mailbox::new(),
+        lapic_errors: Default::default(),
+        last_ipi_vector: AtomicU8::new(0),
+        irq: IrqCounters::new(),
+    }));
+
+    block.this = block as *const PerCpu;
+    GsBase::write(VirtAddr::from_ptr(block as *const PerCpu));
+
+    CPUS.write().push(block);
+}
+
+/// The blocks of every CPU set up so far
+pub fn all() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.read().clone().into_iter()
+}
+
+/// The block of the executing CPU. Must not be called before [`init`] ran on it.
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) this,
+            options(nostack, preserves_flags, readonly)
+        );
+
+        &*this
+    }
+}
+
+/// The block of the executing CPU, or `None` if [`init`] didn't run on it yet. For code that
+/// can interrupt anything, like the NMI handler.
+pub fn try_current() -> Option<&'static PerCpu> {
+    (GsBase::read().as_u64() != 0).then(current)
+}
+
+/// The process running on the executing CPU
+pub fn current_process() -> Option<Arc<RwLock<Process<'static>>>> {
+    let cpu = current();
+
+    cpu.with_run_queue(|queue| Some(Arc::clone(&queue.get(cpu.current_task()?)?.process)))
+}
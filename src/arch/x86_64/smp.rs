@@ -0,0 +1,336 @@
+//! Starting the application processors
+//!
+//! Every AP from the MADT is started with INIT-SIPI-SIPI, one after the other. The SIPI points
+//! it at a real-mode trampoline below 1MiB which, like the S3 wakeup trampoline, switches
+//! straight to long mode on the kernel's page tables. From there it jumps onto a stack of its
+//! own into [`ap_main`], which loads the AP's GDT and IDT, enables its local APIC and checks
+//! in.
+
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use acpi::platform::{ProcessorInfo, ProcessorState};
+use alloc::{alloc::Global, vec, vec::Vec};
+use spin::{Once, RwLock};
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::{
+        control::{Cr0, Cr3, Cr4},
+        model_specific::{Efer, Msr},
+    },
+    structures::paging::{PageTableFlags, Size4KiB},
+};
+
+use crate::{
+    acpi_impl::{pm_timer_poll, pm_timer_stall},
+    apic_impl::{get_active_lapic, local_apic_id},
+    cralloc::take_low_frame,
+    get_phys_offset, map_page,
+};
+
+use log::*;
+
+const IA32_PAT: u32 = 0x277;
+/// EFER.LMA is read-only, the CPU sets it once paging is enabled
+const EFER_LMA: u64 = 1 << 10;
+
+/// Size of the stack an AP starts on
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// Wait between INIT and the first SIPI
+const INIT_DELAY_US: u64 = 10_000;
+/// Wait after each SIPI
+const SIPI_DELAY_US: u64 = 200;
+/// How long an AP gets to check in after its second SIPI
+const CHECK_IN_TIMEOUT_US: u64 = 100_000;
+
+global_asm!(
+    r#"
+    .section .rodata.smp, "a"
+    .balign 16
+    .global smp_trampoline_start
+smp_trampoline_start:
+    .code16
+    cli
+    cld
+
+    # The SIPI starts the AP at CS:IP = vector << 8 : 0, data is addressed relative to that
+    mov %cs, %ax
+    mov %ax, %ds
+
+    lgdtl (smp_gdtr - smp_trampoline_start)
+
+    mov %cr4, %eax
+    or $0x20, %eax
+    mov %eax, %cr4
+
+    movl (smp_cr3 - smp_trampoline_start), %eax
+    mov %eax, %cr3
+
+    mov $0xc0000080, %ecx
+    movl (smp_efer - smp_trampoline_start), %eax
+    xor %edx, %edx
+    wrmsr
+
+    # Paging and protection at once, which lands directly in long mode
+    mov %cr0, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+
+    ljmpl *(smp_far - smp_trampoline_start)
+
+    .code64
+    .global smp_long_entry
+smp_long_entry:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov smp_stack(%rip), %rsp
+
+    # The call leaves the stack aligned the way the callee expects
+    call *smp_target(%rip)
+1:
+    hlt
+    jmp 1b
+
+    .balign 8
+    .global smp_gdt
+smp_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+    .global smp_gdtr
+smp_gdtr:
+    .word 23
+    .long 0
+    .global smp_far
+smp_far:
+    .long 0
+    .word 0x08
+    .global smp_cr3
+smp_cr3:
+    .long 0
+    .global smp_efer
+smp_efer:
+    .long 0
+    .global smp_stack
+smp_stack:
+    .quad 0
+    .global smp_target
+smp_target:
+    .quad 0
+    .global smp_trampoline_end
+smp_trampoline_end:
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_gdt: u8;
+    static smp_gdtr: u8;
+    static smp_far: u8;
+    static smp_long_entry: u8;
+    static smp_cr3: u8;
+    static smp_efer: u8;
+    static smp_stack: u8;
+    static smp_target: u8;
+}
+
+/// Control registers of the BSP, which the APs take over once in long mode
+struct BspState {
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    pat: u64,
+}
+
+static BSP_STATE: Once<BspState> = Once::new();
+
+/// Number of CPUs running kernel code, the BSP included
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Set by the AP being started once it is up
+static AP_CHECKED_IN: AtomicBool = AtomicBool::new(false);
+/// Local APIC IDs of the CPUs running kernel code
+static ONLINE_LAPIC_IDS: RwLock<Vec<u32>> = RwLock::new(Vec::new());
+
+/// Offset of `symbol` from the start of the trampoline
+fn trampoline_offset(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - unsafe { &smp_trampoline_start as *const u8 as u64 }
+}
+
+/// Number of CPUs running kernel code
+pub fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::SeqCst)
+}
+
+/// Local APIC IDs of the CPUs running kernel code, starting with the BSP. Only the current
+/// CPU before [`start_aps`] ran.
+pub fn online_lapic_ids() -> impl Iterator<Item = u32> + Clone {
+    let ids = ONLINE_LAPIC_IDS.read();
+
+    if ids.is_empty() {
+        return vec![local_apic_id()].into_iter();
+    }
+
+    ids.clone().into_iter()
+}
+
+/// Entry point of an AP, called by the trampoline on its own stack
+extern "C" fn ap_main() -> ! {
+    let state = BSP_STATE.get().expect("AP started without the BSP's state");
+
+    unsafe {
+        Cr4::write_raw(state.cr4);
+        Cr0::write_raw(state.cr0);
+        Efer::write_raw(state.efer);
+        Msr::new(IA32_PAT).write(state.pat);
+    }
+
+    let kernel_stack = super::exceptions::init_ap();
+    super::interrupts::init();
+
+    // The APs come up one at a time, so the count so far is free as an index
+    super::percpu::init(cpus_online(), kernel_stack);
+    super::mce::init();
+    super::syscall::init();
+
+    let lapic = get_active_lapic();
+
+    unsafe { lapic.enable() };
+
+    // Drives preemption here, the rate was calibrated on the BSP already
+    crate::time::start_lapic_timer(lapic);
+
+    let id = local_apic_id();
+    ONLINE_LAPIC_IDS.write().push(id);
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+    AP_CHECKED_IN.store(true, Ordering::SeqCst);
+
+    interrupts::enable();
+
+    loop {
+        hlt();
+    }
+}
+
+/// Copies the trampoline into a frame below 1MiB and identity-maps it. Returns the SIPI vector,
+/// which is the number of that frame, and a pointer to the trampoline's stack slot.
+fn prepare_trampoline() -> Option<(u8, *mut u64)> {
+    let frame = take_low_frame()?;
+    let phys = frame.start_address().as_u64();
+    let base = (phys + get_phys_offset()) as *mut u8;
+
+    map_page!(
+        phys,
+        phys,
+        Size4KiB,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    );
+    map_page!(
+        phys,
+        phys + get_phys_offset(),
+        Size4KiB,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    );
+
+    let (pml4, _) = Cr3::read();
+
+    // The trampoline loads CR3 while still in 32-bit mode
+    if pml4.start_address().as_u64() > u32::MAX as u64 {
+        warn!("SMP: PML4 lies above 4GiB, can't start the APs");
+        return None;
+    }
+
+    unsafe {
+        let start = &smp_trampoline_start as *const u8;
+        let length = trampoline_offset(&smp_trampoline_end) as usize;
+
+        core::ptr::copy_nonoverlapping(start, base, length);
+
+        let patch = |symbol: &u8| base.add(trampoline_offset(symbol) as usize);
+
+        // Both the GDT and the far jump target are linear addresses
+        (patch(&smp_gdtr).add(2) as *mut u32)
+            .write_unaligned((phys + trampoline_offset(&smp_gdt)) as u32);
+        (patch(&smp_far) as *mut u32)
+            .write_unaligned((phys + trampoline_offset(&smp_long_entry)) as u32);
+        (patch(&smp_cr3) as *mut u32).write_unaligned(pml4.start_address().as_u64() as u32);
+        (patch(&smp_efer) as *mut u32).write_unaligned((Efer::read_raw() & !EFER_LMA) as u32);
+        (patch(&smp_target) as *mut u64).write_unaligned(ap_main as usize as u64);
+
+        Some(((phys >> 12) as u8, patch(&smp_stack) as *mut u64))
+    }
+}
+
+/// Sends INIT-SIPI-SIPI to the AP with the local APIC ID `id` and waits for it to check in
+fn start_ap(id: u32, vector: u8, stack_slot: *mut u64) -> bool {
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+
+    unsafe { stack_slot.write_volatile(top) };
+    AP_CHECKED_IN.store(false, Ordering::SeqCst);
+
+    let lapic = get_active_lapic();
+
+    unsafe { lapic.send_init_ipi(id) };
+    pm_timer_stall(INIT_DELAY_US);
+
+    for _ in 0..2 {
+        unsafe { lapic.send_sipi(vector, id) };
+        pm_timer_stall(SIPI_DELAY_US);
+    }
+
+    if pm_timer_poll(CHECK_IN_TIMEOUT_US, || AP_CHECKED_IN.load(Ordering::SeqCst)) {
+        return true;
+    }
+
+    // Park it again, so it can't run off the next AP's stack if it shows up late
+    unsafe { lapic.send_init_ipi(id) };
+    false
+}
+
+/// Starts every AP the MADT lists as usable. Must run after the local APIC and the IDT are
+/// set up.
+pub fn start_aps(processors: &ProcessorInfo<Global>) {
+    ONLINE_LAPIC_IDS
+        .write()
+        .push(processors.boot_processor.local_apic_id);
+
+    let waiting = processors
+        .application_processors
+        .iter()
+        .filter(|ap| matches!(ap.state, ProcessorState::WaitingForSipi));
+
+    if waiting.clone().next().is_none() {
+        info!("SMP: 1 CPU online");
+        return;
+    }
+
+    let Some((vector, stack_slot)) = prepare_trampoline() else {
+        warn!("SMP: no trampoline, running on the BSP only");
+        return;
+    };
+
+    BSP_STATE.call_once(|| BspState {
+        cr0: Cr0::read_raw(),
+        cr4: Cr4::read_raw(),
+        efer: Efer::read_raw(),
+        pat: unsafe { Msr::new(IA32_PAT).read() },
+    });
+
+    for ap in waiting {
+        if !start_ap(ap.local_apic_id, vector, stack_slot) {
+            warn!(
+                "SMP: CPU with local APIC ID {} didn't come up",
+                ap.local_apic_id
+            );
+        }
+    }
+
+    info!("SMP: {} CPUs online", cpus_online());
+}
@@ -1,12 +1,10 @@
 use syscall::{Error, MapFlags, PhysallocFlags, PhysmapFlags, Result, EINVAL, ENOMEM};
 use x86_64::{
-    structures::{
-        idt::InterruptStackFrame,
-        paging::{page::PageRangeInclusive, Page, PageTableFlags, PhysFrame, Size4KiB},
-    },
+    structures::paging::{page::PageRangeInclusive, Page, PageTableFlags, PhysFrame, Size4KiB},
     PhysAddr, VirtAddr,
 };
 
+use super::SyscallFrame;
 use crate::{get_phys_offset, map_page, FRAME_ALLOCATOR};
 
 // Compatibility
@@ -50,13 +48,9 @@ pub(crate) fn translate_flags(physmap: PhysmapFlags, map: MapFlags) -> PageTable
 }
 
 // I/O Privilege Level
-pub fn iopl(level: usize, frame: &mut InterruptStackFrame) -> Result<usize> {
+pub fn iopl(level: usize, frame: &mut SyscallFrame) -> Result<usize> {
     if level <= 3 {
-        unsafe {
-            let mut clone = **frame;
-            clone.cpu_flags = (frame.cpu_flags & !(3 << 12)) | (((level & 3) << 12) as u64);
-            frame.as_mut().write(clone);
-        }
+        frame.rflags = (frame.rflags & !(3 << 12)) | (((level & 3) << 12) as u64);
 
         Ok(0)
     } else {
@@ -1 +1,223 @@
+//! System call entry
+//!
+//! System calls come in through `int 0x80` or the faster `syscall` instruction. Both entry
+//! stubs save the caller's registers into the same [`SyscallFrame`] and hand it to
+//! [`dispatch`], so a system call behaves the same no matter how it was made. Either way the
+//! number goes in RAX, the arguments in RDI, RSI, RDX, R10, R8 and R9, and the result comes
+//! back in RAX, as a negated errno on failure.
+
+use core::arch::global_asm;
+
+use syscall::{Error, PhysmapFlags, Result, ENOSYS};
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use super::exceptions::GDT;
+
 pub mod driver;
+
+// Numbers as Redox assigns them
+pub const SYS_IOPL: usize = 110;
+pub const SYS_PHYSALLOC: usize = 945;
+pub const SYS_PHYSFREE: usize = 946;
+pub const SYS_PHYSMAP: usize = 947;
+
+/// User code selector, hardcoded into the return frame `syscall_entry` builds
+const USER_CS: u16 = 0x23;
+/// User data selector, hardcoded into the return frame `syscall_entry` builds
+const USER_DS: u16 = 0x1B;
+
+/// Registers of the caller, in the order the entry stubs push them. The last five words are
+/// laid out like the frame the CPU pushes for an interrupt, `syscall_entry` fakes them.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    /// Number of the system call, holds the result on the way out
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> usize {
+        self.rax as usize
+    }
+
+    pub fn args(&self) -> [usize; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9].map(|arg| arg as usize)
+    }
+}
+
+/// Carries out the system call in `frame`
+pub fn dispatch(frame: &mut SyscallFrame) -> Result<usize> {
+    let [a, b, c, ..] = frame.args();
+
+    match frame.number() {
+        SYS_IOPL => driver::iopl(a, frame),
+        SYS_PHYSALLOC => driver::physalloc(a),
+        SYS_PHYSFREE => driver::physfree(a, b),
+        SYS_PHYSMAP => driver::physmap(a, b, PhysmapFlags::from_bits_truncate(c)),
+        _ => Err(Error::new(ENOSYS)),
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    frame.rax = Error::mux(dispatch(frame)) as u64;
+}
+
+global_asm!(
+    r#"
+    .section .text.syscall_entry, "ax"
+    .global syscall_int80
+syscall_int80:
+    # The per-CPU block is only in IA32_KERNEL_GS_BASE if the caller ran in user mode
+    testb $3, 8(%rsp)
+    jz 1f
+    swapgs
+1:
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+
+    # The CPU aligns the stack before the 5 words of the frame, so with the 9 registers it is
+    # aligned again
+    mov %rsp, %rdi
+    cld
+    call syscall_dispatch
+
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+
+    testb $3, 8(%rsp)
+    jz 2f
+    swapgs
+2:
+    iretq
+
+    .global syscall_entry
+syscall_entry:
+    # FMASK cleared IF, so nothing runs on the caller's stack before it is switched
+    swapgs
+    mov %rsp, %gs:16
+    mov %gs:8, %rsp
+
+    # USER_DS and USER_CS
+    pushq $0x1B
+    pushq %gs:16
+    # RFLAGS and RIP of the caller
+    push %r11
+    pushq $0x23
+    push %rcx
+
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+
+    mov %rsp, %rdi
+    cld
+    call syscall_dispatch
+
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+
+    # On Intel, sysretq to a non-canonical RIP raises the #GP in ring 0, but with the caller's
+    # stack already loaded. iretq takes it on the kernel's stack and GS instead.
+    mov (%rsp), %rcx
+    mov %rcx, %r11
+    shl $16, %r11
+    sar $16, %r11
+    cmp %rcx, %r11
+    jne 3f
+
+    mov 16(%rsp), %r11
+    mov 24(%rsp), %rsp
+    swapgs
+    sysretq
+
+3:
+    iretq
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static syscall_int80: u8;
+    static syscall_entry: u8;
+}
+
+/// Address of the stub to put into the IDT for `int 0x80`
+pub fn int80_entry() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &syscall_int80 })
+}
+
+/// Enables the `syscall` instruction on the executing CPU. Has to run again after the CPU lost
+/// its MSRs, e.g. when waking up from S3.
+pub fn init() {
+    let selectors = &GDT.1;
+
+    // Every CPU builds its GDT the same way, the entry stub relies on it
+    assert_eq!(selectors.user_code.0, USER_CS);
+    assert_eq!(selectors.user_data.0, USER_DS);
+
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.code,
+        selectors.ds,
+    )
+    .expect("GDT layout doesn't suit SYSCALL/SYSRET");
+
+    LStar::write(VirtAddr::from_ptr(unsafe { &syscall_entry }));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::NESTED_TASK,
+    );
+
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
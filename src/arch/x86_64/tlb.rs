@@ -0,0 +1,151 @@
+//! TLB shootdown
+//!
+//! Changing a mapping only flushes the TLB of the CPU doing it. [`flush_range`] leaves the
+//! range in the mailbox of every other online CPU, interrupts them with the
+//! [`IrqIndex::IpiTlb`] vector and waits for each to acknowledge that it flushed. A CPU
+//! receiving several requests before it gets to them flushes its whole TLB instead.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts::without_interrupts, tlb},
+    structures::paging::{Page, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{apic_impl::send_ipi, interrupts::IrqIndex, percpu, smp::cpus_online};
+
+/// Ranges of more pages than this are flushed by reloading CR3 instead of page by page
+const FULL_FLUSH_PAGES: u64 = 32;
+
+/// What a CPU still has to flush
+#[derive(Debug, Clone)]
+enum Flush {
+    None,
+    Range(Range<VirtAddr>),
+    All,
+}
+
+impl Flush {
+    /// Adds `range` to what has to be flushed
+    fn merge(self, range: Range<VirtAddr>) -> Self {
+        match self {
+            Flush::None => Flush::Range(range),
+            Flush::Range(pending) if pending == range => Flush::Range(range),
+            _ => Flush::All,
+        }
+    }
+}
+
+/// Flush requests for one CPU
+#[derive(Debug)]
+pub struct Mailbox {
+    flush: Mutex<Flush>,
+    /// Number of requests left in the mailbox so far
+    requested: AtomicU64,
+    /// Number of requests the CPU has carried out
+    done: AtomicU64,
+}
+
+impl Mailbox {
+    pub const fn new() -> Self {
+        Self {
+            flush: Mutex::new(Flush::None),
+            requested: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+        }
+    }
+
+    /// Leaves `range` for the CPU to flush. Returns the ticket to wait for.
+    fn post(&self, range: Range<VirtAddr>) -> u64 {
+        without_interrupts(|| {
+            let mut flush = self.flush.lock();
+
+            *flush = core::mem::replace(&mut *flush, Flush::None).merge(range);
+            self.requested.fetch_add(1, Ordering::SeqCst) + 1
+        })
+    }
+
+    fn is_done(&self, ticket: u64) -> bool {
+        self.done.load(Ordering::SeqCst) >= ticket
+    }
+}
+
+/// Flushes `range` from the TLB of the executing CPU
+fn flush_local(range: Range<VirtAddr>) {
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end.align_up(4096u64)),
+    );
+
+    if pages.end - pages.start > FULL_FLUSH_PAGES {
+        tlb::flush_all();
+        return;
+    }
+
+    for page in pages {
+        tlb::flush(page.start_address());
+    }
+}
+
+/// Carries out the requests in the mailbox of the executing CPU
+fn process_mailbox() {
+    let mailbox = percpu::current().tlb();
+
+    let (flush, ticket) = without_interrupts(|| {
+        let mut flush = mailbox.flush.lock();
+
+        // Everything up to this ticket is covered by what's taken out of the mailbox
+        let ticket = mailbox.requested.load(Ordering::SeqCst);
+        (core::mem::replace(&mut *flush, Flush::None), ticket)
+    });
+
+    match flush {
+        Flush::None => {}
+        Flush::Range(range) => flush_local(range),
+        Flush::All => tlb::flush_all(),
+    }
+
+    mailbox.done.fetch_max(ticket, Ordering::SeqCst);
+}
+
+/// Flushes the pages overlapping `addrs` from the TLBs of every online CPU. Returns once all
+/// of them did.
+pub fn flush_range(addrs: Range<VirtAddr>) {
+    flush_local(addrs.clone());
+
+    // Also true before the per-CPU data exists
+    if cpus_online() <= 1 {
+        return;
+    }
+
+    let this = percpu::current().cpu_id();
+
+    let tickets = percpu::all()
+        .filter(|cpu| cpu.cpu_id() != this)
+        .map(|cpu| {
+            let ticket = cpu.tlb().post(addrs.clone());
+
+            send_ipi(IrqIndex::IpiTlb as u8, cpu.lapic_id());
+            (cpu, ticket)
+        })
+        .collect::<Vec<_>>();
+
+    for (cpu, ticket) in tickets {
+        while !cpu.tlb().is_done(ticket) {
+            // Another CPU may be waiting on us with interrupts disabled just the same
+            process_mailbox();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Handles the [`IrqIndex::IpiTlb`] IPI, without signalling the end of the interrupt
+pub(crate) fn shootdown_service() {
+    process_mailbox();
+}
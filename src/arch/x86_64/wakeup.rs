@@ -0,0 +1,295 @@
+//! Waking up from S3: the real-mode trampoline the firmware jumps to, and the context switch
+//! that puts the kernel to sleep and picks up where it left off afterwards.
+//!
+//! The firmware enters the trampoline in real mode with paging off. It loads a temporary GDT,
+//! switches straight to long mode on the page tables that were active before the suspend
+//! and jumps to [`wakeup_resume`], which restores the callee-saved registers and the stack and
+//! returns from [`suspend`] a second time.
+
+use core::arch::global_asm;
+
+use spin::Once;
+use x86_64::{
+    instructions::tables::{lgdt, lidt, sgdt, sidt},
+    registers::{
+        control::{Cr0, Cr3, Cr4},
+        model_specific::{Efer, FsBase, GsBase, KernelGsBase, Msr},
+    },
+    structures::{paging::PageTableFlags, DescriptorTablePointer},
+    PhysAddr,
+};
+
+use crate::{cralloc::take_low_frame, get_phys_offset, map_page};
+
+use log::*;
+
+const IA32_PAT: u32 = 0x277;
+/// EFER.LMA is read-only, the CPU sets it once paging is enabled
+const EFER_LMA: u64 = 1 << 10;
+
+/// Callee-saved registers and stack pointer of the suspending code, the layout is relied on
+/// by the assembly below
+#[repr(C)]
+struct SavedRegisters {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+}
+
+static mut SAVED_REGISTERS: SavedRegisters = SavedRegisters {
+    rbx: 0,
+    rbp: 0,
+    r12: 0,
+    r13: 0,
+    r14: 0,
+    r15: 0,
+    rsp: 0,
+};
+
+/// CPU state the trampoline doesn't bring back by itself
+struct SavedState {
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    gdtr: DescriptorTablePointer,
+    idtr: DescriptorTablePointer,
+    fs_base: u64,
+    gs_base: u64,
+    kernel_gs_base: u64,
+    pat: u64,
+}
+
+global_asm!(
+    r#"
+    .section .rodata.wakeup, "a"
+    .balign 16
+    .global wakeup_trampoline_start
+wakeup_trampoline_start:
+    .code16
+    cli
+    cld
+
+    # Firmware jumps here with CS:IP = vector >> 4 : 0, data is addressed relative to that
+    mov %cs, %ax
+    mov %ax, %ds
+
+    lgdtl (wakeup_gdtr - wakeup_trampoline_start)
+
+    mov %cr4, %eax
+    or $0x20, %eax
+    mov %eax, %cr4
+
+    movl (wakeup_cr3 - wakeup_trampoline_start), %eax
+    mov %eax, %cr3
+
+    mov $0xc0000080, %ecx
+    movl (wakeup_efer - wakeup_trampoline_start), %eax
+    xor %edx, %edx
+    wrmsr
+
+    # Paging and protection at once, which lands directly in long mode
+    mov %cr0, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+
+    ljmpl *(wakeup_far - wakeup_trampoline_start)
+
+    .code64
+    .global wakeup_long_entry
+wakeup_long_entry:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    jmp *wakeup_target(%rip)
+
+    .balign 8
+    .global wakeup_gdt
+wakeup_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+    .global wakeup_gdtr
+wakeup_gdtr:
+    .word 23
+    .long 0
+    .global wakeup_far
+wakeup_far:
+    .long 0
+    .word 0x08
+    .global wakeup_cr3
+wakeup_cr3:
+    .long 0
+    .global wakeup_efer
+wakeup_efer:
+    .long 0
+    .global wakeup_target
+wakeup_target:
+    .quad 0
+    .global wakeup_trampoline_end
+wakeup_trampoline_end:
+
+    .text
+    .global wakeup_save_and_sleep
+wakeup_save_and_sleep:
+    mov %rbx, 0(%rdi)
+    mov %rbp, 8(%rdi)
+    mov %r12, 16(%rdi)
+    mov %r13, 24(%rdi)
+    mov %r14, 32(%rdi)
+    mov %r15, 40(%rdi)
+    mov %rsp, 48(%rdi)
+
+    # Keep the stack 16-byte aligned for the callee
+    sub $8, %rsp
+    call *%rsi
+    add $8, %rsp
+
+    # Still here, so the machine didn't go to sleep
+    xor %eax, %eax
+    ret
+
+    .global wakeup_resume
+wakeup_resume:
+    lea {registers}(%rip), %rdi
+    mov 48(%rdi), %rsp
+    mov 0(%rdi), %rbx
+    mov 8(%rdi), %rbp
+    mov 16(%rdi), %r12
+    mov 24(%rdi), %r13
+    mov 32(%rdi), %r14
+    mov 40(%rdi), %r15
+
+    mov $1, %eax
+    ret
+"#,
+    registers = sym SAVED_REGISTERS,
+    options(att_syntax)
+);
+
+extern "C" {
+    static wakeup_trampoline_start: u8;
+    static wakeup_trampoline_end: u8;
+    static wakeup_gdt: u8;
+    static wakeup_gdtr: u8;
+    static wakeup_far: u8;
+    static wakeup_long_entry: u8;
+    static wakeup_cr3: u8;
+    static wakeup_efer: u8;
+    static wakeup_target: u8;
+
+    fn wakeup_save_and_sleep(registers: *mut SavedRegisters, enter: extern "C" fn()) -> u64;
+    fn wakeup_resume();
+}
+
+/// Physical address of the trampoline, which is also the firmware waking vector
+static TRAMPOLINE: Once<Option<PhysAddr>> = Once::new();
+
+/// Offset of `symbol` from the start of the trampoline
+fn trampoline_offset(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - unsafe { &wakeup_trampoline_start as *const u8 as u64 }
+}
+
+/// Copies the trampoline into a frame below 1MiB, identity-maps it so execution survives
+/// turning paging on, and fills in the current page tables. Returns the physical address to
+/// put into the FACS firmware waking vector, or `None` if the trampoline can't be set up.
+pub fn prepare_trampoline() -> Option<PhysAddr> {
+    let phys = (*TRAMPOLINE.call_once(|| {
+        let frame = take_low_frame()?;
+        let phys = frame.start_address();
+
+        map_page!(
+            phys.as_u64(),
+            phys.as_u64(),
+            x86_64::structures::paging::Size4KiB,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        );
+        map_page!(
+            phys.as_u64(),
+            phys.as_u64() + get_phys_offset(),
+            x86_64::structures::paging::Size4KiB,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        );
+
+        Some(phys)
+    }))?;
+
+    let (pml4, _) = Cr3::read();
+
+    // The trampoline loads CR3 while still in 32-bit mode
+    if pml4.start_address().as_u64() > u32::MAX as u64 {
+        warn!("S3: PML4 lies above 4GiB, can't resume from the trampoline");
+        return None;
+    }
+
+    unsafe {
+        let start = &wakeup_trampoline_start as *const u8;
+        let length = trampoline_offset(&wakeup_trampoline_end) as usize;
+        let base = (phys.as_u64() + get_phys_offset()) as *mut u8;
+
+        core::ptr::copy_nonoverlapping(start, base, length);
+
+        let patch = |symbol: &u8| base.add(trampoline_offset(symbol) as usize);
+
+        // Both the GDT and the far jump target are linear addresses
+        (patch(&wakeup_gdtr).add(2) as *mut u32)
+            .write_unaligned((phys.as_u64() + trampoline_offset(&wakeup_gdt)) as u32);
+        (patch(&wakeup_far) as *mut u32)
+            .write_unaligned((phys.as_u64() + trampoline_offset(&wakeup_long_entry)) as u32);
+        (patch(&wakeup_cr3) as *mut u32).write_unaligned(pml4.start_address().as_u64() as u32);
+        (patch(&wakeup_efer) as *mut u32).write_unaligned((Efer::read_raw() & !EFER_LMA) as u32);
+        (patch(&wakeup_target) as *mut u64).write_unaligned(wakeup_resume as usize as u64);
+    }
+
+    Some(phys)
+}
+
+/// Saves the CPU state and calls `enter`, which is expected to put the machine to sleep.
+/// Returns `true` after the machine woke up through the trampoline, with the CPU state
+/// restored but interrupts disabled and the APICs not yet reinitialized, or `false` if
+/// `enter` returned without the machine going to sleep.
+///
+/// # Safety
+/// [`prepare_trampoline`] must have been called and its address handed to the firmware.
+/// Interrupts have to be disabled.
+pub unsafe fn suspend(enter: extern "C" fn()) -> bool {
+    let state = SavedState {
+        cr0: Cr0::read_raw(),
+        cr4: Cr4::read_raw(),
+        efer: Efer::read_raw(),
+        gdtr: sgdt(),
+        idtr: sidt(),
+        fs_base: FsBase::read().as_u64(),
+        gs_base: GsBase::read().as_u64(),
+        kernel_gs_base: KernelGsBase::read().as_u64(),
+        pat: Msr::new(IA32_PAT).read(),
+    };
+
+    if wakeup_save_and_sleep(core::ptr::addr_of_mut!(SAVED_REGISTERS), enter) == 0 {
+        return false;
+    }
+
+    // The trampoline only set PAE, PG and PE
+    Cr4::write_raw(state.cr4);
+    Cr0::write_raw(state.cr0);
+    Efer::write_raw(state.efer);
+    Msr::new(IA32_PAT).write(state.pat);
+
+    lgdt(&state.gdtr);
+    super::exceptions::reload();
+    lidt(&state.idtr);
+
+    // Reloading the segment registers cleared the bases
+    FsBase::write(x86_64::VirtAddr::new(state.fs_base));
+    GsBase::write(x86_64::VirtAddr::new(state.gs_base));
+    KernelGsBase::write(x86_64::VirtAddr::new(state.kernel_gs_base));
+
+    // So did STAR, LSTAR and FMASK
+    super::syscall::init();
+
+    true
+}
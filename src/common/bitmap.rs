@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Fixed-size bitmap, used to track allocated interrupt vectors
+
+use alloc::{
+    alloc::{Allocator, Global},
+    vec::Vec,
+};
+
+pub const BLOCK_BITS: usize = core::mem::size_of::<usize>() * 8;
+
+// Needed for compatibility
+#[derive(Debug)]
+pub struct Bitmap<A: Allocator> {
+    bitmap: Vec<usize, A>,
+    /// Number of bits, the last block may hold a few more that don't count
+    size: usize,
+}
+
+impl Bitmap<Global> {
+    /// Shorthand for `Bitmap::new_in(Global, size)`
+    pub fn new(size: usize) -> Self {
+        Self::new_in(Global, size)
+    }
+}
+
+impl<A: Allocator> Bitmap<A> {
+    /// Constructs a new bitmap with `size` bits and uses `alloc`
+    /// as the alloctor.
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let mut bitmap = Bitmap::new_in(Global, 4096);
+    /// ```
+    pub fn new_in(alloc: A, size: usize) -> Self {
+        let bitmap_blocks = size.div_ceil(BLOCK_BITS);
+        let mut bitmap = Vec::new_in(alloc);
+
+        bitmap.resize(bitmap_blocks, 0);
+        Self { bitmap, size }
+    }
+
+    /// Constructs a new, empty bitmap. This function does *not* perform
+    /// any allocations.
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let bitmap = Bitmap::empty_in(Global);
+    /// assert_eq!(bitmap.find_first_unset(), None);
+    /// ```
+    pub fn empty_in(alloc: A) -> Self {
+        Self {
+            bitmap: Vec::new_in(alloc),
+            size: 0,
+        }
+    }
+
+    /// Sets the bit at the provided `bit_idx` to `yes` (`true` or `false`).
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let mut bitmap = Bitmap::new_in(Global, 4096);
+    ///
+    /// assert!(!bitmap.is_set(69));
+    /// bitmap.set(69, true);
+    /// assert!(bitmap.is_set(69));
+    /// ```
+    pub fn set(&mut self, bit_idx: usize, yes: bool) {
+        if bit_idx >= self.size {
+            return;
+        }
+
+        let (block_idx, mod_bit_idx) = (bit_idx / BLOCK_BITS, bit_idx % BLOCK_BITS);
+        let block = &mut self.bitmap[block_idx];
+
+        if yes {
+            *block |= 1 << mod_bit_idx;
+        } else {
+            *block &= !(1 << mod_bit_idx);
+        }
+    }
+
+    /// Returns weather the bit at the provided `bit_idx` is set.
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let bitmap = Bitmap::new_in(Global, 4096);
+    /// assert!(!bitmap.is_set(69));
+    /// ```
+    pub fn is_set(&self, bit_idx: usize) -> bool {
+        let (block_idx, mod_bit_idx) = (bit_idx / BLOCK_BITS, bit_idx % BLOCK_BITS);
+        let n = self.bitmap[block_idx];
+
+        n & (1 << mod_bit_idx) != 0
+    }
+
+    /// Returns the index of the first unset bit.
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let mut bitmap = Bitmap::new_in(Global, 4096);
+    /// assert_eq!(bitmap.find_first_unset(), Some(0));
+    ///
+    /// bitmap.set(0, true);
+    /// bitmap.set(1, true);
+    /// assert_eq!(bitmap.find_first_unset(), Some(2));
+    /// ```
+    pub fn find_first_unset(&self) -> Option<usize> {
+        self.bitmap
+            .iter()
+            .enumerate()
+            .find(|(_, block)| **block != usize::MAX)
+            .map(|(i, block)| i * BLOCK_BITS + block.trailing_ones() as usize)
+            .filter(|&bit| bit < self.size)
+    }
+
+    /// Returns the index of the first set bit.
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let mut bitmap = Bitmap::new_in(Global, 4096);
+    /// assert_eq!(bitmap.find_first_set(), None);
+    ///
+    /// bitmap.set(69, true);
+    /// assert_eq!(bitmap.find_first_set(), Some(69));
+    /// ```
+    pub fn find_first_set(&self) -> Option<usize> {
+        self.bitmap
+            .iter()
+            .enumerate()
+            .find(|(_, block)| **block != 0)
+            .map(|(i, block)| i * BLOCK_BITS + block.trailing_zeros() as usize)
+    }
+
+    /// Returns the index of the first run of `count` unset bits that starts at a multiple of
+    /// `count`.
+    ///
+    /// ## Example
+    /// ```rust
+    /// use alloc::alloc::Global;
+    ///
+    /// let mut bitmap = Bitmap::new_in(Global, 64);
+    ///
+    /// bitmap.set(1, true);
+    /// assert_eq!(bitmap.find_unset_aligned(4), Some(4));
+    /// ```
+    pub fn find_unset_aligned(&self, count: usize) -> Option<usize> {
+        let count = count.max(1);
+
+        (0..self.size)
+            .step_by(count)
+            .find(|&i| i + count <= self.size && (i..i + count).all(|j| !self.is_set(j)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_first_unset_skips_full_blocks() {
+        let mut bitmap = Bitmap::new(256);
+
+        for bit in 0..BLOCK_BITS + 3 {
+            bitmap.set(bit, true);
+        }
+
+        assert_eq!(bitmap.find_first_unset(), Some(BLOCK_BITS + 3));
+    }
+
+    #[test]
+    fn find_first_unset_in_an_empty_bitmap() {
+        assert_eq!(Bitmap::new(256).find_first_unset(), Some(0));
+        assert_eq!(Bitmap::empty_in(Global).find_first_unset(), None);
+    }
+
+    #[test]
+    fn find_first_unset_finds_holes() {
+        let mut bitmap = Bitmap::new(256);
+
+        for bit in 0..100 {
+            bitmap.set(bit, true);
+        }
+
+        bitmap.set(70, false);
+        assert_eq!(bitmap.find_first_unset(), Some(70));
+    }
+
+    #[test]
+    fn find_first_unset_when_full() {
+        let mut bitmap = Bitmap::new(256);
+
+        for bit in 0..256 {
+            bitmap.set(bit, true);
+        }
+
+        assert_eq!(bitmap.find_first_unset(), None);
+    }
+
+    #[test]
+    fn find_first_unset_ignores_bits_past_the_size() {
+        let mut bitmap = Bitmap::new(10);
+
+        for bit in 0..10 {
+            bitmap.set(bit, true);
+        }
+
+        assert_eq!(bitmap.find_first_unset(), None);
+
+        // Out of range, so it's dropped
+        bitmap.set(10, true);
+        assert_eq!(bitmap.find_first_set(), Some(0));
+    }
+
+    #[test]
+    fn find_first_set_skips_empty_blocks() {
+        let mut bitmap = Bitmap::new(256);
+        assert_eq!(bitmap.find_first_set(), None);
+
+        bitmap.set(200, true);
+        bitmap.set(201, true);
+        assert_eq!(bitmap.find_first_set(), Some(200));
+    }
+
+    #[test]
+    fn set_clears_bits_again() {
+        let mut bitmap = Bitmap::new(256);
+
+        bitmap.set(130, true);
+        assert!(bitmap.is_set(130));
+
+        bitmap.set(130, false);
+        assert!(!bitmap.is_set(130));
+        assert_eq!(bitmap.find_first_set(), None);
+    }
+
+    #[test]
+    fn find_unset_aligned_respects_alignment() {
+        let mut bitmap = Bitmap::new(256);
+
+        // Like the vectors reserved for exceptions and the legacy IRQs
+        for bit in 0..48 {
+            bitmap.set(bit, true);
+        }
+
+        assert_eq!(bitmap.find_unset_aligned(1), Some(48));
+        assert_eq!(bitmap.find_unset_aligned(16), Some(48));
+        assert_eq!(bitmap.find_unset_aligned(32), Some(64));
+
+        bitmap.set(66, true);
+        assert_eq!(bitmap.find_unset_aligned(4), Some(48));
+        assert_eq!(bitmap.find_unset_aligned(32), Some(96));
+    }
+
+    #[test]
+    fn find_unset_aligned_stays_inside_the_bitmap() {
+        let mut bitmap = Bitmap::new(256);
+
+        for bit in 0..240 {
+            bitmap.set(bit, true);
+        }
+
+        assert_eq!(bitmap.find_unset_aligned(16), Some(240));
+        assert_eq!(bitmap.find_unset_aligned(32), None);
+    }
+}
@@ -48,15 +48,18 @@ macro_rules! map_page {
 /// Macro for unmapping pages
 ///
 /// Just like `map_page!`, this macro converts the `Result` thrown by `Mapper::unmap`
-/// to an `Option<MapperFlush<S>>` so the page table just won't be flushed if any errors occur
+/// to an `Option<MapperFlush<S>>` so the page table just won't be flushed if any errors occur.
+/// Other CPUs may have the page cached too, so it is flushed from every TLB.
 #[macro_export]
 macro_rules! unmap_page {
     ($page:expr) => {
         #[allow(unused_imports)] // macro is sometimes called from files that don't import this
         use log::debug;
-        use x86_64::structures::paging::{mapper::UnmapError, Mapper};
+        use x86_64::structures::paging::{mapper::UnmapError, Mapper, PageSize};
 
-        let flush = match $crate::MAPPER.get().unwrap().write().unmap($page) {
+        let page = $page;
+
+        let flush = match $crate::MAPPER.get().unwrap().write().unmap(page) {
             Ok((_, flush)) => Some(flush),
             Err(e) => match e {
                 UnmapError::ParentEntryHugePage => {
@@ -77,7 +80,8 @@ macro_rules! unmap_page {
         };
 
         if let Some(flush) = flush {
-            flush.flush();
+            flush.ignore();
+            $crate::tlb::flush_range(page.start_address()..page.start_address() + page.size());
         }
     };
 }
@@ -11,6 +11,8 @@ use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB}
 use x86_64::VirtAddr;
 
 pub mod atomic_cell;
+pub mod bitmap;
+pub mod crc32;
 pub mod large_numbers;
 pub mod macros;
 
@@ -1,3 +1,5 @@
+use core::ops::Range;
+
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use x86_64::structures::paging::{frame::PhysFrameRangeInclusive, FrameDeallocator};
 
@@ -9,6 +11,8 @@ use x86_64::{
 
 use crate::ahci::util::sync::Mutex;
 
+use log::*;
+
 unsafe fn active_pml4(offset: VirtAddr) -> &'static mut PageTable {
     let (pml4_frame, _) = Cr3::read();
 
@@ -33,10 +37,18 @@ pub unsafe fn map_memory(offset: VirtAddr) -> OffsetPageTable<'static> {
     OffsetPageTable::new(pml4, offset)
 }
 
+/// Most runs of frames [`KernelFrameAlloc::allocate_contiguous_below`] can pass over and keep
+/// for later. Only the tail of a memory region too short for a run gets passed over, so there
+/// are few of them.
+const SKIPPED_RUNS: usize = 8;
+
 /// The frame allocator
 pub struct KernelFrameAlloc {
     map: &'static MemoryRegions,
     next: usize,
+    /// Frames passed over while looking for contiguous runs, as ranges of indices into
+    /// [`usable`](Self::usable). They are handed out before the frames from `next` on.
+    skipped: [Range<usize>; SKIPPED_RUNS],
 }
 
 impl KernelFrameAlloc {
@@ -46,7 +58,11 @@ impl KernelFrameAlloc {
     ///
     /// Caller must ensure that the memory regions they're using point to valid addresses
     pub unsafe fn new(map: &'static MemoryRegions) -> Self {
-        Self { map, next: 0 }
+        Self {
+            map,
+            next: 0,
+            skipped: core::array::from_fn(|_| 0..0),
+        }
     }
 
     pub fn usable(&self) -> impl Iterator<Item = PhysFrame> + '_ {
@@ -77,10 +93,68 @@ impl KernelFrameAlloc {
 
         range.count()
     }
+
+    /// Allocates `count` physically contiguous frames that end below the physical address
+    /// `limit`, returning the first one. Returns `None` if there is no such run left.
+    ///
+    /// Frames are handed out in ascending order, so a run can only be cut short by the end of a
+    /// memory region. The frames passed over there stay available for single allocations.
+    pub fn allocate_contiguous_below(&mut self, count: usize, limit: u64) -> Option<PhysFrame> {
+        let mut start = self.next;
+        let mut end = None;
+        let mut previous: Option<PhysFrame> = None;
+
+        for (index, frame) in self.usable().enumerate().skip(self.next) {
+            // Everything after this frame lies even higher
+            if frame.start_address().as_u64() + frame.size() > limit {
+                break;
+            }
+
+            if previous.is_none_or(|previous| previous + 1 != frame) {
+                start = index;
+            }
+
+            previous = Some(frame);
+
+            if index + 1 - start == count {
+                end = Some(index + 1);
+                break;
+            }
+        }
+
+        let end = end?;
+
+        self.skip(self.next..start);
+        self.next = end;
+
+        self.usable().nth(start)
+    }
+
+    /// Keeps the frames at `indices` around to be handed out later
+    fn skip(&mut self, indices: Range<usize>) {
+        if indices.is_empty() {
+            return;
+        }
+
+        match self.skipped.iter_mut().find(|run| run.is_empty()) {
+            Some(run) => *run = indices,
+            None => warn!(
+                "Frame allocator: too many runs passed over, losing {} frames",
+                indices.len()
+            ),
+        }
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for KernelFrameAlloc {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(run) = self.skipped.iter_mut().find(|run| !run.is_empty()) {
+            let index = run.start;
+            run.start += 1;
+
+            return self.usable().nth(index);
+        }
+
         let f = self.usable().nth(self.next);
         self.next += 1;
         f
@@ -88,14 +162,17 @@ unsafe impl FrameAllocator<Size4KiB> for KernelFrameAlloc {
 }
 
 impl FrameDeallocator<Size4KiB> for KernelFrameAlloc {
-    unsafe fn deallocate_frame(&mut self, mut _frame: PhysFrame<Size4KiB>) {
-        let current_addr = _frame.start_address();
-
-        let new_frame = PhysFrame::<Size4KiB>::containing_address(current_addr - 4096u64);
-        let new_addr = new_frame.start_address();
-
-        self.next -= 1;
-        _frame = PhysFrame::containing_address(new_addr);
+    /// Gives back the frame handed out last, which is all a bump allocator can take back
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let from_skipped = self
+            .skipped
+            .iter()
+            .position(|run| run.start > 0 && self.usable().nth(run.start - 1) == Some(frame));
+
+        match from_skipped {
+            Some(i) => self.skipped[i].start -= 1,
+            None => self.next -= 1,
+        }
     }
 }
 
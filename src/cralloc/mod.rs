@@ -15,12 +15,14 @@ use crate::{common::IrqLock, get_boot_info, get_phys_offset, FRAME_ALLOCATOR, MA
 use self::frames::{map_memory, KernelFrameAlloc};
 
 pub mod frames;
+pub mod vm;
 
 use {
     slab_allocator_rs::*,
     x86_64::{
         structures::paging::{
-            mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+            mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+            Size4KiB,
         },
         VirtAddr,
     },
@@ -31,6 +33,15 @@ pub static ALLOC: LockedHeap = LockedHeap::empty();
 
 pub const BEGIN_HEAP: usize = 0x2000_0000_0000;
 pub const HEAP_LEN: usize = 32 * 1024 * 1024;
+/// Room right after the heap for it to grow into, backed page by page on first access
+pub const HEAP_GROWTH_LEN: usize = 224 * 1024 * 1024;
+
+/// Number of frames below 1MiB set aside for real-mode entry code
+pub const LOW_FRAME_COUNT: usize = 4;
+
+/// Frames below 1MiB reserved before the heap takes them, see [`take_low_frame`]
+static LOW_FRAMES: spin::Mutex<[Option<PhysFrame>; LOW_FRAME_COUNT]> =
+    spin::Mutex::new([None; LOW_FRAME_COUNT]);
 
 pub static MAP_ADDR: AtomicU64 = AtomicU64::new(0);
 pub static FRAME_ALLOC_ADDR: AtomicU64 = AtomicU64::new(0);
@@ -81,7 +92,9 @@ pub fn heap_init() {
     let offset = VirtAddr::new(get_phys_offset());
 
     let map = unsafe { map_memory(offset) };
-    let falloc = unsafe { KernelFrameAlloc::new(&boot_info.memory_regions) };
+    let mut falloc = unsafe { KernelFrameAlloc::new(&boot_info.memory_regions) };
+
+    reserve_low_frames(&mut falloc);
 
     MAPPER.get_or_init(move || IrqLock::new(map));
     FRAME_ALLOCATOR.get_or_init(move || IrqLock::new(falloc));
@@ -91,6 +104,51 @@ pub fn heap_init() {
         &mut *FRAME_ALLOCATOR.get().unwrap().write(),
     )
     .unwrap_or_else(|e| panic!("Failed to initialize heap: {:#?}", e));
+
+    // The slab allocator writes its free lists all over the heap itself, before there is an IDT
+    // to take page faults, so only the room after it can be lazy
+    let growth = VirtAddr::new((BEGIN_HEAP + HEAP_LEN) as u64);
+
+    vm::register_lazy_region(
+        growth..growth + HEAP_GROWTH_LEN,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+    .expect("Failed to register the heap growth area");
+}
+
+/// Keeps a few frames below 1MiB out of the heap's reach. The allocator hands out frames in
+/// ascending order, so they have to be taken before anything else.
+fn reserve_low_frames(falloc: &mut KernelFrameAlloc) {
+    let mut low = LOW_FRAMES.lock();
+    let mut reserved = 0;
+
+    while reserved < LOW_FRAME_COUNT {
+        let Some(frame) = falloc.allocate_frame() else {
+            break;
+        };
+
+        let address = frame.start_address().as_u64();
+
+        // The real-mode IVT and BDA live in the first page
+        if address == 0 {
+            continue;
+        }
+
+        if address >= 0x10_0000 {
+            // Give it back, there is nothing usable left below 1MiB
+            unsafe { falloc.deallocate_frame(frame) };
+            break;
+        }
+
+        low[reserved] = Some(frame);
+        reserved += 1;
+    }
+}
+
+/// Takes one of the frames below 1MiB reserved at boot, for code that has to run in real
+/// mode such as the ACPI wakeup vector. The frame is not mapped.
+pub fn take_low_frame() -> Option<PhysFrame> {
+    LOW_FRAMES.lock().iter_mut().find_map(|frame| frame.take())
 }
 
 /// Structure that provides page/frame-aligned physical memory access
@@ -0,0 +1,183 @@
+//! Demand-allocated regions of the address space
+//!
+//! Touching an unmapped page is a bug, unless the page lies in a region registered with
+//! [`register_lazy_region`]. The page-fault handler backs those with a zeroed frame on first
+//! access, every other fault is fatal to whoever caused it.
+
+use core::{fmt, ops::Range};
+
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    common::{IrqLock, IrqLockWriteGuard},
+    get_phys_offset, FRAME_ALLOCATOR, MAPPER,
+};
+
+/// Most regions that can be registered. Faults may come in before the heap exists, so the
+/// regions are kept in a fixed-size table.
+pub const MAX_LAZY_REGIONS: usize = 16;
+
+#[derive(Debug, Clone)]
+struct LazyRegion {
+    range: Range<VirtAddr>,
+    flags: PageTableFlags,
+}
+
+const NO_REGION: Option<LazyRegion> = None;
+
+static LAZY_REGIONS: IrqLock<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    IrqLock::new([NO_REGION; MAX_LAZY_REGIONS]);
+
+/// Why a region couldn't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// All [`MAX_LAZY_REGIONS`] slots are taken
+    TableFull,
+    /// The range overlaps one registered before
+    Overlap,
+    /// The range is empty or doesn't start on a page boundary
+    BadRange,
+}
+
+/// Why a fault couldn't be resolved by mapping a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyFaultError {
+    /// The address lies in no registered region
+    NotLazy,
+    /// The region isn't accessible from user mode, but the fault came from there
+    NotUserAccessible,
+    /// The page table or frame allocator lock stayed taken, likely by the faulting code
+    Locked,
+    OutOfMemory,
+}
+
+impl fmt::Display for LazyFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LazyFaultError::NotLazy => "address is outside every lazy region",
+            LazyFaultError::NotUserAccessible => "lazy region isn't accessible from user mode",
+            LazyFaultError::Locked => "page tables stayed locked, likely by the faulting code",
+            LazyFaultError::OutOfMemory => "no frame left to back the page with",
+        })
+    }
+}
+
+/// Lets pages in `range` be backed on first access, mapped with `flags`. `PRESENT` is
+/// implied.
+pub fn register_lazy_region(
+    range: Range<VirtAddr>,
+    flags: PageTableFlags,
+) -> Result<(), RegisterError> {
+    if range.is_empty() || !range.start.is_aligned(4096u64) {
+        return Err(RegisterError::BadRange);
+    }
+
+    let mut regions = LAZY_REGIONS.write();
+
+    let overlaps = regions
+        .iter()
+        .flatten()
+        .any(|region| region.range.start < range.end && range.start < region.range.end);
+
+    if overlaps {
+        return Err(RegisterError::Overlap);
+    }
+
+    let slot = regions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegisterError::TableFull)?;
+
+    *slot = Some(LazyRegion {
+        range,
+        flags: flags | PageTableFlags::PRESENT,
+    });
+
+    Ok(())
+}
+
+/// Attempts at taking a lock before the fault is given up on
+const LOCK_SPINS: usize = 1_000_000;
+
+/// Write-locks `lock`, unless it stays locked for [`LOCK_SPINS`] attempts. Waiting forever
+/// would deadlock if the faulting code is the one holding it, another CPU lets go eventually.
+fn write_bounded<T>(lock: &IrqLock<T>) -> Option<IrqLockWriteGuard<'_, T>> {
+    (0..LOCK_SPINS).find_map(|_| {
+        let guard = lock.try_write();
+
+        if guard.is_none() {
+            core::hint::spin_loop();
+        }
+
+        guard
+    })
+}
+
+/// Backs the page containing `addr` with a zeroed frame if it lies in a lazy region. Called by
+/// the page-fault handler for faults on non-present pages.
+pub(crate) fn map_lazy(addr: VirtAddr, user: bool) -> Result<(), LazyFaultError> {
+    let flags = LAZY_REGIONS
+        .read()
+        .iter()
+        .flatten()
+        .find(|region| region.range.contains(&addr))
+        .map(|region| region.flags)
+        .ok_or(LazyFaultError::NotLazy)?;
+
+    if user && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err(LazyFaultError::NotUserAccessible);
+    }
+
+    // Same order as `map_page!`
+    let mut mapper = MAPPER
+        .get()
+        .and_then(write_bounded)
+        .ok_or(LazyFaultError::Locked)?;
+    let mut falloc = FRAME_ALLOCATOR
+        .get()
+        .and_then(write_bounded)
+        .ok_or(LazyFaultError::Locked)?;
+
+    let frame = falloc.allocate_frame().ok_or(LazyFaultError::OutOfMemory)?;
+
+    // Zeroed through the physical memory mapping, the page may be read-only
+    unsafe {
+        core::ptr::write_bytes(
+            (get_phys_offset() + frame.start_address().as_u64()) as *mut u8,
+            0,
+            4096,
+        );
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    match unsafe { mapper.map_to(page, frame, flags, &mut *falloc) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => {
+            unsafe { falloc.deallocate_frame(frame) };
+            Err(LazyFaultError::OutOfMemory)
+        }
+        // Another CPU faulted on the same page first
+        Err(_) => {
+            unsafe { falloc.deallocate_frame(frame) };
+            Ok(())
+        }
+    }
+}
+
+/// Reads the byte at `addr` if it is mapped, or `None` if it isn't or the page tables are
+/// locked. For exception handlers looking at addresses they can't trust.
+pub fn peek_byte(addr: VirtAddr) -> Option<u8> {
+    let mapped = MAPPER.get()?.try_read()?.translate_addr(addr).is_some();
+
+    mapped.then(|| unsafe { *addr.as_ptr::<u8>() })
+}
@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Bookkeeping of the frames mapped for ACPI tables and AML accesses
+
+use alloc::collections::BTreeMap;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Start addresses of the frames `size` bytes at physical `address` lie in
+pub(crate) fn frames_of(address: u64, size: u64) -> impl Iterator<Item = u64> {
+    let first = address & !(FRAME_SIZE - 1);
+
+    (first..address + size).step_by(FRAME_SIZE as usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameUse {
+    /// Stays mapped for good, because it was mapped before we got to it or AML uses it
+    Pinned,
+    /// Mapped for this many table regions, unmapped once the last one goes away
+    Regions(usize),
+}
+
+/// Frames mapped to the physical memory offset for ACPI, by start address
+#[derive(Debug)]
+pub(crate) struct MappedFrames(BTreeMap<u64, FrameUse>);
+
+impl MappedFrames {
+    pub(crate) const fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Whether `frame` stays mapped for good
+    pub(crate) fn is_pinned(&self, frame: u64) -> bool {
+        self.0.get(&frame) == Some(&FrameUse::Pinned)
+    }
+
+    /// Takes a reference to `frame`, where `pin` keeps it mapped for good. Returns whether it
+    /// has to be mapped, which is only the case the first time unless `premapped` says
+    /// something else mapped it before us. Whatever that was may still use it, so it's never
+    /// unmapped.
+    pub(crate) fn acquire(
+        &mut self,
+        frame: u64,
+        pin: bool,
+        premapped: impl FnOnce() -> bool,
+    ) -> bool {
+        match self.0.get_mut(&frame) {
+            Some(FrameUse::Pinned) => false,
+            Some(use_) if pin => {
+                *use_ = FrameUse::Pinned;
+                false
+            }
+            Some(FrameUse::Regions(count)) => {
+                *count += 1;
+                false
+            }
+            None => {
+                let premapped = premapped();
+
+                let use_ = if pin || premapped {
+                    FrameUse::Pinned
+                } else {
+                    FrameUse::Regions(1)
+                };
+
+                self.0.insert(frame, use_);
+                !premapped
+            }
+        }
+    }
+
+    /// Drops a reference to `frame` taken by [`acquire`](MappedFrames::acquire). Returns
+    /// whether it was the last one, so the frame has to be unmapped.
+    pub(crate) fn release(&mut self, frame: u64) -> bool {
+        let Some(FrameUse::Regions(count)) = self.0.get_mut(&frame) else {
+            return false;
+        };
+
+        *count -= 1;
+
+        if *count == 0 {
+            self.0.remove(&frame);
+            return true;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn frames(address: u64, size: u64) -> Vec<u64> {
+        frames_of(address, size).collect()
+    }
+
+    /// Accesses `size` bytes at `address` the way the AML accessors do, returning the frames
+    /// that had to be mapped
+    fn aml_access(mapped: &mut MappedFrames, address: u64, size: u64) -> Vec<u64> {
+        frames_of(address, size)
+            .filter(|frame| !mapped.is_pinned(*frame) && mapped.acquire(*frame, true, || false))
+            .collect()
+    }
+
+    #[test]
+    fn accesses_cover_the_frames_they_touch() {
+        assert_eq!(frames(0x1000, 4), [0x1000]);
+        assert_eq!(frames(0x1FFC, 4), [0x1000]);
+
+        // Not the frame after it
+        assert_eq!(frames(0x1234, 8), [0x1000]);
+
+        // Crossing into the next frame
+        assert_eq!(frames(0x1FFE, 4), [0x1000, 0x2000]);
+        assert_eq!(frames(0x1800, 0x2000), [0x1000, 0x2000, 0x3000]);
+    }
+
+    #[test]
+    fn repeated_aml_accesses_map_once() {
+        let mut mapped = MappedFrames::new();
+
+        assert_eq!(aml_access(&mut mapped, 0xFED0_0010, 4), [0xFED0_0000]);
+
+        for _ in 0..1000 {
+            assert!(aml_access(&mut mapped, 0xFED0_0010, 4).is_empty());
+            assert!(aml_access(&mut mapped, 0xFED0_0FF0, 8).is_empty());
+        }
+
+        assert_eq!(aml_access(&mut mapped, 0xFED0_0FFE, 4), [0xFED0_1000]);
+    }
+
+    #[test]
+    fn premapped_frames_are_not_mapped_again() {
+        let mut mapped = MappedFrames::new();
+
+        assert!(!mapped.acquire(0x1000, false, || true));
+        assert!(mapped.is_pinned(0x1000));
+    }
+
+    /// Maps a table region the way `map_physical_region` does, returning the frames that had
+    /// to be mapped
+    fn map_region(mapped: &mut MappedFrames, address: u64, size: u64) -> Vec<u64> {
+        frames_of(address, size)
+            .filter(|frame| mapped.acquire(*frame, false, || false))
+            .collect()
+    }
+
+    /// Unmaps a table region, returning the frames that have to be unmapped
+    fn unmap_region(mapped: &mut MappedFrames, address: u64, size: u64) -> Vec<u64> {
+        frames_of(address, size)
+            .filter(|frame| mapped.release(*frame))
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_unmapped_with_their_last_region() {
+        let mut mapped = MappedFrames::new();
+
+        // Two tables sharing the frame at 0x2000
+        assert_eq!(map_region(&mut mapped, 0x1F00, 0x200), [0x1000, 0x2000]);
+        assert_eq!(map_region(&mut mapped, 0x2100, 0x1000), [0x3000]);
+
+        assert_eq!(unmap_region(&mut mapped, 0x1F00, 0x200), [0x1000]);
+        assert_eq!(unmap_region(&mut mapped, 0x2100, 0x1000), [0x2000, 0x3000]);
+
+        // Mapping it again maps it again
+        assert_eq!(map_region(&mut mapped, 0x2100, 0x10), [0x2000]);
+    }
+
+    #[test]
+    fn repeated_map_unmap_cycles_balance_out() {
+        let mut mapped = MappedFrames::new();
+
+        for _ in 0..10_000 {
+            assert_eq!(map_region(&mut mapped, 0x7FE0_0000, 0x24), [0x7FE0_0000]);
+            assert_eq!(map_region(&mut mapped, 0x7FE0_0100, 0xF4), []);
+            assert_eq!(unmap_region(&mut mapped, 0x7FE0_0000, 0x24), []);
+            assert_eq!(unmap_region(&mut mapped, 0x7FE0_0100, 0xF4), [0x7FE0_0000]);
+        }
+
+        assert!(mapped.0.is_empty());
+    }
+
+    #[test]
+    fn pinned_frames_are_never_unmapped() {
+        let mut mapped = MappedFrames::new();
+
+        // Mapped before ACPI got to it
+        assert!(!mapped.acquire(0x1000, false, || true));
+        assert_eq!(unmap_region(&mut mapped, 0x1000, 0x10), []);
+
+        // A table whose frame AML starts using
+        assert_eq!(map_region(&mut mapped, 0x2000, 0x10), [0x2000]);
+        assert_eq!(aml_access(&mut mapped, 0x2008, 4), []);
+        assert_eq!(unmap_region(&mut mapped, 0x2000, 0x10), []);
+        assert!(mapped.is_pinned(0x2000));
+    }
+
+    #[test]
+    fn releasing_unknown_frames_does_nothing() {
+        let mut mapped = MappedFrames::new();
+
+        assert!(!mapped.release(0x1000));
+        assert!(mapped.0.is_empty());
+    }
+}
@@ -1,31 +1,51 @@
 use acpi::{
+    address::AddressSpace,
     bgrt::Bgrt,
     fadt::Fadt,
     hpet::HpetTable,
     madt::Madt,
     mcfg::{Mcfg, McfgEntry},
+    platform::interrupt::{Polarity, TriggerMode},
     sdt::SdtHeader,
     AmlTable,
 };
 use aml::{
+    namespace::LevelType,
     pci_routing::{PciRoutingTable, Pin},
+    resource::{
+        resource_descriptor_list, AddressSpaceResourceType, InterruptPolarity, InterruptTrigger,
+        IrqDescriptor, MemoryRangeDescriptor, Resource,
+    },
     value::Args,
     AmlName, AmlValue,
 };
-use log::{debug, info};
-use pcics::{header::InterruptPin, Header};
+use bit_field::BitField;
+use bitflags::bitflags;
+use log::{debug, error, info, warn};
 use x86_64::instructions::port::Port;
 
+use super::acpi_frames::{frames_of, MappedFrames};
+use super::acpi_sleep::{slp_types, with_slp_typ, SLP_EN};
+use super::acpi_user::UserTables;
 use crate::{
-    apic_impl::{get_active_lapic, APIC_IS_INITIALIZED},
-    arch::x86_64::interrupts::{INTA_IRQ, INTB_IRQ, INTC_IRQ, INTD_IRQ},
+    ahci::util::Stopwatch,
+    apic_impl::{init_all_available_apics, local_apic_id, restore_routes, route_gsi},
+    arch::x86_64::wakeup,
+    hpet,
+    interrupts::{irqalloc, register_irq},
+    pci_impl::{
+        parent_bridge, power_down_all, restore_config_state, save_config_state, Bdf, ConfigAccess,
+    },
+    pci_scan::swizzle_to_table,
     unmap_page,
 };
 
 use {
     crate::{get_phys_offset, map_page},
-    acpi::{AcpiHandler, AcpiTables, PhysicalMapping},
+    acpi::{AcpiError, AcpiHandler, AcpiTables, PhysicalMapping},
     alloc::boxed::Box,
+    alloc::collections::BTreeMap,
+    alloc::format,
     alloc::sync::Arc,
     alloc::vec::Vec,
     aml::AmlContext,
@@ -33,12 +53,13 @@ use {
     core::{
         arch::asm,
         ptr::NonNull,
-        sync::atomic::{AtomicU64, Ordering},
+        sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     },
-    spin::RwLock,
+    spin::{Mutex, RwLock},
     x86_64::{
-        structures::paging::{Page, PageTableFlags, Size4KiB},
-        VirtAddr,
+        instructions::interrupts::without_interrupts,
+        structures::paging::{mapper::TranslateError, Mapper, Page, PageTableFlags, Size4KiB},
+        PhysAddr, VirtAddr,
     },
 };
 
@@ -194,181 +215,123 @@ impl AcpiHandler for KernelAcpi {
         physical_address: usize,
         size: usize,
     ) -> PhysicalMapping<Self, T> {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(
-            physical_address as u64 + get_phys_offset(),
-        ));
-        let virtual_address = test.start_address().as_u64();
+        let first = physical_address as u64 & !0xFFF;
+        let mapped_length = page_align(physical_address as u64 - first + size as u64, first);
 
-        // now that we handle the PageAlreadyMapped and ParentEntryHugePage errors properly, i.e. without panicking
-        map_page!(
-            physical_address,
-            virtual_address,
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+        for frame in frames_of(physical_address as u64, size as u64) {
+            acquire_frame(frame, false);
+        }
 
         PhysicalMapping::new(
             physical_address,
-            NonNull::new(virtual_address as *mut T).unwrap(), //page must exist
+            NonNull::new((physical_address as u64 + get_phys_offset()) as *mut T).unwrap(),
             size,
-            page_align(size as u64, virtual_address),
+            mapped_length,
             Self,
         )
     }
 
+    /// Every frame that isn't used by another region any more is unmapped, which also shoots
+    /// it down from the other CPUs' TLBs
     fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
-        let region_start = region.virtual_start().addr().get() as u64;
-        let p = Page::<Size4KiB>::containing_address(VirtAddr::new(region_start));
+        let first = region.physical_start() as u64 & !0xFFF;
 
-        unmap_page!(p);
+        for frame in frames_of(first, region.mapped_length() as u64) {
+            release_frame(frame);
+        }
     }
 }
 
-impl aml::Handler for KernelAcpi {
-    fn read_u8(&self, address: usize) -> u8 {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
+/// Frames mapped to the physical memory offset for ACPI. AML touches the same few opregions
+/// over and over, and going through the global mapper every time serializes on it.
+static MAPPED_FRAMES: RwLock<MappedFrames> = RwLock::new(MappedFrames::new());
 
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+/// Maps `frame` to the physical memory offset unless it already is, and takes a reference to
+/// it. `pin` keeps it mapped for good.
+fn acquire_frame(frame: u64, pin: bool) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame + get_phys_offset()));
 
-        unsafe { core::ptr::read_volatile((address + get_phys_offset() as usize) as *const u8) }
-    }
+    let mut frames = MAPPED_FRAMES.write();
 
-    fn read_u16(&self, address: usize) -> u16 {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
+    let premapped = || {
+        !matches!(
+            crate::MAPPER.get().unwrap().read().translate_page(page),
+            Err(TranslateError::PageNotMapped)
+        )
+    };
 
+    // The lock is held until the frame is mapped, so no other CPU uses it before then
+    if frames.acquire(frame, pin, premapped) {
         map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
+            frame,
+            frame + get_phys_offset(),
             Size4KiB,
             PageTableFlags::PRESENT
                 | PageTableFlags::WRITABLE
                 | PageTableFlags::NO_CACHE
                 | PageTableFlags::WRITE_THROUGH
         );
+    }
+}
 
-        unsafe { core::ptr::read_volatile((address + get_phys_offset() as usize) as *const u16) }
+/// Drops a reference to `frame` taken by `acquire_frame()`, unmapping it once it was the last
+fn release_frame(frame: u64) {
+    // The lock is dropped before unmapping, other CPUs can't acknowledge the TLB flush while
+    // waiting for it
+    let unmap = MAPPED_FRAMES.write().release(frame);
+
+    if unmap {
+        // Page table frames can't be handed back, the frame allocator only ever grows
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame + get_phys_offset()));
+        without_interrupts(|| {
+            unmap_page!(page);
+        });
     }
+}
 
-    fn read_u32(&self, address: usize) -> u32 {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
+/// Maps the frames a `T` at physical `address` lies in for good, unless an earlier access
+/// already did, and returns a pointer to it
+fn aml_map<T>(address: usize) -> *mut T {
+    for frame in frames_of(address as u64, core::mem::size_of::<T>() as u64) {
+        if !MAPPED_FRAMES.read().is_pinned(frame) {
+            acquire_frame(frame, true);
+        }
+    }
 
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+    (address + get_phys_offset() as usize) as *mut T
+}
 
-        unsafe { core::ptr::read_volatile((address + get_phys_offset() as usize) as *const u32) }
+impl aml::Handler for KernelAcpi {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { core::ptr::read_volatile(aml_map::<u8>(address)) }
     }
 
-    fn read_u64(&self, address: usize) -> u64 {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { core::ptr::read_volatile(aml_map::<u16>(address)) }
+    }
 
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(aml_map::<u32>(address)) }
+    }
 
-        unsafe { core::ptr::read_volatile((address + get_phys_offset() as usize) as *const u64) }
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { core::ptr::read_volatile(aml_map::<u64>(address)) }
     }
 
     fn write_u8(&mut self, address: usize, value: u8) {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile((address + get_phys_offset() as usize) as *mut u8, value)
-        }
+        unsafe { core::ptr::write_volatile(aml_map::<u8>(address), value) }
     }
 
     fn write_u16(&mut self, address: usize, value: u16) {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile((address + get_phys_offset() as usize) as *mut u16, value)
-        }
+        unsafe { core::ptr::write_volatile(aml_map::<u16>(address), value) }
     }
 
     fn write_u32(&mut self, address: usize, value: u32) {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile((address + get_phys_offset() as usize) as *mut u32, value)
-        }
+        unsafe { core::ptr::write_volatile(aml_map::<u32>(address), value) }
     }
 
     fn write_u64(&mut self, address: usize, value: u64) {
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
-        let test_start = test.start_address().as_u64();
-        map_page!(
-            address,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile((address + get_phys_offset() as usize) as *mut u64, value)
-        }
+        unsafe { core::ptr::write_volatile(aml_map::<u64>(address), value) }
     }
 
     fn read_io_u8(&self, port: u16) -> u8 {
@@ -414,93 +377,18 @@ impl aml::Handler for KernelAcpi {
     }
 
     fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
-        let seg_bytes = segment.to_be_bytes();
-        let offs_bytes = offset.to_be_bytes();
-        let rebuilt = u64::from_be_bytes([
-            0,
-            seg_bytes[0],
-            seg_bytes[1],
-            bus,
-            device,
-            function,
-            offs_bytes[0],
-            offs_bytes[1],
-        ]);
-
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(rebuilt));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            rebuilt,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe { core::ptr::read_volatile(((rebuilt + get_phys_offset()) as usize) as *const u8) }
+        let bdf = Bdf::new(segment, bus, device, function);
+        ConfigAccess::for_bdf(bdf).read8(bdf, offset as usize)
     }
 
     fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
-        let seg_bytes = segment.to_be_bytes();
-        let offs_bytes = offset.to_be_bytes();
-        let rebuilt = u64::from_be_bytes([
-            0,
-            seg_bytes[0],
-            seg_bytes[1],
-            bus,
-            device,
-            function,
-            offs_bytes[0],
-            offs_bytes[1],
-        ]);
-
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(rebuilt));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            rebuilt,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe { core::ptr::read_volatile(((rebuilt + get_phys_offset()) as usize) as *const u16) }
+        let bdf = Bdf::new(segment, bus, device, function);
+        ConfigAccess::for_bdf(bdf).read16(bdf, offset as usize)
     }
 
     fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
-        let seg_bytes = segment.to_be_bytes();
-        let offs_bytes = offset.to_be_bytes();
-        let rebuilt = u64::from_be_bytes([
-            0,
-            seg_bytes[0],
-            seg_bytes[1],
-            bus,
-            device,
-            function,
-            offs_bytes[0],
-            offs_bytes[1],
-        ]);
-
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(rebuilt));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            rebuilt,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe { core::ptr::read_volatile(((rebuilt + get_phys_offset()) as usize) as *const u32) }
+        let bdf = Bdf::new(segment, bus, device, function);
+        ConfigAccess::for_bdf(bdf).read32(bdf, offset as usize)
     }
 
     fn write_pci_u8(
@@ -512,35 +400,8 @@ impl aml::Handler for KernelAcpi {
         offset: u16,
         value: u8,
     ) {
-        let seg_bytes = segment.to_be_bytes();
-        let offs_bytes = offset.to_be_bytes();
-        let rebuilt = u64::from_be_bytes([
-            0,
-            seg_bytes[0],
-            seg_bytes[1],
-            bus,
-            device,
-            function,
-            offs_bytes[0],
-            offs_bytes[1],
-        ]);
-
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(rebuilt));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            rebuilt,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile(((rebuilt + get_phys_offset()) as usize) as *mut u8, value)
-        }
+        let bdf = Bdf::new(segment, bus, device, function);
+        ConfigAccess::for_bdf(bdf).write8(bdf, offset as usize, value)
     }
 
     fn write_pci_u16(
@@ -552,35 +413,8 @@ impl aml::Handler for KernelAcpi {
         offset: u16,
         value: u16,
     ) {
-        let seg_bytes = segment.to_be_bytes();
-        let offs_bytes = offset.to_be_bytes();
-        let rebuilt = u64::from_be_bytes([
-            0,
-            seg_bytes[0],
-            seg_bytes[1],
-            bus,
-            device,
-            function,
-            offs_bytes[0],
-            offs_bytes[1],
-        ]);
-
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(rebuilt));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            rebuilt,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile(((rebuilt + get_phys_offset()) as usize) as *mut u16, value)
-        }
+        let bdf = Bdf::new(segment, bus, device, function);
+        ConfigAccess::for_bdf(bdf).write16(bdf, offset as usize, value)
     }
 
     fn write_pci_u32(
@@ -592,49 +426,30 @@ impl aml::Handler for KernelAcpi {
         offset: u16,
         value: u32,
     ) {
-        let seg_bytes = segment.to_be_bytes();
-        let offs_bytes = offset.to_be_bytes();
-        let rebuilt = u64::from_be_bytes([
-            0,
-            seg_bytes[0],
-            seg_bytes[1],
-            bus,
-            device,
-            function,
-            offs_bytes[0],
-            offs_bytes[1],
-        ]);
-
-        let test = Page::<Size4KiB>::containing_address(VirtAddr::new(rebuilt));
-        let test_start = test.start_address().as_u64();
-
-        map_page!(
-            rebuilt,
-            test_start + get_phys_offset() + test.size(),
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-
-        unsafe {
-            core::ptr::write_volatile(((rebuilt + get_phys_offset()) as usize) as *mut u32, value)
-        }
+        let bdf = Bdf::new(segment, bus, device, function);
+        ConfigAccess::for_bdf(bdf).write32(bdf, offset as usize, value)
     }
 
-    fn stall(&self, _microseconds: u64) {
-        unimplemented!()
+    fn stall(&self, microseconds: u64) {
+        if hpet::is_available() {
+            hpet::busy_wait_ns(microseconds * 1000);
+        } else {
+            pm_timer_stall(microseconds);
+        }
     }
 
-    fn sleep(&self, _milliseconds: u64) {
-        unimplemented!()
+    // There's no scheduler to yield to while AML runs, so sleeping is stalling for longer
+    fn sleep(&self, milliseconds: u64) {
+        self.stall(milliseconds * 1000)
     }
 }
 
 unsafe impl Send for KernelAcpi {}
 unsafe impl Sync for KernelAcpi {}
 
+/// Whether `system_shutdown()` puts every PCI function into D3hot before powering off
+pub(crate) const SHUTDOWN_POWERS_DOWN_DEVICES: bool = true;
+
 pub(crate) static AML_CONTEXT: OnceCell<Arc<RwLock<AmlContext>>> = OnceCell::uninit();
 pub(crate) static DSDT_MAPPED: AtomicU64 = AtomicU64::new(0);
 pub(crate) static FADT: OnceCell<Arc<RwLock<Fadt>>> = OnceCell::uninit();
@@ -694,6 +509,9 @@ pub fn aml_init(tables: &AcpiTables<KernelAcpi>) {
                 ]),
             );
 
+            negotiate_osc(&mut aml_ctx);
+            thermal_init(&mut aml_ctx);
+
             // Check the SMI command port
             let smi_cmd = fadt.smi_cmd_port;
             let acpi_en = fadt.acpi_enable;
@@ -707,217 +525,1478 @@ pub fn aml_init(tables: &AcpiTables<KernelAcpi>) {
     }
 }
 
-pub fn aml_route(header: &Header) -> Option<[(u32, InterruptPin); 4]> {
-    let aml_clone = Arc::clone(AML_CONTEXT.get().expect("AML context failed to initialize"));
-    let mut aml_ctx = aml_clone.write();
-
-    if let Ok(prt) =
-        PciRoutingTable::from_prt_path(&AmlName::from_str("\\_SB.PCI0._PRT").unwrap(), &mut aml_ctx)
-    {
-        let mut a: [(u32, InterruptPin); 4] = [
-            (0, InterruptPin::IntA),
-            (0, InterruptPin::IntB),
-            (0, InterruptPin::IntC),
-            (0, InterruptPin::IntD),
-        ];
-        if let Ok(desc) = prt.route(
-            header.class_code.base as u16,
-            header.class_code.base as u16,
-            Pin::IntA,
-            &mut aml_ctx,
-        ) {
-            debug!("IntA IRQ number: {:#?}", desc.irq.clone());
-            INTA_IRQ.store((desc.irq + 32) as u64, Ordering::SeqCst);
-            a[0] = (desc.irq, InterruptPin::IntA);
-        }
-        if let Ok(desc) = prt.route(
-            header.class_code.base as u16,
-            header.class_code.base as u16,
-            Pin::IntB,
-            &mut aml_ctx,
-        ) {
-            debug!("IntB IRQ number: {:#?}", desc.irq.clone());
-            INTB_IRQ.store((desc.irq + 32) as u64, Ordering::SeqCst);
-            a[1] = (desc.irq, InterruptPin::IntB);
-        }
-        if let Ok(desc) = prt.route(
-            header.class_code.base as u16,
-            header.class_code.base as u16,
-            Pin::IntC,
-            &mut aml_ctx,
-        ) {
-            debug!("IntC IRQ number: {:#?}", desc.irq.clone());
-            INTC_IRQ.store((desc.irq + 32) as u64, Ordering::SeqCst);
-            a[2] = (desc.irq, InterruptPin::IntC);
-        }
-        if let Ok(desc) = prt.route(
-            header.class_code.base as u16,
-            header.class_code.base as u16,
-            Pin::IntD,
-            &mut aml_ctx,
-        ) {
-            debug!("IntD IRQ number: {:#?}", desc.irq.clone());
-            INTD_IRQ.store((desc.irq + 32) as u64, Ordering::SeqCst);
-            a[3] = (desc.irq, InterruptPin::IntD);
-        }
+/// `_OSC` UUID of PCI host bridges, 33DB4D5B-1FF7-401C-9657-7441C03DD766, in buffer order
+const PCI_HOST_BRIDGE_OSC_UUID: [u8; 16] = [
+    0x5b, 0x4d, 0xdb, 0x33, 0xf7, 0x1f, 0x1c, 0x40, 0x96, 0x57, 0x74, 0x41, 0xc0, 0x3d, 0xd7, 0x66,
+];
+
+bitflags! {
+    /// Support field of the PCI host bridge `_OSC`: what the OS can do
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OscSupport: u32 {
+        const EXTENDED_CONFIG = 1 << 0;
+        const ASPM            = 1 << 1;
+        const CLOCK_PM        = 1 << 2;
+        const SEGMENTS        = 1 << 3;
+        const MSI             = 1 << 4;
+    }
+}
 
-        debug!("Loading IDT...");
-        crate::arch::x86_64::interrupts::init();
+bitflags! {
+    /// Control field of the PCI host bridge `_OSC`: what the OS wants to own
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OscControl: u32 {
+        const NATIVE_HOTPLUG  = 1 << 0;
+        const SHPC_HOTPLUG    = 1 << 1;
+        const PME             = 1 << 2;
+        const AER             = 1 << 3;
+        const PCIE_CAPABILITY = 1 << 4;
+        const LTR             = 1 << 5;
+    }
+}
 
-        if !APIC_IS_INITIALIZED.load(Ordering::Relaxed) {
-            debug!("Setting up interrupts...");
-            crate::apic_impl::init_all_available_apics();
+/// Error bits in the first dword `_OSC` returns
+const OSC_FAILURE: u32 = 1 << 1;
+const OSC_UNRECOGNIZED_UUID: u32 = 1 << 2;
+const OSC_UNRECOGNIZED_REVISION: u32 = 1 << 3;
 
-            info!("Local APIC ID: {:#?}", unsafe { get_active_lapic().id() });
-        }
+/// Controls the firmware handed over through `_OSC`, see [`osc_granted`]
+static OSC_GRANTED: AtomicU32 = AtomicU32::new(0);
 
-        return Some(a);
-    }
-    None
+/// Returns which PCIe features firmware handed over to us. Without an `_OSC`, or if it
+/// failed, nothing is granted and the firmware keeps control.
+pub fn osc_granted() -> OscControl {
+    OscControl::from_bits_truncate(OSC_GRANTED.load(Ordering::Relaxed))
 }
 
-// Needed for cloning the ACPI tables into an abstraction for usermode use
-pub struct UserAcpi {
-    pub bgrt: Bgrt,
-    pub fadt: Fadt,
-    pub hpet: HpetTable,
-    pub madt: Madt,
-    pub mcfg: Vec<McfgEntry>,
-    pub dsdt: Option<AmlTable>,
-    pub ssdts: Vec<AmlTable>,
-}
+/// Builds an AML buffer object holding `bytes`
+fn aml_buffer(bytes: &[u8]) -> AmlValue {
+    let value = AmlValue::Buffer(Arc::new(Default::default()));
 
-impl UserAcpi {
-    pub fn new(tables: &AcpiTables<KernelAcpi>) -> Self {
-        Self {
-            bgrt: *tables
-                .find_table::<Bgrt>()
-                .unwrap_or_else(|e| panic!("Failed to find BGRT table: {:#?}", e)),
-            fadt: *tables
-                .find_table::<Fadt>()
-                .unwrap_or_else(|e| panic!("Failed to find FADT table: {:#?}", e)),
-            hpet: *tables
-                .find_table::<HpetTable>()
-                .unwrap_or_else(|e| panic!("Failed to find HPET table: {:#?}", e)),
-            madt: *tables
-                .find_table::<Madt>()
-                .unwrap_or_else(|e| panic!("Failed to find MADT table: {:#?}", e)),
-            mcfg: tables
-                .find_table::<Mcfg>()
-                .unwrap_or_else(|e| panic!("Failed to find MCFG table: {:#?}", e))
-                .entries()
-                .to_vec(),
-            dsdt: if let Ok(dsdt) = tables.dsdt() {
-                Some(AmlTable {
-                    address: dsdt.address,
-                    length: dsdt.length,
-                })
-            } else {
-                None
-            },
-            ssdts: {
-                let mut v = Vec::new();
-                for ssdt in tables.ssdts() {
-                    v.push(AmlTable {
-                        address: ssdt.address,
-                        length: ssdt.length,
-                    });
-                }
-                v
-            },
-        }
+    if let AmlValue::Buffer(buffer) = &value {
+        buffer.lock().extend_from_slice(bytes);
     }
-}
 
-impl Clone for UserAcpi {
-    fn clone(&self) -> Self {
-        Self {
-            bgrt: self.bgrt,
-            fadt: self.fadt,
-            hpet: self.hpet,
-            madt: self.madt,
-            mcfg: self.mcfg.clone(),
-            dsdt: self.dsdt.as_ref().map(|dsdt| AmlTable {
-                address: dsdt.address,
-                length: dsdt.length,
-            }),
-            ssdts: {
-                let mut v = Vec::new();
-                for table in self.ssdts.iter() {
-                    v.push(AmlTable {
-                        address: table.address,
-                        length: table.length,
-                    });
-                }
-                v
-            },
-        }
-    }
+    value
 }
 
-unsafe impl Send for UserAcpi {}
-unsafe impl Sync for UserAcpi {}
-
-/// Invokes the ACPI shutdown command
-///
-/// # Safety
-/// Doesn't save anything before shutting down! Equivalent to straight-up unplugging your system.
-pub unsafe fn system_shutdown() -> ! {
-    let aml_clone = Arc::clone(AML_CONTEXT.get().expect("AML context failed to initialize"));
-    let mut aml_ctx = aml_clone.write();
-
-    let _ = aml_ctx.invoke_method(
-        &AmlName::from_str("\\_PTS").unwrap(),
+/// Tells the root bridge's `_OSC` what we support and asks for native control of PCIe
+/// hotplug, PME, AER and the PCIe capability, then records what was granted
+fn negotiate_osc(aml_ctx: &mut AmlContext) {
+    let support = OscSupport::all();
+    let requested = OscControl::NATIVE_HOTPLUG
+        | OscControl::PME
+        | OscControl::AER
+        | OscControl::PCIE_CAPABILITY;
+
+    let capabilities = [0, support.bits(), requested.bits()]
+        .iter()
+        .flat_map(|dword: &u32| dword.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    let result = aml_ctx.invoke_method(
+        &AmlName::from_str("\\_SB.PCI0._OSC").unwrap(),
         Args([
-            Some(AmlValue::Integer(5)),
-            None,
-            None,
-            None,
+            Some(aml_buffer(&PCI_HOST_BRIDGE_OSC_UUID)),
+            Some(AmlValue::Integer(1)),
+            Some(AmlValue::Integer(3)),
+            Some(aml_buffer(&capabilities)),
             None,
             None,
             None,
         ]),
     );
 
-    let fadt_lock = Arc::clone(FADT.get().unwrap());
-    let fadt = fadt_lock.write();
+    let returned = match result {
+        Ok(AmlValue::Buffer(buffer)) => buffer.lock().clone(),
+        Ok(other) => {
+            warn!("ACPI: _OSC returned {:?} instead of a buffer", other);
+            return;
+        }
+        Err(e) => {
+            info!(
+                "ACPI: no usable _OSC ({:?}), firmware keeps PCIe control",
+                e
+            );
+            return;
+        }
+    };
 
-    let pm1a_block = fadt.pm1a_control_block().ok().map(|block| block.address);
+    let dword = |i: usize| {
+        returned
+            .get(i * 4..i * 4 + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
 
-    let pm1b_block = fadt
-        .pm1b_control_block()
-        .ok()
-        .map(|block_opt| block_opt.map(|block| block.address))
-        .flatten();
+    let (Some(status), Some(control)) = (dword(0), dword(2)) else {
+        warn!("ACPI: _OSC returned a {}-byte buffer", returned.len());
+        return;
+    };
 
-    let no_value = [None, None, None, None, None, None, None];
+    if status & (OSC_FAILURE | OSC_UNRECOGNIZED_UUID | OSC_UNRECOGNIZED_REVISION) != 0 {
+        warn!("ACPI: _OSC failed with status {:#x}", status);
+        return;
+    }
 
-    if let Ok(AmlValue::Package(pkg)) = aml_ctx.invoke_method(
-        &AmlName::from_str("\\_S5").unwrap_or_else(|e| panic!("Failed to execute method: {:?}", e)),
-        Args(no_value),
-    ) {
-        if let Some(pm1a) = pm1a_block {
-            let mut p = Port::new(pm1a as u16);
+    let granted = OscControl::from_bits_truncate(control) & requested;
+    OSC_GRANTED.store(granted.bits(), Ordering::Relaxed);
 
-            if let AmlValue::Integer(value) = pkg[0] {
-                let sleep_a = value as u16;
-                let out = sleep_a | 1 << 13;
+    info!(
+        "ACPI: _OSC granted {:?}, firmware kept {:?}",
+        granted,
+        requested - granted
+    );
+}
 
-                unsafe { p.write(out) };
+/// A resource from a device's `_CRS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiResource {
+    Io {
+        base: u16,
+        length: u16,
+    },
+    Memory {
+        base: u64,
+        length: u64,
+    },
+    Irq {
+        irq: u32,
+        level_triggered: bool,
+        active_low: bool,
+    },
+}
+
+/// A device described in the AML namespace
+#[derive(Debug, Clone)]
+pub struct AcpiDevice {
+    pub name: AmlName,
+    pub resources: Vec<AcpiResource>,
+}
+
+/// `_STA` bit telling whether a device is present
+const STA_PRESENT: u64 = 1 << 0;
+
+/// Encodes a PNP ID like "PNP0103" the way `EISAID()` compiles it into AML
+pub(crate) fn eisa_id(id: &str) -> Option<u64> {
+    let bytes = id.as_bytes();
+
+    if bytes.len() != 7 || !bytes[..3].iter().all(u8::is_ascii_uppercase) {
+        return None;
+    }
+
+    let letter = |c: u8| ((c - 0x40) & 0x1F) as u32;
+    let product = u32::from_str_radix(&id[3..], 16).ok()?;
+
+    let value = letter(bytes[0]) << 26 | letter(bytes[1]) << 21 | letter(bytes[2]) << 16 | product;
+    Some(value.swap_bytes() as u64)
+}
+
+/// Names of all objects of type `typ` in the namespace
+fn namespace_levels(aml_ctx: &mut AmlContext, typ: LevelType) -> Vec<AmlName> {
+    let mut names = Vec::new();
+
+    let _ = aml_ctx.namespace.traverse(|name, level| {
+        if level.typ == typ {
+            names.push(name.clone());
+        }
+
+        Ok(true)
+    });
+
+    names
+}
+
+/// Evaluates `object` relative to the scope `scope`
+pub(crate) fn evaluate_in(
+    aml_ctx: &mut AmlContext,
+    scope: &AmlName,
+    object: &str,
+) -> Option<AmlValue> {
+    let name = AmlName::from_str(object).ok()?.resolve(scope).ok()?;
+
+    aml_ctx
+        .invoke_method(&name, Args([None, None, None, None, None, None, None]))
+        .ok()
+}
+
+/// Whether `id`, a `_HID` or a single `_CID` value, is `hid`
+fn id_matches(aml_ctx: &AmlContext, id: &AmlValue, hid: &str) -> bool {
+    match id {
+        AmlValue::String(id) => id == hid,
+        id => id
+            .as_integer(aml_ctx)
+            .is_ok_and(|id| Some(id) == eisa_id(hid)),
+    }
+}
+
+fn device_matches(aml_ctx: &mut AmlContext, device: &AmlName, hid: &str) -> bool {
+    if let Some(id) = evaluate_in(aml_ctx, device, "_HID") {
+        if id_matches(aml_ctx, &id, hid) {
+            return true;
+        }
+    }
+
+    // _CID is either one ID or a package of them
+    match evaluate_in(aml_ctx, device, "_CID") {
+        Some(AmlValue::Package(ids)) => ids.iter().any(|id| id_matches(aml_ctx, id, hid)),
+        Some(id) => id_matches(aml_ctx, &id, hid),
+        None => false,
+    }
+}
+
+/// Whether `device` is present; devices without `_STA` always are
+fn device_present(aml_ctx: &mut AmlContext, device: &AmlName) -> bool {
+    match evaluate_in(aml_ctx, device, "_STA") {
+        Some(sta) => sta
+            .as_integer(aml_ctx)
+            .is_ok_and(|sta| sta & STA_PRESENT != 0),
+        None => true,
+    }
+}
+
+/// Evaluates the `_CRS` of `device` into the resources drivers care about
+fn device_resources(aml_ctx: &mut AmlContext, device: &AmlName) -> Vec<AcpiResource> {
+    let Some(crs) = evaluate_in(aml_ctx, device, "_CRS") else {
+        return Vec::new();
+    };
+
+    let Ok(resources) = resource_descriptor_list(&crs) else {
+        warn!("ACPI: can't parse the _CRS of {}", device);
+        return Vec::new();
+    };
+
+    resources
+        .into_iter()
+        .filter_map(|resource| match resource {
+            Resource::IOPort(io) => Some(AcpiResource::Io {
+                base: io.memory_range.0,
+                length: io.range_length as u16,
+            }),
+            Resource::MemoryRange(MemoryRangeDescriptor::FixedLocation {
+                base_address,
+                range_length,
+                ..
+            }) => Some(AcpiResource::Memory {
+                base: base_address as u64,
+                length: range_length as u64,
+            }),
+            Resource::AddressSpace(space) => match space.resource_type {
+                AddressSpaceResourceType::MemoryRange => Some(AcpiResource::Memory {
+                    base: space.address_range.0,
+                    length: space.length,
+                }),
+                AddressSpaceResourceType::IORange => Some(AcpiResource::Io {
+                    base: space.address_range.0 as u16,
+                    length: space.length as u16,
+                }),
+                _ => None,
+            },
+            Resource::Irq(irq) => Some(AcpiResource::Irq {
+                irq: irq.irq,
+                level_triggered: matches!(irq.trigger, InterruptTrigger::Level),
+                active_low: matches!(irq.polarity, InterruptPolarity::ActiveLow),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Finds the present devices whose `_HID` or `_CID` is `hid`, e.g. "PNP0103" for the HPET,
+/// along with the resources from their `_CRS`. Empty before `aml_init()` ran.
+pub fn find_devices_by_hid(hid: &str) -> Vec<AcpiDevice> {
+    let Some(aml) = AML_CONTEXT.get() else {
+        return Vec::new();
+    };
+
+    let mut aml_ctx = aml.write();
+
+    namespace_levels(&mut aml_ctx, LevelType::Device)
+        .into_iter()
+        .filter(|name| device_matches(&mut aml_ctx, name, hid))
+        .filter(|name| device_present(&mut aml_ctx, name))
+        .map(|name| AcpiDevice {
+            resources: device_resources(&mut aml_ctx, &name),
+            name,
+        })
+        .collect()
+}
+
+/// INTx pins in swizzle order
+const PINS: [Pin; 4] = [Pin::IntA, Pin::IntB, Pin::IntC, Pin::IntD];
+
+/// Namespace node of the device at `device` and `function` directly below `parent`, found
+/// through its `_ADR`
+fn child_by_address(
+    aml_ctx: &mut AmlContext,
+    parent: &AmlName,
+    device: u8,
+    function: u8,
+) -> Option<AmlName> {
+    let address = (device as u64) << 16 | function as u64;
+
+    namespace_levels(aml_ctx, LevelType::Device)
+        .into_iter()
+        .filter(|name| name.parent().is_ok_and(|name| name == *parent))
+        .find(|name| {
+            evaluate_in(aml_ctx, name, "_ADR")
+                .and_then(|adr| adr.as_integer(aml_ctx).ok())
+                .is_some_and(|adr| adr == address)
+        })
+}
+
+/// Namespace node describing `bus`: the root bridge for bus 0, otherwise the bridge leading to
+/// it
+fn bus_node(aml_ctx: &mut AmlContext, segment: u16, bus: u8) -> Option<AmlName> {
+    if bus == 0 {
+        return AmlName::from_str("\\_SB.PCI0").ok();
+    }
+
+    let bridge = parent_bridge(segment, bus)?;
+    let parent = bus_node(aml_ctx, bridge.segment, bridge.bus)?;
+
+    child_by_address(aml_ctx, &parent, bridge.device, bridge.function)
+}
+
+/// Looks up the interrupt that `pin` of the function at `bdf` is wired to. Functions on buses
+/// without a `_PRT` are routed as the bridge above them, with the pin rotated by their device
+/// number, until a bus with one is reached.
+pub fn aml_route(bdf: Bdf, pin: Pin) -> Option<IrqDescriptor> {
+    let aml_clone = Arc::clone(AML_CONTEXT.get().expect("AML context failed to initialize"));
+    let mut aml_ctx = aml_clone.write();
+
+    let index = PINS.iter().position(|&p| p == pin)?;
+
+    let Some((prt, at, index)) = swizzle_to_table(
+        bdf,
+        index,
+        |at| {
+            bus_node(&mut aml_ctx, at.segment, at.bus)
+                .and_then(|node| AmlName::from_str("_PRT").ok()?.resolve(&node).ok())
+                .and_then(|path| PciRoutingTable::from_prt_path(&path, &mut aml_ctx).ok())
+        },
+        |at| parent_bridge(at.segment, at.bus),
+    ) else {
+        debug!("PCI: no _PRT above {}", bdf);
+        return None;
+    };
+
+    let desc = prt
+        .route(
+            at.device as u16,
+            at.function as u16,
+            PINS[index],
+            &mut aml_ctx,
+        )
+        .ok()?;
+
+    debug!("PCI: {} {:?} is routed to IRQ {}", bdf, pin, desc.irq);
+    Some(desc)
+}
+
+/// Rate of the ACPI PM timer in Hz
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+#[derive(Debug, Clone, Copy)]
+enum PmTimerBlock {
+    Io(u16),
+    Memory(u64),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PmTimer {
+    block: PmTimerBlock,
+    /// Whether the counter is 32 instead of 24 bits wide
+    wide: bool,
+}
+
+static PM_TIMER: OnceCell<PmTimer> = OnceCell::uninit();
+
+/// Finds the PM timer in the FADT, preferring the extended X_PM_TMR_BLK. Only needs the
+/// ACPI tables, so it can run before anything else is set up.
+pub fn pm_timer_init(tables: &AcpiTables<KernelAcpi>) {
+    let Ok(fadt) = tables.find_table::<Fadt>() else {
+        return;
+    };
+
+    let Ok(Some(address)) = fadt.pm_timer_block() else {
+        warn!("ACPI: FADT has no PM timer");
+        return;
+    };
+
+    let block = match address.address_space {
+        AddressSpace::SystemIo => PmTimerBlock::Io(address.address as u16),
+        AddressSpace::SystemMemory => {
+            let virt = address.address + get_phys_offset();
+
+            map_page!(
+                address.address,
+                virt,
+                Size4KiB,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH
+            );
+
+            PmTimerBlock::Memory(virt)
+        }
+        space => {
+            warn!("ACPI: PM timer in unsupported address space {:?}", space);
+            return;
+        }
+    };
+
+    let wide = fadt.flags.pm_timer_is_32_bit();
+
+    PM_TIMER.get_or_init(|| PmTimer { block, wide });
+    info!(
+        "ACPI: {}-bit PM timer at {:?}",
+        if wide { 32 } else { 24 },
+        block
+    );
+}
+
+/// Reads the PM timer counter, or `None` if the machine has none
+pub fn pm_timer_read() -> Option<u32> {
+    let timer = PM_TIMER.get()?;
+
+    let value = match timer.block {
+        PmTimerBlock::Io(port) => unsafe { Port::<u32>::new(port).read() },
+        PmTimerBlock::Memory(address) => unsafe { (address as *const u32).read_volatile() },
+    };
+
+    Some(if timer.wide { value } else { value & 0xFF_FFFF })
+}
+
+/// PM timer ticks between two readings, assuming the counter wrapped at most once
+fn pm_timer_ticks(start: u32, end: u32) -> u64 {
+    let mask = match PM_TIMER.get() {
+        Some(PmTimer { wide: true, .. }) => u32::MAX,
+        _ => 0xFF_FFFF,
+    };
+
+    (end.wrapping_sub(start) & mask) as u64
+}
+
+/// Microseconds between two PM timer readings, assuming the counter wrapped at most once
+pub fn pm_timer_elapsed_us(start: u32, end: u32) -> u64 {
+    pm_timer_ticks(start, end) * 1_000_000 / PM_TIMER_FREQUENCY
+}
+
+/// Polls `done` until it returns `true` or `timeout_us` microseconds passed, returning
+/// whether it did. Measures time with the PM timer, or the TSC if there is none.
+pub fn pm_timer_poll(timeout_us: u64, mut done: impl FnMut() -> bool) -> bool {
+    let Some(mut last) = pm_timer_read() else {
+        let stopwatch = Stopwatch::start();
+
+        while stopwatch.elapsed_micros() < timeout_us {
+            if done() {
+                return true;
             }
 
-            if let Some(pm1b) = pm1b_block {
-                let mut p = Port::new(pm1b as u16);
+            core::hint::spin_loop();
+        }
+
+        return done();
+    };
+
+    // Accumulate ticks on every poll, so the counter can't wrap more than once in between
+    let timeout = timeout_us * PM_TIMER_FREQUENCY / 1_000_000;
+    let mut elapsed = 0;
+
+    while elapsed < timeout {
+        if done() {
+            return true;
+        }
+
+        let now = pm_timer_read().unwrap_or(last);
+        elapsed += pm_timer_ticks(last, now);
+        last = now;
+
+        core::hint::spin_loop();
+    }
+
+    done()
+}
+
+/// Spins for at least `microseconds`
+pub fn pm_timer_stall(microseconds: u64) {
+    pm_timer_poll(microseconds, || false);
+}
+
+bitflags! {
+    /// Fixed events in the PM1 status registers. The PM1 enable registers use the same bits,
+    /// except for WAKE which has no enable bit.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Pm1Event: u16 {
+        const TIMER        = 1 << 0;
+        const BUS_MASTER   = 1 << 4;
+        const GLOBAL       = 1 << 5;
+        const POWER_BUTTON = 1 << 8;
+        const SLEEP_BUTTON = 1 << 9;
+        const RTC          = 1 << 10;
+        const PCIE_WAKE    = 1 << 14;
+        const WAKE         = 1 << 15;
+    }
+}
+
+/// I/O ports of the PM1a and PM1b status registers; each enable register follows its status
+/// register at `enable_offset`
+#[derive(Debug, Clone, Copy)]
+struct Pm1EventBlocks {
+    status: [Option<u16>; 2],
+    enable_offset: u16,
+}
+
+static PM1_EVENT_BLOCKS: OnceCell<Pm1EventBlocks> = OnceCell::uninit();
+
+/// Set by the SCI handler when the power button was pressed. Shutting down takes locks the
+/// interrupted code may hold, so the main loop calls `system_shutdown()` once it sees this.
+pub static POWER_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+
+fn pm1_event_blocks() -> Option<&'static Pm1EventBlocks> {
+    PM1_EVENT_BLOCKS.get()
+}
+
+/// Returns the pending fixed events of both PM1 register blocks
+pub fn read_pm1_status() -> Pm1Event {
+    let Some(blocks) = pm1_event_blocks() else {
+        return Pm1Event::empty();
+    };
+
+    blocks
+        .status
+        .iter()
+        .flatten()
+        .fold(Pm1Event::empty(), |events, &port| {
+            events | Pm1Event::from_bits_truncate(unsafe { Port::<u16>::new(port).read() })
+        })
+}
+
+/// Acknowledges `events`; status bits are cleared by writing ones to them
+pub fn clear_pm1_status(events: Pm1Event) {
+    if let Some(blocks) = pm1_event_blocks() {
+        for &port in blocks.status.iter().flatten() {
+            unsafe { Port::<u16>::new(port).write(events.bits()) };
+        }
+    }
+}
+
+/// Enables exactly `events` to raise the SCI
+pub fn enable_pm1_events(events: Pm1Event) {
+    if let Some(blocks) = pm1_event_blocks() {
+        for &port in blocks.status.iter().flatten() {
+            let enable = (events - Pm1Event::WAKE).bits();
+            unsafe { Port::<u16>::new(port + blocks.enable_offset).write(enable) };
+        }
+    }
+}
+
+/// Enables the power button fixed event and routes the SCI to its handler through the I/O
+/// APIC. Must run after `aml_init()` and after the APICs were set up.
+pub fn sci_init() {
+    let Some(fadt) = FADT.get() else {
+        return;
+    };
+
+    let (sci, blocks) = {
+        let fadt = fadt.read();
+
+        let blocks = Pm1EventBlocks {
+            status: [
+                fadt.pm1a_event_block()
+                    .ok()
+                    .map(|block| block.address as u16),
+                fadt.pm1b_event_block()
+                    .ok()
+                    .flatten()
+                    .map(|block| block.address as u16),
+            ],
+            enable_offset: fadt.pm1_event_length as u16 / 2,
+        };
+
+        (fadt.sci_interrupt, blocks)
+    };
+
+    if blocks.status[0].is_none() {
+        warn!("ACPI: FADT has no PM1a event block, fixed events stay disabled");
+        return;
+    }
+
+    PM1_EVENT_BLOCKS.get_or_init(move || blocks);
+
+    // Don't take events that happened before we were listening
+    clear_pm1_status(Pm1Event::all());
+    enable_pm1_events(Pm1Event::POWER_BUTTON);
+    gpe_init();
+
+    let Some(vector) = irqalloc() else {
+        warn!("ACPI: out of vectors for the SCI");
+        return;
+    };
+
+    register_irq(
+        vector,
+        |_, _| sci_service(),
+        core::ptr::null_mut(),
+        Some("acpi-sci"),
+    );
+
+    // The SCI is a shareable, level-triggered, active-low interrupt
+    let dest = local_apic_id();
+
+    match route_gsi(
+        sci as u32,
+        vector,
+        dest,
+        Polarity::ActiveLow,
+        TriggerMode::Level,
+    ) {
+        Some(gsi) => info!("ACPI: SCI (IRQ {}) routed to GSI {}", sci, gsi),
+        None => warn!("ACPI: no I/O APIC handles SCI IRQ {}", sci),
+    }
+}
+
+/// Handles the fixed events that raised the SCI, without signalling the end of the interrupt
+pub fn sci_service() {
+    let status = read_pm1_status();
+
+    if !status.is_empty() {
+        clear_pm1_status(status);
+    }
+
+    if status.contains(Pm1Event::POWER_BUTTON) {
+        info!("ACPI: power button pressed");
+        POWER_BUTTON_PRESSED.store(true, Ordering::SeqCst);
+    }
+
+    gpe_service();
+}
+
+/// Offset of the global lock in the FACS
+const FACS_GLOBAL_LOCK: usize = 16;
+
+/// Global lock bits: someone waits for the lock, and someone owns it
+const GLOBAL_LOCK_PENDING: u32 = 1 << 0;
+const GLOBAL_LOCK_OWNED: u32 = 1 << 1;
+
+/// PM1 control bit telling the firmware that the global lock it waits for was released
+const GBL_RLS: u16 = 1 << 2;
+
+/// How long to wait for the firmware to release the global lock before going ahead anyway
+const GLOBAL_LOCK_TIMEOUT_US: u64 = 1_000_000;
+
+/// Virtual address of the global lock in the FACS, `None` if there is no FACS
+static GLOBAL_LOCK: OnceCell<Option<usize>> = OnceCell::uninit();
+
+/// Serializes the kernel's own users of the global lock, the FACS only tracks one owner
+static GLOBAL_LOCK_USERS: Mutex<()> = Mutex::new(());
+
+fn global_lock() -> Option<&'static AtomicU32> {
+    // Nothing is cached before the FADT is known
+    let fadt = FADT.get()?;
+
+    let address = *GLOBAL_LOCK.get_or_init(|| {
+        let facs = fadt.read().facs_address().ok()?;
+        Some(aml_map::<u32>(facs as usize + FACS_GLOBAL_LOCK) as usize)
+    });
+
+    address.map(|address| unsafe { &*(address as *const AtomicU32) })
+}
+
+/// Takes the global lock, or marks it pending if the firmware owns it. Returns whether the
+/// lock was taken.
+fn try_acquire_global_lock(lock: &AtomicU32) -> bool {
+    let mut old = lock.load(Ordering::Acquire);
+
+    loop {
+        let mut new = (old & !GLOBAL_LOCK_PENDING) | GLOBAL_LOCK_OWNED;
+
+        if old & GLOBAL_LOCK_OWNED != 0 {
+            new |= GLOBAL_LOCK_PENDING;
+        }
+
+        match lock.compare_exchange_weak(old, new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return new & GLOBAL_LOCK_PENDING == 0,
+            Err(current) => old = current,
+        }
+    }
+}
+
+/// Releases the global lock, signalling the firmware through GBL_RLS if it waits for it
+fn release_global_lock(lock: &AtomicU32) {
+    let old = lock.fetch_and(!(GLOBAL_LOCK_PENDING | GLOBAL_LOCK_OWNED), Ordering::AcqRel);
+
+    if old & GLOBAL_LOCK_PENDING == 0 {
+        return;
+    }
+
+    let pm1a = FADT
+        .get()
+        .and_then(|fadt| fadt.read().pm1a_control_block().ok())
+        .map(|block| block.address as u16);
+
+    if let Some(pm1a) = pm1a {
+        let mut port = Port::<u16>::new(pm1a);
+
+        unsafe {
+            let value = port.read() & !SLP_EN;
+            port.write(value | GBL_RLS);
+        }
+    }
+}
+
+/// Runs `f` holding the ACPI global lock, which keeps SMM firmware off hardware shared with
+/// it, such as the embedded controller and GPE registers. Interrupts are disabled meanwhile.
+/// Without a FACS `f` just runs.
+pub fn with_global_lock<R>(f: impl FnOnce() -> R) -> R {
+    without_interrupts(|| {
+        let _users = GLOBAL_LOCK_USERS.lock();
+
+        let Some(lock) = global_lock() else {
+            return f();
+        };
+
+        // The firmware raises an SCI once it released a pending lock, polling it is simpler
+        let acquired = pm_timer_poll(GLOBAL_LOCK_TIMEOUT_US, || try_acquire_global_lock(lock));
+
+        if !acquired {
+            warn!("ACPI: firmware holds on to the global lock, going ahead without it");
+        }
+
+        let result = f();
+
+        if acquired {
+            release_global_lock(lock);
+        }
+
+        result
+    })
+}
+
+/// Handler for a general-purpose event, called in interrupt context. Returns whether the
+/// event was handled.
+pub type GpeHandler = fn(u32) -> bool;
+
+/// A GPE register block: `length` status bytes at `port`, followed by as many enable bytes
+#[derive(Debug, Clone, Copy)]
+struct GpeBlock {
+    port: u16,
+    length: u16,
+    /// Number of the GPE in bit 0 of the first status byte
+    base: u32,
+}
+
+/// Number of unhandled events in a row after which a GPE gets masked
+pub const GPE_STORM_THRESHOLD: u32 = 100;
+
+static GPE_BLOCKS: OnceCell<Vec<GpeBlock>> = OnceCell::uninit();
+static GPE_HANDLERS: RwLock<BTreeMap<u32, GpeHandler>> = RwLock::new(BTreeMap::new());
+/// GPEs waiting for their AML method, masked until it ran
+static GPE_PENDING: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+static GPE_UNHANDLED: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
+
+fn gpe_blocks() -> &'static [GpeBlock] {
+    GPE_BLOCKS.get_or_init(|| {
+        let Some(fadt) = FADT.get() else {
+            return Vec::new();
+        };
+
+        let fadt = fadt.read();
+        let gpe0 = fadt.gpe0_block().ok().flatten().map(|block| GpeBlock {
+            port: block.address as u16,
+            length: fadt.gpe0_block_length as u16 / 2,
+            base: 0,
+        });
+        let gpe1 = fadt.gpe1_block().ok().flatten().map(|block| GpeBlock {
+            port: block.address as u16,
+            length: fadt.gpe1_block_length as u16 / 2,
+            base: fadt.gpe1_base as u32,
+        });
+
+        gpe0.into_iter().chain(gpe1).collect()
+    })
+}
+
+/// Returns the status port, enable port and bit of `gpe`
+fn gpe_register(gpe: u32) -> Option<(u16, u16, usize)> {
+    gpe_blocks().iter().find_map(|block| {
+        let index = gpe.checked_sub(block.base)?;
+        let byte = (index / 8) as u16;
+
+        (byte < block.length).then(|| {
+            (
+                block.port + byte,
+                block.port + block.length + byte,
+                index as usize % 8,
+            )
+        })
+    })
+}
+
+// GPE registers may be shared with SMM firmware, so they're only touched with the global
+// lock held
+
+fn set_gpe_enabled(gpe: u32, enabled: bool) {
+    if let Some((_, enable_port, bit)) = gpe_register(gpe) {
+        with_global_lock(|| unsafe {
+            let mut port = Port::<u8>::new(enable_port);
+            let mut value = port.read();
+
+            value.set_bit(bit, enabled);
+            port.write(value);
+        });
+    }
+}
+
+fn clear_gpe_status(gpe: u32) {
+    if let Some((status_port, _, bit)) = gpe_register(gpe) {
+        with_global_lock(|| unsafe { Port::<u8>::new(status_port).write(1 << bit) });
+    }
+}
+
+/// Returns the `\_GPE` method handling `gpe` and whether it is level-triggered (`_Lxx`)
+/// rather than edge-triggered (`_Exx`)
+fn gpe_method(gpe: u32) -> Option<(AmlName, bool)> {
+    let aml_ctx = AML_CONTEXT.get()?.read();
+
+    [('L', true), ('E', false)]
+        .into_iter()
+        .find_map(|(kind, level)| {
+            let name = AmlName::from_str(&format!("\\_GPE._{}{:02X}", kind, gpe)).ok()?;
+
+            aml_ctx
+                .namespace
+                .get_by_path(&name)
+                .is_ok()
+                .then_some((name, level))
+        })
+}
+
+/// Lets a driver handle `gpe` natively instead of through AML and enables it. Returns `false`
+/// if the FADT's GPE blocks don't contain `gpe`.
+pub fn register_gpe_handler(gpe: u32, handler: GpeHandler) -> bool {
+    if gpe_register(gpe).is_none() {
+        return false;
+    }
+
+    without_interrupts(|| {
+        GPE_HANDLERS.write().insert(gpe, handler);
+        GPE_UNHANDLED.lock().remove(&gpe);
+    });
+
+    clear_gpe_status(gpe);
+    set_gpe_enabled(gpe, true);
+
+    true
+}
+
+/// Masks every GPE, then enables those that have a native handler or an AML method
+fn gpe_init() {
+    let count = gpe_blocks()
+        .iter()
+        .map(|block| block.base + block.length as u32 * 8)
+        .max()
+        .unwrap_or(0);
+
+    let mut enabled = 0;
+
+    for gpe in (0..count).filter(|&gpe| gpe_register(gpe).is_some()) {
+        set_gpe_enabled(gpe, false);
+        clear_gpe_status(gpe);
+
+        if GPE_HANDLERS.read().contains_key(&gpe) || gpe_method(gpe).is_some() {
+            set_gpe_enabled(gpe, true);
+            enabled += 1;
+        }
+    }
+
+    info!("ACPI: {} of {} GPEs enabled", enabled, count);
+}
 
-                if let AmlValue::Integer(value) = pkg[1] {
-                    let sleep_b = value as u16;
-                    let out = sleep_b | 1 << 13;
+/// Counts an unhandled event of `gpe` and masks it once it storms. Returns whether the GPE
+/// may stay enabled.
+fn note_gpe_result(gpe: u32, handled: bool) -> bool {
+    let mut unhandled = GPE_UNHANDLED.lock();
 
-                    unsafe { p.write(out) };
+    if handled {
+        unhandled.remove(&gpe);
+        return true;
+    }
+
+    let count = unhandled.entry(gpe).or_insert(0);
+    *count += 1;
+
+    if *count < GPE_STORM_THRESHOLD {
+        return true;
+    }
+
+    warn!(
+        "ACPI: masking GPE {:#x} after {} unhandled events",
+        gpe, *count
+    );
+    set_gpe_enabled(gpe, false);
+
+    false
+}
+
+/// Dispatches every pending, enabled GPE. Native handlers run right away; AML methods may
+/// need locks the interrupted code holds, so their GPEs are masked and queued for
+/// [`process_gpes`].
+fn gpe_service() {
+    for block in gpe_blocks() {
+        for byte in 0..block.length {
+            let (status, enable) = unsafe {
+                (
+                    Port::<u8>::new(block.port + byte).read(),
+                    Port::<u8>::new(block.port + block.length + byte).read(),
+                )
+            };
+
+            for bit in (0..8).filter(|&bit| (status & enable).get_bit(bit)) {
+                let gpe = block.base + byte as u32 * 8 + bit as u32;
+                let handler = GPE_HANDLERS.read().get(&gpe).copied();
+
+                if let Some(handler) = handler {
+                    let handled = handler(gpe);
+
+                    clear_gpe_status(gpe);
+                    note_gpe_result(gpe, handled);
+                } else {
+                    set_gpe_enabled(gpe, false);
+                    GPE_PENDING.lock().push(gpe);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the `\_GPE._Lxx`/`_Exx` methods of the GPEs queued by the SCI handler and unmasks
+/// them again. Called from the main loop.
+pub fn process_gpes() {
+    let pending = without_interrupts(|| core::mem::take(&mut *GPE_PENDING.lock()));
+
+    for gpe in pending {
+        let handled = match gpe_method(gpe) {
+            Some((name, level)) => {
+                // Edge events are acknowledged before, level events after running the method
+                if !level {
+                    clear_gpe_status(gpe);
+                }
+
+                let result = AML_CONTEXT
+                    .get()
+                    .map(|aml| {
+                        aml.write()
+                            .invoke_method(&name, Args([None, None, None, None, None, None, None]))
+                    })
+                    .map(|result| result.is_ok())
+                    .unwrap_or(false);
+
+                if level {
+                    clear_gpe_status(gpe);
+                }
+
+                result
+            }
+            None => {
+                clear_gpe_status(gpe);
+                false
+            }
+        };
+
+        if without_interrupts(|| note_gpe_result(gpe, handled)) {
+            set_gpe_enabled(gpe, true);
+        }
+    }
+}
+
+/// Timer ticks between two runs of `poll_thermal_zones()` from the main loop
+pub const THERMAL_POLL_INTERVAL_TICKS: u64 = 5000;
+
+/// Whether a thermal zone crossing its critical trip point shuts the machine down
+pub const THERMAL_CRITICAL_SHUTDOWN: bool = true;
+
+/// Number of failed `_TMP` evaluations in a row after which a zone stops being polled
+pub const THERMAL_MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThermalState {
+    Normal,
+    /// At or above the passive cooling trip point
+    Passive,
+    /// At or above the critical trip point
+    Critical,
+}
+
+#[derive(Debug)]
+struct ThermalZone {
+    name: AmlName,
+    state: ThermalState,
+    failures: u32,
+}
+
+static THERMAL_ZONES: Mutex<Vec<ThermalZone>> = Mutex::new(Vec::new());
+
+/// Formats a temperature in tenths of a Kelvin, as AML reports it, in degrees Celsius
+struct DeciKelvin(u64);
+
+impl core::fmt::Display for DeciKelvin {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let celsius = self.0 as i64 - 2732;
+        let sign = if celsius < 0 { "-" } else { "" };
+
+        write!(f, "{}{}.{}°C", sign, celsius.abs() / 10, celsius.abs() % 10)
+    }
+}
+
+/// Evaluates the thermal zone object `zone.object` to an integer
+fn thermal_value(aml_ctx: &mut AmlContext, zone: &AmlName, object: &str) -> Option<u64> {
+    evaluate_in(aml_ctx, zone, object)?.as_integer(aml_ctx).ok()
+}
+
+/// Collects the thermal zones in the namespace for `poll_thermal_zones()`
+fn thermal_init(aml_ctx: &mut AmlContext) {
+    let names = namespace_levels(aml_ctx, LevelType::ThermalZone);
+    let mut zones = THERMAL_ZONES.lock();
+
+    for name in names {
+        match thermal_value(aml_ctx, &name, "_CRT") {
+            Some(critical) => info!(
+                "ACPI: thermal zone {}, critical at {}",
+                name,
+                DeciKelvin(critical)
+            ),
+            None => info!("ACPI: thermal zone {} without a critical trip point", name),
+        }
+
+        zones.push(ThermalZone {
+            name,
+            state: ThermalState::Normal,
+            failures: 0,
+        });
+    }
+}
+
+/// Reads the temperature of every thermal zone, logging when one crosses a trip point and
+/// shutting down once one crosses its critical trip point, if `THERMAL_CRITICAL_SHUTDOWN` is
+/// set. Called from the main loop every `THERMAL_POLL_INTERVAL_TICKS`.
+pub fn poll_thermal_zones() {
+    let Some(aml) = AML_CONTEXT.get() else {
+        return;
+    };
+
+    let mut critical = false;
+
+    {
+        let mut aml_ctx = aml.write();
+        let mut zones = THERMAL_ZONES.lock();
+
+        for zone in zones
+            .iter_mut()
+            .filter(|zone| zone.failures < THERMAL_MAX_FAILURES)
+        {
+            let Some(temperature) = thermal_value(&mut aml_ctx, &zone.name, "_TMP") else {
+                zone.failures += 1;
+
+                if zone.failures == THERMAL_MAX_FAILURES {
+                    warn!(
+                        "ACPI: _TMP of thermal zone {} failed {} times, no longer polling it",
+                        zone.name, THERMAL_MAX_FAILURES
+                    );
+                }
+
+                continue;
+            };
+
+            zone.failures = 0;
+
+            // Firmware may move the trip points at runtime, so they're read every time
+            let crt = thermal_value(&mut aml_ctx, &zone.name, "_CRT");
+            let psv = thermal_value(&mut aml_ctx, &zone.name, "_PSV");
+
+            let state = if crt.is_some_and(|crt| temperature >= crt) {
+                ThermalState::Critical
+            } else if psv.is_some_and(|psv| temperature >= psv) {
+                ThermalState::Passive
+            } else {
+                ThermalState::Normal
+            };
+
+            if state != zone.state {
+                match state {
+                    ThermalState::Critical => error!(
+                        "ACPI: thermal zone {} is critical at {}",
+                        zone.name,
+                        DeciKelvin(temperature)
+                    ),
+                    ThermalState::Passive => warn!(
+                        "ACPI: thermal zone {} needs passive cooling at {}",
+                        zone.name,
+                        DeciKelvin(temperature)
+                    ),
+                    ThermalState::Normal => info!(
+                        "ACPI: thermal zone {} is back to normal at {}",
+                        zone.name,
+                        DeciKelvin(temperature)
+                    ),
                 }
+
+                zone.state = state;
             }
+
+            critical |= state == ThermalState::Critical;
+        }
+    }
+
+    // system_shutdown() takes the AML context itself
+    if critical && THERMAL_CRITICAL_SHUTDOWN {
+        error!("ACPI: critical temperature reached, shutting down");
+        unsafe { system_shutdown() };
+    }
+}
+
+// Needed for cloning the ACPI tables into an abstraction for usermode use
+pub struct UserAcpi {
+    pub bgrt: Option<Bgrt>,
+    pub fadt: Fadt,
+    pub hpet: Option<HpetTable>,
+    pub madt: Madt,
+    pub mcfg: Vec<McfgEntry>,
+    pub dsdt: Option<AmlTable>,
+    pub ssdts: Vec<AmlTable>,
+}
+
+impl UserAcpi {
+    /// Snapshots the tables for usermode. Fails without the tables [`UserTables::collect`]
+    /// requires.
+    pub fn new(tables: &AcpiTables<KernelAcpi>) -> Result<Self, AcpiError> {
+        let fixed = UserTables::collect(
+            tables.find_table::<Bgrt>().map(|bgrt| *bgrt),
+            tables.find_table::<Fadt>().map(|fadt| *fadt),
+            tables.find_table::<HpetTable>().map(|hpet| *hpet),
+            tables.find_table::<Madt>().map(|madt| *madt),
+        )?;
+
+        Ok(Self {
+            bgrt: fixed.bgrt,
+            fadt: fixed.fadt,
+            hpet: fixed.hpet,
+            madt: fixed.madt,
+            // Machines without PCIe have no MCFG, PCI falls back to port I/O there
+            mcfg: tables
+                .find_table::<Mcfg>()
+                .map(|mcfg| mcfg.entries().to_vec())
+                .unwrap_or_default(),
+            dsdt: if let Ok(dsdt) = tables.dsdt() {
+                Some(AmlTable {
+                    address: dsdt.address,
+                    length: dsdt.length,
+                })
+            } else {
+                None
+            },
+            ssdts: {
+                let mut v = Vec::new();
+                for ssdt in tables.ssdts() {
+                    v.push(AmlTable {
+                        address: ssdt.address,
+                        length: ssdt.length,
+                    });
+                }
+                v
+            },
+        })
+    }
+}
+
+impl Clone for UserAcpi {
+    fn clone(&self) -> Self {
+        Self {
+            bgrt: self.bgrt,
+            fadt: self.fadt,
+            hpet: self.hpet,
+            madt: self.madt,
+            mcfg: self.mcfg.clone(),
+            dsdt: self.dsdt.as_ref().map(|dsdt| AmlTable {
+                address: dsdt.address,
+                length: dsdt.length,
+            }),
+            ssdts: {
+                let mut v = Vec::new();
+                for table in self.ssdts.iter() {
+                    v.push(AmlTable {
+                        address: table.address,
+                        length: table.length,
+                    });
+                }
+                v
+            },
+        }
+    }
+}
+
+unsafe impl Send for UserAcpi {}
+unsafe impl Sync for UserAcpi {}
+
+/// Why the machine couldn't be suspended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendError {
+    /// The firmware doesn't define `\_S3`
+    NotSupported,
+    /// There is no FACS to put the waking vector into, or no trampoline to point it at
+    NoWakingVector,
+    /// The sleep registers were written, but the machine kept running
+    DidNotSleep,
+}
+
+/// PM1 control register I/O ports and the SLP_TYP values to write into them
+static SLEEP_REGISTERS: Mutex<[Option<(u16, u16)>; 2]> = Mutex::new([None, None]);
+
+const SCI_EN: u16 = 1 << 0;
+
+/// Evaluates `\_Sx` for `state` and returns its SLP_TYPa and SLP_TYPb values
+fn sleep_type(aml_ctx: &mut AmlContext, state: u8) -> Option<(u16, u16)> {
+    let name = AmlName::from_str(&format!("\\_S{}", state)).ok()?;
+
+    let Ok(AmlValue::Package(pkg)) =
+        aml_ctx.invoke_method(&name, Args([None, None, None, None, None, None, None]))
+    else {
+        return None;
+    };
+
+    let element = |i: usize| match pkg.get(i) {
+        Some(AmlValue::Integer(value)) => Some(*value),
+        _ => None,
+    };
+
+    slp_types(element(0), element(1))
+}
+
+/// Invokes a sleep-related method such as `\_PTS` or `\_WAK` with the sleep state as its
+/// argument. Both are optional, so a missing method is not an error.
+fn invoke_sleep_method(aml_ctx: &mut AmlContext, path: &str, state: u8) {
+    let _ = aml_ctx.invoke_method(
+        &AmlName::from_str(path).unwrap(),
+        Args([
+            Some(AmlValue::Integer(state as u64)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ]),
+    );
+}
+
+/// Points the FACS firmware waking vector at `vector`. The 64-bit X_Firmware_Waking_Vector
+/// takes precedence when set, so it is cleared to make the firmware enter in real mode.
+fn set_waking_vector(facs: u64, vector: PhysAddr) {
+    let virt = facs + get_phys_offset();
+
+    map_page!(
+        facs,
+        virt,
+        Size4KiB,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    );
+
+    unsafe {
+        let length = ((virt + 4) as *const u32).read_volatile();
+
+        ((virt + 12) as *mut u32).write_volatile(vector.as_u64() as u32);
+
+        // ACPI 1.0 FACS end before X_Firmware_Waking_Vector
+        if length >= 32 {
+            ((virt + 24) as *mut u64).write_volatile(0);
+        }
+    }
+}
+
+/// Writes SLP_TYP and then SLP_EN into the PM1 control registers. For S3 this runs with the
+/// CPU state saved, the machine powers down while this spins.
+extern "C" fn enter_sleep() {
+    let registers = *SLEEP_REGISTERS.lock();
+
+    // Dirty cache lines are lost once the CPU powers down
+    unsafe { asm!("wbinvd", options(nostack)) };
+
+    for (port, slp_typ) in registers.into_iter().flatten() {
+        let mut port = Port::<u16>::new(port);
+
+        unsafe {
+            let value = with_slp_typ(port.read(), slp_typ);
+
+            port.write(value);
+            port.write(value | SLP_EN);
         }
+    }
+
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Suspends the machine to RAM (S3) and returns once it woke up again.
+///
+/// The disks are flushed and their controllers stopped, and the PCI configuration of every
+/// function is saved. On wakeup the CPU comes back through the trampoline in
+/// [`wakeup`], after which the PCI functions, AHCI ports, ACPI events and APICs are
+/// brought back up before interrupts, and with them the scheduler, resume.
+///
+/// # Safety
+/// Must be called on the bootstrap processor with no other processors running. Devices
+/// without a driver that knows about suspend lose their state.
+pub unsafe fn system_suspend() -> Result<(), SuspendError> {
+    let aml_clone = Arc::clone(AML_CONTEXT.get().expect("AML context failed to initialize"));
+
+    let (slp_typ_a, slp_typ_b) =
+        sleep_type(&mut aml_clone.write(), 3).ok_or(SuspendError::NotSupported)?;
+
+    let (pm1a, pm1b, facs) = {
+        let fadt = FADT.get().unwrap().read();
+
+        (
+            fadt.pm1a_control_block()
+                .ok()
+                .map(|block| block.address as u16),
+            fadt.pm1b_control_block()
+                .ok()
+                .flatten()
+                .map(|block| block.address as u16),
+            fadt.facs_address().ok(),
+        )
     };
 
-    unreachable!()
+    let facs = facs.ok_or(SuspendError::NoWakingVector)?;
+    let vector = wakeup::prepare_trampoline().ok_or(SuspendError::NoWakingVector)?;
+
+    set_waking_vector(facs as u64, vector);
+    *SLEEP_REGISTERS.lock() = [
+        pm1a.map(|port| (port, slp_typ_a)),
+        pm1b.map(|port| (port, slp_typ_b)),
+    ];
+
+    info!("ACPI: entering S3, waking vector at {:#x}", vector.as_u64());
+
+    crate::ahci::suspend();
+    save_config_state();
+
+    invoke_sleep_method(&mut aml_clone.write(), "\\_PTS", 3);
+
+    x86_64::instructions::interrupts::disable();
+
+    // A stale WAK_STS would make it look like we already woke up
+    clear_pm1_status(Pm1Event::all());
+
+    let slept = wakeup::suspend(enter_sleep);
+
+    if slept {
+        info!("ACPI: woke up from S3");
+
+        // The firmware may hand control back with ACPI mode off
+        if let Some(pm1a) = pm1a {
+            if Port::<u16>::new(pm1a).read() & SCI_EN == 0 {
+                let fadt = FADT.get().unwrap().read();
+                let (smi_cmd, acpi_enable) = (fadt.smi_cmd_port, fadt.acpi_enable);
+
+                Port::new(smi_cmd as u16).write(acpi_enable);
+            }
+        }
+    } else {
+        warn!("ACPI: the machine didn't enter S3");
+    }
+
+    restore_config_state();
+    crate::ahci::resume();
+
+    invoke_sleep_method(&mut aml_clone.write(), "\\_WAK", 3);
+
+    clear_pm1_status(Pm1Event::all());
+    enable_pm1_events(Pm1Event::POWER_BUTTON);
+    gpe_init();
+
+    // Brings back the local APIC and enables interrupts again
+    init_all_available_apics();
+    restore_routes();
+
+    if slept {
+        Ok(())
+    } else {
+        Err(SuspendError::DidNotSleep)
+    }
+}
+
+/// PM1a control registers of QEMU's q35 and pc machine types, and the value that powers
+/// them off, for when the firmware's `\_S5` can't be used
+const QEMU_PM1_CONTROL_PORTS: [u16; 2] = [0x604, 0xB004];
+const QEMU_POWER_OFF: u16 = SLP_EN;
+
+/// Default port of QEMU's isa-debug-exit device, which ends QEMU when written to
+const QEMU_DEBUG_EXIT_PORT: u16 = 0x501;
+
+/// Invokes the ACPI shutdown command
+///
+/// Falls back to the power management ports of QEMU's machine types and its isa-debug-exit
+/// device if the firmware doesn't power off, and halts if nothing works.
+///
+/// # Safety
+/// Only the disk controllers are quiesced and flushed, nothing else is saved before shutting
+/// down! PCI functions may be put into D3hot, so no driver may touch its device afterwards.
+pub unsafe fn system_shutdown() -> ! {
+    // Stop DMA and get the disks' write caches onto the medium before the power goes
+    crate::ahci::shutdown();
+
+    if SHUTDOWN_POWERS_DOWN_DEVICES {
+        power_down_all();
+    }
+
+    let slp_typ = AML_CONTEXT.get().and_then(|aml| {
+        let mut aml_ctx = aml.write();
+
+        invoke_sleep_method(&mut aml_ctx, "\\_PTS", 5);
+        sleep_type(&mut aml_ctx, 5)
+    });
+
+    let pm1 = FADT.get().map(|fadt| {
+        let fadt = fadt.read();
+
+        (
+            fadt.pm1a_control_block()
+                .ok()
+                .map(|block| block.address as u16),
+            fadt.pm1b_control_block()
+                .ok()
+                .flatten()
+                .map(|block| block.address as u16),
+        )
+    });
+
+    x86_64::instructions::interrupts::disable();
+
+    match (slp_typ, pm1) {
+        (Some((slp_typ_a, slp_typ_b)), Some((pm1a, pm1b))) => {
+            *SLEEP_REGISTERS.lock() = [
+                pm1a.map(|port| (port, slp_typ_a)),
+                pm1b.map(|port| (port, slp_typ_b)),
+            ];
+
+            enter_sleep();
+            warn!("ACPI: the machine didn't power off through \\_S5");
+        }
+        _ => warn!("ACPI: no usable \\_S5, trying QEMU's power off ports"),
+    }
+
+    for port in QEMU_PM1_CONTROL_PORTS {
+        Port::<u16>::new(port).write(QEMU_POWER_OFF);
+    }
+
+    Port::<u8>::new(QEMU_DEBUG_EXIT_PORT).write(0);
+
+    error!("ACPI: failed to power off, halting");
+
+    loop {
+        x86_64::instructions::hlt();
+    }
 }
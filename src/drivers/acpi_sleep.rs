@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Encoding of sleep states in the PM1 control registers
+
+/// Position of SLP_TYP in the PM1 control registers, bits 10-12
+const SLP_TYP_SHIFT: usize = 10;
+const SLP_TYP_MASK: u16 = 0x7;
+
+/// Starts the transition into the sleep state selected by SLP_TYP
+pub(crate) const SLP_EN: u16 = 1 << 13;
+
+/// Returns the SLP_TYPa and SLP_TYPb values out of the first two elements of a `\_Sx`
+/// package. Some packages leave out the second, which then defaults to 0.
+pub(crate) fn slp_types(a: Option<u64>, b: Option<u64>) -> Option<(u16, u16)> {
+    let slp_typ = |value: u64| value as u16 & SLP_TYP_MASK;
+
+    Some((slp_typ(a?), b.map_or(0, slp_typ)))
+}
+
+/// Returns the value of a PM1 control register holding `current` with its SLP_TYP replaced
+/// by `slp_typ`. SLP_EN is left as it was, it has to be written separately.
+pub(crate) fn with_slp_typ(current: u16, slp_typ: u16) -> u16 {
+    (current & !(SLP_TYP_MASK << SLP_TYP_SHIFT)) | (slp_typ << SLP_TYP_SHIFT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SCI_EN, which firmware sets when switching to ACPI mode
+    const SCI_EN: u16 = 1 << 0;
+
+    #[test]
+    fn slp_typ_goes_into_bits_10_to_12() {
+        assert_eq!(with_slp_typ(0, 0), 0);
+        assert_eq!(with_slp_typ(0, 5), 0x1400);
+        assert_eq!(with_slp_typ(0, 7), 0x1C00);
+    }
+
+    #[test]
+    fn other_bits_are_kept() {
+        assert_eq!(with_slp_typ(SCI_EN, 5), 0x1400 | SCI_EN);
+
+        // The previous SLP_TYP is replaced, not or-ed into
+        assert_eq!(with_slp_typ(0x1C00 | SCI_EN, 1), 0x0400 | SCI_EN);
+        assert_eq!(with_slp_typ(0x1C00, 0), 0);
+    }
+
+    #[test]
+    fn slp_en_is_written_separately() {
+        assert_eq!(with_slp_typ(0, 7) & SLP_EN, 0);
+        assert_eq!(with_slp_typ(SLP_EN, 0), SLP_EN);
+    }
+
+    #[test]
+    fn packages_give_both_values() {
+        assert_eq!(slp_types(Some(5), Some(5)), Some((5, 5)));
+        assert_eq!(slp_types(Some(0), Some(0)), Some((0, 0)));
+
+        // Only the low 3 bits fit into SLP_TYP
+        assert_eq!(slp_types(Some(0x1D), Some(0xF)), Some((5, 7)));
+    }
+
+    #[test]
+    fn slp_typb_is_optional() {
+        assert_eq!(slp_types(Some(7), None), Some((7, 0)));
+        assert_eq!(slp_types(None, Some(7)), None);
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Which ACPI tables usermode can do without
+
+/// The fixed tables usermode gets a copy of
+#[derive(Debug, PartialEq)]
+pub(crate) struct UserTables<Bgrt, Fadt, Hpet, Madt> {
+    pub bgrt: Option<Bgrt>,
+    pub fadt: Fadt,
+    pub hpet: Option<Hpet>,
+    pub madt: Madt,
+}
+
+impl<Bgrt, Fadt, Hpet, Madt> UserTables<Bgrt, Fadt, Hpet, Madt> {
+    /// Collects the results of looking up each table. Only the FADT and the MADT are
+    /// required, the kernel can't run without them anyway. Plenty of machines have no BGRT or
+    /// HPET, so failing to find those is fine.
+    pub(crate) fn collect<E>(
+        bgrt: Result<Bgrt, E>,
+        fadt: Result<Fadt, E>,
+        hpet: Result<Hpet, E>,
+        madt: Result<Madt, E>,
+    ) -> Result<Self, E> {
+        Ok(Self {
+            bgrt: bgrt.ok(),
+            fadt: fadt?,
+            hpet: hpet.ok(),
+            madt: madt?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Missing(&'static str);
+
+    type Tables = UserTables<&'static str, &'static str, &'static str, &'static str>;
+
+    fn find(name: &'static str, present: &[&str]) -> Result<&'static str, Missing> {
+        present.contains(&name).then_some(name).ok_or(Missing(name))
+    }
+
+    fn collect(present: &[&str]) -> Result<Tables, Missing> {
+        UserTables::collect(
+            find("BGRT", present),
+            find("FACP", present),
+            find("HPET", present),
+            find("APIC", present),
+        )
+    }
+
+    #[test]
+    fn every_table_present() {
+        assert_eq!(
+            collect(&["BGRT", "FACP", "HPET", "APIC"]),
+            Ok(UserTables {
+                bgrt: Some("BGRT"),
+                fadt: "FACP",
+                hpet: Some("HPET"),
+                madt: "APIC",
+            })
+        );
+    }
+
+    #[test]
+    fn bgrt_and_hpet_are_optional() {
+        // Legacy BIOS boots have no BGRT
+        let tables = collect(&["FACP", "HPET", "APIC"]).unwrap();
+        assert_eq!(tables.bgrt, None);
+        assert_eq!(tables.hpet, Some("HPET"));
+
+        let tables = collect(&["FACP", "APIC"]).unwrap();
+        assert_eq!(tables.bgrt, None);
+        assert_eq!(tables.hpet, None);
+    }
+
+    #[test]
+    fn fadt_and_madt_are_required() {
+        assert_eq!(collect(&["BGRT", "HPET", "APIC"]), Err(Missing("FACP")));
+        assert_eq!(collect(&["BGRT", "FACP", "HPET"]), Err(Missing("APIC")));
+    }
+}
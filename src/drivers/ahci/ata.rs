@@ -0,0 +1,371 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! ATA register encodings that don't depend on the HBA
+
+use alloc::vec::Vec;
+
+/// BSY bit of the status field in PxTFD
+pub(crate) const ATA_DEV_BUSY: u32 = 1 << 7;
+/// DRQ bit of the status field in PxTFD
+pub(crate) const ATA_DEV_DRQ: u32 = 1 << 3;
+
+/// Whether the task file data in `tfd` says the device can't accept a command yet
+pub(crate) fn tfd_busy(tfd: u32) -> bool {
+    tfd & (ATA_DEV_BUSY | ATA_DEV_DRQ) != 0
+}
+
+/// LBA bit of the device register, selecting LBA instead of CHS addressing
+const ATA_DEVICE_LBA: u8 = 1 << 6;
+
+/// Whether reading `count` sectors from `sector` needs a 48-bit command. LBA28 commands can't
+/// address anything past the first 2^28 sectors.
+pub(crate) fn needs_lba48(sector: usize, count: usize) -> bool {
+    sector + count > 1 << 28
+}
+
+#[allow(unused)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub(crate) enum AtaCommand {
+    WriteDma = 0xCA,
+    WriteDmaQueued = 0xCC,
+    WriteMultiple = 0xC5,
+    WriteSectors = 0x30,
+
+    ReadDma = 0xC8,
+    ReadDmaQueued = 0xC7,
+    ReadMultiple = 0xC4,
+    ReadSectors = 0x20,
+
+    WriteDmaExt = 0x35,
+    WriteDmaQueuedExt = 0x36,
+    WriteMultipleExt = 0x39,
+    WriteSectorsExt = 0x34,
+
+    ReadDmaExt = 0x25,
+    ReadDmaQueuedExt = 0x26,
+    ReadMultipleExt = 0x29,
+    ReadSectorsExt = 0x24,
+
+    Packet = 0xA0,
+    DeviceReset = 0x08,
+
+    Service = 0xA2,
+    Nop = 0,
+    NopNopAutopoll = 1,
+
+    GetMediaStatus = 0xDA,
+
+    FlushCache = 0xE7,
+    FlushCacheExt = 0xEA,
+
+    DataSetManagement = 0x06,
+
+    MediaEject = 0xED,
+
+    IdentifyPacketDevice = 0xA1,
+    IdentifyDevice = 0xEC,
+
+    Smart = 0xB0,
+
+    SanitizeDevice = 0xB4,
+
+    SecuritySetPassword = 0xF1,
+    SecurityErasePrepare = 0xF3,
+    SecurityEraseUnit = 0xF4,
+
+    ReadPortMultiplier = 0xE4,
+    WritePortMultiplier = 0xE8,
+
+    ReadFpdmaQueued = 0x60,
+    WriteFpdmaQueued = 0x61,
+
+    SetFeatures = 0xEF,
+    SetFeaturesEnableReleaseInt = 0x5D,
+    SetFeaturesEnableServiceInt = 0x5E,
+    SetFeaturesDisableReleaseInt = 0xDD,
+    SetFeaturesDisableServiceInt = 0xDE,
+}
+
+impl AtaCommand {
+    /// Whether the command takes a 48-bit LBA and a 16-bit count
+    pub(crate) fn is_lba48(&self) -> bool {
+        matches!(
+            self,
+            AtaCommand::ReadDmaExt
+                | AtaCommand::WriteDmaExt
+                | AtaCommand::ReadDmaQueuedExt
+                | AtaCommand::WriteDmaQueuedExt
+                | AtaCommand::ReadMultipleExt
+                | AtaCommand::WriteMultipleExt
+                | AtaCommand::ReadSectorsExt
+                | AtaCommand::WriteSectorsExt
+                | AtaCommand::FlushCacheExt
+                | AtaCommand::DataSetManagement
+                | AtaCommand::SanitizeDevice
+        )
+    }
+
+    /// Largest sector count a single command can transfer. ATAPI packets carry their own
+    /// 32-bit count.
+    pub(crate) fn max_sectors(&self) -> usize {
+        match self {
+            AtaCommand::Packet => u32::MAX as usize,
+            AtaCommand::ReadFpdmaQueued | AtaCommand::WriteFpdmaQueued => 0x10000,
+            command if command.is_lba48() => 0x10000,
+            _ => 0x100,
+        }
+    }
+
+    /// Encodes `count` for the count register, where 0 stands for the largest count
+    pub(crate) fn encode_count(&self, count: usize) -> u16 {
+        let max = if self.is_lba48() { 0x10000 } else { 0x100 };
+
+        assert!(count <= max, "AHCI: {} sectors for {:?}", count, self);
+
+        (count % max) as u16
+    }
+
+    /// Value of the device register addressing `sector`. LBA28 commands take LBA bits 24..28
+    /// from it.
+    pub(crate) fn device(&self, sector: usize) -> u8 {
+        let mut device = ATA_DEVICE_LBA;
+
+        if !self.is_lba48() {
+            device |= (sector >> 24) as u8 & 0xF;
+        }
+
+        device
+    }
+
+    pub(crate) fn is_write(&self) -> bool {
+        matches!(
+            self,
+            AtaCommand::WriteDmaExt
+                | AtaCommand::WriteDma
+                | AtaCommand::WriteFpdmaQueued
+                | AtaCommand::DataSetManagement
+                | AtaCommand::SecuritySetPassword
+                | AtaCommand::SecurityEraseUnit
+        )
+    }
+}
+
+/// Maximum number of sectors a single DATA SET MANAGEMENT range entry can describe
+pub(crate) const DSM_RANGE_MAX: usize = 0xFFFF;
+
+/// Range entries in each 512-byte block of a DATA SET MANAGEMENT payload
+pub(crate) const DSM_ENTRIES_PER_BLOCK: usize = 64;
+
+/// Splits `count` sectors starting at `sector` into DATA SET MANAGEMENT range entries. Each
+/// 8-byte entry holds a 48-bit LBA and a 16-bit sector count.
+pub(crate) fn dsm_entries(sector: usize, count: usize) -> Vec<u64> {
+    let mut entries = Vec::new();
+    let mut lba = sector;
+    let mut remaining = count;
+
+    while remaining > 0 {
+        let length = core::cmp::min(remaining, DSM_RANGE_MAX);
+
+        entries.push(lba as u64 | (length as u64) << 48);
+
+        lba += length;
+        remaining -= length;
+    }
+
+    entries
+}
+
+/// Lays out `entries` as the payload of one DATA SET MANAGEMENT command, zero-padded to whole
+/// 512-byte blocks
+pub(crate) fn dsm_payload(entries: &[u64]) -> Vec<u8> {
+    let blocks = entries.len().div_ceil(DSM_ENTRIES_PER_BLOCK);
+
+    let mut payload = alloc::vec![0u8; blocks * 512];
+    for (bytes, entry) in payload.chunks_exact_mut(8).zip(entries) {
+        bytes.copy_from_slice(&entry.to_le_bytes());
+    }
+
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::layout::{command_blocks, AHCI_PRDT_ENTRIES, DMA_BUFFER_SIZE};
+    use super::*;
+    use alloc::vec;
+
+    /// DRDY, set by almost every device that is ready, which an equality check used to trip on
+    const ATA_DEV_READY: u32 = 1 << 6;
+    /// ERR bit of the status field
+    const ATA_DEV_ERR: u32 = 1 << 0;
+
+    #[test]
+    fn tfd_busy_checks_bsy_and_drq_individually() {
+        assert!(tfd_busy(ATA_DEV_BUSY));
+        assert!(tfd_busy(ATA_DEV_DRQ));
+        assert!(tfd_busy(ATA_DEV_BUSY | ATA_DEV_READY));
+        assert!(tfd_busy(ATA_DEV_DRQ | ATA_DEV_READY));
+        assert!(tfd_busy(ATA_DEV_BUSY | ATA_DEV_DRQ));
+    }
+
+    #[test]
+    fn tfd_busy_ignores_other_status_bits() {
+        assert!(!tfd_busy(0));
+        assert!(!tfd_busy(ATA_DEV_READY));
+        assert!(!tfd_busy(ATA_DEV_READY | ATA_DEV_ERR));
+    }
+
+    #[test]
+    fn tfd_busy_ignores_the_error_register() {
+        // PxTFD holds the error register in bits 8..16
+        assert!(!tfd_busy(0xFF00 | ATA_DEV_READY));
+        assert!(tfd_busy(0xFF00 | ATA_DEV_BUSY));
+    }
+
+    #[test]
+    fn dsm_entries_pack_lba_and_count() {
+        assert_eq!(dsm_entries(0x1234, 8), vec![0x0008_0000_0000_1234]);
+
+        // LBAs use all 48 bits
+        assert_eq!(
+            dsm_entries(0xFFFF_FFFF_FFFF, 1),
+            vec![0x0001_FFFF_FFFF_FFFF]
+        );
+    }
+
+    #[test]
+    fn dsm_entries_split_at_range_max() {
+        assert_eq!(dsm_entries(0, DSM_RANGE_MAX), vec![0xFFFF_0000_0000_0000]);
+
+        assert_eq!(
+            dsm_entries(100, DSM_RANGE_MAX + 1),
+            vec![0xFFFF_0000_0000_0064, 0x0001_0000_0001_0063]
+        );
+
+        let entries = dsm_entries(7, 3 * DSM_RANGE_MAX + 5);
+        assert_eq!(entries.len(), 4);
+
+        // Ranges follow each other without gaps and add up to the whole count
+        let mut next = 7;
+        for entry in &entries {
+            assert_eq!(entry & 0xFFFF_FFFF_FFFF, next);
+            next += entry >> 48;
+        }
+        assert_eq!(next, 7 + 3 * DSM_RANGE_MAX as u64 + 5);
+    }
+
+    #[test]
+    fn dsm_entries_for_nothing() {
+        assert!(dsm_entries(42, 0).is_empty());
+        assert!(dsm_payload(&[]).is_empty());
+    }
+
+    #[test]
+    fn dsm_payload_is_little_endian_and_padded() {
+        let payload = dsm_payload(&[0x0008_0000_0000_1234]);
+
+        assert_eq!(payload.len(), 512);
+        assert_eq!(&payload[..8], &[0x34, 0x12, 0, 0, 0, 0, 0x08, 0]);
+        assert!(payload[8..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn dsm_payload_fills_whole_blocks() {
+        let entries = dsm_entries(0, DSM_ENTRIES_PER_BLOCK * DSM_RANGE_MAX);
+        assert_eq!(dsm_payload(&entries).len(), 512);
+
+        let entries = dsm_entries(0, DSM_ENTRIES_PER_BLOCK * DSM_RANGE_MAX + 1);
+        let payload = dsm_payload(&entries);
+        assert_eq!(payload.len(), 1024);
+        assert_eq!(&payload[512..520], &[0xC0, 0xFF, 0x3F, 0, 0, 0, 0x01, 0]);
+    }
+
+    #[test]
+    fn lba28_ends_at_2_pow_28() {
+        assert!(!needs_lba48(0, 1));
+        assert!(!needs_lba48((1 << 28) - 1, 1));
+        assert!(!needs_lba48((1 << 28) - 256, 256));
+
+        // Crossing the boundary needs the Ext commands as well
+        assert!(needs_lba48((1 << 28) - 1, 2));
+        assert!(needs_lba48(1 << 28, 1));
+    }
+
+    #[test]
+    fn counts_are_limited_by_the_command() {
+        assert_eq!(AtaCommand::ReadDma.max_sectors(), 256);
+        assert_eq!(AtaCommand::ReadSectors.max_sectors(), 256);
+        assert_eq!(AtaCommand::ReadDmaExt.max_sectors(), 65536);
+        assert_eq!(AtaCommand::ReadSectorsExt.max_sectors(), 65536);
+        assert_eq!(AtaCommand::ReadFpdmaQueued.max_sectors(), 65536);
+    }
+
+    #[test]
+    fn the_largest_count_is_encoded_as_0() {
+        assert_eq!(AtaCommand::ReadDma.encode_count(1), 1);
+        assert_eq!(AtaCommand::ReadDma.encode_count(255), 255);
+        assert_eq!(AtaCommand::ReadDma.encode_count(256), 0);
+
+        assert_eq!(AtaCommand::ReadDmaExt.encode_count(256), 256);
+        assert_eq!(AtaCommand::ReadDmaExt.encode_count(65535), 65535);
+        assert_eq!(AtaCommand::ReadDmaExt.encode_count(65536), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn lba28_counts_past_256_are_rejected() {
+        AtaCommand::ReadDma.encode_count(257);
+    }
+
+    #[test]
+    fn lba28_commands_put_the_top_lba_bits_in_the_device_register() {
+        assert_eq!(AtaCommand::ReadDma.device(0), 0x40);
+        assert_eq!(AtaCommand::ReadDma.device(0x0ABC_DEF0), 0x4A);
+        assert_eq!(AtaCommand::ReadDma.device(0x0FFF_FFFF), 0x4F);
+
+        // LBA48 commands keep the whole LBA in the LBA registers
+        assert_eq!(AtaCommand::ReadDmaExt.device(0x0ABC_DEF0), 0x40);
+    }
+
+    /// Splits a read of `count` sectors into commands the way `DmaRequest::command_count` does,
+    /// returning each command's sector count along with how it goes into the count register
+    fn split(command: AtaCommand, count: usize) -> Vec<(usize, u16)> {
+        let mut commands = Vec::new();
+        let mut offset = 0;
+
+        while offset < count {
+            let blocks = command_blocks(count, offset, 512, command.max_sectors());
+
+            commands.push((blocks, command.encode_count(blocks)));
+            offset += blocks;
+        }
+
+        commands
+    }
+
+    #[test]
+    fn a_1gib_read_splits_by_the_count_register_and_the_prdt() {
+        let sectors = (1 << 30) / 512;
+
+        // LBA28 commands fill their count register, which holds 256 as 0
+        let lba28 = split(AtaCommand::ReadDma, sectors);
+        assert_eq!(lba28.len(), 8192);
+        assert!(lba28.iter().all(|&command| command == (256, 0)));
+
+        // LBA48 commands run out of PRDT entries first
+        let prdt = AHCI_PRDT_ENTRIES * DMA_BUFFER_SIZE / 512;
+        let lba48 = split(AtaCommand::ReadDmaExt, sectors);
+        let (last, full) = lba48.split_last().unwrap();
+
+        assert_eq!(full.len(), sectors / prdt);
+        assert!(full.iter().all(|&command| command == (prdt, prdt as u16)));
+        assert_eq!(*last, (sectors % prdt, (sectors % prdt) as u16));
+
+        // A tail that doesn't fill a command
+        assert_eq!(
+            split(AtaCommand::ReadDma, 600),
+            [(256, 0), (256, 0), (88, 88)]
+        );
+    }
+}
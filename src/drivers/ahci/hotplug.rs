@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Hand-off of hotplug events from the interrupt handler to the main loop
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Ports of a controller with a connect or PhyRdy change waiting to be handled. The interrupt
+/// handler queues them without taking a lock, so it never waits for code it interrupted.
+#[derive(Debug)]
+pub(crate) struct HotplugQueue(AtomicU32);
+
+impl HotplugQueue {
+    pub(crate) const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    /// Queues port `i`. Several changes before the next [`take`](HotplugQueue::take) are
+    /// handled once, by looking at the port as it is by then.
+    pub(crate) fn push(&self, i: usize) {
+        self.0.fetch_or(1 << i, Ordering::SeqCst);
+    }
+
+    /// Empties the queue, returning the ports that were on it in ascending order
+    pub(crate) fn take(&self) -> impl Iterator<Item = usize> {
+        let ports = self.0.swap(0, Ordering::SeqCst);
+
+        (0..32).filter(move |i| ports & (1 << i) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    extern crate std;
+
+    #[test]
+    fn repeated_changes_are_handled_once() {
+        let queue = HotplugQueue::new();
+
+        queue.push(3);
+        queue.push(0);
+        queue.push(3);
+        queue.push(31);
+
+        assert_eq!(queue.take().collect::<Vec<_>>(), [0, 3, 31]);
+        assert_eq!(queue.take().count(), 0);
+    }
+
+    #[test]
+    fn changes_during_handling_wait_for_the_next_round() {
+        let queue = HotplugQueue::new();
+
+        queue.push(1);
+
+        let mut handled = Vec::new();
+        for i in queue.take() {
+            // The interrupt fired again while the main loop probed the port
+            queue.push(2);
+            handled.push(i);
+        }
+
+        assert_eq!(handled, [1]);
+        assert_eq!(queue.take().collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn no_change_is_lost_to_a_concurrent_take() {
+        use std::sync::atomic::AtomicBool;
+
+        const ROUNDS: usize = 100_000;
+
+        let queue = HotplugQueue::new();
+        let done = AtomicBool::new(false);
+
+        // Two interrupt handlers queueing a port each, against the main loop draining the queue
+        let seen = std::thread::scope(|scope| {
+            let main_loop = scope.spawn(|| {
+                let mut seen = [false; 32];
+
+                while !done.load(Ordering::SeqCst) {
+                    for i in queue.take() {
+                        seen[i] = true;
+                    }
+                }
+
+                seen
+            });
+
+            let handlers = [4, 9].map(|port| {
+                let queue = &queue;
+
+                scope.spawn(move || {
+                    for _ in 0..ROUNDS {
+                        queue.push(port);
+                    }
+                })
+            });
+
+            for handler in handlers {
+                handler.join().unwrap();
+            }
+
+            done.store(true, Ordering::SeqCst);
+            main_loop.join().unwrap()
+        });
+
+        let mut left: Vec<usize> = queue.take().collect();
+        left.extend((0..32).filter(|i| seen[*i]));
+        left.sort_unstable();
+        left.dedup();
+
+        assert_eq!(left, [4, 9]);
+    }
+}
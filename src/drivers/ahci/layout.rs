@@ -0,0 +1,400 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Layout of a request's blocks over its DMA buffers, and of the ports in the ABAR
+
+use alloc::vec::Vec;
+
+/// Most bytes a single DMA buffer holds, one 8KiB region
+pub(crate) const DMA_BUFFER_SIZE: usize = 0x2000;
+
+/// Number of PRDT entries in each command table. With the 128-byte header this makes every
+/// table 1KiB, and each entry points at one DMA buffer.
+pub(crate) const AHCI_PRDT_ENTRIES: usize = 56;
+
+/// Offset of the first port's registers in the ABAR, after the generic host control block
+pub(crate) const HBA_PORTS_OFFSET: usize = 0x100;
+/// Size of each port's registers
+pub(crate) const HBA_PORT_SIZE: usize = 0x80;
+
+/// Returns where the registers of the highest port set in the PI register `ports_implemented`
+/// end in the ABAR, or `None` without any ports
+pub(crate) fn ports_end(ports_implemented: u32) -> Option<usize> {
+    let last = 31usize.checked_sub(ports_implemented.leading_zeros() as usize)?;
+
+    Some(HBA_PORTS_OFFSET + HBA_PORT_SIZE * (last + 1))
+}
+
+/// Command header DW0: length of the command FIS in dwords, 2 to 16
+const CMD_HEADER_CFL: u16 = 0x1F;
+/// Command header DW0: port multiplier port the command goes to
+const CMD_HEADER_PMP_SHIFT: u16 = 12;
+
+/// Returns DW0 of a command header `flags` with the command FIS length set to `dwords`
+pub(crate) fn with_command_fis_size(flags: u16, dwords: usize) -> u16 {
+    debug_assert!((2..=16).contains(&dwords), "{} dword command FIS", dwords);
+
+    flags & !CMD_HEADER_CFL | dwords as u16 & CMD_HEADER_CFL
+}
+
+/// Returns the command FIS length in dwords from DW0 of a command header
+pub(crate) fn command_fis_size(flags: u16) -> usize {
+    (flags & CMD_HEADER_CFL) as usize
+}
+
+/// Returns DW0 of a command header `flags` directed at port multiplier port `pmp`
+pub(crate) fn with_port_multiplier_port(flags: u16, pmp: u8) -> u16 {
+    flags & !(0xF << CMD_HEADER_PMP_SHIFT) | (pmp as u16 & 0xF) << CMD_HEADER_PMP_SHIFT
+}
+
+/// Returns the port multiplier port from DW0 of a command header
+pub(crate) fn port_multiplier_port(flags: u16) -> u8 {
+    (flags >> CMD_HEADER_PMP_SHIFT) as u8
+}
+
+/// Returns the sizes of the buffers holding `size` bytes. Every buffer but the last is full.
+pub(crate) fn buffer_sizes(mut size: usize) -> Vec<usize> {
+    let mut sizes = Vec::new();
+
+    while size > 0 {
+        let data_size = core::cmp::min(size, DMA_BUFFER_SIZE);
+
+        sizes.push(data_size);
+        size -= data_size;
+    }
+
+    sizes
+}
+
+/// Returns how many blocks of `block_size` bytes, starting at block `offset` of a request, the
+/// PRDT of a single command can point at. Each entry points into one buffer, the first one
+/// possibly not at its start.
+pub(crate) fn prdt_blocks(offset: usize, block_size: usize) -> usize {
+    let skipped = offset * block_size % DMA_BUFFER_SIZE;
+
+    (AHCI_PRDT_ENTRIES * DMA_BUFFER_SIZE - skipped) / block_size
+}
+
+/// Returns how many blocks of a request of `count` blocks of `block_size` bytes the command
+/// starting at block `offset` covers: the rest of the request, as far as the PRDT reaches and
+/// the command's count register holds `max_blocks`
+pub(crate) fn command_blocks(
+    count: usize,
+    offset: usize,
+    block_size: usize,
+    max_blocks: usize,
+) -> usize {
+    [count - offset, prdt_blocks(offset, block_size), max_blocks]
+        .into_iter()
+        .min()
+        .unwrap()
+}
+
+/// A part of one of a request's buffers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BufferSlice {
+    /// Index of the buffer
+    pub buffer: usize,
+    /// Where the slice starts, in bytes from the start of the buffer
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Returns the slices of buffers with the given `sizes` covering bytes `start..end` of the
+/// whole request. The range doesn't have to line up with the buffers: the first and last
+/// slice are cut down to the part of their buffer inside it.
+pub(crate) fn slices(
+    sizes: impl IntoIterator<Item = usize>,
+    start: usize,
+    end: usize,
+) -> Vec<BufferSlice> {
+    let mut slices = Vec::new();
+    let mut position = 0;
+
+    for (buffer, size) in sizes.into_iter().enumerate() {
+        let buffer_end = position + size;
+
+        let from = core::cmp::max(start, position);
+        let to = core::cmp::min(end, buffer_end);
+
+        if from < to {
+            slices.push(BufferSlice {
+                buffer,
+                offset: from - position,
+                size: to - from,
+            });
+        }
+
+        position = buffer_end;
+    }
+
+    slices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: usize = 512;
+
+    /// Checks that `slices` cover bytes `start..end` of buffers with the given `sizes` in
+    /// order, with no gaps or overlaps, and stay inside their buffers
+    fn assert_covers(sizes: &[usize], slices: &[BufferSlice], start: usize, end: usize) {
+        let mut position = start;
+
+        for slice in slices {
+            let buffer_start: usize = sizes[..slice.buffer].iter().sum();
+
+            assert!(slice.size > 0, "empty slice {:?}", slice);
+            assert!(
+                slice.offset + slice.size <= sizes[slice.buffer],
+                "{:?}",
+                slice
+            );
+            assert_eq!(
+                buffer_start + slice.offset,
+                position,
+                "gap or overlap at {:?}",
+                slice
+            );
+
+            position += slice.size;
+        }
+
+        assert_eq!(position, end);
+    }
+
+    #[test]
+    fn buffer_sizes_fill_all_but_the_last_buffer() {
+        assert_eq!(buffer_sizes(0), Vec::<usize>::new());
+        assert_eq!(buffer_sizes(SECTOR), [SECTOR]);
+        assert_eq!(buffer_sizes(16 * SECTOR), [DMA_BUFFER_SIZE]);
+        assert_eq!(buffer_sizes(17 * SECTOR), [DMA_BUFFER_SIZE, SECTOR]);
+        assert_eq!(
+            buffer_sizes(40 * SECTOR),
+            [DMA_BUFFER_SIZE, DMA_BUFFER_SIZE, 8 * SECTOR]
+        );
+    }
+
+    #[test]
+    fn whole_requests_are_covered_exactly() {
+        for count in [1, 15, 16, 17, 127, 128, 129, 255, 256] {
+            let sizes = buffer_sizes(count * SECTOR);
+            let slices = slices(sizes.iter().copied(), 0, count * SECTOR);
+
+            assert_covers(&sizes, &slices, 0, count * SECTOR);
+            assert_eq!(slices.len(), sizes.len(), "{} sectors", count);
+        }
+    }
+
+    #[test]
+    fn every_sector_range_is_covered_exactly() {
+        for count in [17, 127, 129, 255] {
+            let sizes = buffer_sizes(count * SECTOR);
+
+            for offset in 0..count {
+                for length in 1..=count - offset {
+                    let start = offset * SECTOR;
+                    let end = start + length * SECTOR;
+
+                    assert_covers(
+                        &sizes,
+                        &slices(sizes.iter().copied(), start, end),
+                        start,
+                        end,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_inside_one_buffer_give_one_slice() {
+        let sizes = buffer_sizes(129 * SECTOR);
+
+        assert_eq!(
+            slices(sizes.iter().copied(), 18 * SECTOR, 20 * SECTOR),
+            [BufferSlice {
+                buffer: 1,
+                offset: 2 * SECTOR,
+                size: 2 * SECTOR,
+            }]
+        );
+
+        // The last buffer of a 129-sector request only holds one sector
+        assert_eq!(
+            slices(sizes.iter().copied(), 128 * SECTOR, 129 * SECTOR),
+            [BufferSlice {
+                buffer: 8,
+                offset: 0,
+                size: SECTOR,
+            }]
+        );
+    }
+
+    #[test]
+    fn empty_ranges_have_no_slices() {
+        let sizes = buffer_sizes(17 * SECTOR);
+
+        assert!(slices(sizes.iter().copied(), 4 * SECTOR, 4 * SECTOR).is_empty());
+        assert!(slices(sizes.iter().copied(), 17 * SECTOR, 17 * SECTOR).is_empty());
+    }
+
+    #[test]
+    fn prdt_blocks_fill_the_table_from_a_buffer_start() {
+        assert_eq!(prdt_blocks(0, SECTOR), AHCI_PRDT_ENTRIES * 16);
+        assert_eq!(prdt_blocks(16, SECTOR), AHCI_PRDT_ENTRIES * 16);
+
+        // ATAPI blocks
+        assert_eq!(prdt_blocks(0, 2048), AHCI_PRDT_ENTRIES * 4);
+    }
+
+    #[test]
+    fn prdt_blocks_leave_room_for_a_partial_first_buffer() {
+        // The first entry only covers the 13 sectors left in its buffer
+        assert_eq!(prdt_blocks(3, SECTOR), AHCI_PRDT_ENTRIES * 16 - 3);
+        assert_eq!(prdt_blocks(15, SECTOR), AHCI_PRDT_ENTRIES * 16 - 15);
+    }
+
+    #[test]
+    fn commands_stop_at_the_first_limit_they_reach() {
+        let prdt = AHCI_PRDT_ENTRIES * 16;
+
+        // The rest of the request
+        assert_eq!(command_blocks(100, 40, SECTOR, 65536), 60);
+        // The PRDT, less what an unaligned start leaves unused in the first buffer
+        assert_eq!(command_blocks(4096, 0, SECTOR, 65536), prdt);
+        assert_eq!(command_blocks(4096, 3, SECTOR, 65536), prdt - 3);
+        // The count register
+        assert_eq!(command_blocks(4096, 0, SECTOR, 256), 256);
+        assert_eq!(command_blocks(4096, 0, 2048, 223), 223);
+    }
+
+    /// Splits `count` blocks into commands with [`command_blocks`], checking that each
+    /// command's slices fit into the PRDT. Returns the number of commands.
+    fn split(count: usize, block_size: usize, max_blocks: usize) -> usize {
+        let sizes = buffer_sizes(count * block_size);
+        let mut offset = 0;
+        let mut commands = 0;
+
+        while offset < count {
+            let blocks = command_blocks(count, offset, block_size, max_blocks);
+
+            let start = offset * block_size;
+            let end = start + blocks * block_size;
+            let slices = slices(sizes.iter().copied(), start, end);
+
+            assert!(
+                slices.len() <= AHCI_PRDT_ENTRIES,
+                "{} entries for blocks {}..{}",
+                slices.len(),
+                offset,
+                offset + blocks
+            );
+            assert_covers(&sizes, &slices, start, end);
+
+            offset += blocks;
+            commands += 1;
+        }
+
+        commands
+    }
+
+    #[test]
+    fn a_1mib_read_splits_at_the_prdt() {
+        // 2048 sectors, 896 per command
+        assert_eq!(split(2048, SECTOR, 65536), 3);
+    }
+
+    #[test]
+    fn unaligned_command_limits_stay_within_the_prdt() {
+        // LBA28 commands of 256 sectors line up with the buffers, odd limits don't
+        for max_blocks in [256, 255, 897, 1000] {
+            split(4096, SECTOR, max_blocks);
+        }
+
+        split(1024, 2048, 65535);
+        split(1025, 2048, 223);
+    }
+
+    /// Reads `len` bytes the way `AhciPort::read` does: whole sectors into buffers filled with
+    /// their byte offsets, then only the first `len` bytes are copied out
+    fn read_partial(len: usize) -> Vec<u8> {
+        let count = len.div_ceil(SECTOR);
+        let sizes = buffer_sizes(count * SECTOR);
+
+        let mut position = 0;
+        let buffers: Vec<Vec<u8>> = sizes
+            .iter()
+            .map(|&size| {
+                position += size;
+                (position - size..position).map(|byte| byte as u8).collect()
+            })
+            .collect();
+
+        let mut into = Vec::new();
+        for slice in slices(sizes.iter().copied(), 0, len) {
+            into.extend_from_slice(&buffers[slice.buffer][slice.offset..][..slice.size]);
+        }
+
+        into
+    }
+
+    #[test]
+    fn partial_sectors_copy_only_what_fits() {
+        for len in [1, 511, 512, 513, 16 * SECTOR + 1] {
+            let read = read_partial(len);
+
+            assert_eq!(read.len(), len);
+            assert!(read
+                .iter()
+                .enumerate()
+                .all(|(offset, &byte)| byte == offset as u8));
+        }
+    }
+
+    #[test]
+    fn ports_end_at_the_highest_implemented_port() {
+        assert_eq!(ports_end(0), None);
+        assert_eq!(ports_end(0b1), Some(0x180));
+        assert_eq!(ports_end(0b11_1111), Some(0x400));
+
+        // Gaps in PI don't matter, port 31's registers start at 0x1080
+        assert_eq!(ports_end(1 << 31), Some(0x1100));
+        assert_eq!(ports_end(u32::MAX), Some(0x1100));
+    }
+
+    /// H2D register FIS, as programmed by `prepare_command`
+    const H2D_FIS_DWORDS: usize = 5;
+    const WRITE: u16 = 1 << 6;
+
+    #[test]
+    fn a_zeroed_command_header_gets_a_valid_fis_length() {
+        // `start` hands the HBA a freshly allocated, zeroed command list
+        let flags = with_command_fis_size(0, H2D_FIS_DWORDS);
+
+        assert_eq!(command_fis_size(flags), 5);
+        assert_eq!(flags, 5);
+    }
+
+    #[test]
+    fn the_fis_length_keeps_the_other_flags() {
+        let flags = with_port_multiplier_port(WRITE | 0x1F, 3);
+        let flags = with_command_fis_size(flags, H2D_FIS_DWORDS);
+
+        assert_eq!(command_fis_size(flags), 5);
+        assert_eq!(port_multiplier_port(flags), 3);
+        assert_eq!(flags & WRITE, WRITE);
+    }
+
+    #[test]
+    fn the_port_multiplier_port_keeps_the_fis_length() {
+        let flags = with_command_fis_size(0, H2D_FIS_DWORDS);
+
+        let flags = with_port_multiplier_port(flags, 0xF);
+        assert_eq!(port_multiplier_port(flags), 0xF);
+
+        let flags = with_port_multiplier_port(flags, 2);
+        assert_eq!(port_multiplier_port(flags), 2);
+        assert_eq!(command_fis_size(flags), 5);
+    }
+}
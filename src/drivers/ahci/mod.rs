@@ -3,35 +3,58 @@
 
 #![allow(unused)]
 
-use core::sync::atomic::Ordering;
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use acpi::AcpiTables;
 use conquer_once::spin::OnceCell;
 use pcics::header::{HeaderType, InterruptPin};
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use x86_64::{
-    instructions::interrupts::without_interrupts, registers::control::Cr3,
-    structures::paging::FrameAllocator,
-};
+use spin::RwLock;
+use x86_64::{instructions::interrupts::without_interrupts, registers::control::Cr3};
 
 use crate::{
     acpi_impl::{aml_route, KernelAcpi},
-    arch::x86_64::interrupts::{self, IDT},
+    arch::x86_64::interrupts::{self, IrqHandler, IDT},
     cralloc::frames::safe_active_pml4,
+    disk::{register_disk, unregister_disk, Disk, DiskLocation},
     get_phys_offset, map_page, MAPPER,
 };
 
-use self::util::sync::MutexGuard;
+use self::ata::{
+    dsm_entries, dsm_payload, needs_lba48, tfd_busy, AtaCommand, DSM_ENTRIES_PER_BLOCK,
+};
+use self::hotplug::HotplugQueue;
+use self::layout::{
+    buffer_sizes, command_blocks, port_multiplier_port, ports_end, slices, with_command_fis_size,
+    with_port_multiplier_port, BufferSlice, AHCI_PRDT_ENTRIES, HBA_PORTS_OFFSET, HBA_PORT_SIZE,
+};
+use self::pool::FreeList;
+use self::slots::CommandSlots;
+use self::util::sync::{
+    Completion, IrqGuard, IrqRwLock, IrqRwLockReadGuard, IrqRwLockWriteGuard, MutexGuard,
+};
 
+mod ata;
+mod hotplug;
+mod layout;
+mod pool;
+mod slots;
 pub mod util;
 
 use {
     crate::{pci_impl::*, FRAME_ALLOCATOR},
-    alloc::{sync::Arc, vec::Vec},
+    alloc::{
+        borrow::ToOwned,
+        string::String,
+        sync::{Arc, Weak},
+        vec::Vec,
+    },
     bit_field::BitField,
     log::*,
     spin::Once,
-    util::{sync::Mutex, CeilDiv, VolatileCell},
+    util::{sync::Mutex, CeilDiv, Deadline, VolatileCell},
     x86_64::{
         structures::paging::{
             mapper::MapToError, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size2MiB,
@@ -41,63 +64,221 @@ use {
     },
 };
 
-static DRIVER: Once<Arc<AhciDriver>> = Once::new();
-static BUDDY_SIZE: [u64; 3] = [
-    Page::<Size4KiB>::SIZE,
-    Page::<Size4KiB>::SIZE * 4,
-    Page::<Size2MiB>::SIZE,
-];
+/// Highest physical address (exclusive) all AHCI controllers can DMA to. Lowered to 4GiB as
+/// soon as one of them lacks CAP.S64A.
+static DMA_LIMIT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Allocates DMA memory every AHCI controller can reach, if there is any left
+fn dma_alloc(order: BuddyOrdering) -> Option<PhysAddr> {
+    pmm_alloc_below(order, DMA_LIMIT.load(Ordering::Relaxed))
+}
+
+/// Allocates `count` contiguous frames of DMA memory every AHCI controller can reach, if
+/// there are any left
+fn dma_alloc_contiguous(count: usize) -> Option<PhysAddr> {
+    pmm_alloc_contiguous_below(count, DMA_LIMIT.load(Ordering::Relaxed))
+}
+
+/// Regions handed back by dropped [`DmaBuffer`]s, reused before allocating new frames
+static DMA_POOL: DmaPool = DmaPool::new();
+
+/// Free lists of 4KiB and 8KiB DMA regions. The frame allocator can't free, so every
+/// region [`DmaRequest`]s ever used stays here once its buffer is dropped.
+#[derive(Debug)]
+pub struct DmaPool {
+    free_4k: Mutex<FreeList>,
+    free_8k: Mutex<FreeList>,
+}
+
+impl DmaPool {
+    pub const fn new() -> Self {
+        Self {
+            free_4k: Mutex::new(FreeList::new(0x1000)),
+            free_8k: Mutex::new(FreeList::new(0x2000)),
+        }
+    }
+
+    fn free_list(&self, order: BuddyOrdering) -> &Mutex<FreeList> {
+        match order {
+            BuddyOrdering::Size4KiB => &self.free_4k,
+            BuddyOrdering::Size8KiB => &self.free_8k,
+        }
+    }
+
+    /// Takes a zeroed region of `order` from the pool, allocating a new one if it is empty
+    pub fn alloc(&'static self, order: BuddyOrdering) -> DmaBuffer {
+        let size = order as usize * Page::<Size4KiB>::SIZE as usize;
+        let limit = DMA_LIMIT.load(Ordering::Relaxed);
+
+        // Regions returned before a 32-bit HBA lowered the limit may be out of reach now
+        let reused = self.free_list(order).lock_irq().take_below(limit);
+
+        let start = match reused.map(PhysAddr::new) {
+            Some(start) => {
+                let virt = VirtAddr::new(start.as_u64() + get_phys_offset());
+
+                // Zero it like a fresh allocation, so no data leaks between requests
+                unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, size) };
+                start
+            }
+            None => dma_alloc(order).expect("AHCI: out of DMA memory"),
+        };
+
+        DmaBuffer {
+            start,
+            data_size: size,
+            order,
+            pool: self,
+        }
+    }
+
+    /// Puts the region of `order` at `start` back on its free list
+    fn release(&self, order: BuddyOrdering, start: PhysAddr) {
+        self.free_list(order).lock_irq().push(start.as_u64());
+    }
+
+    /// Number of regions of `order` waiting to be reused
+    pub fn free_count(&self, order: BuddyOrdering) -> usize {
+        self.free_list(order).lock_irq().len()
+    }
+}
+
+/// Checks that `size` bytes at `address` are reachable by every AHCI controller
+fn assert_dma_reachable(address: PhysAddr, size: usize) {
+    let limit = DMA_LIMIT.load(Ordering::Relaxed);
+
+    assert!(
+        address.as_u64() + size as u64 <= limit,
+        "AHCI: DMA address {:#x} is out of reach of a 32-bit HBA",
+        address.as_u64()
+    );
+}
+
+/// Every AHCI controller found on the PCI bus, indexed by the order they were started in
+static DRIVERS: RwLock<Vec<Arc<AhciDriver>>> = RwLock::new(Vec::new());
+static HANDLE: Once<Arc<AhciHandle>> = Once::new();
 
 pub static ABAR: OnceCell<u64> = OnceCell::uninit();
 
+/// Description of the most recent disk error on any port, for debugging. See
+/// [`AhciPort::last_error`] for the errors of a single port.
+pub static EIO_DEBUG: RwLock<Option<String>> = RwLock::new(None);
+/// Kind of the most recent disk error on any port
+pub static EIO_STATUS: RwLock<Option<InterruptError>> = RwLock::new(None);
+
+/// How many times a failed command is retried before giving up
+const AHCI_MAX_RETRIES: usize = 3;
+
+/// Number of failed DMA attempts after which a read is retried with PIO commands
+const AHCI_PIO_FALLBACK_ATTEMPTS: usize = 2;
+
+/// How long to wait for a device to drop BSY and DRQ before issuing a command anyway
+const AHCI_BUSY_TIMEOUT_MS: u64 = 1000;
+
+/// How long a device may take to establish its link after being spun up
+const AHCI_SPIN_UP_TIMEOUT_MS: u64 = 1000;
+
+/// How long shutdown waits for commands still in flight before stopping the port anyway
+const AHCI_SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+/// How long DET is held at 1 for a COMRESET, the spec asks for at least 1ms
+const AHCI_COMRESET_HOLD_MS: u64 = 1;
+
+/// How long a device may take to re-establish its link after a COMRESET
+const AHCI_COMRESET_TIMEOUT_MS: u64 = 1000;
+
+/// PhyRdy change bit (DIAG.N) of PxSERR, mirrored by PxIS.PRCS
+const SERR_DIAG_N: u32 = 1 << 16;
+
+/// Returns a description of the last disk error on any port, if any occurred
+pub fn eio_debug() -> Option<String> {
+    // Written from the interrupt handler
+    without_interrupts(|| EIO_DEBUG.read().clone())
+}
+
+/// Returns a description of the last error on port `port` of AHCI controller `controller`
+pub fn eio_debug_port(controller: usize, port: usize) -> Option<String> {
+    let driver = get_ahci(controller)?;
+    let port = driver.read().ports.get(port)?.clone()?;
+
+    port.last_error().map(|error| error.message)
+}
+
+/// Converts the error bits of a port's interrupt status into an [`InterruptError`] and
+/// records it, along with a readable message, as the last error of `$port` and as the most
+/// recent disk error anywhere
+macro_rules! refactor_hba_int_err {
+    ($port:expr, $status:expr, $serr:expr, $task_file:expr) => {
+        if let Some(error) = InterruptError::from_status($status) {
+            let task_file: Option<TaskFileError> = $task_file;
+            let message = match task_file {
+                Some(tf) => alloc::format!(
+                    "{} (serr={:#x}, status={:#x}, error={:#x}, lba={:#x})",
+                    error,
+                    $serr,
+                    tf.status,
+                    tf.error,
+                    tf.lba
+                ),
+                None => alloc::format!("{} (serr={:#x})", error, $serr),
+            };
+            warn!("AHCI: {}", message);
+
+            *EIO_STATUS.write() = Some(error);
+            *EIO_DEBUG.write() = Some(message.clone());
+
+            $port.last_error = Some(PortError {
+                error,
+                serr: $serr,
+                task_file,
+                message,
+            });
+        }
+    };
+}
+
+/// Size of a DMA allocation, in 4KiB frames
 #[repr(usize)]
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum BuddyOrdering {
-    Size4KiB = 0,
+    Size4KiB = 1,
     Size8KiB = 2,
 }
 
 pub fn pmm_alloc(order: BuddyOrdering) -> PhysAddr {
-    // borrow checker
-    let copied_order = order.clone();
+    pmm_alloc_contiguous(order as usize)
+}
 
-    let raw_order = order as usize;
-    debug_assert!(raw_order <= BUDDY_SIZE.len());
+/// Same as [`pmm_alloc`], but the whole allocation lies below the physical address `limit`.
+/// Returns `None` if there is no memory left below it.
+pub fn pmm_alloc_below(order: BuddyOrdering, limit: u64) -> Option<PhysAddr> {
+    pmm_alloc_contiguous_below(order as usize, limit)
+}
+
+/// Allocates `count` physically contiguous frames, mapped uncached and zeroed
+pub fn pmm_alloc_contiguous(count: usize) -> PhysAddr {
+    pmm_alloc_contiguous_below(count, u64::MAX).expect("Out of memory")
+}
 
-    let frame = FRAME_ALLOCATOR
+/// Allocates `count` physically contiguous frames ending below the physical address `limit`,
+/// mapped uncached and zeroed. Returns `None` if there is no such run left.
+pub fn pmm_alloc_contiguous_below(count: usize, limit: u64) -> Option<PhysAddr> {
+    let phys = FRAME_ALLOCATOR
         .get()
         .expect("Frame allocator not initialized")
         .write()
-        .allocate_frame()
-        .expect("Out of memory");
+        .allocate_contiguous_below(count, limit)?
+        .start_address()
+        .as_u64();
 
-    let phys = frame.start_address().as_u64();
-    let virt = unsafe { phys + get_phys_offset() };
+    let size = count as u64 * Page::<Size4KiB>::SIZE;
 
-    if let BuddyOrdering::Size4KiB = copied_order {
-        map_page!(
-            phys,
-            virt,
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
-    } else {
-        // map 2 pages to take the 8KiB size into account
-        map_page!(
-            phys,
-            virt,
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+    let virt = phys + get_phys_offset();
+
+    for offset in (0..size).step_by(Page::<Size4KiB>::SIZE as usize) {
         map_page!(
-            phys + 4096,
-            virt + 4096,
+            phys + offset,
+            virt + offset,
             Size4KiB,
             PageTableFlags::PRESENT
                 | PageTableFlags::WRITABLE
@@ -106,13 +287,12 @@ pub fn pmm_alloc(order: BuddyOrdering) -> PhysAddr {
         );
     }
 
-    let fill_size = BUDDY_SIZE[raw_order] as usize;
-    let slice = unsafe { core::slice::from_raw_parts_mut(virt as *mut u8, fill_size) };
+    let slice = unsafe { core::slice::from_raw_parts_mut(virt as *mut u8, size as usize) };
 
     // We always zero out memory for security reasons.
     slice.fill(0x00);
 
-    PhysAddr::new(phys)
+    Some(PhysAddr::new(phys))
 }
 
 bitflags::bitflags! {
@@ -132,6 +312,7 @@ bitflags::bitflags! {
 }
 
 bitflags::bitflags! {
+    #[derive(Clone, Copy)]
     struct HbaCapabilities2: u32 {
         const BOH   = 1 << 0; // BIOS/OS Handoff
         const NVMP  = 1 << 1; // NVMHCI Present
@@ -154,6 +335,7 @@ bitflags::bitflags! {
 }
 
 bitflags::bitflags! {
+    #[derive(Clone, Copy)]
     struct HbaCapabilities: u32 {
         const SXS           = 1 << 5;  // Supports External SATA
         const EMS           = 1 << 6;  // Enclosure Management Supported
@@ -175,6 +357,13 @@ bitflags::bitflags! {
     }
 }
 
+impl HbaCapabilities {
+    /// Number of command slots per port (CAP.NCS is zero-based)
+    fn command_slots(&self) -> usize {
+        self.bits().get_bits(8..13) as usize + 1
+    }
+}
+
 bitflags::bitflags! {
     #[derive(Clone, Copy)]
     struct HbaHostCont: u32 {
@@ -208,6 +397,61 @@ bitflags::bitflags! {
     }
 }
 
+/// Error conditions reported through a port's interrupt status register, or caught by the
+/// driver before a command reached the device
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum InterruptError {
+    TaskFile,
+    HostBusFatal,
+    HostBusData,
+    InterfaceFatal,
+    InterfaceNonFatal,
+    Overflow,
+    IncorrectPortMultiplier,
+    /// The request went past the last sector of the device
+    OutOfRange,
+}
+
+impl InterruptError {
+    /// Picks the most severe error set in `status`, if any
+    pub fn from_status(status: HbaPortIS) -> Option<Self> {
+        if status.contains(HbaPortIS::HBFS) {
+            Some(Self::HostBusFatal)
+        } else if status.contains(HbaPortIS::HBDS) {
+            Some(Self::HostBusData)
+        } else if status.contains(HbaPortIS::IFS) {
+            Some(Self::InterfaceFatal)
+        } else if status.contains(HbaPortIS::TFES) {
+            Some(Self::TaskFile)
+        } else if status.contains(HbaPortIS::OFS) {
+            Some(Self::Overflow)
+        } else if status.contains(HbaPortIS::IPMS) {
+            Some(Self::IncorrectPortMultiplier)
+        } else if status.contains(HbaPortIS::INFS) {
+            Some(Self::InterfaceNonFatal)
+        } else {
+            None
+        }
+    }
+}
+
+impl core::fmt::Display for InterruptError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let message = match self {
+            Self::TaskFile => "task file error",
+            Self::HostBusFatal => "host bus fatal error",
+            Self::HostBusData => "host bus data error",
+            Self::InterfaceFatal => "interface fatal error",
+            Self::InterfaceNonFatal => "interface non-fatal error",
+            Self::Overflow => "overflow",
+            Self::IncorrectPortMultiplier => "incorrect port multiplier",
+            Self::OutOfRange => "LBA out of range",
+        };
+
+        f.write_str(message)
+    }
+}
+
 bitflags::bitflags! {
     #[derive(Clone, Copy)]
     struct HbaPortIE: u32 {
@@ -274,13 +518,136 @@ impl HbaCmdHeaderFlags {
     /// length of the FIS it shall send to the device.
     #[inline]
     fn set_command_fis_size(&mut self, size: usize) {
-        self.bits().set_bits(0..=4, size as _);
+        *self = Self::from_bits_retain(with_command_fis_size(self.bits(), size));
+    }
+
+    /// Returns the port multiplier port the command is sent to.
+    #[inline]
+    fn port_multiplier_port(&self) -> u8 {
+        port_multiplier_port(self.bits())
+    }
+
+    /// Sets the port multiplier port the command is sent to.
+    #[inline]
+    fn set_port_multiplier_port(&mut self, pmp: u8) {
+        *self = Self::from_bits_retain(with_port_multiplier_port(self.bits(), pmp));
     }
 }
 
 #[derive(Debug)]
 enum DmaCommand {
     Read,
+    PacketRead,
+}
+
+/// Signature reported in PxSIG by a plain SATA drive
+const SATA_SIG_ATA: u32 = 0x0000_0101;
+/// Signature reported in PxSIG by a SATAPI device (e.g. a CD-ROM)
+const SATA_SIG_ATAPI: u32 = 0xEB14_0101;
+/// Signature reported in PxSIG by a port multiplier
+const SATA_SIG_PM: u32 = 0x9669_0101;
+/// Signature reported in PxSIG by an enclosure management bridge (SEMB)
+const SATA_SIG_SEMB: u32 = 0xC33C_0101;
+
+/// Port multiplier port of the multiplier's own control port
+const PM_CONTROL_PORT: u8 = 0xF;
+/// GSCR[2]: number of fan-out ports exposed by the port multiplier
+const PM_GSCR_INFO: u8 = 2;
+/// PSCR registers of a fan-out port, mirroring PxSSTS, PxSERR and PxSCTL
+const PM_PSCR_SSTATUS: u8 = 0;
+const PM_PSCR_SERROR: u8 = 1;
+const PM_PSCR_SCONTROL: u8 = 2;
+/// Command slots handed to each device behind a port multiplier if the HBA has enough.
+/// Without FIS-based switching all of them share the host port's command list.
+const PM_SLOTS_PER_PORT: usize = 2;
+
+/// TRIM bit in the features register of DATA SET MANAGEMENT
+const DSM_TRIM: u16 = 1 << 0;
+
+/// Features register values selecting the SMART subcommand
+const SMART_READ_DATA: u16 = 0xD0;
+const SMART_RETURN_STATUS: u16 = 0xDA;
+
+/// Magic value SMART commands expect in LBA bits 8..24
+const SMART_LBA: usize = 0xC2_4F00;
+/// LBA bits 8..24 returned by SMART RETURN STATUS once a threshold was exceeded
+const SMART_LBA_THRESHOLD_EXCEEDED: usize = 0x2C_F400;
+
+/// Number of attribute entries in the SMART data structure, 12 bytes each from offset 2
+const SMART_ATTRIBUTE_COUNT: usize = 30;
+
+/// Features register values selecting the SANITIZE DEVICE subcommand
+const SANITIZE_STATUS_EXT: u16 = 0x0000;
+const SANITIZE_CRYPTO_SCRAMBLE_EXT: u16 = 0x0011;
+/// "Cryp", required in the LBA of CRYPTO SCRAMBLE EXT so it can't be issued by accident
+const SANITIZE_CRYPTO_SIGNATURE: usize = 0x4372_7970;
+
+/// Control word bit of the SECURITY ERASE UNIT payload selecting the enhanced erase
+const SECURITY_ENHANCED_ERASE: u16 = 1 << 1;
+
+/// Logical block size used by ATAPI optical drives
+const ATAPI_SECTOR_SIZE: usize = 2048;
+
+/// Trade-off between latency and power used for a port's link
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PowerPolicy {
+    /// Keep the link active at all times
+    Performance,
+    /// Let the HBA put the idle link into partial or slumber
+    PowerSave,
+}
+
+/// What kind of device is attached to an AHCI port, based on its signature
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum HbaPortKind {
+    SataDrive,
+    SataPacketInterface,
+    PortMultiplier,
+    EnclosureManagementBridge,
+    Unknown(u32),
+}
+
+impl HbaPortKind {
+    fn from_signature(sig: u32) -> Self {
+        match sig {
+            SATA_SIG_ATA => Self::SataDrive,
+            SATA_SIG_ATAPI => Self::SataPacketInterface,
+            SATA_SIG_PM => Self::PortMultiplier,
+            SATA_SIG_SEMB => Self::EnclosureManagementBridge,
+            sig => Self::Unknown(sig),
+        }
+    }
+
+    /// Whether the device stores data and should be registered as a disk
+    pub fn is_disk(&self) -> bool {
+        matches!(self, Self::SataDrive | Self::SataPacketInterface)
+    }
+}
+
+/// SCSI commands sent to ATAPI devices inside a PACKET command
+#[allow(dead_code)] //future-proof
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub(crate) enum ScsiCommand {
+    TestUnitReady = 0x00,
+    RequestSense = 0x03,
+    ReadCapacity10 = 0x25,
+    Read12 = 0xA8,
+}
+
+impl ScsiCommand {
+    /// Builds the 12-byte command packet for this command
+    fn packet(self, lba: u32, count: u32) -> [u8; 12] {
+        let mut packet = [0u8; 12];
+        packet[0] = self as u8;
+
+        if let ScsiCommand::Read12 = self {
+            packet[2..6].copy_from_slice(&lba.to_be_bytes());
+            packet[6..10].copy_from_slice(&count.to_be_bytes());
+        }
+
+        packet
+    }
 }
 
 #[derive(Debug)]
@@ -289,11 +656,22 @@ pub struct DmaBuffer {
     start: PhysAddr,
     /// The data size of the DMA buffer.
     data_size: usize,
+    /// Size of the region backing the buffer
+    order: BuddyOrdering,
+    /// Pool the region goes back to when the buffer is dropped
+    pool: &'static DmaPool,
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        self.pool.release(self.order, self.start);
+    }
 }
 
 impl DmaBuffer {
-    pub fn sectors(&self) -> usize {
-        self.data_size.ceil_div(512)
+    /// Number of `block_size`-byte blocks the buffer holds
+    pub fn sectors(&self, block_size: usize) -> usize {
+        self.data_size.ceil_div(block_size)
     }
 
     pub fn start(&self) -> PhysAddr {
@@ -305,39 +683,66 @@ impl DmaBuffer {
     }
 }
 
+/// A physically contiguous part of a [`DmaRequest`], described by a single PRDT entry
+#[derive(Debug, Clone, Copy)]
+pub struct DmaSegment {
+    start: PhysAddr,
+    size: usize,
+}
+
+impl DmaSegment {
+    pub fn start(&self) -> PhysAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
 #[derive(Debug)]
 pub struct DmaRequest {
     sector: usize,
     pub count: usize,
+    block_size: usize,
     buffer: Vec<DmaBuffer>,
     command: DmaCommand,
 }
 
 impl DmaRequest {
-    /// Creates a new DMA request for the given sector and count.
-    pub fn new(sector: usize, count: usize) -> Self {
-        let mut size = count * 512;
+    /// Creates a new DMA request for the given sector and count, using the device's
+    /// logical sector size.
+    pub fn new(sector: usize, count: usize, block_size: usize) -> Self {
+        Self::new_inner(sector, count, block_size, DmaCommand::Read)
+    }
+
+    /// Creates a new DMA request reading `count` 2KiB blocks from an ATAPI device.
+    pub fn new_packet(sector: usize, count: usize) -> Self {
+        Self::new_inner(sector, count, ATAPI_SECTOR_SIZE, DmaCommand::PacketRead)
+    }
+
+    fn new_inner(sector: usize, count: usize, block_size: usize, command: DmaCommand) -> Self {
         let mut buffer = Vec::<DmaBuffer>::new();
 
-        while size > 0 {
-            let data_size = core::cmp::min(size, 0x2000);
-            let ordering = if size > 0x1000 {
+        for data_size in buffer_sizes(count * block_size) {
+            let ordering = if data_size > 0x1000 {
                 BuddyOrdering::Size8KiB
             } else {
                 BuddyOrdering::Size4KiB
             };
 
-            let start = pmm_alloc(ordering);
+            let mut dma = DMA_POOL.alloc(ordering);
+            dma.data_size = data_size;
 
-            buffer.push(DmaBuffer { start, data_size });
-            size -= data_size; // Subtract the data size from the total size.
+            buffer.push(dma);
         }
 
         Self {
             sector,
             count,
+            block_size,
             buffer,
-            command: DmaCommand::Read,
+            command,
         }
     }
 
@@ -345,31 +750,55 @@ impl DmaRequest {
         self.sector
     }
 
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
     /// Copys the data from the DMA buffer into the given buffer.
     pub fn copy_into(&self, into: &mut [u8]) {
         let mut offset = 0x00; // Keep track of the offset
-        let mut remaning = into.len(); // Keep track of the remaining data
 
-        for buffer in self.buffer.iter() {
-            let count = core::cmp::min(remaning, 0x2000);
+        for slice in self.byte_slices(into.len()) {
+            let buffer = unsafe {
+                core::slice::from_raw_parts::<u8>(self.slice_addr(&slice).as_ptr(), slice.size)
+            };
 
-            let buffer_phys = buffer.start();
-            let buffer_virt = VirtAddr::new(buffer_phys.as_u64() + get_phys_offset());
+            into[offset..offset + slice.size].copy_from_slice(buffer);
+            offset += slice.size;
+        }
+    }
 
-            let buffer_pointer = buffer_virt.as_ptr();
-            let buffer = unsafe { core::slice::from_raw_parts::<u8>(buffer_pointer, count) };
+    /// Copys the data from the given buffer into the DMA buffer.
+    pub fn copy_from(&self, from: &[u8]) {
+        let mut offset = 0x00;
 
-            // Copy the data from the buffer into the given buffer with the
-            // calculated offset.
-            into[offset..offset + count].copy_from_slice(buffer);
+        for slice in self.byte_slices(from.len()) {
+            let buffer = unsafe {
+                core::slice::from_raw_parts_mut::<u8>(
+                    self.slice_addr(&slice).as_mut_ptr(),
+                    slice.size,
+                )
+            };
 
-            remaning -= count; // Subtract the size from the remaining size.
-            offset += count; // Add the size to the offset.
+            buffer.copy_from_slice(&from[offset..offset + slice.size]);
+            offset += slice.size;
         }
     }
 
+    /// Returns the slices of the buffers holding the first `len` bytes of the request, or all
+    /// of them if there are fewer
+    fn byte_slices(&self, len: usize) -> Vec<BufferSlice> {
+        slices(self.buffer.iter().map(DmaBuffer::data_size), 0, len)
+    }
+
+    fn slice_addr(&self, slice: &BufferSlice) -> VirtAddr {
+        let start = self.buffer[slice.buffer].start().as_u64() + slice.offset as u64;
+
+        VirtAddr::new(start + get_phys_offset())
+    }
+
     pub(crate) fn as_command(&self) -> AtaCommand {
-        let lba48 = self.sector > 0x0FFF_FFFF;
+        let lba48 = needs_lba48(self.sector, self.count);
 
         match self.command {
             DmaCommand::Read => {
@@ -379,76 +808,248 @@ impl DmaRequest {
                     AtaCommand::ReadDma
                 }
             }
+            DmaCommand::PacketRead => AtaCommand::Packet,
         }
     }
 
-    pub fn at_offset(&self, offset: usize) -> &[DmaBuffer] {
-        &self.buffer[offset / 16..]
+    /// Returns the PIO equivalent of [`as_command`](DmaRequest::as_command), if there is one.
+    /// The HBA still moves the data through the PRDT, only the device sees a PIO transfer.
+    pub(crate) fn as_pio_command(&self) -> Option<AtaCommand> {
+        match self.as_command() {
+            AtaCommand::ReadDma => Some(AtaCommand::ReadSectors),
+            AtaCommand::ReadDmaExt => Some(AtaCommand::ReadSectorsExt),
+            _ => None,
+        }
+    }
+
+    /// Returns the NCQ equivalent of this request's command, if there is one
+    pub(crate) fn as_queued_command(&self) -> Option<AtaCommand> {
+        match self.command {
+            DmaCommand::Read => Some(AtaCommand::ReadFpdmaQueued),
+            DmaCommand::PacketRead => None,
+        }
+    }
+
+    /// Returns how many blocks the command transferring block `offset` onwards may cover,
+    /// limited by the PRDT and by the command's count register
+    pub(crate) fn command_count(&self, offset: usize) -> usize {
+        command_blocks(
+            self.count,
+            offset,
+            self.block_size,
+            self.as_command().max_sectors(),
+        )
+    }
+
+    /// Returns the segments covering every block from block `offset` to the end
+    pub fn at_offset(&self, offset: usize) -> Vec<DmaSegment> {
+        self.segments(offset, self.count.saturating_sub(offset))
+    }
+
+    /// Returns the segments covering exactly `count` blocks starting at block `offset`.
+    ///
+    /// The range doesn't have to line up with the buffers: the first and last segment are
+    /// cut down to the part of their buffer inside the range.
+    pub fn segments(&self, offset: usize, count: usize) -> Vec<DmaSegment> {
+        let start = offset * self.block_size;
+        let end = start + count * self.block_size;
+
+        let sizes = self.buffer.iter().map(DmaBuffer::data_size);
+
+        slices(sizes, start, end)
+            .into_iter()
+            .map(|slice| DmaSegment {
+                start: self.buffer[slice.buffer].start + slice.offset as u64,
+                size: slice.size,
+            })
+            .collect()
     }
 }
 
-#[allow(unused)]
-#[derive(Debug, PartialEq, Copy, Clone)]
-#[repr(u8)]
-pub(crate) enum AtaCommand {
-    WriteDma = 0xCA,
-    WriteDmaQueued = 0xCC,
-    WriteMultiple = 0xC5,
-    WriteSectors = 0x30,
+/// The 256 words returned by IDENTIFY DEVICE or IDENTIFY PACKET DEVICE
+#[derive(Debug, Clone)]
+pub struct IdentifyData([u16; 256]);
+
+impl IdentifyData {
+    fn new(raw: &[u8; 512]) -> Self {
+        let mut words = [0u16; 256];
+
+        for (word, bytes) in words.iter_mut().zip(raw.array_chunks::<2>()) {
+            *word = u16::from_le_bytes(*bytes);
+        }
+
+        Self(words)
+    }
+
+    /// Returns the raw word at `index`
+    pub fn word(&self, index: usize) -> u16 {
+        self.0[index]
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.0[83].get_bit(10)
+    }
+
+    pub fn supports_ncq(&self) -> bool {
+        self.0[76].get_bit(8)
+    }
 
-    ReadDma = 0xC8,
-    ReadDmaQueued = 0xC7,
-    ReadMultiple = 0xC4,
-    ReadSectors = 0x20,
+    /// Whether the device implements the SMART feature set
+    pub fn supports_smart(&self) -> bool {
+        self.0[82].get_bit(0)
+    }
+
+    /// Whether the device implements the Security feature set
+    pub fn supports_security(&self) -> bool {
+        self.0[82].get_bit(1) && self.0[128].get_bit(0)
+    }
+
+    /// Whether a user password is set, which SECURITY ERASE UNIT requires
+    pub fn security_enabled(&self) -> bool {
+        self.0[128].get_bit(1)
+    }
+
+    /// Whether security commands are refused until the next power cycle, which BIOSes
+    /// commonly do at boot
+    pub fn security_frozen(&self) -> bool {
+        self.0[128].get_bit(3)
+    }
+
+    pub fn supports_enhanced_erase(&self) -> bool {
+        self.0[128].get_bit(5)
+    }
 
-    WriteDmaExt = 0x35,
-    WriteDmaQueuedExt = 0x36,
-    WriteMultipleExt = 0x39,
-    WriteSectorsExt = 0x34,
+    pub fn supports_crypto_scramble(&self) -> bool {
+        self.0[59].get_bit(12) && self.0[59].get_bit(13)
+    }
 
-    ReadDmaExt = 0x25,
-    ReadDmaQueuedExt = 0x26,
-    ReadMultipleExt = 0x29,
-    ReadSectorsExt = 0x24,
+    /// Whether DATA SET MANAGEMENT supports the TRIM bit
+    pub fn supports_trim(&self) -> bool {
+        self.0[169].get_bit(0)
+    }
 
-    Packet = 0xA0,
-    DeviceReset = 0x08,
+    /// Maximum number of 512-byte blocks of LBA range entries per DATA SET MANAGEMENT command
+    pub fn max_dsm_blocks(&self) -> usize {
+        core::cmp::max(self.0[105] as usize, 1)
+    }
 
-    Service = 0xA2,
-    Nop = 0,
-    NopNopAutopoll = 1,
+    /// Maximum number of outstanding NCQ commands the device accepts
+    pub fn queue_depth(&self) -> usize {
+        self.0[75].get_bits(0..5) as usize + 1
+    }
 
-    GetMediaStatus = 0xDA,
+    /// Whether word 106 holds valid sector size information
+    fn sector_size_valid(&self) -> bool {
+        self.0[106].get_bits(14..16) == 0b01
+    }
 
-    FlushCache = 0xE7,
-    FlushCacheExt = 0xEA,
+    /// Size in bytes of a logical sector, the unit LBAs and sector counts refer to
+    pub fn logical_sector_size(&self) -> usize {
+        if self.sector_size_valid() && self.0[106].get_bit(12) {
+            // Words 117-118 hold the size in words
+            ((self.0[118] as usize) << 16 | self.0[117] as usize) * 2
+        } else {
+            512
+        }
+    }
 
-    DataSetManagement = 0x06,
+    /// Size in bytes of a physical sector, which may hold several logical sectors
+    pub fn physical_sector_size(&self) -> usize {
+        if self.sector_size_valid() && self.0[106].get_bit(13) {
+            self.logical_sector_size() << self.0[106].get_bits(0..4)
+        } else {
+            self.logical_sector_size()
+        }
+    }
 
-    MediaEject = 0xED,
+    /// Number of user-addressable sectors on the device
+    pub fn sectors(&self) -> usize {
+        if self.supports_lba48() {
+            self.0[100..104]
+                .iter()
+                .rev()
+                .fold(0, |acc, word| (acc << 16) | *word as usize)
+        } else {
+            (self.0[61] as usize) << 16 | self.0[60] as usize
+        }
+    }
 
-    IdentifyPacketDevice = 0xA1,
-    IdentifyDevice = 0xEC,
+    /// Model number string, with the byte-swapping of ATA strings undone
+    pub fn model(&self) -> String {
+        self.0[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(char::from)
+            .collect::<String>()
+            .trim()
+            .to_owned()
+    }
+}
 
-    SetFeatures = 0xEF,
-    SetFeaturesEnableReleaseInt = 0x5D,
-    SetFeaturesEnableServiceInt = 0x5E,
-    SetFeaturesDisableReleaseInt = 0xDD,
-    SetFeaturesDisableServiceInt = 0xDE,
+/// One entry of the attribute table returned by SMART READ DATA
+#[derive(Debug, Clone, Copy)]
+pub struct SmartAttribute {
+    pub id: u8,
+    /// Normalized current value, compared against the vendor's threshold
+    pub value: u8,
+    /// Lowest normalized value ever recorded
+    pub worst: u8,
+    /// Vendor-specific 48-bit raw value
+    pub raw: u64,
 }
 
-impl AtaCommand {
-    #[allow(dead_code)] // will use this function later
-    pub(crate) fn is_lba48(&self) -> bool {
-        matches!(self, AtaCommand::ReadDmaExt | AtaCommand::WriteDmaExt)
+impl SmartAttribute {
+    /// Parses the attribute table of a 512-byte SMART data structure, skipping unused entries
+    fn parse_table(data: &[u8; 512]) -> Vec<Self> {
+        data[2..2 + SMART_ATTRIBUTE_COUNT * 12]
+            .array_chunks::<12>()
+            .filter(|entry| entry[0] != 0)
+            .map(|entry| {
+                let mut raw = [0u8; 8];
+                raw[..6].copy_from_slice(&entry[5..11]);
+
+                Self {
+                    id: entry[0],
+                    value: entry[3],
+                    worst: entry[4],
+                    raw: u64::from_le_bytes(raw),
+                }
+            })
+            .collect()
     }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SmartError {
+    /// The device doesn't implement SMART, according to IDENTIFY word 82
+    Unsupported,
+    /// The command failed, see [`eio_debug`]
+    Failed,
+}
+
+/// Proof that the caller really means to destroy everything on a disk, required by
+/// [`AhciPort::security_erase`] and [`AhciPort::sanitize_crypto_scramble`]
+pub struct EraseConfirmation(());
 
-    #[allow(dead_code)] // will use this function later
-    pub(crate) fn is_write(&self) -> bool {
-        matches!(self, AtaCommand::WriteDmaExt | AtaCommand::WriteDma)
+impl EraseConfirmation {
+    /// # Safety
+    ///
+    /// Passing the token to an erase method irrecoverably destroys all data on that disk.
+    pub unsafe fn new() -> Self {
+        Self(())
     }
 }
 
+/// Progress of a SANITIZE operation, as reported by SANITIZE STATUS EXT
+#[derive(Debug, Clone, Copy)]
+pub struct SanitizeStatus {
+    pub in_progress: bool,
+    /// Whether the last sanitize operation completed without error
+    pub completed: bool,
+    /// Fraction of the operation done, out of 0xFFFF
+    pub progress: u16,
+}
+
 #[repr(C)]
 pub(crate) struct HbaMemory {
     host_capability: VolatileCell<HbaCapabilities>,
@@ -466,6 +1067,8 @@ pub(crate) struct HbaMemory {
     vendor: [u8; 0x100 - 0xa0],
 }
 
+const _: () = assert!(core::mem::size_of::<HbaMemory>() == HBA_PORTS_OFFSET);
+
 #[repr(C)]
 struct FisRegH2D {
     fis_type: VolatileCell<FisType>,
@@ -510,6 +1113,34 @@ struct FisRegD2H {
     pub _rsvd1: [VolatileCell<u8>; 6],
 }
 
+impl FisRegD2H {
+    fn lba(&self) -> u64 {
+        [
+            &self.lba5, &self.lba4, &self.lba3, &self.lba2, &self.lba1, &self.lba0,
+        ]
+        .iter()
+        .fold(0, |lba, byte| lba << 8 | byte.get() as u64)
+    }
+}
+
+/// Layout of the 256-byte received FIS area PxFB points at, where the HBA stores the last
+/// FIS of each kind the device sent
+#[repr(C)]
+#[allow(dead_code)] //future-proof
+struct HbaReceivedFis {
+    dma_setup: [u8; 0x1C],
+    _reserved0: [u8; 0x04],
+    pio_setup: [u8; 0x14],
+    _reserved1: [u8; 0x0C],
+    d2h: FisRegD2H,
+    _reserved2: [u8; 0x04],
+    set_device_bits: [u8; 0x08],
+    unknown: [u8; 0x40],
+    _reserved3: [u8; 0x60],
+}
+
+const _: () = assert!(core::mem::size_of::<HbaReceivedFis>() == 0x100);
+
 impl FisRegH2D {
     fn set_lba(&mut self, lba: usize) {
         debug_assert!(lba < 1 << 48, "LBA is limited to 48 bits");
@@ -536,7 +1167,7 @@ struct HbaCmdTbl {
     acmd: [u8; 16],
     _reserved: [u8; 48],
 
-    prdt_entry: [HbaPrdtEntry; 1],
+    prdt_entry: [HbaPrdtEntry; AHCI_PRDT_ENTRIES],
 }
 
 impl HbaCmdTbl {
@@ -545,7 +1176,13 @@ impl HbaCmdTbl {
     }
 
     fn prdt_entry_mut(&mut self, i: usize) -> &mut HbaPrdtEntry {
-        unsafe { &mut *self.prdt_entry.as_mut_ptr().add(i) }
+        assert!(
+            i < AHCI_PRDT_ENTRIES,
+            "PRDT entry {} is past the end of the command table",
+            i
+        );
+
+        &mut self.prdt_entry[i]
     }
 }
 
@@ -657,6 +1294,9 @@ pub(crate) struct HbaPort {
     vendor: [u32; 4],
 }
 
+// Port registers follow the generic host control block, which puts port 31's at 0x1080
+const _: () = assert!(core::mem::size_of::<HbaPort>() == HBA_PORT_SIZE);
+
 #[repr(C)]
 struct HbaCmdHeader {
     flags: VolatileCell<HbaCmdHeaderFlags>,
@@ -680,48 +1320,40 @@ impl HbaPort {
 
     /// This function is responsible for allocating space for command lists,
     /// tables, etc.. for a given this instance of HBA port.
-    fn start(&mut self) {
+    ///
+    /// Returns `None` if there's no DMA memory left for them.
+    fn start(&mut self, command_slots: usize) -> Option<()> {
         self.stop_cmd(); // Stop the command engine before starting the port
 
-        /*
-         * size = sizeof(CTB) * 32 == 4KiB * 2 (so we need to allocate
-         * two 4KiB size frames).
-         */
-        let frame_addr = pmm_alloc(BuddyOrdering::Size8KiB);
-        let page_addr = get_phys_offset() + frame_addr.as_u64();
-
-        for size in (0..0x2000u64).step_by(0x1000) {
-            map_page!(
-                VirtAddr::new(page_addr + size).as_u64(),
-                (frame_addr + size).as_u64(),
-                Size4KiB,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::WRITE_THROUGH
-                    | PageTableFlags::NO_CACHE
-            );
-        }
+        // size = sizeof(CTB) * 32, the PRDT length is set per command
+        let table_size = core::mem::size_of::<HbaCmdTbl>();
+        let frame_addr = dma_alloc_contiguous((table_size * 32).ceil_div(0x1000))?;
 
-        for i in 0..32 {
+        // Don't rely on whatever the firmware left in PxCLB and PxFB, that memory may be
+        // reclaimed. Both come zeroed and page-aligned, more than the 1KiB and 256 bytes
+        // of alignment the HBA needs.
+        let clb = dma_alloc(BuddyOrdering::Size4KiB)?;
+        let Some(fb) = dma_alloc(BuddyOrdering::Size4KiB) else {
+            DMA_POOL.release(BuddyOrdering::Size4KiB, clb);
+            return None;
+        };
+
+        self.clb.set(clb);
+        self.fb.set(fb);
+
+        assert_dma_reachable(clb, core::mem::size_of::<HbaCmdHeader>() * 32);
+        assert_dma_reachable(fb, core::mem::size_of::<HbaReceivedFis>());
+        assert_dma_reachable(frame_addr, table_size * 32);
+
+        // Headers past CAP.NCS don't exist as far as the HBA is concerned
+        for i in 0..command_slots {
             let command_header = self.cmd_header_at(i);
 
-            // 8 prdt entries per command table
-            // 256 bytes per command table, 64 + 16 + 48 + 16 * 8
-            command_header.prdtl.set(8);
+            command_header.prdtl.set(0);
             command_header.prdbc.set(0);
-            command_header.ctb.set(PhysAddr::new(
-                (frame_addr.as_u64() as usize + 256 * i) as u64,
-            ));
+            command_header.ctb.set(frame_addr + (table_size * i) as u64);
         }
 
-        // Read and write back command list base
-        let clb = self.clb.get();
-        self.clb.set(clb);
-
-        // Read and write back FIS Base
-        let fb = self.fb.get();
-        self.fb.set(fb);
-
         // Read and write back interrupt status
         let is = self.is.get();
         self.is.set(is);
@@ -738,6 +1370,8 @@ impl HbaPort {
 
         // Start the command engine
         self.start_cmd();
+
+        Some(())
     }
 
     fn start_cmd(&mut self) {
@@ -760,103 +1394,463 @@ impl HbaPort {
         }
     }
 
-    fn probe(&mut self, port: usize) -> bool {
-        let status = self.ssts.get();
-
-        let ipm = status.interface_power_management();
-        let dd = status.device_detection();
+    /// Classifies the attached device using the signature register
+    fn kind(&self) -> HbaPortKind {
+        HbaPortKind::from_signature(self.sig.get())
+    }
 
-        // Check if the port is active and is present. If thats the case
-        // we can start the AHCI port.
-        if let (HbaPortDd::PresentAndE, HbaPortIpm::Active) = (dd, ipm) {
-            if PCI_DRIVER_COUNT.load(Ordering::SeqCst) == 1 {
-                debug!("AHCI: enabling port {}", port);
-            }
+    /// Returns the D2H register FIS the device sent last, as captured in the received FIS area
+    fn d2h_fis(&self) -> &FisRegD2H {
+        &self.received_fis().d2h
+    }
 
-            self.start();
-            true
-        } else {
-            // Else we can't enable the port.
-            false
-        }
+    /// Returns the received FIS area programmed into PxFB
+    fn received_fis(&self) -> &HbaReceivedFis {
+        let fb = VirtAddr::new(get_phys_offset() + self.fb.get().as_u64());
+        unsafe { &*fb.as_ptr::<HbaReceivedFis>() }
     }
 
-    fn run_command(
-        &mut self,
-        command: AtaCommand,
-        sector: usize,
-        count: usize,
-        slot: usize,
-        buffer: &[DmaBuffer],
-    ) {
+    /// Directs the command in `slot` to port `pmp` of an attached port multiplier
+    fn select_port_multiplier_port(&mut self, slot: usize, pmp: u8) {
         let header = self.cmd_header_at(slot);
         let mut flags = header.flags.get();
 
-        if command == AtaCommand::WriteDmaExt || command == AtaCommand::WriteDma {
-            flags.insert(HbaCmdHeaderFlags::W); // If its a write command add the write flag.
+        flags.set_port_multiplier_port(pmp);
+        header.flags.set(flags);
+    }
+
+    /// Hands the command list and received FIS area back to a port that lost its registers in
+    /// a suspend, then brings the link up again like the first probe did and restarts the
+    /// command engine. `kind` is what was attached before the suspend.
+    ///
+    /// Returns `false` if the device didn't come back.
+    fn resume(&mut self, clb: PhysAddr, fb: PhysAddr, kind: HbaPortKind) -> bool {
+        self.stop_cmd();
+
+        self.clb.set(clb);
+        self.fb.set(fb);
+
+        // Both registers are write-1-to-clear
+        self.serr.set(u32::MAX);
+        self.is.set(HbaPortIS::all());
+
+        self.ie.set(HbaPortIE::all());
+
+        let sctl = self.sctl.get();
+        self.sctl.set(sctl | 7 << 8);
+
+        // ATAPI and PMA may only be changed while the command engine is stopped
+        let mut cmd = self.cmd.get();
+        cmd.insert(HbaPortCmd::POD | HbaPortCmd::SUD);
+        cmd.set(HbaPortCmd::ATAPI, kind == HbaPortKind::SataPacketInterface);
+        cmd.set(HbaPortCmd::PMA, kind == HbaPortKind::PortMultiplier);
+        self.cmd.set(cmd);
+
+        // The device lost power along with the HBA
+        let present = self.reset_link();
+
+        // The reset leaves the link errors it went through behind
+        self.serr.set(u32::MAX);
+        self.is.set(HbaPortIS::all());
+
+        self.start_cmd();
+
+        present
+    }
+
+    /// Restarts the command engine and clears the error registers without resetting the link,
+    /// so devices behind a port multiplier don't get knocked off
+    fn restart(&mut self) {
+        self.stop_cmd();
+
+        // Both registers are write-1-to-clear
+        self.serr.set(u32::MAX);
+        self.is.set(HbaPortIS::all());
+
+        self.start_cmd();
+    }
+
+    /// Brings the port back into a usable state after an error: stops the command engine,
+    /// performs a COMRESET and clears the error registers before restarting it.
+    ///
+    /// Returns `false` if the device didn't come back after the reset.
+    fn recover(&mut self) -> bool {
+        self.stop_cmd();
+
+        let present = self.reset_link();
+
+        // Both registers are write-1-to-clear
+        self.serr.set(u32::MAX);
+        self.is.set(HbaPortIS::all());
+
+        self.start_cmd();
+
+        if !present {
+            warn!("AHCI: device didn't come back after COMRESET");
+        }
+
+        present
+    }
+
+    /// Performs a COMRESET and waits for the device to establish communication again, returning
+    /// whether it did. The command engine has to be stopped.
+    fn reset_link(&mut self) -> bool {
+        // COMRESET: hold DET at 1 for at least 1ms, then release it
+        let sctl = self.sctl.get();
+        self.sctl.set((sctl & !0xF) | 1);
+
+        Deadline::after_millis(AHCI_COMRESET_HOLD_MS).wait();
+
+        self.sctl.set(sctl & !0xF);
+
+        Deadline::after_millis(AHCI_COMRESET_TIMEOUT_MS)
+            .poll(|| matches!(self.ssts.get().device_detection(), HbaPortDd::PresentAndE))
+    }
+
+    /// Spins up the device on an HBA with staggered spin-up and waits for its link to come
+    /// up, returning whether it did.
+    fn spin_up(&mut self) -> bool {
+        let cmd = self.cmd.get();
+        self.cmd.set(cmd | HbaPortCmd::SUD);
+
+        let deadline = Deadline::after_millis(AHCI_SPIN_UP_TIMEOUT_MS);
+
+        loop {
+            if let HbaPortDd::PresentAndE = self.ssts.get().device_detection() {
+                return true;
+            }
+
+            if deadline.expired() {
+                return false;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Lets the HBA move the idle link into the partial and/or slumber state on its own
+    fn enable_link_power_management(&mut self, partial: bool, slumber: bool) {
+        // The IPM field of PxSCTL disables partial (bit 8) and slumber (bit 9)
+        let mut sctl = self.sctl.get();
+        sctl.set_bit(8, !partial);
+        sctl.set_bit(9, !slumber);
+        self.sctl.set(sctl);
+
+        let mut cmd = self.cmd.get();
+        cmd.insert(HbaPortCmd::ALPE);
+        cmd.set(HbaPortCmd::ASP, slumber);
+        self.cmd.set(cmd);
+
+        // Every transition changes PhyRdy, which would look like hot-plug
+        let ie = self.ie.get();
+        self.ie.set(ie.difference(HbaPortIE::PRCE));
+    }
+
+    /// Keeps the link active, which is the state [`HbaPort::start`] leaves it in
+    fn disable_link_power_management(&mut self) {
+        let cmd = self.cmd.get();
+        self.cmd
+            .set(cmd.difference(HbaPortCmd::ALPE | HbaPortCmd::ASP));
+
+        let sctl = self.sctl.get();
+        self.sctl.set(sctl | 7 << 8);
+
+        self.clear_phy_ready_change();
+
+        let ie = self.ie.get();
+        self.ie.set(ie | HbaPortIE::PRCE);
+    }
+
+    /// Acknowledges a PhyRdy change, which keeps PxIS.PRCS set until cleared
+    pub(crate) fn clear_phy_ready_change(&mut self) {
+        self.serr.set(SERR_DIAG_N);
+    }
+
+    fn probe(&mut self, port: usize, command_slots: usize) -> Option<HbaPortKind> {
+        let status = self.ssts.get();
+
+        let ipm = status.interface_power_management();
+        let dd = status.device_detection();
+
+        // Check if the port is active and is present. If thats the case
+        // we can start the AHCI port.
+        if let (HbaPortDd::PresentAndE, HbaPortIpm::Active) = (dd, ipm) {
+            debug!("AHCI: enabling port {}", port);
+
+            let kind = self.kind();
+            debug!("AHCI: port {} has device kind {:?}", port, kind);
+
+            if self.start(command_slots).is_none() {
+                warn!("AHCI: no DMA memory left for port {}", port);
+                return None;
+            }
+
+            match kind {
+                HbaPortKind::SataPacketInterface => {
+                    // Let the HBA know it's talking to an ATAPI device
+                    let cmd = self.cmd.get();
+                    self.cmd.set(cmd | HbaPortCmd::ATAPI);
+                }
+                HbaPortKind::PortMultiplier => {
+                    // PMA may only be changed while the command engine is stopped
+                    self.stop_cmd();
+
+                    let cmd = self.cmd.get();
+                    self.cmd.set(cmd | HbaPortCmd::PMA);
+
+                    self.start_cmd();
+                }
+                _ => {}
+            }
+
+            Some(kind)
         } else {
-            flags.remove(HbaCmdHeaderFlags::W); // If its a read command remove the write flag.
+            // Else we can't enable the port.
+            None
         }
+    }
 
-        flags.insert(HbaCmdHeaderFlags::P | HbaCmdHeaderFlags::C);
+    /// Programs the command header in `slot` along with the PRDT of its command table,
+    /// returning the command table so the caller can fill in the command FIS.
+    fn prepare_command(
+        &mut self,
+        slot: usize,
+        extra_flags: HbaCmdHeaderFlags,
+        length: usize,
+        buffer: &[DmaSegment],
+    ) -> &mut HbaCmdTbl {
+        assert!(
+            length <= AHCI_PRDT_ENTRIES && length <= buffer.len(),
+            "AHCI: {} PRDT entries requested",
+            length
+        );
+
+        let header = self.cmd_header_at(slot);
+        let mut flags = header.flags.get();
+
+        flags.remove(HbaCmdHeaderFlags::W | HbaCmdHeaderFlags::A);
+        flags.insert(extra_flags | HbaCmdHeaderFlags::P | HbaCmdHeaderFlags::C);
         flags.set_command_fis_size(core::mem::size_of::<FisRegH2D>() / 4);
 
         header.flags.set(flags); // Update command header flags.
-
-        let length = ((count - 1) >> 4) + 1;
         header.prdtl.set(length as _); // Update the number of PRD entries.
 
         let command_table_addr = VirtAddr::new(get_phys_offset() + header.ctb.get().as_u64());
         let command_table = unsafe { &mut *(command_table_addr).as_mut_ptr::<HbaCmdTbl>() };
 
-        for (pri, dma) in buffer.iter().enumerate().take(length) {
+        // The FIS has to be addressed to the same port multiplier port as the header
+        command_table
+            .cfis_as_h2d_mut()
+            .flags
+            .set(flags.port_multiplier_port());
+
+        for (pri, segment) in buffer.iter().enumerate().take(length) {
+            assert_dma_reachable(segment.start, segment.size);
+
             let prdt = command_table.prdt_entry_mut(pri);
 
-            prdt.dba.set(buffer[pri].start);
-            prdt.set_data_byte_count(buffer[pri].data_size - 1);
+            prdt.dba.set(segment.start);
+            prdt.set_data_byte_count(segment.size - 1);
             prdt.set_interrupt_on_completion(pri == length - 1);
         }
 
+        command_table
+    }
+
+    fn run_command(
+        &mut self,
+        command: AtaCommand,
+        sector: usize,
+        count: usize,
+        slot: usize,
+        buffer: &[DmaSegment],
+    ) {
+        self.run_command_with_features(command, sector, count, slot, buffer, 0);
+    }
+
+    /// Same as [`HbaPort::run_command`], but also programs the features register
+    fn run_command_with_features(
+        &mut self,
+        command: AtaCommand,
+        sector: usize,
+        count: usize,
+        slot: usize,
+        buffer: &[DmaSegment],
+        features: u16,
+    ) {
+        // If its a write command add the write flag.
+        let flags = if command.is_write() {
+            HbaCmdHeaderFlags::W
+        } else {
+            HbaCmdHeaderFlags::empty()
+        };
+
+        let command_table = self.prepare_command(slot, flags, buffer.len(), buffer);
+
+        let fis = command_table.cfis_as_h2d_mut();
+
+        fis.control.set(0x00);
+        fis.icc.set(0x00);
+        fis.featurel.set(features as u8);
+        fis.featureh.set((features >> 8) as u8);
+        fis._reserved.fill(0x00);
+
+        fis.fis_type.set(FisType::RegH2D);
+        fis.command.set(command);
+        fis.count.set(command.encode_count(count));
+        fis.set_lba(sector);
+
+        fis.device.set(command.device(sector));
+        fis.set_command(true);
+
+        self.issue_command(slot);
+    }
+
+    /// Queues a READ/WRITE FPDMA QUEUED command using `slot` as the NCQ tag.
+    ///
+    /// Unlike [`HbaPort::run_command`] this doesn't wait for completion: the device
+    /// clears the tag's bit in PxSACT and raises an SDB FIS once it's done.
+    fn run_queued_command(
+        &mut self,
+        command: AtaCommand,
+        sector: usize,
+        count: usize,
+        slot: usize,
+        buffer: &[DmaSegment],
+    ) {
+        debug_assert!(matches!(
+            command,
+            AtaCommand::ReadFpdmaQueued | AtaCommand::WriteFpdmaQueued
+        ));
+
+        let flags = if command.is_write() {
+            HbaCmdHeaderFlags::W
+        } else {
+            HbaCmdHeaderFlags::empty()
+        };
+
+        let command_table = self.prepare_command(slot, flags, buffer.len(), buffer);
+
         let fis = command_table.cfis_as_h2d_mut();
 
         fis.control.set(0x00);
         fis.icc.set(0x00);
-        fis.featurel.set(0x00);
-        fis.featureh.set(0x00);
         fis._reserved.fill(0x00);
 
         fis.fis_type.set(FisType::RegH2D);
         fis.device.set(1 << 6);
         fis.command.set(command);
-        fis.count.set(count as _);
+
+        // FPDMA commands carry the sector count in the features registers...
+        fis.featurel.set(count as u8);
+        fis.featureh.set((count >> 8) as u8);
+
+        // ...and the tag in bits 7:3 of the count register
+        fis.count.set((slot << 3) as u16);
 
         fis.set_lba(sector);
         fis.set_command(true);
 
-        // Issue the command!
+        // PxSACT must be set before the command is issued
+        self.sact.set(1 << slot);
         self.ci.set(1 << slot);
+    }
+
+    /// Issues READ PORT MULTIPLIER or WRITE PORT MULTIPLIER, accessing `register` of fan-out
+    /// port `port` (or a GSCR register if `port` is [`PM_CONTROL_PORT`]).
+    ///
+    /// The command has to be sent to the multiplier's control port. Reads return the
+    /// register value in the D2H FIS, see [`HbaPort::d2h_fis`].
+    fn run_pm_command(
+        &mut self,
+        command: AtaCommand,
+        port: u8,
+        register: u8,
+        value: u32,
+        slot: usize,
+    ) {
+        debug_assert!(matches!(
+            command,
+            AtaCommand::ReadPortMultiplier | AtaCommand::WritePortMultiplier
+        ));
+
+        let command_table = self.prepare_command(slot, HbaCmdHeaderFlags::empty(), 0, &[]);
+        let fis = command_table.cfis_as_h2d_mut();
+
+        fis.control.set(0x00);
+        fis.icc.set(0x00);
+        fis._reserved.fill(0x00);
+
+        fis.fis_type.set(FisType::RegH2D);
+        fis.command.set(command);
+        fis.device.set(port & 0xF);
+        fis.featurel.set(register);
+        fis.featureh.set(0x00);
+
+        // Values to write are spread over the count and LBA registers
+        fis.count.set(value as u8 as u16);
+        fis.set_lba((value >> 8) as usize);
+        fis.set_command(true);
+
+        self.issue_command(slot);
+    }
+
+    /// Sends a SCSI command packet to an ATAPI device, transferring `byte_count` bytes
+    /// into `buffer` using DMA.
+    fn run_packet_command(
+        &mut self,
+        packet: &[u8; 12],
+        byte_count: usize,
+        slot: usize,
+        buffer: &[DmaSegment],
+    ) {
+        debug_assert!(buffer.iter().map(DmaSegment::size).sum::<usize>() >= byte_count);
+
+        let command_table = self.prepare_command(slot, HbaCmdHeaderFlags::A, buffer.len(), buffer);
+
+        // The SCSI command itself lives in the ATAPI command area of the table
+        command_table.acmd.fill(0x00);
+        command_table.acmd[..12].copy_from_slice(packet);
+
+        let fis = command_table.cfis_as_h2d_mut();
+
+        fis.control.set(0x00);
+        fis.icc.set(0x00);
+        fis._reserved.fill(0x00);
+
+        fis.fis_type.set(FisType::RegH2D);
+        fis.command.set(AtaCommand::Packet);
+        fis.device.set(0);
+        fis.count.set(0);
 
-        let mut spin = 100;
+        // Bit 0 of the features register selects DMA for the data phase
+        fis.featurel.set(0x01);
+        fis.featureh.set(0x00);
 
-        // Make sure the port is not busy.
+        // Byte count limit goes into the LBA mid/high registers
+        fis.set_lba((byte_count.min(0xFFFF) << 8) & 0xFF_FF00);
+        fis.set_command(true);
+
+        self.issue_command(slot);
+    }
+
+    /// Issues the command in `slot`. Completion is picked up later by
+    /// [`AhciPortProtected::complete_commands`].
+    fn issue_command(&mut self, slot: usize) {
+        let deadline = Deadline::after_millis(AHCI_BUSY_TIMEOUT_MS);
+
+        // Make sure the port is not busy before handing it a new command.
         // Also, thanks Clippy for helping me find an upstream Aero bug!
-        while (self.tfd.get() == 0x80 || self.tfd.get() == 0x08) && spin > 0 {
+        while tfd_busy(self.tfd.get()) && !deadline.expired() {
             core::hint::spin_loop();
-            spin -= 1;
         }
 
-        if spin == 0 {
+        if tfd_busy(self.tfd.get()) {
             warn!("AHCI: port hung");
-            return;
         }
 
-        // Wait for the command to complete.
-        while self.ci.get() & (1 << slot) == 1 {
-            if self.is.get().contains(HbaPortIS::TFES) {
-                warn!("AHCI: disk error (serr={:#x})", self.serr.get());
-                break;
-            }
-        }
+        // Issue the command!
+        self.ci.set(1 << slot);
     }
 }
 
@@ -866,17 +1860,93 @@ impl HbaMemory {
     }
 }
 
-#[allow(dead_code)] // need this for later
+/// I/O counters of a single device, see [`AhciPort::stats`]
+#[derive(Debug, Default, Clone)]
+pub struct AhciPortStats {
+    /// Read commands issued for DMA requests
+    pub reads: u64,
+    /// Write commands issued for DMA requests
+    pub writes: u64,
+    /// Sectors transferred by those commands
+    pub sectors: u64,
+    /// Commands that completed with an error
+    pub errors: u64,
+    /// Commands and requests that were resubmitted after an error
+    pub retries: u64,
+    /// Total times waiters had to relax before their command completed
+    pub completion_spins: u64,
+    /// Number of waits `completion_spins` was collected over
+    pub completion_waits: u64,
+}
+
+impl AhciPortStats {
+    /// Average number of times a waiter had to relax before its command completed
+    pub fn average_completion_spins(&self) -> u64 {
+        self.completion_spins
+            .checked_div(self.completion_waits)
+            .unwrap_or(0)
+    }
+}
+
+/// The last error reported by a port
+#[derive(Debug, Clone)]
+pub struct PortError {
+    pub error: InterruptError,
+    /// PxSERR at the time of the error
+    pub serr: u32,
+    /// What the device reported, for task file errors
+    pub task_file: Option<TaskFileError>,
+    pub message: String,
+}
+
+/// The registers of the D2H FIS a device answered a failed command with
+#[derive(Debug, Clone, Copy)]
+pub struct TaskFileError {
+    pub status: u8,
+    /// ATA error register, e.g. bit 4 (IDNF) for an LBA out of range
+    pub error: u8,
+    /// LBA of the first sector that failed
+    pub lba: u64,
+}
+
 #[derive(Debug)]
 struct AhciCommand {
     request: Arc<DmaRequest>,
+    completion: Arc<Completion>,
 }
 
 #[derive(Debug)]
 pub(crate) struct AhciPortProtected {
     address: VirtAddr,
-    cmds: [Option<AhciCommand>; 32],
-    free_cmds: usize,
+    kind: HbaPortKind,
+    identify: Option<IdentifyData>,
+    /// Logical sector size in bytes
+    block_size: usize,
+    /// Physical sector size in bytes, a multiple of the logical one
+    physical_block_size: usize,
+    /// Number of logical blocks, from IDENTIFY or READ CAPACITY
+    capacity: Option<usize>,
+    /// Commands in flight in the slots this device may use
+    cmds: CommandSlots<AhciCommand>,
+    /// Number of NCQ tags usable on this port, or 0 if NCQ is disabled
+    ncq_depth: usize,
+    /// Port multiplier port the device sits behind, if any
+    pmp: Option<u8>,
+    /// Devices behind this port if it has a port multiplier attached
+    downstream: Vec<Arc<AhciPort>>,
+    stats: AhciPortStats,
+    /// Link power management capabilities of the HBA (SALP, PSC and SSC)
+    link_power: HbaCapabilities,
+    power_policy: PowerPolicy,
+    /// Last error reported by the port. Errors of devices behind a port multiplier are
+    /// recorded on the multiplier, since they share its registers.
+    last_error: Option<PortError>,
+    /// Set by [`AhciDriver::shutdown`], after which new commands are rejected
+    stopped: bool,
+    /// Read with PIO commands instead of DMA, for controllers whose DMA misbehaves
+    pio: bool,
+    /// The device stayed busy after an error, waiting for [`process_resets`] to reset the link
+    needs_reset: bool,
 }
 
 impl AhciPortProtected {
@@ -884,96 +1954,1049 @@ impl AhciPortProtected {
         unsafe { &mut *(self.address.as_mut_ptr::<HbaPort>()) }
     }
 
-    fn run_request(&mut self, request: Arc<DmaRequest>, mut offset: usize) -> usize {
+    /// Finds a free slot for an NCQ command if `queued` is set, or a non-queued one otherwise,
+    /// and addresses it to this device's port multiplier port
+    ///
+    /// Non-queued commands may not be issued while the device still holds NCQ tags, so they
+    /// wait until PxSACT is clear as well.
+    ///
+    /// FIS-based switching isn't enabled, so with command-based switching the HBA may only
+    /// talk to one port multiplier port at a time. Devices behind a multiplier don't get a
+    /// slot until the commands of every other one have finished. The multiplier's lock has to
+    /// be held from here until the command is issued, see [`AhciPort::host_port`].
+    fn claim_slot(&mut self, queued: bool) -> Option<usize> {
+        let hba = self.hba_port();
+        let (ci, sact) = (hba.ci.get(), hba.sact.get());
+
+        if !queued && sact != 0 {
+            return None;
+        }
+
+        if self.pmp.is_some() && self.cmds.others_busy(ci, sact) {
+            return None;
+        }
+
+        let slot = self.cmds.find_free(queued.then_some(self.ncq_depth))?;
+        let pmp = self.pmp.unwrap_or(0);
+
+        self.hba_port().select_port_multiplier_port(slot, pmp);
+
+        Some(slot)
+    }
+
+    /// Records the command issued in `slot` so its completion can be picked up later
+    fn track(&mut self, slot: usize, request: Arc<DmaRequest>, queued: bool) -> Arc<Completion> {
+        let completion = Arc::new(Completion::new());
+
+        let command = AhciCommand {
+            request,
+            completion: completion.clone(),
+        };
+
+        self.cmds.track(slot, command, queued);
+
+        completion
+    }
+
+    /// Fails every command still in flight, e.g. because the device was removed.
+    fn fail_all(&mut self) {
+        self.cmds.take_all(|command| command.completion.fail());
+    }
+
+    /// Retires every command the HBA has finished with and signals its waiter.
+    ///
+    /// Non-queued commands are done once their PxCI bit clears, NCQ commands once the
+    /// device clears their tag from PxSACT.
+    ///
+    /// The interrupt handler calls this while other CPUs issue commands through
+    /// [`AhciPortProtected::run_request`]. Both run under the port's [`IrqRwLock`], so a command
+    /// is always tracked before its slot can be retired, and a slot is only claimed again once
+    /// it was. The lock keeps interrupts disabled, so the handler never waits for a holder on
+    /// its own CPU, only for those on other ones. Ports have locks of their own and don't wait
+    /// for each other.
+    pub(crate) fn complete_commands(&mut self) {
+        let (ci, sact, is) = {
+            let hba = self.hba_port();
+            (hba.ci.get(), hba.sact.get(), hba.is.get())
+        };
+
+        // The HBA stops processing the list on these errors, so nothing still in flight
+        // is going to complete on its own
+        let failed =
+            is.intersects(HbaPortIS::TFES | HbaPortIS::HBFS | HbaPortIS::HBDS | HbaPortIS::IFS);
+
+        if failed {
+            let hba = self.hba_port();
+            let serr = hba.serr.get();
+
+            // On task file errors the device sent a D2H FIS describing what went wrong
+            let task_file = is.contains(HbaPortIS::TFES).then(|| {
+                let fis = hba.d2h_fis();
+
+                TaskFileError {
+                    status: fis.status.get(),
+                    error: fis.err.get(),
+                    lba: fis.lba(),
+                }
+            });
+
+            refactor_hba_int_err!(self, is, serr, task_file);
+        }
+
+        self.retire(ci, sact, failed);
+
+        // Devices behind a port multiplier share the host port's registers
+        for port in self.downstream.iter() {
+            port.inner.write().retire(ci, sact, failed);
+        }
+
+        if failed {
+            let hba = self.hba_port();
+            hba.restart();
+
+            // A device still busy after the restart needs a COMRESET, which takes too long for
+            // the interrupt handler. It would also reset every device behind a multiplier.
+            if tfd_busy(hba.tfd.get()) && self.downstream.is_empty() {
+                self.needs_reset = true;
+            }
+        }
+    }
+
+    /// Completes the commands in this device's slots according to the given PxCI and PxSACT
+    /// values, failing those still busy if the HBA reported an error.
+    fn retire(&mut self, ci: u32, sact: u32, failed: bool) {
+        let stats = &mut self.stats;
+
+        self.cmds.retire(ci, sact, failed, |command, busy| {
+            // Dropping the command releases our reference to the request's buffers
+            if busy {
+                stats.errors += 1;
+                command.completion.fail();
+            } else {
+                command.completion.complete();
+            }
+        });
+    }
+
+    /// Issues commands for `request` from block `offset` onwards until it's covered or the
+    /// slots run out, returning the offset reached. `pio` forces PIO commands for reads.
+    fn run_request(
+        &mut self,
+        request: Arc<DmaRequest>,
+        mut offset: usize,
+        pio: bool,
+        completions: &mut Vec<Arc<Completion>>,
+    ) -> usize {
+        let pio_command = request.as_pio_command().filter(|_| pio || self.pio);
+
+        let kind = self.kind;
+
+        // Decided up front, since queued and non-queued commands get their slots differently
+        let ncq = self.ncq_depth > 0
+            && pio_command.is_none()
+            && !matches!(kind, HbaPortKind::SataPacketInterface)
+            && request.as_queued_command().is_some();
+
         let mut remaining = request.count - offset;
 
         while remaining > 0 {
-            let slot = {
-                let command =
-                    self.cmds
-                        .iter()
-                        .enumerate()
-                        .find_map(|(i, e)| if e.is_none() { Some(i) } else { None });
+            let (slot, queued) = {
+                let command = self.claim_slot(ncq);
 
                 if let Some(i) = command {
+                    let count = request.command_count(offset);
+
+                    if request.as_command().is_write() {
+                        self.stats.writes += 1;
+                    } else {
+                        self.stats.reads += 1;
+                    }
+
+                    self.stats.sectors += count as u64;
+
                     let hba = self.hba_port();
-                    let count = core::cmp::min(remaining, 128);
 
-                    hba.run_command(
-                        request.as_command(),
-                        request.sector + offset,
-                        count,
-                        i,
-                        request.at_offset(offset),
-                    );
+                    if let HbaPortKind::SataPacketInterface = kind {
+                        let packet = ScsiCommand::Read12
+                            .packet((request.sector + offset) as u32, count as u32);
+
+                        hba.run_packet_command(
+                            &packet,
+                            count * request.block_size(),
+                            i,
+                            &request.segments(offset, count),
+                        );
+                    } else if let (true, Some(command)) = (ncq, request.as_queued_command()) {
+                        hba.run_queued_command(
+                            command,
+                            request.sector + offset,
+                            count,
+                            i,
+                            &request.segments(offset, count),
+                        );
+                    } else {
+                        hba.run_command(
+                            pio_command.unwrap_or_else(|| request.as_command()),
+                            request.sector + offset,
+                            count,
+                            i,
+                            &request.segments(offset, count),
+                        );
+                    }
 
                     remaining -= count;
                     offset += count;
 
-                    i
+                    (i, ncq)
                 } else {
                     return offset;
                 }
             };
 
-            self.cmds[slot] = Some(AhciCommand {
-                request: request.clone(),
-            });
+            completions.push(self.track(slot, request.clone(), queued));
+        }
+
+        offset
+    }
+}
+
+/// A request started by [`AhciPort::submit`]. Checking on it only looks at the completions
+/// signalled by the interrupt handler, so it's cheap to poll.
+pub struct IoHandle<'a> {
+    port: &'a AhciPort,
+    request: Arc<DmaRequest>,
+    completions: Vec<Arc<Completion>>,
+    /// Number of times the request was resubmitted after failing
+    attempt: usize,
+    /// Whether the request fell back to PIO after failing with DMA
+    pio: bool,
+}
+
+impl IoHandle<'_> {
+    pub fn request(&self) -> &Arc<DmaRequest> {
+        &self.request
+    }
+
+    /// Whether every command of the current attempt has finished, successfully or not
+    pub fn is_complete(&self) -> bool {
+        self.completions
+            .iter()
+            .all(|completion| completion.is_complete())
+    }
+
+    fn is_failed(&self) -> bool {
+        self.completions
+            .iter()
+            .any(|completion| completion.is_failed())
+    }
+
+    /// Returns the number of bytes transferred, or `None` while the request is pending or if
+    /// it failed
+    pub fn result(&self) -> Option<usize> {
+        (self.is_complete() && !self.is_failed())
+            .then(|| self.request.count * self.request.block_size())
+    }
+
+    /// Blocks until the request completes, resubmitting it up to [`AHCI_MAX_RETRIES`] times
+    /// if it fails, and returns its [`result`](IoHandle::result)
+    pub fn wait(&mut self) -> Option<usize> {
+        loop {
+            for completion in self.completions.iter() {
+                self.port.wait(completion);
+            }
+
+            if !self.is_failed() {
+                return self.result();
+            }
+
+            warn!(
+                "AHCI: request for sector {} failed (attempt {})",
+                self.request.sector(),
+                self.attempt + 1
+            );
+
+            if self.attempt == AHCI_MAX_RETRIES || self.port.is_stopped() {
+                return None;
+            }
+
+            self.attempt += 1;
+            self.port.inner.write().stats.retries += 1;
+
+            // Broken DMA setups often still manage PIO transfers
+            if self.attempt == AHCI_PIO_FALLBACK_ATTEMPTS
+                && !self.pio
+                && self.request.as_pio_command().is_some()
+            {
+                warn!("AHCI: retrying with PIO");
+                self.pio = true;
+            }
+
+            self.completions = self.port.submit_commands(&self.request, self.pio);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AhciPort {
+    /// Taken by the interrupt handler too, so it keeps interrupts disabled while held
+    pub(crate) inner: IrqRwLock<AhciPortProtected>,
+    /// The port multiplier this device is attached to, which owns the shared host port
+    parent: Option<Weak<AhciPort>>,
+}
+
+impl AhciPort {
+    #[inline]
+    fn new(address: VirtAddr, kind: HbaPortKind, command_slots: usize) -> Self {
+        Self::new_inner(address, kind, None, 0..command_slots, None)
+    }
+
+    /// Creates the device attached to port `pmp` of the port multiplier `parent`, using
+    /// `slots` of the shared command list
+    fn new_downstream(
+        parent: &Arc<AhciPort>,
+        pmp: u8,
+        kind: HbaPortKind,
+        slots: Range<usize>,
+    ) -> Self {
+        let address = parent.inner.read().address;
+
+        Self::new_inner(
+            address,
+            kind,
+            Some(pmp),
+            slots,
+            Some(Arc::downgrade(parent)),
+        )
+    }
+
+    fn new_inner(
+        address: VirtAddr,
+        kind: HbaPortKind,
+        pmp: Option<u8>,
+        slots: Range<usize>,
+        parent: Option<Weak<AhciPort>>,
+    ) -> Self {
+        // Until IDENTIFY tells us otherwise
+        let block_size = match kind {
+            HbaPortKind::SataPacketInterface => ATAPI_SECTOR_SIZE,
+            _ => 512,
+        };
+
+        Self {
+            inner: IrqRwLock::new(AhciPortProtected {
+                address,
+                kind,
+                identify: None,
+                block_size,
+                physical_block_size: block_size,
+                capacity: None,
+                cmds: CommandSlots::new(slots),
+                ncq_depth: 0,
+                pmp,
+                downstream: Vec::new(),
+                stats: AhciPortStats::default(),
+                link_power: HbaCapabilities::empty(),
+                power_policy: PowerPolicy::Performance,
+                last_error: None,
+                stopped: false,
+                pio: false,
+                needs_reset: false,
+            }),
+            parent,
+        }
+    }
+
+    /// Returns the port multiplier port this device is attached to, if any
+    pub fn port_multiplier_port(&self) -> Option<u8> {
+        self.inner.read().pmp
+    }
+
+    /// Returns the devices attached behind this port's port multiplier
+    pub(crate) fn downstream(&self) -> Vec<Arc<AhciPort>> {
+        self.inner.read().downstream.clone()
+    }
+
+    /// Retires finished commands. Devices behind a port multiplier go through the
+    /// multiplier, since errors affect the whole host port.
+    fn poll(&self) {
+        match self.parent.as_ref().and_then(Weak::upgrade) {
+            Some(parent) => parent.poll(),
+            None => self.inner.write().complete_commands(),
+        }
+    }
+
+    /// Returns the port multiplier this device is attached to, if any. Its lock serialises
+    /// issuing commands between the devices behind it, and is taken before theirs like in the
+    /// interrupt handler.
+    fn host_port(&self) -> Option<Arc<AhciPort>> {
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+
+    /// Returns a snapshot of the I/O counters of this device
+    pub fn stats(&self) -> AhciPortStats {
+        self.inner.read().stats.clone()
+    }
+
+    /// Returns the last error this port reported, if any
+    pub fn last_error(&self) -> Option<PortError> {
+        self.inner.read().last_error.clone()
+    }
+
+    /// Whether reads use PIO commands instead of DMA
+    pub fn uses_pio(&self) -> bool {
+        self.inner.read().pio
+    }
+
+    /// Switches reads between DMA and PIO commands. PIO is slower but works around
+    /// controllers and emulators with broken DMA during bring-up.
+    pub fn set_pio(&self, pio: bool) {
+        self.inner.write().pio = pio;
+    }
+
+    /// Whether the port was shut down and rejects new commands
+    pub fn is_stopped(&self) -> bool {
+        self.inner.read().stopped
+    }
+
+    /// Waits up to [`AHCI_SHUTDOWN_TIMEOUT_MS`] for every command in flight to complete.
+    /// Returns whether the device went idle in time.
+    fn wait_idle(&self) -> bool {
+        let deadline = Deadline::after_millis(AHCI_SHUTDOWN_TIMEOUT_MS);
+
+        loop {
+            self.poll();
+
+            if self.inner.read().cmds.is_idle() {
+                return true;
+            }
+
+            if deadline.expired() {
+                return false;
+            }
+
+            Completion::relax();
+        }
+    }
+
+    /// Writes the device's volatile write cache out to the medium
+    pub fn flush_cache(&self) -> Option<()> {
+        let command = {
+            let inner = self.inner.read();
+
+            match inner.identify.as_ref() {
+                // SYNCHRONIZE CACHE isn't implemented for ATAPI, and optical drives we only
+                // read from anyway
+                _ if inner.kind != HbaPortKind::SataDrive => return Some(()),
+                Some(identify) if identify.supports_lba48() => AtaCommand::FlushCacheExt,
+                _ => AtaCommand::FlushCache,
+            }
+        };
+
+        self.run_non_data_command(command, 0, 0)
+    }
+
+    /// Waits for outstanding commands, flushes the write cache and marks the device stopped
+    fn quiesce(&self) {
+        if !self.wait_idle() {
+            warn!("AHCI: commands still in flight at shutdown, failing them");
+        }
+
+        if self.flush_cache().is_none() {
+            warn!("AHCI: failed to flush the write cache");
+        }
+
+        let mut inner = self.inner.write();
+        inner.stopped = true;
+        inner.fail_all();
+    }
+
+    pub fn power_policy(&self) -> PowerPolicy {
+        self.inner.read().power_policy
+    }
+
+    /// Switches the link between staying active and aggressive link power management.
+    /// Returns `None` if the HBA can't manage the link's power, or if the device sits
+    /// behind a port multiplier and so shares its link.
+    pub fn set_power_policy(&self, policy: PowerPolicy) -> Option<()> {
+        let mut inner = self.inner.write();
+
+        if inner.pmp.is_some() {
+            return None;
+        }
+
+        let caps = inner.link_power;
+
+        match policy {
+            PowerPolicy::PowerSave => {
+                if !caps.contains(HbaCapabilities::SALP)
+                    || !caps.intersects(HbaCapabilities::PSC | HbaCapabilities::SSC)
+                {
+                    warn!("AHCI: HBA doesn't support aggressive link power management");
+                    return None;
+                }
+
+                inner.hba_port().enable_link_power_management(
+                    caps.contains(HbaCapabilities::PSC),
+                    caps.contains(HbaCapabilities::SSC),
+                );
+            }
+            PowerPolicy::Performance => inner.hba_port().disable_link_power_management(),
+        }
+
+        inner.power_policy = policy;
+
+        Some(())
+    }
+
+    /// Returns what kind of device is attached to this port
+    pub fn kind(&self) -> HbaPortKind {
+        self.inner.read().kind
+    }
+
+    /// Returns the size in bytes of a single logical block on this device
+    pub fn block_size(&self) -> usize {
+        self.inner.read().block_size
+    }
+
+    /// Returns the number of logical blocks on the device, from IDENTIFY or READ CAPACITY
+    pub fn sectors(&self) -> Option<usize> {
+        self.inner.read().capacity
+    }
+
+    /// Checks that `count` sectors starting at `sector` lie on the device, recording an
+    /// [`InterruptError::OutOfRange`] error if they don't. Devices of unknown size pass.
+    fn check_range(&self, sector: usize, count: usize) -> Option<()> {
+        let mut inner = self.inner.write();
+
+        let Some(capacity) = inner.capacity else {
+            return Some(());
+        };
+
+        if sector.checked_add(count).is_some_and(|end| end <= capacity) {
+            return Some(());
+        }
+
+        let error = InterruptError::OutOfRange;
+        let message = alloc::format!(
+            "{} ({} sectors at {}, device has {})",
+            error,
+            count,
+            sector,
+            capacity
+        );
+        warn!("AHCI: {}", message);
+
+        *EIO_STATUS.write() = Some(error);
+        *EIO_DEBUG.write() = Some(message.clone());
+
+        inner.last_error = Some(PortError {
+            error,
+            serr: 0,
+            task_file: None,
+            message,
+        });
+
+        None
+    }
+
+    /// Returns the size in bytes of a physical sector, the device's smallest unit of writing
+    pub fn physical_block_size(&self) -> usize {
+        self.inner.read().physical_block_size
+    }
+
+    /// Issues IDENTIFY DEVICE (or IDENTIFY PACKET DEVICE for ATAPI) and caches the result
+    pub(crate) fn identify(&self) -> Option<IdentifyData> {
+        let command = match self.kind() {
+            HbaPortKind::SataPacketInterface => AtaCommand::IdentifyPacketDevice,
+            _ => AtaCommand::IdentifyDevice,
+        };
+
+        // IDENTIFY data is always 512 bytes, whatever the sector size
+        let request = Arc::new(DmaRequest::new(0, 1, 512));
+
+        self.run_single(request.clone(), |hba, slot| {
+            hba.run_command(command, 0, 1, slot, &request.at_offset(0))
+        })?;
+
+        let mut raw = [0u8; 512];
+        request.copy_into(&mut raw);
+
+        let identify = IdentifyData::new(&raw);
+        let mut inner = self.inner.write();
+
+        // ATAPI devices report their block size through READ CAPACITY instead
+        (inner.block_size, inner.physical_block_size) = match inner.kind {
+            HbaPortKind::SataPacketInterface => (ATAPI_SECTOR_SIZE, ATAPI_SECTOR_SIZE),
+            _ => (
+                identify.logical_sector_size(),
+                identify.physical_sector_size(),
+            ),
+        };
+
+        // IDENTIFY PACKET DEVICE doesn't report the capacity
+        if inner.kind != HbaPortKind::SataPacketInterface {
+            inner.capacity = Some(identify.sectors());
+        }
+
+        inner.identify = Some(identify.clone());
+
+        Some(identify)
+    }
+
+    /// Tells the device that `count` sectors starting at `sector` no longer hold useful data,
+    /// so an SSD can reclaim them. Returns `None` if the device doesn't support TRIM.
+    pub(crate) fn discard(&self, sector: usize, count: usize) -> Option<usize> {
+        self.check_range(sector, count)?;
+
+        let max_blocks = match self.inner.read().identify.as_ref() {
+            Some(identify) if identify.supports_trim() => identify.max_dsm_blocks(),
+            _ => return None,
+        };
+
+        let entries = dsm_entries(sector, count);
+
+        for chunk in entries.chunks(max_blocks * DSM_ENTRIES_PER_BLOCK) {
+            let payload = dsm_payload(chunk);
+            let blocks = payload.len() / 512;
+
+            let request = Arc::new(DmaRequest::new(0, blocks, 512));
+            request.copy_from(&payload);
+
+            self.run_single(request.clone(), |hba, slot| {
+                hba.run_command_with_features(
+                    AtaCommand::DataSetManagement,
+                    0,
+                    blocks,
+                    slot,
+                    &request.at_offset(0),
+                    DSM_TRIM,
+                )
+            })?;
+        }
+
+        Some(count)
+    }
+
+    /// Returns an error if the device doesn't implement SMART, which includes ATAPI devices
+    fn check_smart(&self) -> Result<(), SmartError> {
+        let inner = self.inner.read();
+
+        match inner.identify.as_ref() {
+            Some(identify) if inner.kind == HbaPortKind::SataDrive && identify.supports_smart() => {
+                Ok(())
+            }
+            _ => Err(SmartError::Unsupported),
+        }
+    }
+
+    /// Issues SMART READ DATA and returns the device's attribute table
+    pub fn smart_attributes(&self) -> Result<Vec<SmartAttribute>, SmartError> {
+        self.check_smart()?;
+
+        // The SMART data structure is always 512 bytes, whatever the sector size
+        let request = Arc::new(DmaRequest::new(0, 1, 512));
+
+        self.run_single(request.clone(), |hba, slot| {
+            hba.run_command_with_features(
+                AtaCommand::Smart,
+                SMART_LBA,
+                1,
+                slot,
+                &request.at_offset(0),
+                SMART_READ_DATA,
+            )
+        })
+        .ok_or(SmartError::Failed)?;
+
+        let mut data = [0u8; 512];
+        request.copy_into(&mut data);
+
+        Ok(SmartAttribute::parse_table(&data))
+    }
+
+    /// Issues SMART RETURN STATUS, returning `false` if the device reports that one of its
+    /// attributes crossed its failure threshold
+    pub fn is_healthy(&self) -> Result<bool, SmartError> {
+        self.check_smart()?;
+
+        let request = Arc::new(DmaRequest::new(0, 0, 512));
+
+        self.run_single(request, |hba, slot| {
+            hba.run_command_with_features(
+                AtaCommand::Smart,
+                SMART_LBA,
+                0,
+                slot,
+                &[],
+                SMART_RETURN_STATUS,
+            )
+        })
+        .ok_or(SmartError::Failed)?;
+
+        // The verdict comes back in the LBA mid and high registers of the D2H FIS
+        let mut inner = self.inner.write();
+        let fis = inner.hba_port().d2h_fis();
+        let lba = (fis.lba2.get() as usize) << 16 | (fis.lba1.get() as usize) << 8;
+
+        Ok(lba != SMART_LBA_THRESHOLD_EXCEEDED)
+    }
+
+    /// Returns the cached IDENTIFY data if the device is a disk that can be erased through
+    /// the feature set `supported` checks for
+    fn check_erase(&self, supported: fn(&IdentifyData) -> bool) -> Option<IdentifyData> {
+        let inner = self.inner.read();
+
+        match inner.identify.as_ref() {
+            Some(identify) if inner.kind == HbaPortKind::SataDrive && supported(identify) => {
+                Some(identify.clone())
+            }
+            _ => {
+                warn!("AHCI: device doesn't support the requested erase");
+                None
+            }
+        }
+    }
+
+    /// Issues a security command carrying the 512-byte password payload. Like any PIO
+    /// data-out command, the HBA transfers the payload through the PRDT as it does for DMA.
+    fn run_security_command(
+        &self,
+        command: AtaCommand,
+        control: u16,
+        password: &[u8; 32],
+    ) -> Option<()> {
+        let mut payload = [0u8; 512];
+        payload[0..2].copy_from_slice(&control.to_le_bytes());
+        payload[2..34].copy_from_slice(password);
+
+        let request = Arc::new(DmaRequest::new(0, 1, 512));
+        request.copy_from(&payload);
+
+        self.run_single(request.clone(), |hba, slot| {
+            hba.run_command(command, 0, 1, slot, &request.at_offset(0))
+        })
+    }
+
+    /// Issues a command that transfers no data
+    fn run_non_data_command(
+        &self,
+        command: AtaCommand,
+        sector: usize,
+        features: u16,
+    ) -> Option<()> {
+        let request = Arc::new(DmaRequest::new(0, 0, 512));
+
+        self.run_single(request, |hba, slot| {
+            hba.run_command_with_features(command, sector, 0, slot, &[], features)
+        })
+    }
+
+    /// Erases the whole disk with SECURITY ERASE UNIT, setting `password` as the user
+    /// password first if none is set. Enhanced erase also overwrites reallocated sectors
+    /// and, on self-encrypting drives, replaces the encryption key.
+    ///
+    /// This can take hours, see IDENTIFY words 89 and 90 for the device's estimate. Cached
+    /// blocks of the disk are stale afterwards.
+    pub fn security_erase(
+        &self,
+        password: &[u8; 32],
+        enhanced: bool,
+        _confirmation: EraseConfirmation,
+    ) -> Option<()> {
+        let identify = self.check_erase(IdentifyData::supports_security)?;
+
+        if identify.security_frozen() {
+            warn!("AHCI: security is frozen, power cycle the drive without the BIOS touching it");
+            return None;
+        }
+
+        if enhanced && !identify.supports_enhanced_erase() {
+            warn!("AHCI: device doesn't support enhanced security erase");
+            return None;
+        }
+
+        if !identify.security_enabled() {
+            self.run_security_command(AtaCommand::SecuritySetPassword, 0, password)?;
+        }
+
+        // ERASE PREPARE has to come right before ERASE UNIT
+        self.run_non_data_command(AtaCommand::SecurityErasePrepare, 0, 0)?;
+
+        // Erase using the user password
+        let control = if enhanced { SECURITY_ENHANCED_ERASE } else { 0 };
+
+        self.run_security_command(AtaCommand::SecurityEraseUnit, control, password)?;
+
+        // The erase also disables security again
+        self.identify()?;
+
+        info!("AHCI: security erase completed");
+        Some(())
+    }
+
+    /// Starts a SANITIZE CRYPTO SCRAMBLE, which replaces the device's internal encryption
+    /// key so everything stored becomes unreadable. The device keeps working in the
+    /// background, see [`AhciPort::sanitize_status`].
+    pub fn sanitize_crypto_scramble(&self, _confirmation: EraseConfirmation) -> Option<()> {
+        self.check_erase(IdentifyData::supports_crypto_scramble)?;
+
+        self.run_non_data_command(
+            AtaCommand::SanitizeDevice,
+            SANITIZE_CRYPTO_SIGNATURE,
+            SANITIZE_CRYPTO_SCRAMBLE_EXT,
+        )
+    }
+
+    /// Issues SANITIZE STATUS EXT to check on a running sanitize operation
+    pub fn sanitize_status(&self) -> Option<SanitizeStatus> {
+        self.check_erase(IdentifyData::supports_crypto_scramble)?;
+        self.run_non_data_command(AtaCommand::SanitizeDevice, 0, SANITIZE_STATUS_EXT)?;
+
+        let mut inner = self.inner.write();
+        let fis = inner.hba_port().d2h_fis();
+        let count = fis.count_high.get();
+
+        Some(SanitizeStatus {
+            in_progress: count.get_bit(6),
+            completed: count.get_bit(7),
+            progress: u16::from_le_bytes([fis.lba0.get(), fis.lba1.get()]),
+        })
+    }
+
+    /// Issues a READ CAPACITY(10) packet to an ATAPI device, returning the
+    /// number of blocks and the size of each block in bytes
+    pub(crate) fn read_capacity(&self) -> Option<(usize, usize)> {
+        if self.kind() != HbaPortKind::SataPacketInterface {
+            return None;
+        }
+
+        let request = Arc::new(DmaRequest::new_packet(0, 1));
+        let packet = ScsiCommand::ReadCapacity10.packet(0, 0);
+
+        self.run_single(request.clone(), |hba, slot| {
+            hba.run_packet_command(&packet, 8, slot, &request.at_offset(0))
+        })?;
+
+        let mut data = [0u8; 8];
+        request.copy_into(&mut data);
+
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+        self.inner.write().capacity = Some(last_lba + 1);
+
+        Some((last_lba + 1, block_size))
+    }
+
+    /// Reads `register` of fan-out port `port` of the attached port multiplier
+    fn read_pm_register(&self, port: u8, register: u8) -> Option<u32> {
+        let request = Arc::new(DmaRequest::new(0, 0, 512));
+
+        self.run_single(request, |hba, slot| {
+            hba.run_pm_command(AtaCommand::ReadPortMultiplier, port, register, 0, slot)
+        })?;
+
+        let mut inner = self.inner.write();
+        let fis = inner.hba_port().d2h_fis();
+
+        Some(u32::from_le_bytes([
+            fis.count_low.get(),
+            fis.lba0.get(),
+            fis.lba1.get(),
+            fis.lba2.get(),
+        ]))
+    }
+
+    /// Writes `value` to `register` of fan-out port `port` of the attached port multiplier
+    fn write_pm_register(&self, port: u8, register: u8, value: u32) -> Option<()> {
+        let request = Arc::new(DmaRequest::new(0, 0, 512));
+
+        self.run_single(request, |hba, slot| {
+            hba.run_pm_command(AtaCommand::WritePortMultiplier, port, register, value, slot)
+        })
+    }
+
+    /// Blocks until `completion` is signalled.
+    fn wait(&self, completion: &Completion) {
+        let mut spins = 0;
+
+        while !completion.is_complete() {
+            // The interrupt handler normally retires commands, but poll here too so a
+            // missing IRQ route can't leave us waiting forever
+            self.poll();
+
+            if !completion.is_complete() {
+                Completion::relax();
+                spins += 1;
+            }
+        }
+
+        let stats = &mut self.inner.write().stats;
+        stats.completion_spins += spins;
+        stats.completion_waits += 1;
+    }
+
+    /// Issues a single command through `issue` on a free slot and waits for it to complete,
+    /// retrying it if the device reports an error.
+    fn run_single<F>(&self, request: Arc<DmaRequest>, issue: F) -> Option<()>
+    where
+        F: Fn(&mut HbaPort, usize),
+    {
+        for attempt in 0..=AHCI_MAX_RETRIES {
+            if self.is_stopped() {
+                return None;
+            }
+
+            if attempt > 0 {
+                self.inner.write().stats.retries += 1;
+            }
+
+            let completion = self.issue_single(&request, &issue);
+
+            self.wait(&completion);
+
+            if !completion.is_failed() {
+                return Some(());
+            }
 
-            self.free_cmds -= 1;
+            warn!("AHCI: command failed (attempt {})", attempt + 1);
         }
 
-        offset
+        None
     }
-}
-
-#[derive(Debug)]
-pub(crate) struct AhciPort {
-    pub(crate) inner: RwLock<AhciPortProtected>,
-}
 
-impl AhciPort {
-    #[inline]
-    fn new(address: VirtAddr) -> Self {
-        const EMPTY: Option<AhciCommand> = None;
+    /// Issues a single command through `issue`, waiting for a slot to be reclaimed if
+    /// they're all in use.
+    fn issue_single<F>(&self, request: &Arc<DmaRequest>, issue: &F) -> Arc<Completion>
+    where
+        F: Fn(&mut HbaPort, usize),
+    {
+        loop {
+            self.poll();
+
+            let host = self.host_port();
+            let host = host.as_ref().map(|host| host.inner.write());
+            let mut inner = self.inner.write();
+
+            if let Some(slot) = inner.claim_slot(false) {
+                issue(inner.hba_port(), slot);
+                return inner.track(slot, request.clone(), false);
+            }
 
-        Self {
-            inner: RwLock::new(AhciPortProtected {
-                address,
-                cmds: [EMPTY; 32],
-                free_cmds: 32,
-            }),
+            drop((inner, host));
+            Completion::relax();
         }
     }
 
-    fn run_request(&self, request: Arc<DmaRequest>) -> Option<usize> {
+    /// Issues every command making up `request`, waiting for slots to free up if we run
+    /// out, but not for the commands themselves.
+    fn submit_commands(&self, request: &Arc<DmaRequest>, pio: bool) -> Vec<Arc<Completion>> {
         let mut offset = 0x00;
+        let mut completions = Vec::new();
+
+        if self.is_stopped() {
+            let completion = Arc::new(Completion::new());
+            completion.fail();
+
+            return alloc::vec![completion];
+        }
 
-        // Run request and wait for it to complete.
         while offset < request.count {
-            offset = self.inner.write().run_request(request.clone(), offset);
+            self.poll();
+
+            let host = self.host_port();
+            let host = host.as_ref().map(|host| host.inner.write());
+            let mut inner = self.inner.write();
+            offset = inner.run_request(request.clone(), offset, pio, &mut completions);
+
+            if offset < request.count {
+                drop((inner, host));
+                Completion::relax();
+            }
+        }
+
+        completions
+    }
+
+    /// Starts `request` without waiting for it to complete. The returned handle is completed
+    /// from the interrupt handler and retries the request when waited on if it failed.
+    pub fn submit(&self, request: Arc<DmaRequest>) -> IoHandle<'_> {
+        IoHandle {
+            port: self,
+            completions: self.submit_commands(&request, false),
+            request,
+            attempt: 0,
+            pio: false,
         }
+    }
 
-        Some(request.count * 512)
+    fn run_request(&self, request: Arc<DmaRequest>) -> Option<usize> {
+        self.submit(request).wait()
     }
 
+    /// Reads `buffer.len()` bytes starting at `sector`, returning the number of bytes copied
+    /// into `buffer`. A partial last sector is read in full, but only the part that fits is
+    /// copied.
     pub(crate) fn read(&self, sector: usize, buffer: &mut [u8]) -> Option<usize> {
-        let count = (buffer.len() + 512 - 1) / 512;
-        let request = Arc::new(DmaRequest::new(sector, count));
+        let block_size = self.block_size();
+        let count = buffer.len().ceil_div(block_size);
+
+        self.check_range(sector, count)?;
+
+        let request = Arc::new(match self.kind() {
+            HbaPortKind::SataPacketInterface => DmaRequest::new_packet(sector, count),
+            _ => DmaRequest::new(sector, count, block_size),
+        });
+
+        self.run_request(request.clone())?; // Perform the DMA request.
+        request.copy_into(buffer); // Copy the result into the provided buffer.
+
+        Some(buffer.len())
+    }
 
-        let result = self.run_request(request.clone()); // Perform the DMA request.
+    /// Reads whole sectors starting at `sector` into `buffer`, whose length has to be a
+    /// multiple of the block size. Returns the number of sectors read.
+    pub(crate) fn read_sectors(&self, sector: usize, buffer: &mut [u8]) -> Option<usize> {
+        let block_size = self.block_size();
 
-        if result.is_some() {
-            request.copy_into(buffer); // Copy the result into the provided buffer.
+        if buffer.len() % block_size != 0 {
+            warn!(
+                "AHCI: {}-byte buffer isn't a whole number of {}-byte sectors",
+                buffer.len(),
+                block_size
+            );
+            return None;
         }
 
-        result
+        self.read(sector, buffer).map(|bytes| bytes / block_size)
+    }
+}
+
+impl Disk for AhciPort {
+    fn read(&self, sector: usize, buffer: &mut [u8]) -> Option<usize> {
+        AhciPort::read(self, sector, buffer)
+    }
+
+    fn block_size(&self) -> usize {
+        AhciPort::block_size(self)
+    }
+
+    fn sectors(&self) -> Option<usize> {
+        AhciPort::sectors(self)
     }
 }
 
 pub(crate) struct AhciProtected {
     pub(crate) ports: [Option<Arc<AhciPort>>; 32],
     hba: VirtAddr,
+    controller: usize,
+    /// Number of command slots per port implemented by the HBA (CAP.NCS)
+    command_slots: usize,
+    /// Set by [`AhciDriver::shutdown`], after which hotplugged devices are ignored
+    stopped: bool,
+    /// Command list, received FIS base and device kind of every attached port, kept by
+    /// [`AhciDriver::suspend`] for [`AhciDriver::resume`]
+    suspended: Vec<(usize, PhysAddr, PhysAddr, HbaPortKind)>,
 }
 
 impl Clone for AhciProtected {
@@ -981,6 +3004,10 @@ impl Clone for AhciProtected {
         Self {
             ports: self.ports.clone(),
             hba: self.hba,
+            controller: self.controller,
+            command_slots: self.command_slots,
+            stopped: self.stopped,
+            suspended: self.suspended.clone(),
         }
     }
 }
@@ -991,11 +3018,57 @@ impl AhciProtected {
         unsafe { &mut *(self.hba.as_u64() as *mut HbaMemory) }
     }
 
-    fn start_hba(&mut self) {
-        let mut hba = self.hba_mem();
+    /// Takes ownership of the HBA from the firmware, as described in section 10.6.3 of the
+    /// AHCI specification.
+    fn bios_handoff(&mut self) {
+        let hba = self.hba_mem();
+
+        if !hba
+            .host_capabilities_extended
+            .get()
+            .contains(HbaCapabilities2::BOH)
+        {
+            return;
+        }
+
+        let bohc = hba.bios_handoff_ctrl_sts.get();
+        hba.bios_handoff_ctrl_sts.set(bohc | HbaBohc::OOS);
+
+        // The firmware acknowledges by clearing BOS within 25ms
+        let deadline = Deadline::after_millis(25);
+
+        while hba.bios_handoff_ctrl_sts.get().contains(HbaBohc::BOS) && !deadline.expired() {
+            core::hint::spin_loop();
+        }
+
+        // It then gets another 25ms to set BB if it has outstanding commands to finish...
+        let deadline = Deadline::after_millis(25);
+
+        while !hba.bios_handoff_ctrl_sts.get().contains(HbaBohc::BB) && !deadline.expired() {
+            core::hint::spin_loop();
+        }
+
+        // ...which may take up to 2 seconds
+        let deadline = Deadline::after_millis(2000);
+
+        while hba.bios_handoff_ctrl_sts.get().contains(HbaBohc::BB) && !deadline.expired() {
+            core::hint::spin_loop();
+        }
+
+        let bohc = hba.bios_handoff_ctrl_sts.get();
+
+        if bohc.intersects(HbaBohc::BOS | HbaBohc::BB) {
+            warn!("AHCI: firmware didn't release the controller, continuing anyway");
+        } else {
+            debug!("AHCI: took ownership of the controller from the firmware");
+        }
+    }
 
+    fn start_hba(&mut self) {
         // Take back control from the firmware
-        hba.bios_handoff_ctrl_sts.set(HbaBohc::OOC | HbaBohc::OOS);
+        self.bios_handoff();
+
+        let mut hba = self.hba_mem();
 
         // Enable interrupts
         let current_flags = hba.global_host_control.get();
@@ -1010,144 +3083,670 @@ impl AhciProtected {
             major_version, minor_version
         );
 
+        let caps = hba.host_capability.get();
+
+        self.command_slots = caps.command_slots();
+        debug!("AHCI: {} command slots per port", self.command_slots);
+
+        if caps.contains(HbaCapabilities::S64A) {
+            debug!("AHCI: controller supports 64-bit addressing");
+        } else {
+            // Every buffer allocated from now on has to stay below 4GiB
+            info!("AHCI: controller is limited to 32-bit addressing");
+            DMA_LIMIT.fetch_min(1 << 32, Ordering::Relaxed);
+        }
+
         let pi = hba.ports_implemented.get();
 
         for i in 0..32 {
-            if pi.get_bit(i) {
-                let port = hba.port_mut(i);
+            if pi.get_bit(i) && self.attach_port(i).is_none() {
+                // Keep listening on empty ports so we notice a drive being plugged in
+                let port = self.hba_mem().port_mut(i);
+                port.serr.set(u32::MAX);
+                port.ie.set(HbaPortIE::PCE | HbaPortIE::PRCE);
+            }
+        }
+    }
+
+    /// Probes port `i` and, if a device is attached, sets it up and registers it.
+    fn attach_port(&mut self, i: usize) -> Option<Arc<AhciPort>> {
+        let hba = self.hba_mem();
+        let caps = hba.host_capability.get();
+        let port = hba.port_mut(i);
+
+        // With staggered spin-up devices stay spun down until we ask for them, one port
+        // at a time
+        if caps.contains(HbaCapabilities::SSS) && !port.spin_up() {
+            debug!("AHCI: no device came up on port {}", i);
+            return None;
+        }
+
+        let kind = port.probe(i, self.command_slots)?;
 
-                if port.probe(i) {
-                    // Get the address of the HBA port.
-                    let address = VirtAddr::new(port as *const _ as _);
+        // Get the address of the HBA port.
+        let address = VirtAddr::new(port as *const _ as _);
 
-                    debug!("AHCI: Port {:#?} address: {:#x}", i, address.as_u64());
+        debug!("AHCI: Port {:#?} address: {:#x}", i, address.as_u64());
 
-                    let port = Arc::new(AhciPort::new(address));
+        let port = Arc::new(AhciPort::new(address, kind, self.command_slots));
+        port.inner.write().link_power =
+            caps.intersection(HbaCapabilities::SALP | HbaCapabilities::PSC | HbaCapabilities::SSC);
+
+        match kind {
+            HbaPortKind::SataDrive => {
+                if let Some(identify) = port.identify() {
+                    debug!("AHCI: port {} model: {}", i, identify.model());
+                    debug!(
+                        "AHCI: port {} has {}-byte sectors ({} bytes physical)",
+                        i,
+                        identify.logical_sector_size(),
+                        identify.physical_sector_size()
+                    );
 
-                    // Add the port to the ports array.
-                    self.ports[i] = Some(port);
+                    if caps.contains(HbaCapabilities::SNCQ) && identify.supports_ncq() {
+                        let depth = identify.queue_depth();
+                        debug!("AHCI: enabling NCQ on port {} (depth {})", i, depth);
 
-                    // Workaround to get access to the HBA and still satify the
-                    // borrow checker.
-                    hba = self.hba_mem();
+                        port.inner.write().ncq_depth = depth;
+                    }
+                }
+            }
+            HbaPortKind::PortMultiplier if caps.contains(HbaCapabilities::SPM) => {
+                if self.attach_port_multiplier(i, &port).is_none() {
+                    warn!("AHCI: failed to enumerate port multiplier on port {}", i);
                 }
             }
+            HbaPortKind::PortMultiplier => {
+                warn!(
+                    "AHCI: port multiplier on port {} but the HBA doesn't support them",
+                    i
+                );
+            }
+            HbaPortKind::EnclosureManagementBridge => {
+                info!("AHCI: enclosure management bridge on port {}", i);
+            }
+            HbaPortKind::Unknown(sig) => {
+                warn!(
+                    "AHCI: unknown device with signature {:#x} on port {}",
+                    sig, i
+                );
+            }
+            HbaPortKind::SataPacketInterface => {}
+        }
+
+        if let Some((blocks, block_size)) = port.read_capacity() {
+            debug!(
+                "AHCI: ATAPI device on port {} has {} blocks of {} bytes",
+                i, blocks, block_size
+            );
+        }
+
+        // Add the port to the ports array.
+        self.ports[i] = Some(port.clone());
+
+        // The devices behind a port multiplier were registered on their own, and bridges
+        // don't hold any data
+        if kind.is_disk() {
+            register_disk(
+                DiskLocation::Ahci {
+                    controller: self.controller,
+                    port: i,
+                    pmp: None,
+                },
+                port.clone(),
+            );
+        }
+
+        Some(port)
+    }
+
+    /// Returns the command slots used for port `pmp` of a port multiplier, or `None` if the
+    /// HBA doesn't implement enough slots to give that port its own.
+    fn pm_slots(&self, pmp: u8) -> Option<Range<usize>> {
+        // 15 fan-out ports plus the control port
+        let per_port = core::cmp::max(self.command_slots / 16, 1);
+        let control = self.command_slots - per_port;
+
+        if pmp == PM_CONTROL_PORT {
+            return Some(control..self.command_slots);
+        }
+
+        let per_port = core::cmp::min(per_port, PM_SLOTS_PER_PORT);
+        let base = pmp as usize * per_port;
+
+        (base + per_port <= control).then_some(base..base + per_port)
+    }
+
+    /// Enumerates the fan-out ports of the port multiplier attached to port `i` and
+    /// registers every device found behind it.
+    fn attach_port_multiplier(&mut self, i: usize, pm: &Arc<AhciPort>) -> Option<()> {
+        {
+            // Commands for the multiplier itself go to its control port
+            let slots = self.pm_slots(PM_CONTROL_PORT)?;
+            let mut inner = pm.inner.write();
+
+            inner.pmp = Some(PM_CONTROL_PORT);
+            inner.cmds.set_range(slots);
+        }
+
+        let ports = pm
+            .read_pm_register(PM_CONTROL_PORT, PM_GSCR_INFO)?
+            .get_bits(0..4) as u8;
+
+        debug!("AHCI: port multiplier on port {} has {} ports", i, ports);
+
+        for pmp in 0..ports {
+            // COMRESET the fan-out port, just like PxSCTL.DET on a host port
+            pm.write_pm_register(pmp, PM_PSCR_SCONTROL, 1)?;
+
+            Deadline::after_millis(AHCI_COMRESET_HOLD_MS).wait();
+
+            pm.write_pm_register(pmp, PM_PSCR_SCONTROL, 0)?;
+
+            let mut status = Some(0);
+
+            // A failed read ends the wait early, it's propagated right after
+            let present = Deadline::after_millis(AHCI_COMRESET_TIMEOUT_MS).poll(|| {
+                status = pm.read_pm_register(pmp, PM_PSCR_SSTATUS);
+                status.is_none_or(|status| status.get_bits(0..4) == HbaPortDd::PresentAndE as u32)
+            });
+
+            status?;
+
+            if !present {
+                continue;
+            }
+
+            pm.write_pm_register(pmp, PM_PSCR_SERROR, u32::MAX)?;
+
+            let Some(slots) = self.pm_slots(pmp) else {
+                warn!("AHCI: not enough command slots for port {}.{}", i, pmp);
+                continue;
+            };
+
+            let device = Arc::new(AhciPort::new_downstream(
+                pm,
+                pmp,
+                HbaPortKind::SataDrive,
+                slots,
+            ));
+            pm.inner.write().downstream.push(device.clone());
+
+            // Fan-out ports don't report a signature without a soft reset, so try
+            // IDENTIFY DEVICE first and fall back to IDENTIFY PACKET DEVICE for ATAPI.
+            let identify = device.identify().or_else(|| {
+                device.inner.write().kind = HbaPortKind::SataPacketInterface;
+                device.identify()
+            });
+
+            if let Some(identify) = identify {
+                debug!("AHCI: port {}.{} model: {}", i, pmp, identify.model());
+            } else {
+                warn!("AHCI: couldn't identify the device on port {}.{}", i, pmp);
+
+                pm.inner.write().downstream.pop();
+                continue;
+            }
+
+            register_disk(
+                DiskLocation::Ahci {
+                    controller: self.controller,
+                    port: i,
+                    pmp: Some(pmp),
+                },
+                device,
+            );
+        }
+
+        Some(())
+    }
+
+    /// Tears down port `i` after its device went away, failing anything still in flight.
+    fn detach_port(&mut self, i: usize) {
+        if let Some(port) = self.ports[i].take() {
+            for device in port.downstream() {
+                device.inner.write().fail_all();
+
+                unregister_disk(DiskLocation::Ahci {
+                    controller: self.controller,
+                    port: i,
+                    pmp: device.port_multiplier_port(),
+                });
+            }
+
+            let mut inner = port.inner.write();
+
+            inner.hba_port().stop_cmd();
+            inner.fail_all();
+        }
+
+        unregister_disk(DiskLocation::Ahci {
+            controller: self.controller,
+            port: i,
+            pmp: None,
+        });
+    }
+
+    /// Re-probes port `i` after a port connect or PhyRdy change and attaches or detaches
+    /// its device accordingly.
+    fn handle_hotplug(&mut self, i: usize) {
+        if self.stopped {
+            return;
+        }
+
+        let port = self.hba_mem().port_mut(i);
+        let present = matches!(port.ssts.get().device_detection(), HbaPortDd::PresentAndE);
+
+        // PxIS.PCS stays set until PxSERR.DIAG.X is cleared
+        port.serr.set(u32::MAX);
+
+        match (present, self.ports[i].is_some()) {
+            (true, false) => {
+                info!("AHCI: device attached to port {}", i);
+                self.attach_port(i);
+            }
+            (false, true) => {
+                info!("AHCI: device removed from port {}", i);
+                self.detach_port(i);
+            }
+            _ => {}
         }
     }
 
     /// This function is responsible for enabling bus mastering and add AHCI
     /// IRQ handler.
-    fn enable_interrupts(&mut self, header: &mut pcics::Header) {
+    fn enable_interrupts(&mut self, bdf: Bdf, header: &mut pcics::Header) {
         header.command.io_space = false;
         header.command.memory_space = true;
         header.command.bus_master = true;
+
+        // The header is only a snapshot, the device has to see the change too
+        let mut command = read_command(bdf);
+        command.remove(PciCommand::IO_SPACE);
+        command.insert(PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER);
+        write_command(bdf, command);
     }
 
-    /// This function is responsible for initializing and starting the AHCI driver.
-    fn start_driver(&mut self, header: &mut pcics::Header) {
-        if let HeaderType::Normal(normal_header) = header.header_type.clone() {
-            let abar = normal_header.base_addresses.orig()[5] as u64;
+    /// This function is responsible for initializing and starting the AHCI driver. Returns
+    /// `None`, after logging why, if the controller's registers can't be used.
+    fn start_driver(&mut self, device: &mut PciDeviceInfo) -> Option<()> {
+        let bdf = device.bdf;
+
+        let HeaderType::Normal(_) = device.header.header_type else {
+            warn!("AHCI: {} doesn't have a normal header, skipping it", bdf);
+            return None;
+        };
+
+        // Some firmware hands the controller off in D3
+        set_power_state(bdf, PowerState::D0);
+
+        // The HBA's registers live in BAR5 (ABAR), which spans more than a page once
+        // enough ports are implemented
+        let Some(abar) = device.bars[5] else {
+            warn!("AHCI: {} doesn't implement the ABAR, skipping it", bdf);
+            return None;
+        };
+
+        debug!("ABAR: {:#x} ({:#x} bytes)", abar.address, abar.size);
+
+        let Some(hba) = map_bar(bdf, 5) else {
+            warn!("AHCI: the ABAR of {} is an I/O BAR, skipping it", bdf);
+            return None;
+        };
+
+        self.hba = hba;
+
+        // Every implemented port's registers have to be inside the mapping, which takes
+        // the whole 0x1100 bytes on a controller with 32 ports
+        if let Some(end) = ports_end(self.hba_mem().ports_implemented.get()) {
+            if end as u64 > abar.size {
+                warn!(
+                    "AHCI: ports of {} end at {:#x}, past the {:#x} byte ABAR, skipping it",
+                    bdf, end, abar.size
+                );
+
+                return None;
+            }
+        }
 
-            debug!("ABAR: {:#x}", &abar);
+        without_interrupts(|| {
+            // The HBA fetches command lists and FISes itself, so it has to be a bus master
+            // before any port is started
+            self.enable_interrupts(bdf, &mut device.header);
+            self.start_hba();
+        });
 
-            let abar_test_page = Page::<Size4KiB>::containing_address(VirtAddr::new(abar));
-            let abar_virt = abar_test_page.start_address().as_u64() + get_phys_offset();
+        Some(())
+    }
+}
 
-            map_page!(
-                abar,
-                abar_virt,
-                Size4KiB,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::NO_CACHE
-                    | PageTableFlags::WRITE_THROUGH
-            );
+/// Structure representing the ACHI driver.
+pub struct AhciDriver {
+    /// Location of the controller on the PCI bus
+    bdf: Bdf,
+    /// Taken by the interrupt handler too, so it keeps interrupts disabled while held
+    inner: IrqRwLock<AhciProtected>,
+    /// Ports with a connect or PhyRdy change the interrupt handler saw, waiting for
+    /// [`process_hotplug`]
+    hotplug: HotplugQueue,
+}
 
-            self.hba = VirtAddr::new(abar_virt);
+impl AhciDriver {
+    fn new(bdf: Bdf, controller: usize) -> Self {
+        const EMPTY: Option<Arc<AhciPort>> = None; // To satisfy the Copy trait bound when the AHCI creating data.
 
-            without_interrupts(|| {
-                self.start_hba();
-                self.enable_interrupts(header);
-            });
+        Self {
+            bdf,
+            inner: IrqRwLock::new(AhciProtected {
+                ports: [EMPTY; 32],    // Initialize the AHCI ports to an empty slice.
+                hba: VirtAddr::zero(), // Initialize the AHCI HBA address to zero.
+                controller,
+                command_slots: 32, // Updated from CAP.NCS once the HBA is mapped.
+                stopped: false,
+                suspended: Vec::new(),
+            }),
+            hotplug: HotplugQueue::new(),
+        }
+    }
+
+    pub(crate) fn read(&self) -> IrqRwLockReadGuard<AhciProtected> {
+        self.inner.read()
+    }
+
+    pub(crate) fn write(&self) -> IrqRwLockWriteGuard<AhciProtected> {
+        self.inner.write()
+    }
 
-            // Test code: can confirm this actually works
-            // info!("Powering off");
-            // unsafe { super::acpi_impl::system_shutdown() };
+    /// Notes that port `i` saw a connect or PhyRdy change. Called from the interrupt
+    /// handler, which can't probe the port itself.
+    pub(crate) fn queue_hotplug(&self, i: usize) {
+        self.hotplug.push(i);
+    }
 
-            // Test code
-            for port in self.ports.iter().filter(|p| p.is_some()) {
-                if let Some(port) = port {
-                    let buffer = &mut [0u8; 512];
-                    let sector = 0;
+    /// Returns the location of the controller on the PCI bus
+    pub(crate) fn bdf(&self) -> Bdf {
+        self.bdf
+    }
 
-                    if port.read(sector, buffer).is_some() {
-                        debug!("Read sector {:?}: {:?}", sector, buffer);
-                    } else {
-                        warn!("Couldn't read any data")
+    /// Quiesces the controller before a shutdown or reboot: waits for outstanding commands,
+    /// flushes every device's write cache, stops the command engines and disables HBA
+    /// interrupts. Requests made afterwards fail.
+    pub fn shutdown(&self) {
+        // Holding the lock keeps interrupts off, so don't hold it while waiting on
+        // the devices
+        let ports = {
+            let mut inner = self.write();
+
+            if inner.stopped {
+                return;
+            }
+
+            inner.stopped = true;
+            inner.ports.clone()
+        };
+
+        for port in ports.iter().flatten() {
+            for device in port.downstream() {
+                device.quiesce();
+            }
+
+            port.quiesce();
+            port.inner.write().hba_port().stop_cmd();
+        }
+
+        let inner = self.write();
+        let hba = inner.hba_mem();
+
+        let ghc = hba.global_host_control.get();
+        hba.global_host_control.set(ghc - HbaHostCont::IE);
+
+        info!("AHCI: controller {} stopped", inner.controller);
+    }
+
+    /// Quiesces the controller like [`AhciDriver::shutdown`] before a suspend, remembering
+    /// where each port's command list and received FIS area live
+    pub fn suspend(&self) {
+        let lists = {
+            let inner = self.read();
+            let hba = inner.hba_mem();
+
+            (0..32)
+                .filter_map(|i| {
+                    let kind = inner.ports[i].as_ref()?.kind();
+                    let port = hba.port_mut(i);
+
+                    Some((i, port.clb.get(), port.fb.get(), kind))
+                })
+                .collect()
+        };
+
+        self.shutdown();
+        self.write().suspended = lists;
+    }
+
+    /// Brings the controller back after a resume. The HBA lost its registers, but the command
+    /// lists and everything we know about the devices survived in RAM, so the ports get
+    /// their memory back instead of being probed again.
+    pub fn resume(&self) {
+        let ports = {
+            let mut inner = self.write();
+            let lists = core::mem::take(&mut inner.suspended);
+            let hba = inner.hba_mem();
+
+            let ghc = hba.global_host_control.get();
+            hba.global_host_control
+                .set(ghc | HbaHostCont::AE | HbaHostCont::IE);
+
+            let pi = hba.ports_implemented.get();
+
+            for i in (0..32).filter(|&i| pi.get_bit(i)) {
+                let port = hba.port_mut(i);
+
+                match lists.iter().find(|(attached, ..)| *attached == i) {
+                    Some(&(_, clb, fb, kind)) => {
+                        if !port.resume(clb, fb, kind) {
+                            warn!("AHCI: device on port {} didn't come back after resume", i);
+                        }
+                    }
+                    None => {
+                        // Keep listening on empty ports so we notice a drive being plugged in
+                        port.serr.set(u32::MAX);
+                        port.ie.set(HbaPortIE::PCE | HbaPortIE::PRCE);
                     }
-                } else {
-                    unreachable!()
                 }
             }
-        } else {
-            panic!("AHCI: Not a normal header")
+
+            inner.stopped = false;
+            inner.ports.clone()
+        };
+
+        for port in ports.iter().flatten() {
+            for device in core::iter::once(port.clone()).chain(port.downstream()) {
+                device.inner.write().stopped = false;
+            }
         }
+
+        info!("AHCI: controller {} resumed", self.read().controller);
     }
 }
 
-/// Structure representing the ACHI driver.
-pub struct AhciDriver {
-    inner: RwLock<AhciProtected>,
+/// Functions [`AhciHandle`] binds to. Intel controllers switched to RAID mode report the RAID
+/// class, but the firmware's RAID still sits on top of a plain AHCI HBA.
+const AHCI_MATCHES: &[DeviceMatch] = &[
+    DeviceMatch::ByClass(DeviceKind::SataController),
+    // ICH8R to ICH10R and their successors in RAID mode
+    DeviceMatch::ById(0x8086, 0x2822),
+    // Z68/Z77 RAID mode
+    DeviceMatch::ById(0x8086, 0x282A),
+];
+
+/// Whether a function of class `kind` with the given IDs is an AHCI controller
+pub fn is_ahci(kind: DeviceKind, vendor_id: u16, device_id: u16) -> bool {
+    any_matches(AHCI_MATCHES, kind, vendor_id, device_id)
 }
 
-impl AhciDriver {
-    pub(crate) fn read(&self) -> RwLockReadGuard<AhciProtected> {
-        self.inner.read()
+/// PCI handle that spawns a new [`AhciDriver`] for every SATA controller it's started on.
+pub struct AhciHandle;
+
+impl FOSSPciDeviceHandle for AhciHandle {
+    fn matches(&self) -> &[DeviceMatch] {
+        AHCI_MATCHES
     }
 
-    pub(crate) fn write(&self) -> RwLockWriteGuard<AhciProtected> {
-        self.inner.write()
+    fn start(&self, device: &mut PciDeviceInfo) {
+        let bdf = device.bdf;
+        let driver = {
+            // The interrupt handler looks controllers up while this lock could be held
+            let _irq = IrqGuard::new();
+            let mut drivers = DRIVERS.write();
+
+            // Each controller only gets started once
+            if drivers.iter().any(|driver| driver.bdf == bdf) {
+                return;
+            }
+
+            let driver = Arc::new(AhciDriver::new(bdf, drivers.len()));
+            drivers.push(driver.clone());
+            driver
+        };
+
+        // Only used if the controller has neither MSI nor MSI-X
+        register_intx_handler(bdf, |_| interrupts::ahci_service());
+
+        debug!("AHCI: Initializing controller at {}", bdf);
+
+        if driver.write().start_driver(device).is_none() {
+            // It was the last one added, so the other controllers keep their numbers
+            let _irq = IrqGuard::new();
+            DRIVERS.write().retain(|driver| driver.bdf != bdf);
+        }
+    }
+
+    fn msix_vectors(&self, _: Bdf, table_len: u16) -> Vec<(u16, IrqHandler, *mut ())> {
+        // The handler services every port of every controller, so it doesn't matter which
+        // entry a port's interrupts come in on
+        (0..table_len)
+            .map(|i| (i, interrupts::ahci as IrqHandler, core::ptr::null_mut()))
+            .collect()
+    }
+}
+
+/// Returns a reference-counting pointer to the driver of the given AHCI controller, if it was started.
+pub(crate) fn get_ahci(controller: usize) -> Option<Arc<AhciDriver>> {
+    DRIVERS.read().get(controller).cloned()
+}
+
+/// Logs the I/O counters of every device on every AHCI controller
+pub fn log_stats() {
+    for (controller, driver) in DRIVERS.read().iter().enumerate() {
+        let ports = driver.read().ports.clone();
+
+        for (i, port) in ports.iter().enumerate() {
+            let Some(port) = port else {
+                continue;
+            };
+
+            let devices = core::iter::once(port.clone()).chain(port.downstream());
+
+            for device in devices {
+                let stats = device.stats();
+
+                info!(
+                    "AHCI {}:{}{}: {} reads, {} writes, {} sectors, {} errors, {} retries, {} spins per wait",
+                    controller,
+                    i,
+                    device
+                        .port_multiplier_port()
+                        .map(|pmp| alloc::format!(".{}", pmp))
+                        .unwrap_or_default(),
+                    stats.reads,
+                    stats.writes,
+                    stats.sectors,
+                    stats.errors,
+                    stats.retries,
+                    stats.average_completion_spins()
+                );
+            }
+        }
     }
 }
 
-impl FOSSPciDeviceHandle for AhciDriver {
-    fn handles(&self, vendor_id: Vendor, device_id: DeviceKind) -> bool {
-        matches!((vendor_id, device_id), (_, DeviceKind::SataController))
+/// Attaches or detaches the devices on the ports the interrupt handler saw a connect or
+/// PhyRdy change on. Probing a port takes commands and polling, so it doesn't happen in the
+/// handler. Called from the main loop.
+pub fn process_hotplug() {
+    let drivers = DRIVERS.read().clone();
+
+    for driver in drivers.iter() {
+        let mut ports = driver.hotplug.take().peekable();
+
+        if ports.peek().is_none() {
+            continue;
+        }
+
+        let mut inner = driver.write();
+
+        for i in ports {
+            inner.handle_hotplug(i);
+        }
     }
+}
+
+/// Performs a COMRESET on the ports whose device stayed busy after an error, once the
+/// restart in the interrupt handler didn't bring it back. Called from the main loop.
+pub fn process_resets() {
+    let drivers = DRIVERS.read().clone();
+
+    for driver in drivers.iter() {
+        let ports = driver.read().ports.clone();
 
-    fn start(&self, header: &mut pcics::Header) {
-        // keeps restarting endlessly if I don't put this check in here
-        if PCI_DRIVER_COUNT.load(Ordering::Relaxed) == 1 {
-            debug!("AHCI: Initializing");
-            get_ahci().write().start_driver(header);
+        for port in ports.iter().flatten() {
+            let mut inner = port.inner.write();
+
+            if !core::mem::take(&mut inner.needs_reset) {
+                continue;
+            }
+
+            // The device may have finished on its own since
+            if tfd_busy(inner.hba_port().tfd.get()) {
+                inner.hba_port().recover();
+            }
         }
     }
 }
 
-pub(crate) fn get_hba<'a>() -> &'a mut HbaMemory {
-    get_ahci().read().hba_mem()
+/// Shuts down every AHCI controller, see [`AhciDriver::shutdown`]
+pub fn shutdown() {
+    let drivers = DRIVERS.read().clone();
+
+    for driver in drivers.iter() {
+        driver.shutdown();
+    }
 }
 
-/// Returns a reference-counting pointer to the AHCI driver.
-pub(crate) fn get_ahci<'a>() -> &'a Arc<AhciDriver> {
-    DRIVER
-        .get()
-        .expect("Attempted to get the AHCI driver before it was initialized")
+/// Quiesces every AHCI controller before a suspend, see [`AhciDriver::suspend`]
+pub fn suspend() {
+    let drivers = DRIVERS.read().clone();
+
+    for driver in drivers.iter() {
+        driver.suspend();
+    }
 }
 
-pub(crate) fn ahci_init() {
-    // Initialize the AHCI driver instance.
-    DRIVER.call_once(|| {
-        const EMPTY: Option<Arc<AhciPort>> = None; // To satisfy the Copy trait bound when the AHCI creating data.
+/// Restarts every AHCI controller after a resume, see [`AhciDriver::resume`]
+pub fn resume() {
+    let drivers = DRIVERS.read().clone();
 
-        Arc::new(AhciDriver {
-            inner: RwLock::new(AhciProtected {
-                ports: [EMPTY; 32],    // Initialize the AHCI ports to an empty slice.
-                hba: VirtAddr::zero(), // Initialize the AHCI HBA address to zero.
-            }),
-        })
-    });
+    for driver in drivers.iter() {
+        driver.resume();
+    }
+}
 
-    // Now register the AHCI driver with the PCI subsystem.
-    register_device_driver(get_ahci().clone());
+pub(crate) fn ahci_init() {
+    // Register the AHCI handle with the PCI subsystem, once for all controllers.
+    HANDLE.call_once(|| {
+        let handle = Arc::new(AhciHandle);
+        register_device_driver(handle.clone());
+        handle
+    });
 }
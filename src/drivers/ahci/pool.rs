@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Free lists behind the AHCI DMA pool
+
+use alloc::vec::Vec;
+
+/// Physical addresses of free regions of one size, waiting to be reused
+#[derive(Debug)]
+pub(crate) struct FreeList {
+    /// Size of every region on the list, in bytes
+    size: u64,
+    starts: Vec<u64>,
+}
+
+impl FreeList {
+    pub(crate) const fn new(size: u64) -> Self {
+        Self {
+            size,
+            starts: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, start: u64) {
+        self.starts.push(start);
+    }
+
+    /// Takes the most recently freed region that ends at or below `limit`. Regions out of
+    /// reach are dropped, since the limit only ever gets lower.
+    pub(crate) fn take_below(&mut self, limit: u64) -> Option<u64> {
+        let size = self.size;
+
+        self.starts.retain(|start| start + size <= limit);
+        self.starts.pop()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.starts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 0x2000;
+
+    #[test]
+    fn regions_are_reused_last_in_first_out() {
+        let mut free = FreeList::new(SIZE);
+
+        free.push(0x10_0000);
+        free.push(0x20_0000);
+        assert_eq!(free.len(), 2);
+
+        assert_eq!(free.take_below(u64::MAX), Some(0x20_0000));
+        assert_eq!(free.take_below(u64::MAX), Some(0x10_0000));
+        assert_eq!(free.take_below(u64::MAX), None);
+    }
+
+    #[test]
+    fn regions_above_the_limit_are_dropped() {
+        let mut free = FreeList::new(SIZE);
+
+        free.push(0x1000);
+        free.push(0x1_0000_0000);
+        // Ends exactly at the limit
+        free.push(0xFFFF_E000);
+
+        assert_eq!(free.take_below(0x1_0000_0000), Some(0xFFFF_E000));
+        assert_eq!(free.len(), 1);
+        assert_eq!(free.take_below(0x1_0000_0000), Some(0x1000));
+        assert_eq!(free.take_below(u64::MAX), None);
+    }
+
+    #[test]
+    fn repeated_requests_stop_allocating_once_warm() {
+        let mut free = FreeList::new(SIZE);
+        let mut fresh = 0u64;
+
+        for _ in 0..10_000 {
+            // Every read takes three regions and hands them back once it completes
+            let regions: Vec<u64> = (0..3)
+                .map(|_| {
+                    free.take_below(u64::MAX).unwrap_or_else(|| {
+                        fresh += 1;
+                        fresh * SIZE
+                    })
+                })
+                .collect();
+
+            for start in regions {
+                free.push(start);
+            }
+        }
+
+        assert_eq!(fresh, 3);
+        assert_eq!(free.len(), 3);
+    }
+}
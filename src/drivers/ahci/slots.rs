@@ -0,0 +1,410 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Bookkeeping of the commands in flight in a device's command slots
+
+use core::ops::Range;
+
+/// Number of slots in a port's command list
+pub(crate) const AHCI_COMMAND_SLOTS: usize = 32;
+
+/// Commands issued in the slots of a port's command list that a device may use
+#[derive(Debug)]
+pub(crate) struct CommandSlots<T> {
+    commands: [Option<T>; AHCI_COMMAND_SLOTS],
+    free: usize,
+    /// Bitmap of slots currently holding an outstanding NCQ command
+    queued: u32,
+    /// Slots this device may use
+    range: Range<usize>,
+}
+
+impl<T> CommandSlots<T> {
+    pub(crate) fn new(range: Range<usize>) -> Self {
+        Self {
+            commands: core::array::from_fn(|_| None),
+            free: range.len(),
+            queued: 0,
+            range,
+        }
+    }
+
+    /// Moves the device to another range of slots. Nothing may be in flight.
+    pub(crate) fn set_range(&mut self, range: Range<usize>) {
+        debug_assert!(self.is_idle());
+
+        self.free = range.len();
+        self.range = range;
+    }
+
+    /// Whether no command is in flight
+    pub(crate) fn is_idle(&self) -> bool {
+        self.free == self.range.len()
+    }
+
+    /// Whether any slot outside this device's range is active according to the given PxCI and
+    /// PxSACT values, i.e. another device behind the same port multiplier has commands in flight
+    pub(crate) fn others_busy(&self, ci: u32, sact: u32) -> bool {
+        let own = self.range.clone().fold(0u32, |mask, slot| mask | 1 << slot);
+
+        (ci | sact) & !own != 0
+    }
+
+    /// Finds a free slot for an NCQ command if `ncq_depth` is given, or for a non-queued one
+    /// otherwise. NCQ commands only use the first `ncq_depth` slots of the range, and neither
+    /// kind gets a slot while commands of the other one are in flight.
+    pub(crate) fn find_free(&self, ncq_depth: Option<usize>) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+
+        let in_flight = self.range.len() - self.free;
+        let queued = self.queued.count_ones() as usize;
+
+        let limit = match ncq_depth {
+            Some(_) if queued < in_flight => return None,
+            Some(depth) => core::cmp::min(self.range.end, self.range.start + depth),
+            None if queued > 0 => return None,
+            None => self.range.end,
+        };
+
+        (self.range.start..limit).find(|i| self.commands[*i].is_none())
+    }
+
+    /// Records `command` as issued in `slot`
+    pub(crate) fn track(&mut self, slot: usize, command: T, queued: bool) {
+        debug_assert!(self.range.contains(&slot) && self.commands[slot].is_none());
+
+        self.commands[slot] = Some(command);
+
+        if queued {
+            self.queued |= 1 << slot;
+        } else {
+            self.queued &= !(1 << slot);
+        }
+
+        self.free -= 1;
+    }
+
+    /// Removes every command in flight, handing each to `f`
+    pub(crate) fn take_all(&mut self, mut f: impl FnMut(T)) {
+        for command in self.commands.iter_mut().filter_map(Option::take) {
+            self.free += 1;
+            f(command);
+        }
+
+        self.queued = 0;
+    }
+
+    /// Removes the commands that are done according to the given PxCI and PxSACT values and
+    /// hands each to `f`, along with whether it was still busy. Busy commands are only
+    /// removed if `failed` is set, since the HBA won't finish them after an error.
+    ///
+    /// Non-queued commands are done once their PxCI bit clears, NCQ commands once the
+    /// device clears their tag from PxSACT.
+    pub(crate) fn retire(&mut self, ci: u32, sact: u32, failed: bool, mut f: impl FnMut(T, bool)) {
+        for slot in self.range.clone() {
+            let bit = 1 << slot;
+
+            let busy = if self.queued & bit != 0 {
+                sact & bit != 0
+            } else {
+                ci & bit != 0
+            };
+
+            if busy && !failed {
+                continue;
+            }
+
+            if let Some(command) = self.commands[slot].take() {
+                self.queued &= !bit;
+                self.free += 1;
+
+                debug_assert!(self.free <= self.range.len());
+
+                f(command, busy);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    extern crate std;
+
+    /// Retires with the given register values, returning the commands and whether they failed
+    fn retire(
+        slots: &mut CommandSlots<usize>,
+        ci: u32,
+        sact: u32,
+        failed: bool,
+    ) -> Vec<(usize, bool)> {
+        let mut retired = Vec::new();
+        slots.retire(ci, sact, failed, |command, busy| {
+            retired.push((command, busy))
+        });
+        retired
+    }
+
+    #[test]
+    fn slots_are_claimed_in_order_until_none_are_left() {
+        let mut slots = CommandSlots::new(0..4);
+
+        for slot in 0..4 {
+            assert_eq!(slots.find_free(None), Some(slot));
+            slots.track(slot, slot, false);
+        }
+
+        assert_eq!(slots.find_free(None), None);
+        assert!(!slots.is_idle());
+    }
+
+    #[test]
+    fn retired_slots_are_reclaimed() {
+        let mut slots = CommandSlots::new(0..4);
+
+        for slot in 0..4 {
+            slots.track(slot, slot, false);
+        }
+
+        // Slots 1 and 3 finished
+        assert_eq!(
+            retire(&mut slots, 0b0101, 0, false),
+            [(1, false), (3, false)]
+        );
+        assert_eq!(slots.find_free(None), Some(1));
+
+        slots.track(1, 10, false);
+        assert_eq!(slots.find_free(None), Some(3));
+
+        assert_eq!(
+            retire(&mut slots, 0, 0, false),
+            [(0, false), (10, false), (2, false)]
+        );
+        assert!(slots.is_idle());
+    }
+
+    #[test]
+    fn queued_commands_complete_through_sact() {
+        let mut slots = CommandSlots::new(0..4);
+
+        slots.track(0, 0, true);
+        slots.track(1, 1, false);
+
+        // PxCI clears for NCQ commands as soon as the device accepted them
+        assert_eq!(retire(&mut slots, 0b10, 0b01, false), []);
+        assert_eq!(retire(&mut slots, 0b00, 0b01, false), [(1, false)]);
+        assert_eq!(retire(&mut slots, 0b00, 0b00, false), [(0, false)]);
+
+        // The slot forgets it held an NCQ command
+        slots.track(0, 2, false);
+        assert_eq!(retire(&mut slots, 0b01, 0b00, false), []);
+    }
+
+    #[test]
+    fn errors_fail_busy_commands() {
+        let mut slots = CommandSlots::new(0..4);
+
+        slots.track(0, 0, false);
+        slots.track(1, 1, true);
+        slots.track(2, 2, false);
+
+        assert_eq!(
+            retire(&mut slots, 0b001, 0b010, true),
+            [(0, true), (1, true), (2, false)]
+        );
+        assert!(slots.is_idle());
+    }
+
+    #[test]
+    fn only_the_own_range_is_used() {
+        let mut slots = CommandSlots::new(4..6);
+
+        assert_eq!(slots.find_free(None), Some(4));
+        slots.track(4, 4, false);
+        slots.track(5, 5, false);
+        assert_eq!(slots.find_free(None), None);
+
+        // Slots of other devices behind the same port are left alone
+        assert_eq!(retire(&mut slots, 0b11_0000, 0, false), []);
+        assert_eq!(
+            retire(&mut slots, 0b1111, 0, false),
+            [(4, false), (5, false)]
+        );
+
+        slots.set_range(8..10);
+        assert_eq!(slots.find_free(None), Some(8));
+    }
+
+    #[test]
+    fn commands_of_other_devices_keep_the_port_busy() {
+        let slots = CommandSlots::<usize>::new(4..6);
+
+        assert!(!slots.others_busy(0, 0));
+        assert!(!slots.others_busy(0b11_0000, 0b01_0000));
+
+        // A command in flight for another port multiplier port, issued or queued
+        assert!(slots.others_busy(0b01_0000_0000, 0));
+        assert!(slots.others_busy(0, 0b1000));
+        assert!(slots.others_busy(0, 1 << 31));
+    }
+
+    #[test]
+    fn ncq_depth_limits_the_slots() {
+        let mut slots = CommandSlots::new(0..32);
+
+        slots.track(0, 0, true);
+        slots.track(1, 1, true);
+
+        assert_eq!(slots.find_free(Some(2)), None);
+        assert_eq!(slots.find_free(Some(3)), Some(2));
+    }
+
+    #[test]
+    fn ncq_depth_counts_from_the_start_of_the_range() {
+        let mut slots = CommandSlots::new(8..12);
+
+        assert_eq!(slots.find_free(Some(2)), Some(8));
+        slots.track(8, 8, true);
+        assert_eq!(slots.find_free(Some(2)), Some(9));
+        slots.track(9, 9, true);
+        assert_eq!(slots.find_free(Some(2)), None);
+
+        // The depth never reaches past the range
+        assert_eq!(slots.find_free(Some(32)), Some(10));
+    }
+
+    #[test]
+    fn queued_and_non_queued_commands_do_not_mix() {
+        let mut slots = CommandSlots::new(0..4);
+
+        slots.track(0, 0, true);
+
+        // Non-queued commands wait until every NCQ command finished
+        assert_eq!(slots.find_free(None), None);
+        assert_eq!(retire(&mut slots, 0, 0, false), [(0, false)]);
+        assert_eq!(slots.find_free(None), Some(0));
+
+        // And NCQ commands wait for non-queued ones
+        slots.track(0, 1, false);
+        assert_eq!(slots.find_free(Some(32)), None);
+        assert_eq!(retire(&mut slots, 0, 0, false), [(1, false)]);
+        assert_eq!(slots.find_free(Some(32)), Some(0));
+    }
+
+    #[test]
+    fn take_all_empties_every_slot() {
+        let mut slots = CommandSlots::new(0..4);
+
+        slots.track(0, 0, true);
+        slots.track(2, 2, false);
+
+        let mut taken = Vec::new();
+        slots.take_all(|command| taken.push(command));
+
+        assert_eq!(taken, [0, 2]);
+        assert!(slots.is_idle());
+        assert_eq!(slots.find_free(None), Some(0));
+    }
+
+    #[test]
+    fn completions_race_issuing_on_two_ports() {
+        use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+        use std::sync::Mutex;
+
+        const COMMANDS: usize = 10_000;
+
+        /// A port's slots behind the lock the driver keeps them under, standing in for its
+        /// `IrqRwLock`, and its PxCI
+        struct Port {
+            slots: Mutex<CommandSlots<usize>>,
+            ci: AtomicU32,
+        }
+
+        let ports = [0, 1].map(|_| Port {
+            slots: Mutex::new(CommandSlots::new(0..AHCI_COMMAND_SLOTS)),
+            ci: AtomicU32::new(0),
+        });
+        let done = AtomicBool::new(false);
+
+        let completed = std::thread::scope(|scope| {
+            // complete_commands from the interrupt handler, for every port in turn
+            let handler = scope.spawn(|| {
+                let mut completed = vec![Vec::new(), Vec::new()];
+
+                loop {
+                    let last = done.load(Ordering::SeqCst);
+
+                    for (port, completed) in ports.iter().zip(completed.iter_mut()) {
+                        let mut slots = port.slots.lock().unwrap();
+                        let ci = port.ci.load(Ordering::SeqCst);
+
+                        slots.retire(ci, 0, false, |command, busy| {
+                            assert!(!busy);
+                            completed.push(command);
+                        });
+                    }
+
+                    if last {
+                        return completed;
+                    }
+
+                    std::thread::yield_now();
+                }
+            });
+
+            // The HBA, finishing the lowest command in flight on each port, without any lock
+            let hba = scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    for port in ports.iter() {
+                        let ci = port.ci.load(Ordering::SeqCst);
+                        port.ci
+                            .fetch_and(!(ci & ci.wrapping_neg()), Ordering::SeqCst);
+                    }
+                }
+            });
+
+            // run_request on each port, issuing before tracking like the driver does
+            let issuers = ports.each_ref().map(|port| {
+                scope.spawn(move || {
+                    let mut command = 0;
+
+                    while command < COMMANDS {
+                        let mut slots = port.slots.lock().unwrap();
+
+                        if let Some(slot) = slots.find_free(None) {
+                            port.ci.fetch_or(1 << slot, Ordering::SeqCst);
+                            slots.track(slot, command, false);
+                            command += 1;
+                        } else {
+                            drop(slots);
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            });
+
+            for issuer in issuers {
+                issuer.join().unwrap();
+            }
+
+            while ports.iter().any(|port| port.ci.load(Ordering::SeqCst) != 0) {
+                std::thread::yield_now();
+            }
+
+            done.store(true, Ordering::SeqCst);
+            hba.join().unwrap();
+            handler.join().unwrap()
+        });
+
+        // Every command completed exactly once, on the port it was issued on
+        for (port, mut completed) in ports.iter().zip(completed) {
+            completed.sort_unstable();
+
+            assert_eq!(completed, (0..COMMANDS).collect::<Vec<_>>());
+            assert!(port.slots.lock().unwrap().is_idle());
+        }
+    }
+}
@@ -6,6 +6,8 @@ pub mod sync;
 
 use core::cell::UnsafeCell;
 
+use crate::time::tsc_hz;
+
 /// Just like [`Cell`] but with [volatile] read / write operations
 /// Differs from the Aero implementation by being in tuple struct form, which is much simpler to manage
 ///
@@ -59,3 +61,60 @@ macro_rules! ceil_div_impl {
 }
 
 ceil_div_impl!(u8 u16 u32 u64 usize u128);
+
+/// A point in time measured in TSC ticks, used to bound busy-waits on hardware
+/// without depending on the timer interrupt being set up.
+///
+/// Ticks are converted with the TSC frequency calibrated along with the APIC timer. If there
+/// was nothing to calibrate against, deadlines expire right away.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    /// Creates a deadline `millis` milliseconds from now
+    pub fn after_millis(millis: u64) -> Self {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        Self(now + tsc_hz() / 1000 * millis)
+    }
+
+    /// Returns whether the deadline has passed
+    pub fn expired(&self) -> bool {
+        unsafe { core::arch::x86_64::_rdtsc() >= self.0 }
+    }
+
+    /// Spins until the deadline has passed
+    pub fn wait(&self) {
+        while !self.expired() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Polls `done` until it returns `true` or the deadline passes, returning whether it did
+    pub fn poll(&self, mut done: impl FnMut() -> bool) -> bool {
+        while !self.expired() {
+            if done() {
+                return true;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        done()
+    }
+}
+
+/// A point in time measured in TSC ticks, used to time work in the boot log
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch(u64);
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Self(unsafe { core::arch::x86_64::_rdtsc() })
+    }
+
+    /// Returns the microseconds passed since the stopwatch was started
+    pub fn elapsed_micros(&self) -> u64 {
+        let ticks = unsafe { core::arch::x86_64::_rdtsc() } - self.0;
+        ticks / (tsc_hz() / 1_000_000).max(1)
+    }
+}
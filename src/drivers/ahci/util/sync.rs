@@ -5,6 +5,7 @@
 use {
     crate::arch::x86_64::interrupts,
     alloc::{sync::Arc, vec::Vec},
+    core::sync::atomic::{AtomicBool, Ordering},
     spin::relax::RelaxStrategy,
 };
 
@@ -55,6 +56,61 @@ impl RelaxStrategy for IrqStrategy {
     }
 }
 
+/// A one-shot flag that lets a waiter block until an interrupt handler signals it.
+#[derive(Debug, Default)]
+pub struct Completion {
+    done: AtomicBool,
+    failed: AtomicBool,
+}
+
+impl Completion {
+    /// Creates a new, not yet completed [`Completion`].
+    pub const fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks the [`Completion`] as done, releasing anyone waiting on it.
+    pub fn complete(&self) {
+        self.done.store(true, Ordering::Release);
+    }
+
+    /// Marks the [`Completion`] as done but unsuccessful, releasing anyone waiting on it.
+    pub fn fail(&self) {
+        self.failed.store(true, Ordering::Release);
+        self.complete();
+    }
+
+    /// Returns whether [`Completion::complete`] or [`Completion::fail`] has been called.
+    pub fn is_complete(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the [`Completion`] was signalled through [`Completion::fail`].
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the [`Completion`] is signalled.
+    pub fn wait(&self) {
+        while !self.is_complete() {
+            Self::relax();
+        }
+    }
+
+    /// Halts the CPU until the next interrupt if interrupts are enabled, or spins otherwise
+    /// so we never halt with no way of waking up again.
+    pub fn relax() {
+        if interrupts::is_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
 /// A spin-based lock providing mutually exclusive access to data.
 #[derive(Debug)]
 pub struct Mutex<T> {
@@ -112,6 +168,95 @@ impl<T> Mutex<T> {
     }
 }
 
+/// A spin-based reader-writer lock that keeps interrupts disabled while it is held.
+///
+/// Meant for data an interrupt handler locks as well: the handler can't interrupt a holder on
+/// the same CPU and wait for a lock that is never going to be released.
+#[derive(Debug)]
+pub struct IrqRwLock<T> {
+    inner: spin::RwLock<T>,
+}
+
+impl<T> IrqRwLock<T> {
+    /// Creates a new [`IrqRwLock`] wrapping the supplied data.
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::RwLock::new(value),
+        }
+    }
+
+    /// Locks the [`IrqRwLock`] for shared access, disabling interrupts until the guard is
+    /// dropped.
+    pub fn read(&self) -> IrqRwLockReadGuard<T> {
+        let irq = IrqGuard::new();
+
+        IrqRwLockReadGuard {
+            guard: self.inner.read(),
+            _irq: irq,
+        }
+    }
+
+    /// Locks the [`IrqRwLock`] for exclusive access, disabling interrupts until the guard is
+    /// dropped.
+    pub fn write(&self) -> IrqRwLockWriteGuard<T> {
+        let irq = IrqGuard::new();
+
+        IrqRwLockWriteGuard {
+            guard: self.inner.write(),
+            _irq: irq,
+        }
+    }
+
+    /// Like [`IrqRwLock::write`], but returns `None` instead of spinning if the lock is
+    /// held.
+    pub fn try_write(&self) -> Option<IrqRwLockWriteGuard<T>> {
+        let irq = IrqGuard::new();
+
+        Some(IrqRwLockWriteGuard {
+            guard: self.inner.try_write()?,
+            _irq: irq,
+        })
+    }
+}
+
+/// Shared access to the data of an [`IrqRwLock`]. Fields drop in declaration order, so the
+/// lock is released before interrupts are enabled again.
+pub struct IrqRwLockReadGuard<'a, T: 'a> {
+    guard: spin::RwLockReadGuard<'a, T>,
+    _irq: IrqGuard,
+}
+
+impl<'a, T> core::ops::Deref for IrqRwLockReadGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+/// Exclusive access to the data of an [`IrqRwLock`], see [`IrqRwLockReadGuard`]
+pub struct IrqRwLockWriteGuard<'a, T: 'a> {
+    guard: spin::RwLockWriteGuard<'a, T>,
+    _irq: IrqGuard,
+}
+
+impl<'a, T> core::ops::Deref for IrqRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> core::ops::DerefMut for IrqRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
 pub struct MutexGuard<'a, T: ?Sized + 'a> {
     guard: core::mem::ManuallyDrop<spin::MutexGuard<'a, T>>,
     irq_lock: bool,
@@ -1,16 +1,27 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::{
+    ops::Range,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
+use bit_field::BitField;
+use bitflags::bitflags;
+use log::*;
+use spin::{Mutex, Once};
 use x2apic::lapic::xapic_base;
-use x86_64::structures::paging::PageTableFlags;
+use x86_64::{registers::model_specific::Msr, structures::paging::PageTableFlags};
 
-use crate::get_phys_offset;
+use crate::{get_phys_offset, percpu};
 
 use {
     crate::{arch::x86_64::interrupts::IrqIndex, map_page, INTERRUPT_MODEL},
-    acpi::InterruptModel,
+    acpi::{
+        platform::interrupt::{Polarity, TriggerMode},
+        InterruptModel,
+    },
     alloc::vec::Vec,
     x2apic::{
-        ioapic::IoApic,
+        ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry},
         lapic::{LocalApic, LocalApicBuilder},
     },
     x86_64::{instructions::port::Port, structures::paging::Size4KiB},
@@ -18,26 +29,39 @@ use {
 
 pub(crate) static APIC_IS_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
-/// Function returning an Iterator of all XAPIC IDs present on the system
-///
-/// Uses `raw_cpuid::ExtendedTopologyIter` to extract this information at runtime,
-/// so this should work on pretty much all hardware
-pub fn get_lapic_ids() -> impl Iterator<Item = u32> + Clone {
-    let mut id_vec = Vec::new();
-
-    if let Some(iter) = raw_cpuid::CpuId::new().get_extended_topology_info() {
-        for topology in iter {
-            id_vec.push(topology.x2apic_id());
-        }
-    } else {
-        //only have one core
-        id_vec.push(unsafe { get_active_lapic().id() });
+/// An I/O APIC and the GSIs its redirection entries handle
+#[derive(Debug)]
+pub(crate) struct IoApicRange {
+    virt: u64,
+    /// GSI of the first redirection entry
+    gsi_base: u32,
+    /// Number of redirection entries
+    entries: u32,
+}
+
+impl IoApicRange {
+    fn gsis(&self) -> Range<u32> {
+        self.gsi_base..self.gsi_base + self.entries
     }
 
-    id_vec.into_iter()
+    fn overlaps(&self, other: &IoApicRange) -> bool {
+        self.gsi_base < other.gsis().end && other.gsi_base < self.gsis().end
+    }
+}
+
+/// Every I/O APIC from the MADT, built once and reused after a resume
+static IO_APICS: Once<Vec<IoApicRange>> = Once::new();
+
+/// The I/O APIC handling `gsi` and the input it arrives on
+fn ioapic_for(gsi: u32) -> Option<(IoApic, u8)> {
+    IO_APICS
+        .get()?
+        .iter()
+        .find(|range| range.gsis().contains(&gsi))
+        .map(|range| unsafe { (IoApic::new(range.virt), (gsi - range.gsi_base) as u8) })
 }
 
-pub(crate) fn build_all_available_apics() -> Option<(LocalApic, Vec<IoApic>)> {
+pub(crate) fn build_all_available_apics() -> Option<(LocalApic, &'static [IoApicRange])> {
     unsafe {
         // Disable 8259 immediately
 
@@ -73,7 +97,6 @@ pub(crate) fn build_all_available_apics() -> Option<(LocalApic, Vec<IoApic>)> {
 
     if let InterruptModel::Apic(apic) = INTERRUPT_MODEL.get().unwrap() {
         let offset = crate::get_phys_offset();
-        let mut ioapic_impl_vec = Vec::new();
 
         let lapic_virt = apic.local_apic_address + offset;
 
@@ -95,50 +118,73 @@ pub(crate) fn build_all_available_apics() -> Option<(LocalApic, Vec<IoApic>)> {
             .build()
             .unwrap_or_else(|e| panic!("Error building the local APIC: {:#?}", e));
 
-        for ioapic in apic.io_apics.iter() {
-            let phys = ioapic.address as u64;
-            let virt = phys + offset;
-
-            ioapic_impl_vec.push(unsafe { IoApic::new(virt) });
-            map_page!(
-                phys,
-                virt,
-                Size4KiB,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::NO_CACHE
-                    | PageTableFlags::WRITE_THROUGH
-            );
-        }
-        Some((first_lapic, ioapic_impl_vec))
+        let ioapics = IO_APICS.call_once(|| {
+            let mut ranges: Vec<IoApicRange> = Vec::new();
+
+            for ioapic in apic.io_apics.iter() {
+                let phys = ioapic.address as u64;
+                let virt = phys + offset;
+
+                map_page!(
+                    phys,
+                    virt,
+                    Size4KiB,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE
+                        | PageTableFlags::WRITE_THROUGH
+                );
+
+                let range = IoApicRange {
+                    virt,
+                    gsi_base: ioapic.global_system_interrupt_base,
+                    entries: unsafe { IoApic::new(virt).max_table_entry() } as u32 + 1,
+                };
+
+                // Programming a GSI on both controllers would deliver it twice
+                if let Some(other) = ranges.iter().find(|other| other.overlaps(&range)) {
+                    warn!(
+                        "APIC: I/O APIC {} claims GSIs {:?}, which overlap with {:?}, ignoring it",
+                        ioapic.id,
+                        range.gsis(),
+                        other.gsis()
+                    );
+                    continue;
+                }
+
+                debug!(
+                    "APIC: I/O APIC {} handles GSIs {:?}",
+                    ioapic.id,
+                    range.gsis()
+                );
+                ranges.push(range);
+            }
+
+            ranges
+        });
+
+        Some((first_lapic, ioapics))
     } else {
         None
     }
 }
 
-macro_rules! ioapic_irq {
-    ($pic:expr, $irq:expr, $dest:expr) => {
-        use x2apic::ioapic::{IrqFlags, IrqMode, RedirectionTableEntry};
-        let mut e = RedirectionTableEntry::default();
-        e.set_mode(IrqMode::Fixed);
-        e.set_flags(IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE);
-        e.set_vector($irq as u8);
-        e.set_dest($dest as u8);
-
-        $pic.set_table_entry($irq, e);
-        $pic.enable_irq($irq);
-    };
-}
-
 pub(crate) fn init_all_available_apics() {
-    let (lapic, ioapics) = build_all_available_apics().expect("Legacy 8259 PIC not supported");
+    let (mut lapic, ioapics) = build_all_available_apics().expect("Legacy 8259 PIC not supported");
 
     unsafe {
-        for mut ioapic in ioapics.into_iter() {
-            ioapic.init(32);
+        lapic.enable();
+        crate::time::start_lapic_timer(&mut lapic);
+        *addr_of_mut!(LOCAL_APIC) = Some(lapic);
+
+        // Every entry starts out masked, drivers unmask the ones they need through route_gsi()
+        for range in ioapics {
+            let mut ioapic = IoApic::new(range.virt);
 
-            for i in 0..(255 - 32) {
-                ioapic_irq!(ioapic, i, lapic.id());
+            for pin in 0..range.entries {
+                let mut entry = RedirectionTableEntry::default();
+                entry.set_flags(IrqFlags::MASKED);
+                ioapic.set_table_entry(pin as u8, entry);
             }
         }
 
@@ -148,10 +194,253 @@ pub(crate) fn init_all_available_apics() {
     APIC_IS_INITIALIZED.store(true, Ordering::Relaxed);
 }
 
+/// A programmed redirection entry: GSI, vector, destination APIC ID, level-triggered and
+/// active-low
+type Route = (u32, u8, u32, bool, bool);
+
+/// Every entry programmed so far, replayed by `restore_routes()`
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+/// Trigger mode and polarity of ISA interrupts unless an override says otherwise
+const ISA_LEVEL_TRIGGERED: bool = false;
+const ISA_ACTIVE_LOW: bool = false;
+
+/// The MADT's interrupt source override for ISA IRQ `irq`, as the GSI it is wired to and
+/// whether it is level-triggered and active-low
+fn isa_override(irq: u8) -> Option<(u32, bool, bool)> {
+    let InterruptModel::Apic(apic) = INTERRUPT_MODEL.get()? else {
+        return None;
+    };
+
+    let over = apic
+        .interrupt_source_overrides
+        .iter()
+        .find(|over| over.isa_source == irq)?;
+
+    let level_triggered = match over.trigger_mode {
+        TriggerMode::Edge => false,
+        TriggerMode::Level => true,
+        TriggerMode::SameAsBus => ISA_LEVEL_TRIGGERED,
+    };
+
+    let active_low = match over.polarity {
+        Polarity::ActiveHigh => false,
+        Polarity::ActiveLow => true,
+        Polarity::SameAsBus => ISA_ACTIVE_LOW,
+    };
+
+    Some((over.global_system_interrupt, level_triggered, active_low))
+}
+
+/// Trigger mode and polarity of `gsi` according to the MADT. GSIs an ISA IRQ is wired to,
+/// directly or through an override, behave like ISA interrupts; all others are assumed to be
+/// shareable PCI interrupts.
+fn gsi_flags(gsi: u32) -> (bool, bool) {
+    // Overrides that move an ISA IRQ onto this GSI, e.g. the PIT's IRQ 0 onto GSI 2
+    if let Some((_, level_triggered, active_low)) = (0..16)
+        .filter_map(isa_override)
+        .find(|&(target, ..)| target == gsi)
+    {
+        return (level_triggered, active_low);
+    }
+
+    if gsi < 16 && isa_override(gsi as u8).is_none() {
+        return (ISA_LEVEL_TRIGGERED, ISA_ACTIVE_LOW);
+    }
+
+    (true, true)
+}
+
+/// Routes `gsi` to `vector` on the local APIC `dest` through the I/O APIC that handles it.
+/// ISA IRQ numbers are moved to the GSI and flags the MADT's interrupt source overrides give
+/// them; otherwise `SameAsBus` takes the trigger mode and polarity the MADT implies for the
+/// GSI. Returns the GSI that was actually programmed, or `None` if no I/O APIC handles it.
+pub(crate) fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    dest: u32,
+    polarity: Polarity,
+    trigger: TriggerMode,
+) -> Option<u32> {
+    // _PRT link devices hand out ISA IRQ numbers, which the firmware may have rewired
+    let route = match u8::try_from(gsi).ok().and_then(isa_override) {
+        Some((gsi, level_triggered, active_low)) => {
+            (gsi, vector, dest, level_triggered, active_low)
+        }
+        None => {
+            let (bus_level_triggered, bus_active_low) = gsi_flags(gsi);
+
+            let level_triggered = match trigger {
+                TriggerMode::Edge => false,
+                TriggerMode::Level => true,
+                TriggerMode::SameAsBus => bus_level_triggered,
+            };
+
+            let active_low = match polarity {
+                Polarity::ActiveHigh => false,
+                Polarity::ActiveLow => true,
+                Polarity::SameAsBus => bus_active_low,
+            };
+
+            (gsi, vector, dest, level_triggered, active_low)
+        }
+    };
+
+    program_gsi(route)?;
+
+    Some(route.0)
+}
+
+/// Programs every interrupt routed so far into the I/O APICs again, after they lost their
+/// redirection tables in a suspend
+pub(crate) fn restore_routes() {
+    for &route in ROUTES.lock().iter() {
+        write_entry(route);
+    }
+}
+
+/// Programs and records `route`
+fn program_gsi(route: Route) -> Option<()> {
+    write_entry(route)?;
+
+    let mut routes = ROUTES.lock();
+    routes.retain(|&(gsi, ..)| gsi != route.0);
+    routes.push(route);
+
+    Some(())
+}
+
+/// Writes and unmasks the redirection entry of `route` in the I/O APIC handling its GSI
+fn write_entry((gsi, vector, dest, level_triggered, active_low): Route) -> Option<()> {
+    let (mut ioapic, pin) = ioapic_for(gsi)?;
+
+    let mut flags = IrqFlags::empty();
+    flags.set(IrqFlags::LEVEL_TRIGGERED, level_triggered);
+    flags.set(IrqFlags::LOW_ACTIVE, active_low);
+
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_flags(flags);
+    entry.set_vector(vector);
+    entry.set_dest(dest as u8);
+
+    unsafe {
+        ioapic.set_table_entry(pin, entry);
+        ioapic.enable_irq(pin);
+    }
+
+    Some(())
+}
+
+const IA32_APIC_BASE: u32 = 0x1B;
+/// IA32_APIC_BASE: the local APIC runs in x2APIC mode
+const APIC_BASE_EXTD: usize = 10;
+/// The error status register in x2APIC mode
+const X2APIC_ESR: u32 = 0x828;
+/// Offset of the error status register in the xAPIC register page
+const XAPIC_ESR_OFFSET: u64 = 0x280;
+/// The local APIC ID register in x2APIC mode
+const X2APIC_ID: u32 = 0x802;
+/// Offset of the local APIC ID register in the xAPIC register page
+const XAPIC_ID_OFFSET: u64 = 0x20;
+
+/// Whether the executing CPU's local APIC runs in x2APIC mode
+fn x2apic_enabled() -> bool {
+    unsafe { Msr::new(IA32_APIC_BASE).read() }.get_bit(APIC_BASE_EXTD)
+}
+
+/// Local APIC ID of the executing CPU as IPIs address it: all 32 bits in x2APIC mode, the 8
+/// bits at the top of the ID register in xAPIC mode
+pub fn local_apic_id() -> u32 {
+    if x2apic_enabled() {
+        return unsafe { Msr::new(X2APIC_ID).read() } as u32;
+    }
+
+    let id = (unsafe { xapic_base() } + get_phys_offset() + XAPIC_ID_OFFSET) as *const u32;
+    unsafe { id.read_volatile() }.get_bits(24..32)
+}
+
+/// Fits the APIC ID `dest` into the destination of an IPI in the current mode. CPUID reports
+/// 32-bit x2APIC IDs, but xAPIC mode only addresses 8 bits, so larger IDs can't be reached.
+fn ipi_destination(dest: u32) -> Option<u32> {
+    if x2apic_enabled() {
+        return Some(dest);
+    }
+
+    u8::try_from(dest).ok().map(u32::from)
+}
+
+bitflags! {
+    /// Error status register of the local APIC
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct LapicError: u32 {
+        const SEND_CHECKSUM = 1 << 0;
+        const RECEIVE_CHECKSUM = 1 << 1;
+        const SEND_ACCEPT = 1 << 2;
+        const RECEIVE_ACCEPT = 1 << 3;
+        /// A lowest-priority IPI was sent, which the local APIC doesn't support
+        const REDIRECTABLE_IPI = 1 << 4;
+        /// An IPI was sent with a vector below 16
+        const SEND_ILLEGAL_VECTOR = 1 << 5;
+        /// An interrupt with a vector below 16 came in
+        const RECEIVE_ILLEGAL_VECTOR = 1 << 6;
+        const ILLEGAL_REGISTER_ADDRESS = 1 << 7;
+    }
+}
+
+/// Reads the error status register of the executing CPU's local APIC. The register only
+/// shows the errors since the last write to it, so it is written first, which also clears it
+/// for the next read.
+pub fn read_esr() -> LapicError {
+    let raw = if x2apic_enabled() {
+        unsafe {
+            let mut esr = Msr::new(X2APIC_ESR);
+            esr.write(0);
+            esr.read() as u32
+        }
+    } else {
+        let esr = (unsafe { xapic_base() } + get_phys_offset() + XAPIC_ESR_OFFSET) as *mut u32;
+
+        unsafe {
+            esr.write_volatile(0);
+            esr.read_volatile()
+        }
+    };
+
+    LapicError::from_bits_truncate(raw)
+}
+
+/// Sends the fixed IPI `vector` to the CPU with the local APIC ID `dest`, remembering the
+/// vector in case the local APIC reports an error for it
+pub fn send_ipi(vector: u8, dest: u32) {
+    let Some(dest) = ipi_destination(dest) else {
+        warn!(
+            "APIC: can't send IPI {} to APIC ID {:#x} in xAPIC mode",
+            vector, dest
+        );
+        return;
+    };
+
+    if let Some(cpu) = percpu::try_current() {
+        cpu.note_ipi_sent(vector);
+    }
+
+    unsafe { get_active_lapic().send_ipi(vector, dest) };
+}
+
+/// The local APIC as built by `init_all_available_apics()`. The struct only holds its
+/// configuration and how to reach its registers, which are the same on every CPU.
+static mut LOCAL_APIC: Option<LocalApic> = None;
+
 /// Workaround for getting a reference to the local APIC without needing to lock it
 ///
 /// Uses raw pointer but is abstracted behind the scenes
 #[inline(always)]
 pub fn get_active_lapic<'a>() -> &'a mut LocalApic {
-    unsafe { &mut *((xapic_base() + get_phys_offset()) as *mut LocalApic) }
+    unsafe {
+        match (*addr_of_mut!(LOCAL_APIC)).as_mut() {
+            Some(lapic) => lapic,
+            None => &mut *((xapic_base() + get_phys_offset()) as *mut LocalApic),
+        }
+    }
 }
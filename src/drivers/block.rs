@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::{boxed::Box, collections::BTreeMap, vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::disk::{DiskEntry, DiskLocation};
+
+/// Size in bytes of a cached block, independent of the disk's sector size
+pub const CACHE_BLOCK_SIZE: usize = 4096;
+
+/// Number of blocks kept by [`BLOCK_CACHE`] (1MiB)
+const CACHE_CAPACITY: usize = 256;
+
+/// Cache shared by every disk in [`crate::disk::ALL_DISKS`]
+pub static BLOCK_CACHE: BlockCache = BlockCache::new(CACHE_CAPACITY);
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    /// Value of [`BlockCacheInner::clock`] when the block was last used
+    last_used: u64,
+}
+
+struct BlockCacheInner {
+    blocks: BTreeMap<(DiskLocation, usize), CachedBlock>,
+    clock: u64,
+}
+
+/// LRU cache of disk contents in [`CACHE_BLOCK_SIZE`] blocks, keyed by disk and block index.
+///
+/// Anything writing to a disk must [`invalidate`](BlockCache::invalidate) the sectors it
+/// touched, otherwise later reads return stale data.
+pub struct BlockCache {
+    inner: Mutex<BlockCacheInner>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(BlockCacheInner {
+                blocks: BTreeMap::new(),
+                clock: 0,
+            }),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads `buffer.len()` bytes starting at `sector` of `disk`, going to the disk only for
+    /// blocks that aren't cached yet. Returns the number of bytes read.
+    pub fn read_cached(&self, disk: &DiskEntry, sector: usize, buffer: &mut [u8]) -> Option<usize> {
+        let mut position = sector * disk.disk.block_size();
+        let mut copied = 0;
+
+        while copied < buffer.len() {
+            let index = position / CACHE_BLOCK_SIZE;
+            let start = position % CACHE_BLOCK_SIZE;
+            let length = core::cmp::min(buffer.len() - copied, CACHE_BLOCK_SIZE - start);
+
+            self.with_block(disk, index, |block| {
+                buffer[copied..copied + length].copy_from_slice(&block[start..start + length])
+            })?;
+
+            copied += length;
+            position += length;
+        }
+
+        Some(copied)
+    }
+
+    /// Drops the cached blocks covering `count` sectors of `block_size` bytes starting at `sector`
+    pub fn invalidate(
+        &self,
+        location: DiskLocation,
+        block_size: usize,
+        sector: usize,
+        count: usize,
+    ) {
+        let first = sector * block_size / CACHE_BLOCK_SIZE;
+        let last = ((sector + count) * block_size).div_ceil(CACHE_BLOCK_SIZE);
+
+        let mut inner = self.inner.lock();
+
+        for index in first..last {
+            inner.blocks.remove(&(location.clone(), index));
+        }
+    }
+
+    /// Drops every cached block of the disk at `location`, e.g. because it was unplugged
+    pub fn invalidate_disk(&self, location: DiskLocation) {
+        self.inner
+            .lock()
+            .blocks
+            .retain(|(block_location, _), _| *block_location != location);
+    }
+
+    /// Number of blocks that were served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of blocks that had to be read from disk
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Runs `f` on the contents of block `index` of `disk`, reading it in first on a miss
+    fn with_block<F: FnOnce(&[u8])>(&self, disk: &DiskEntry, index: usize, f: F) -> Option<()> {
+        let key = (disk.location.clone(), index);
+
+        {
+            let mut inner = self.inner.lock();
+            inner.clock += 1;
+
+            let clock = inner.clock;
+
+            if let Some(block) = inner.blocks.get_mut(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                block.last_used = clock;
+
+                f(&block.data);
+                return Some(());
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let block_size = disk.disk.block_size();
+        let sector = index * CACHE_BLOCK_SIZE / block_size;
+
+        // The last block of a disk whose size isn't a multiple of the block size is only read
+        // as far as the disk goes, the rest stays zeroed
+        let length = match disk.disk.sectors() {
+            Some(sectors) => {
+                let left = sectors.checked_sub(sector).filter(|&left| left > 0)?;
+                CACHE_BLOCK_SIZE.min(left * block_size)
+            }
+            None => CACHE_BLOCK_SIZE,
+        };
+
+        // Don't hold the lock while waiting on the disk
+        let mut data = vec![0u8; CACHE_BLOCK_SIZE].into_boxed_slice();
+        disk.disk.read(sector, &mut data[..length])?;
+
+        f(&data);
+
+        let mut inner = self.inner.lock();
+
+        if inner.blocks.len() >= self.capacity {
+            let oldest = inner
+                .blocks
+                .iter()
+                .min_by_key(|(_, block)| block.last_used)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                inner.blocks.remove(&oldest);
+            }
+        }
+
+        let last_used = inner.clock;
+        inner.blocks.insert(key, CachedBlock { data, last_used });
+
+        Some(())
+    }
+}
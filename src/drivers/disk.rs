@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use spin::RwLock;
+
+use crate::block::BLOCK_CACHE;
+
+/// A block device that can be read one logical sector at a time
+pub trait Disk: Send + Sync {
+    /// Reads from `sector` into `buffer`, returning the number of bytes copied into `buffer`
+    fn read(&self, sector: usize, buffer: &mut [u8]) -> Option<usize>;
+
+    /// Size in bytes of a single logical sector
+    fn block_size(&self) -> usize;
+
+    /// Number of logical sectors on the disk, if the device reports it
+    fn sectors(&self) -> Option<usize>;
+}
+
+/// Where a registered disk is attached, so it can be found again (e.g. on hot-unplug)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiskLocation {
+    /// `controller` is the index of the HBA in the AHCI driver list, `pmp` the port
+    /// multiplier port for devices attached through a port multiplier
+    Ahci {
+        controller: usize,
+        port: usize,
+        pmp: Option<u8>,
+    },
+    /// Entry `index` of the partition table of the disk at `parent`
+    Partition {
+        parent: Box<DiskLocation>,
+        index: usize,
+    },
+}
+
+pub struct DiskEntry {
+    pub location: DiskLocation,
+    pub disk: Arc<dyn Disk>,
+}
+
+/// Every disk currently known to the kernel
+pub static ALL_DISKS: RwLock<Vec<DiskEntry>> = RwLock::new(Vec::new());
+
+/// Adds a disk to [`ALL_DISKS`]
+pub fn register_disk(location: DiskLocation, disk: Arc<dyn Disk>) {
+    ALL_DISKS.write().push(DiskEntry { location, disk });
+}
+
+/// Removes the disk attached at `location` from [`ALL_DISKS`], along with its partitions,
+/// returning it if it was there
+pub fn unregister_disk(location: DiskLocation) -> Option<Arc<dyn Disk>> {
+    unregister_partitions(&location);
+
+    // Whatever gets attached here next is a different disk
+    BLOCK_CACHE.invalidate_disk(location.clone());
+
+    let mut disks = ALL_DISKS.write();
+    let index = disks.iter().position(|entry| entry.location == location)?;
+
+    Some(disks.remove(index).disk)
+}
+
+/// Removes every partition of the disk at `parent` from [`ALL_DISKS`]
+pub fn unregister_partitions(parent: &DiskLocation) {
+    let is_child = |location: &DiskLocation| matches!(location, DiskLocation::Partition { parent: p, .. } if **p == *parent);
+
+    let mut disks = ALL_DISKS.write();
+
+    for entry in disks.iter().filter(|entry| is_child(&entry.location)) {
+        BLOCK_CACHE.invalidate_disk(entry.location.clone());
+    }
+
+    disks.retain(|entry| !is_child(&entry.location));
+}
@@ -0,0 +1,353 @@
+//! High Precision Event Timer
+//!
+//! The HPET has a free-running main counter with a fixed period reported in femtoseconds,
+//! which makes it the reference the other timers get calibrated against. Its comparators can
+//! raise interrupts through the I/O APIC, either once or periodically.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use acpi::{
+    platform::interrupt::{Polarity, TriggerMode},
+    AcpiTables, HpetInfo,
+};
+use bit_field::BitField;
+use conquer_once::spin::OnceCell;
+use spin::RwLock;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{PageTableFlags, Size4KiB},
+};
+
+use crate::{
+    acpi_impl::{find_devices_by_hid, AcpiResource, KernelAcpi},
+    ahci::util::VolatileCell,
+    apic_impl::{local_apic_id, route_gsi},
+    get_phys_offset,
+    interrupts::{irqalloc, irqfree, register_irq},
+    map_page,
+};
+
+use log::*;
+
+const GENERAL_CAPABILITIES: usize = 0x000;
+const GENERAL_CONFIGURATION: usize = 0x010;
+const GENERAL_INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const fn timer_configuration(timer: usize) -> usize {
+    0x100 + 0x20 * timer
+}
+
+const fn timer_comparator(timer: usize) -> usize {
+    0x108 + 0x20 * timer
+}
+
+/// GEN_CONF.ENABLE_CNF, starts the main counter
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// Bits of a timer's configuration and capabilities register
+const TN_INT_ENB_CNF: usize = 2;
+const TN_TYPE_CNF: usize = 3;
+const TN_PER_INT_CAP: usize = 4;
+const TN_VAL_SET_CNF: usize = 6;
+const TN_32MODE_CNF: usize = 8;
+
+/// The spec caps the period at 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Handler of the timer interrupt, called in interrupt context
+pub type HpetHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fires once after the given number of nanoseconds
+    OneShot(u64),
+    /// Fires every given number of nanoseconds
+    Periodic(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NotPresent,
+    /// Timer 0 can't fire periodically
+    PeriodicUnsupported,
+    /// None of the I/O APIC inputs timer 0 can drive is handled by an I/O APIC
+    NotRoutable,
+}
+
+struct Hpet {
+    base: u64,
+    /// Length of a main counter tick in femtoseconds
+    period_fs: u64,
+    /// Whether the main counter is only 32 bits wide
+    narrow: bool,
+}
+
+impl Hpet {
+    fn register(&self, offset: usize) -> &VolatileCell<u64> {
+        unsafe { &*((self.base + offset as u64) as *const VolatileCell<u64>) }
+    }
+
+    fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / 1_000_000) as u64
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * 1_000_000 / self.period_fs as u128).max(1) as u64
+    }
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+static HANDLER: RwLock<Option<HpetHandler>> = RwLock::new(None);
+/// Vector of timer 0, allocated the first time it is armed
+static VECTOR: OnceCell<Option<u8>> = OnceCell::uninit();
+/// Last value of a 32-bit main counter, extended to 64 bits to detect wraparounds
+static NARROW_HIGH: AtomicU64 = AtomicU64::new(0);
+
+/// `_HID` of the HPET in the AML namespace
+const HPET_HID: &str = "PNP0103";
+
+/// Base address of the HPET from its ACPI table, or from the `_CRS` of its device if the
+/// firmware didn't provide the table
+fn base_address(tables: &AcpiTables<KernelAcpi>) -> Option<u64> {
+    if let Ok(info) = HpetInfo::new(tables) {
+        return Some(info.base_address as u64);
+    }
+
+    find_devices_by_hid(HPET_HID)
+        .iter()
+        .flat_map(|device| device.resources.iter())
+        .find_map(|resource| match *resource {
+            AcpiResource::Memory { base, .. } => Some(base),
+            _ => None,
+        })
+}
+
+/// Maps the HPET and starts its main counter. Returns `false` if the machine has no usable
+/// HPET. Must run after `aml_init()` to find HPETs that are only described in the namespace.
+pub fn init(tables: &AcpiTables<KernelAcpi>) -> bool {
+    let Some(phys) = base_address(tables) else {
+        info!("HPET: not present");
+        return false;
+    };
+
+    let virt = phys + get_phys_offset();
+
+    map_page!(
+        phys,
+        virt,
+        Size4KiB,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+    );
+
+    let capabilities =
+        unsafe { ((virt + GENERAL_CAPABILITIES as u64) as *const u64).read_volatile() };
+    let period_fs = capabilities.get_bits(32..64);
+
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        warn!("HPET: invalid period of {}fs", period_fs);
+        return false;
+    }
+
+    let hpet = HPET.get_or_init(|| Hpet {
+        base: virt,
+        period_fs,
+        narrow: !capabilities.get_bit(13),
+    });
+
+    // Stop the counter while resetting it, and keep legacy replacement routing off
+    hpet.register(GENERAL_CONFIGURATION).set(0);
+    hpet.register(MAIN_COUNTER).set(0);
+    hpet.register(GENERAL_CONFIGURATION).set(ENABLE_CNF);
+
+    info!(
+        "HPET: {} timers, {}-bit counter at {}MHz",
+        capabilities.get_bits(8..13) + 1,
+        if hpet.narrow { 32 } else { 64 },
+        1_000_000_000 / period_fs
+    );
+
+    true
+}
+
+pub fn is_available() -> bool {
+    HPET.get().is_some()
+}
+
+/// Length of a main counter tick in femtoseconds
+pub fn period_fs() -> Option<u64> {
+    HPET.get().map(|hpet| hpet.period_fs)
+}
+
+/// Raw value of the main counter, extended to 64 bits if the counter is narrower. A 32-bit
+/// counter has to be read at least once per wraparound for this to stay monotonic.
+fn counter(hpet: &Hpet) -> u64 {
+    let value = hpet.register(MAIN_COUNTER).get();
+
+    if !hpet.narrow {
+        return value;
+    }
+
+    let low = value & 0xFFFF_FFFF;
+    let mut last = NARROW_HIGH.load(Ordering::Relaxed);
+
+    loop {
+        // The low half of the stored value is the last counter value seen
+        let high = if low < last & 0xFFFF_FFFF {
+            (last & !0xFFFF_FFFF) + (1 << 32)
+        } else {
+            last & !0xFFFF_FFFF
+        };
+
+        match NARROW_HIGH.compare_exchange_weak(
+            last,
+            high | low,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return high | low,
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// Nanoseconds since the main counter was started, or 0 without an HPET
+pub fn now_ns() -> u64 {
+    HPET.get()
+        .map(|hpet| hpet.ticks_to_ns(counter(hpet)))
+        .unwrap_or(0)
+}
+
+/// Spins for at least `ns` nanoseconds. Returns immediately without an HPET.
+pub fn busy_wait_ns(ns: u64) {
+    let Some(hpet) = HPET.get() else {
+        return;
+    };
+
+    let end = counter(hpet) + hpet.ns_to_ticks(ns);
+
+    while counter(hpet) < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Routes timer 0 to a freshly allocated vector through the first I/O APIC input past the ISA
+/// range it can drive
+fn route_timer(hpet: &Hpet) -> Option<u8> {
+    let config = hpet.register(timer_configuration(0)).get();
+    let allowed = config.get_bits(32..64);
+
+    // ISA inputs would go through the MADT's overrides in route_gsi, which only apply to
+    // IRQ numbers and not to the GSIs the HPET drives directly
+    let candidates = (16..32).filter(|&gsi| allowed.get_bit(gsi));
+    let vector = irqalloc()?;
+    let dest = local_apic_id();
+
+    for gsi in candidates {
+        // Timer interrupts are edge-triggered and active-high
+        if route_gsi(
+            gsi as u32,
+            vector,
+            dest,
+            Polarity::ActiveHigh,
+            TriggerMode::Edge,
+        )
+        .is_some()
+        {
+            let mut config = config;
+            config.set_bits(9..14, gsi as u64);
+            hpet.register(timer_configuration(0)).set(config);
+
+            register_irq(
+                vector,
+                |_, _| service(),
+                core::ptr::null_mut(),
+                Some("hpet"),
+            );
+            debug!("HPET: timer 0 routed to GSI {}, vector {}", gsi, vector);
+
+            return Some(vector);
+        }
+    }
+
+    irqfree(vector);
+    None
+}
+
+/// Arms timer 0 to call `handler` once or periodically, replacing whatever it was set up for
+/// before
+pub fn start_timer(mode: TimerMode, handler: HpetHandler) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+
+    VECTOR
+        .get_or_init(|| route_timer(hpet))
+        .ok_or(HpetError::NotRoutable)?;
+
+    let register = hpet.register(timer_configuration(0));
+    let mut config = register.get();
+
+    if matches!(mode, TimerMode::Periodic(_)) && !config.get_bit(TN_PER_INT_CAP) {
+        return Err(HpetError::PeriodicUnsupported);
+    }
+
+    without_interrupts(|| {
+        *HANDLER.write() = Some(handler);
+
+        config.set_bit(TN_INT_ENB_CNF, false);
+        register.set(config);
+
+        config.set_bit(TN_32MODE_CNF, false);
+        config.set_bit(TN_INT_ENB_CNF, true);
+
+        match mode {
+            TimerMode::OneShot(ns) => {
+                config.set_bit(TN_TYPE_CNF, false);
+                register.set(config);
+
+                let deadline = hpet.register(MAIN_COUNTER).get() + hpet.ns_to_ticks(ns);
+                hpet.register(timer_comparator(0)).set(deadline);
+            }
+            TimerMode::Periodic(ns) => {
+                let period = hpet.ns_to_ticks(ns);
+
+                // With VAL_SET_CNF the first write sets the comparator, the second one the
+                // amount it advances by on every interrupt
+                config.set_bit(TN_TYPE_CNF, true);
+                config.set_bit(TN_VAL_SET_CNF, true);
+                register.set(config);
+
+                let comparator = hpet.register(timer_comparator(0));
+                comparator.set(hpet.register(MAIN_COUNTER).get() + period);
+                comparator.set(period);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Disarms timer 0
+pub fn stop_timer() {
+    if let Some(hpet) = HPET.get() {
+        let register = hpet.register(timer_configuration(0));
+        let mut config = register.get();
+
+        config.set_bit(TN_INT_ENB_CNF, false);
+        register.set(config);
+    }
+}
+
+/// Acknowledges timer 0 and calls its handler, without signalling the end of the interrupt
+pub fn service() {
+    if let Some(hpet) = HPET.get() {
+        // Only matters for level-triggered timers, but doesn't hurt with edge-triggered ones
+        hpet.register(GENERAL_INTERRUPT_STATUS).set(1);
+    }
+
+    if let Some(handler) = *HANDLER.read() {
+        handler();
+    }
+}
@@ -1,5 +1,21 @@
+pub mod acpi_frames;
 pub mod acpi_impl;
+pub mod acpi_sleep;
+pub mod acpi_user;
 pub mod ahci;
 pub mod apic_impl;
+pub mod block;
+pub mod disk;
+pub mod hpet;
+pub mod partitions;
+pub mod pci_bar;
+pub mod pci_ids;
 pub mod pci_impl;
+pub mod pci_scan;
+pub mod pit;
+pub mod power;
+pub mod rtc;
+pub mod serial;
+pub mod time;
+pub mod virtio;
 pub mod xhci;
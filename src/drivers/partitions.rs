@@ -0,0 +1,459 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::ops::Range;
+use log::{info, warn};
+
+use crate::{
+    common::crc32::crc32,
+    disk::{register_disk, unregister_partitions, Disk, DiskLocation, ALL_DISKS},
+};
+
+/// Offset of the first of the four primary partition entries in the MBR
+const MBR_ENTRIES_OFFSET: usize = 0x1BE;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// Partition types that describe a container of logical partitions
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Partition type of the protective MBR entry covering a GPT disk
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of the fields of the GPT header defined by the spec, the rest of the sector is reserved
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Upper bound on the partition entry array, so a corrupt header can't exhaust the heap
+const GPT_ENTRIES_MAX_SIZE: usize = 1024 * 1024;
+
+/// A GUID as stored on disk, with the first three fields little-endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let g = &self.0;
+
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+
+        g[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// What the partition table says about a partition
+#[derive(Debug, Clone)]
+pub enum PartitionKind {
+    /// Partition type byte of an MBR entry
+    Mbr(u8),
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        name: String,
+    },
+}
+
+/// A range of sectors of another disk, exposed as a disk of its own
+pub struct Partition {
+    parent: Arc<dyn Disk>,
+    /// First sector of the parent disk belonging to the partition
+    start: usize,
+    /// Length of the partition in sectors
+    sectors: usize,
+    kind: PartitionKind,
+}
+
+impl Partition {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn kind(&self) -> &PartitionKind {
+        &self.kind
+    }
+}
+
+impl Disk for Partition {
+    fn read(&self, sector: usize, buffer: &mut [u8]) -> Option<usize> {
+        let count = buffer.len().div_ceil(self.block_size());
+
+        if sector.checked_add(count)? > self.sectors {
+            warn!(
+                "partition: read of {} sectors at {} is past the end ({} sectors)",
+                count, sector, self.sectors
+            );
+            return None;
+        }
+
+        self.parent.read(self.start + sector, buffer)
+    }
+
+    fn block_size(&self) -> usize {
+        self.parent.block_size()
+    }
+
+    fn sectors(&self) -> Option<usize> {
+        Some(self.sectors)
+    }
+}
+
+/// A partition read from a partition table, before it is registered
+struct PartitionEntry {
+    /// Position of the entry in the table
+    index: usize,
+    start: usize,
+    sectors: usize,
+    kind: PartitionKind,
+}
+
+impl PartitionEntry {
+    fn range(&self) -> Range<usize> {
+        self.start..self.start + self.sectors
+    }
+}
+
+/// Adds `entry` to `entries` unless it is zero-length or overlaps one of them
+fn push_entry(entries: &mut Vec<PartitionEntry>, entry: PartitionEntry) {
+    if entry.sectors == 0 {
+        warn!("partition: entry {} has zero length", entry.index);
+        return;
+    }
+
+    let range = entry.range();
+    let overlap = entries.iter().find(|other| {
+        let other = other.range();
+        range.start < other.end && other.start < range.end
+    });
+
+    match overlap {
+        Some(other) => warn!(
+            "partition: entry {} overlaps entry {}",
+            entry.index, other.index
+        ),
+        None => entries.push(entry),
+    }
+}
+
+/// Reads `count` bytes starting at `sector`, rounded up to whole sectors
+fn read_bytes(disk: &dyn Disk, sector: usize, count: usize) -> Option<Vec<u8>> {
+    let block_size = disk.block_size();
+    let mut buffer = vec![0u8; count.div_ceil(block_size) * block_size];
+
+    disk.read(sector, &mut buffer)?;
+    Some(buffer)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_guid(data: &[u8], offset: usize) -> Guid {
+    Guid(data[offset..offset + 16].try_into().unwrap())
+}
+
+/// The fields of a GPT header needed to find and check the partition entries
+struct GptHeader {
+    first_usable: usize,
+    last_usable: usize,
+    entries_lba: usize,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    /// Reads the header at `lba`, checking its signature, CRC and self-reference
+    fn read(disk: &dyn Disk, lba: usize) -> Option<Self> {
+        let sector = read_bytes(disk, lba, disk.block_size())?;
+
+        if &sector[0..8] != GPT_SIGNATURE {
+            warn!("partition: no GPT signature at LBA {}", lba);
+            return None;
+        }
+
+        let size = read_u32(&sector, 12) as usize;
+
+        if !(GPT_HEADER_MIN_SIZE..=sector.len()).contains(&size) {
+            warn!("partition: GPT header at LBA {} has size {}", lba, size);
+            return None;
+        }
+
+        // The CRC is computed with its own field zeroed
+        let mut header = sector[..size].to_vec();
+        let expected = read_u32(&header, 16);
+        header[16..20].fill(0);
+
+        if crc32(&header) != expected {
+            warn!("partition: GPT header at LBA {} has a bad CRC", lba);
+            return None;
+        }
+
+        if read_u64(&header, 24) as usize != lba {
+            warn!("partition: GPT header at LBA {} is misplaced", lba);
+            return None;
+        }
+
+        let entry_size = read_u32(&header, 84) as usize;
+        let entry_count = read_u32(&header, 80) as usize;
+
+        if entry_size < GPT_ENTRY_MIN_SIZE
+            || entry_size % 8 != 0
+            || entry_size * entry_count > GPT_ENTRIES_MAX_SIZE
+        {
+            warn!(
+                "partition: GPT header at LBA {} has {} entries of {} bytes",
+                lba, entry_count, entry_size
+            );
+            return None;
+        }
+
+        Some(Self {
+            first_usable: read_u64(&header, 40) as usize,
+            last_usable: read_u64(&header, 48) as usize,
+            entries_lba: read_u64(&header, 72) as usize,
+            entry_count,
+            entry_size,
+            entries_crc: read_u32(&header, 88),
+        })
+    }
+
+    /// Reads the partition entry array, checking it against the CRC in the header
+    fn read_entries(&self, disk: &dyn Disk) -> Option<Vec<u8>> {
+        let size = self.entry_count * self.entry_size;
+        let entries = read_bytes(disk, self.entries_lba, size)?;
+
+        if crc32(&entries[..size]) != self.entries_crc {
+            warn!(
+                "partition: GPT entries at LBA {} have a bad CRC",
+                self.entries_lba
+            );
+            return None;
+        }
+
+        Some(entries)
+    }
+
+    /// Parses the used entries of the partition entry array `raw`
+    fn parse_entries(&self, raw: &[u8]) -> Vec<PartitionEntry> {
+        let mut entries = Vec::new();
+
+        for index in 0..self.entry_count {
+            let entry = &raw[index * self.entry_size..(index + 1) * self.entry_size];
+            let type_guid = read_guid(entry, 0);
+
+            if type_guid.is_zero() {
+                continue;
+            }
+
+            let first = read_u64(entry, 32) as usize;
+            let last = read_u64(entry, 40) as usize;
+
+            if first < self.first_usable || last > self.last_usable {
+                warn!("partition: GPT entry {} is outside the usable area", index);
+                continue;
+            }
+
+            let name = char::decode_utf16(
+                entry[56..128]
+                    .array_chunks::<2>()
+                    .map(|unit| u16::from_le_bytes(*unit))
+                    .take_while(|unit| *unit != 0),
+            )
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+            push_entry(
+                &mut entries,
+                PartitionEntry {
+                    index,
+                    start: first,
+                    // The last LBA is inclusive, so a reversed range ends up zero-length
+                    sectors: last.wrapping_add(1).saturating_sub(first),
+                    kind: PartitionKind::Gpt {
+                        type_guid,
+                        unique_guid: read_guid(entry, 16),
+                        name,
+                    },
+                },
+            );
+        }
+
+        entries
+    }
+}
+
+/// Parses the GPT of `disk`, falling back to the backup header at `last_lba` if the primary
+/// header or its entries are corrupt
+fn parse_gpt(disk: &dyn Disk, last_lba: Option<usize>) -> Option<Vec<PartitionEntry>> {
+    let primary =
+        GptHeader::read(disk, 1).and_then(|header| Some((header.read_entries(disk)?, header)));
+
+    let (raw, header) = match primary {
+        Some(primary) => primary,
+        None => {
+            let Some(last_lba) = last_lba else {
+                warn!("partition: primary GPT is corrupt and the disk size is unknown");
+                return None;
+            };
+
+            warn!("partition: primary GPT is corrupt, using the backup");
+
+            let header = GptHeader::read(disk, last_lba)?;
+            (header.read_entries(disk)?, header)
+        }
+    };
+
+    Some(header.parse_entries(&raw))
+}
+
+/// One of the four primary entries of an MBR
+struct MbrEntry {
+    kind: u8,
+    start: usize,
+    sectors: usize,
+}
+
+impl MbrEntry {
+    fn parse(raw: &[u8]) -> Self {
+        Self {
+            kind: raw[4],
+            start: read_u32(raw, 8) as usize,
+            sectors: read_u32(raw, 12) as usize,
+        }
+    }
+}
+
+/// Returns the primary entries of the MBR in `sector`, or `None` if it holds no MBR
+fn read_mbr(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector.get(510..512)? != MBR_SIGNATURE {
+        return None;
+    }
+
+    Some(core::array::from_fn(|index| {
+        let offset = MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE;
+        MbrEntry::parse(&sector[offset..offset + MBR_ENTRY_SIZE])
+    }))
+}
+
+/// Parses the primary partitions of an MBR, rejecting zero-length and overlapping entries
+fn parse_mbr(mbr: [MbrEntry; 4]) -> Vec<PartitionEntry> {
+    let mut entries = Vec::new();
+
+    for (index, entry) in mbr.into_iter().enumerate() {
+        if entry.kind == 0 {
+            continue;
+        }
+
+        if MBR_EXTENDED_TYPES.contains(&entry.kind) {
+            info!("partition: skipping extended partition {}", index);
+            continue;
+        }
+
+        push_entry(
+            &mut entries,
+            PartitionEntry {
+                index,
+                start: entry.start,
+                sectors: entry.sectors,
+                kind: PartitionKind::Mbr(entry.kind),
+            },
+        );
+    }
+
+    entries
+}
+
+/// Reads the partition table of the disk at `location` and registers each partition in
+/// [`ALL_DISKS`], replacing the ones found by a previous scan
+pub fn scan_disk(location: &DiskLocation, disk: &Arc<dyn Disk>) {
+    unregister_partitions(location);
+
+    let Some(sector) = read_bytes(&**disk, 0, disk.block_size()) else {
+        warn!("partition: failed to read LBA 0 of {:?}", location);
+        return;
+    };
+
+    let Some(mbr) = read_mbr(&sector) else {
+        return;
+    };
+
+    let protective = mbr.iter().find(|entry| entry.kind == MBR_GPT_PROTECTIVE);
+
+    let entries = match protective {
+        Some(protective) => {
+            // The protective entry covers the whole disk, unless it is too large for the MBR
+            let last_lba = disk
+                .sectors()
+                .and_then(|sectors| sectors.checked_sub(1))
+                .or(match protective.sectors {
+                    0xFFFF_FFFF => None,
+                    sectors => (protective.start + sectors).checked_sub(1),
+                });
+
+            match parse_gpt(&**disk, last_lba) {
+                Some(entries) => entries,
+                None => {
+                    warn!("partition: no valid GPT on {:?}", location);
+                    return;
+                }
+            }
+        }
+        None => parse_mbr(mbr),
+    };
+
+    for entry in entries {
+        info!(
+            "partition: {:?} #{}: {:x?}, {} sectors at {}",
+            location, entry.index, entry.kind, entry.sectors, entry.start
+        );
+
+        let partition = Partition {
+            parent: disk.clone(),
+            start: entry.start,
+            sectors: entry.sectors,
+            kind: entry.kind,
+        };
+
+        register_disk(
+            DiskLocation::Partition {
+                parent: location.clone().into(),
+                index: entry.index,
+            },
+            Arc::new(partition),
+        );
+    }
+}
+
+/// Scans every whole disk in [`ALL_DISKS`] for partitions
+pub fn scan_all() {
+    let disks = ALL_DISKS
+        .read()
+        .iter()
+        .filter(|entry| !matches!(entry.location, DiskLocation::Partition { .. }))
+        .map(|entry| (entry.location.clone(), entry.disk.clone()))
+        .collect::<Vec<_>>();
+
+    // The lock can't be held while scanning, since found partitions get registered
+    for (location, disk) in disks {
+        scan_disk(&location, &disk);
+    }
+}
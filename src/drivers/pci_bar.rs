@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Decoding of base address registers
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// A decoded base address register
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    /// Physical address, or port number for I/O BARs
+    pub address: u64,
+    /// Size of the region in bytes
+    pub size: u64,
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    /// Whether the kernel assigned the address because firmware left the BAR at 0
+    pub assigned: bool,
+}
+
+/// Decodes the first `count` BARs of a function. `read` returns the value of the BAR register
+/// at an index, `probe` writes all ones to it and returns what sticks, restoring the original
+/// value it's handed after. The upper half of a 64-bit BAR and unimplemented BARs are `None`.
+pub(crate) fn decode_bar_registers(
+    count: usize,
+    read: impl Fn(usize) -> u32,
+    probe: impl Fn(usize, u32) -> u32,
+) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let mut i = 0;
+
+    while i < count {
+        let original = read(i);
+
+        let bar = if original & 1 != 0 {
+            let mut mask = probe(i, original) & !0x3;
+
+            // Devices implementing only 16-bit I/O decoding hardwire the upper half to 0
+            if mask >> 16 == 0 {
+                mask |= 0xFFFF_0000;
+            }
+
+            Bar {
+                address: (original & !0x3) as u64,
+                size: (!mask).wrapping_add(1) as u64,
+                kind: BarKind::Io,
+                prefetchable: false,
+                assigned: false,
+            }
+        } else if (original >> 1) & 0b11 == 0b10 && i + 1 < count {
+            let original_high = read(i + 1);
+
+            let low = probe(i, original) & !0xF;
+            let high = probe(i + 1, original_high);
+            let mask = (high as u64) << 32 | low as u64;
+
+            Bar {
+                address: (original_high as u64) << 32 | (original & !0xF) as u64,
+                size: (!mask).wrapping_add(1),
+                kind: BarKind::Memory64,
+                prefetchable: original & (1 << 3) != 0,
+                assigned: false,
+            }
+        } else {
+            let mask = probe(i, original) & !0xF;
+
+            Bar {
+                address: (original & !0xF) as u64,
+                size: (!mask).wrapping_add(1) as u64,
+                kind: BarKind::Memory32,
+                prefetchable: original & (1 << 3) != 0,
+                assigned: false,
+            }
+        };
+
+        // Unimplemented BARs read back as 0, which wraps the size to 0
+        if bar.size != 0 && bar.size.is_power_of_two() {
+            bars[i] = Some(bar);
+        }
+
+        i += match bar.kind {
+            BarKind::Memory64 => 2,
+            _ => 1,
+        };
+    }
+
+    bars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A function's BAR registers, each with the value it holds and the bits that stick
+    /// when all ones are written to it
+    struct Registers([(u32, u32); 6]);
+
+    impl Registers {
+        fn decode(&self, count: usize) -> [Option<Bar>; 6] {
+            decode_bar_registers(
+                count,
+                |i| self.0[i].0,
+                |i, original| {
+                    assert_eq!(original, self.0[i].0, "BAR {} restored wrongly", i);
+                    self.0[i].1
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn a_32_bit_memory_bar_is_sized() {
+        // An AHCI ABAR taking 0x2000 bytes, enough for 32 ports
+        let mut registers = Registers([(0, 0); 6]);
+        registers.0[5] = (0xFEBF_0000, 0xFFFF_E000);
+
+        let bars = registers.decode(6);
+        let abar = bars[5].unwrap();
+
+        assert_eq!(abar.address, 0xFEBF_0000);
+        assert_eq!(abar.size, 0x2000);
+        assert_eq!(abar.kind, BarKind::Memory32);
+        assert!(!abar.prefetchable);
+        assert!(bars[..5].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn a_64_bit_memory_bar_takes_two_registers() {
+        let mut registers = Registers([(0, 0); 6]);
+        registers.0[0] = (0xC000_000C, 0xF000_000C);
+        registers.0[1] = (0x0000_0008, 0xFFFF_FFFF);
+        registers.0[2] = (0xE000_0000, 0xFFFF_F000);
+
+        let bars = registers.decode(6);
+        let bar = bars[0].unwrap();
+
+        assert_eq!(bar.address, 0x8_C000_0000);
+        assert_eq!(bar.size, 0x1000_0000);
+        assert_eq!(bar.kind, BarKind::Memory64);
+        assert!(bar.prefetchable);
+
+        assert!(bars[1].is_none());
+        assert_eq!(bars[2].unwrap().size, 0x1000);
+    }
+
+    #[test]
+    fn io_bars_are_sized_within_16_bits() {
+        let mut registers = Registers([(0, 0); 6]);
+        registers.0[0] = (0xC041, 0xFFE1);
+        registers.0[1] = (0xC081, 0xFFFF_FFE1);
+
+        let bars = registers.decode(6);
+
+        for bar in &bars[..2] {
+            let bar = bar.unwrap();
+
+            assert_eq!(bar.kind, BarKind::Io);
+            assert_eq!(bar.size, 0x20);
+        }
+
+        assert_eq!(bars[0].unwrap().address, 0xC040);
+    }
+
+    #[test]
+    fn only_count_registers_are_read() {
+        // Bridges have two BARs, the registers after them mean something else
+        let registers = Registers([(0xFEB0_0000, 0xFFF0_0000); 6]);
+
+        let bars = registers.decode(2);
+
+        assert!(bars[0].is_some() && bars[1].is_some());
+        assert!(bars[2..].iter().all(Option::is_none));
+
+        // A 64-bit BAR in the last register has no upper half to go with it
+        let mut registers = Registers([(0, 0); 6]);
+        registers.0[1] = (0xFEB0_0004, 0xFFF0_0004);
+
+        assert_eq!(registers.decode(2)[1].unwrap().kind, BarKind::Memory32);
+    }
+}
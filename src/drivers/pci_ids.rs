@@ -0,0 +1,435 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! PCI class codes and the rules drivers pick functions with
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceKind {
+    Unknown,
+
+    /*
+     * Base Class 0x00 - Devices that predate Class Codes
+     */
+    LegacyVgaCompatible,
+    LegacyNotVgaCompatible,
+
+    /*
+     * Base Class 0x01 - Mass Storage Controllers
+     */
+    ScsiBusController,
+    IdeController,
+    FloppyController,
+    IpiBusController,
+    RaidController,
+    AtaController,
+    SataController,
+    SasController,
+    NvmeController,
+    OtherMassStorageController,
+
+    /*
+     * Base Class 0x02 - Network Controllers
+     */
+    EthernetController,
+    TokenRingController,
+    FddiController,
+    AtmController,
+    IsdnController,
+    PicmgController,
+    OtherNetworkController,
+
+    /*
+     * Base Class 0x03 - Display Controllers
+     */
+    VgaCompatibleController,
+    XgaController,
+    ThreeDController,
+    OtherDisplayController,
+
+    /*
+     * Base Class 0x04 - Multimedia Devices
+     */
+    VideoDevice,
+    AudioDevice,
+    TelephonyDevice,
+    OtherMultimediaDevice,
+
+    /*
+     * Base Class 0x05 - Memory Controllers
+     */
+    RamController,
+    FlashController,
+    OtherMemoryController,
+
+    /*
+     * Base Class 0x06 - Bridge Devices
+     */
+    HostBridge,
+    IsaBridge,
+    EisaBridge,
+    McaBridge,
+    PciPciBridge,
+    PcmciaBridge,
+    NuBusBridge,
+    CardBusBridge,
+    RacewayBridge,
+    SemiTransparentPciPciBridge,
+    InfinibandPciHostBridge,
+    OtherBridgeDevice,
+
+    /*
+     * Base Class 0x07 - Simple Communications Controllers
+     */
+    SerialController,
+    ParallelPort,
+    MultiportSerialController,
+    Modem,
+    GpibController,
+    SmartCard,
+    OtherCommunicationsDevice,
+
+    /*
+     * Base Class 0x08 - Generic System Peripherals
+     */
+    InterruptController,
+    DmaController,
+    SystemTimer,
+    RtcController,
+    GenericPciHotPlugController,
+    SdHostController,
+    OtherSystemPeripheral,
+
+    /*
+     * Base Class 0x09 - Input Devices
+     */
+    KeyboardController,
+    Digitizer,
+    MouseController,
+    ScannerController,
+    GameportController,
+    OtherInputController,
+
+    /*
+     * Base Class 0x0a - Docking Stations
+     */
+    GenericDockingStation,
+    OtherDockingStation,
+
+    /*
+     * Base Class 0x0b - Processors
+     */
+    Processor386,
+    Processor486,
+    ProcessorPentium,
+    ProcessorAlpha,
+    ProcessorPowerPc,
+    ProcessorMips,
+    CoProcessor,
+
+    /*
+     * Base Class 0x0c - Serial Bus Controllers
+     */
+    FirewireController,
+    AccessBusController,
+    SsaBusController,
+    UsbController,
+    FibreChannelController,
+    SmBusController,
+    InfiniBandController,
+    IpmiController,
+    SercosController,
+    CanBusController,
+
+    /*
+     * Base Class 0x0d - Wireless Controllers
+     */
+    IrdaController,
+    ConsumerIrController,
+    RfController,
+    BluetoothController,
+    BroadbandController,
+    Ethernet5GHzController,
+    Ethernet24GHzController,
+    OtherWirelessController,
+
+    /*
+     * Base Class 0x0e - Intelligent IO Controllers
+     */
+    IntelligentIoController,
+
+    /*
+     * Base Class 0x0f - Satellite Communications Controllers
+     */
+    TvSatelliteCommunicationsController,
+    AudioSatelliteCommunicationsController,
+    VoiceSatelliteCommunicationsController,
+    DataSatelliteCommunicationsController,
+
+    /*
+     * Base Class 0x10 - Encryption and Decryption Controllers
+     */
+    NetworkCryptionController,
+    EntertainmentCryptionController,
+    OtherCryptionController,
+
+    /*
+     * Base Class 0x11 - Data Acquisition and Signal Processing Controllers
+     */
+    DpioModule,
+    PerformanceCounter,
+    CommunicationsSynchronizationController,
+    ManagementCard,
+    OtherSignalProcessingController,
+}
+
+impl DeviceKind {
+    pub fn new(base_class: u32, sub_class: u32) -> Self {
+        match (base_class, sub_class) {
+            (0x00, 0x00) => DeviceKind::LegacyNotVgaCompatible,
+            (0x00, 0x01) => DeviceKind::LegacyVgaCompatible,
+
+            (0x01, 0x00) => DeviceKind::ScsiBusController,
+            (0x01, 0x01) => DeviceKind::IdeController,
+            (0x01, 0x02) => DeviceKind::FloppyController,
+            (0x01, 0x03) => DeviceKind::IpiBusController,
+            (0x01, 0x04) => DeviceKind::RaidController,
+            (0x01, 0x05) => DeviceKind::AtaController,
+            (0x01, 0x06) => DeviceKind::SataController,
+            (0x01, 0x07) => DeviceKind::SasController,
+            (0x01, 0x08) => DeviceKind::NvmeController,
+            (0x01, 0x80) => DeviceKind::OtherMassStorageController,
+
+            (0x02, 0x00) => DeviceKind::EthernetController,
+            (0x02, 0x01) => DeviceKind::TokenRingController,
+            (0x02, 0x02) => DeviceKind::FddiController,
+            (0x02, 0x03) => DeviceKind::AtmController,
+            (0x02, 0x04) => DeviceKind::IsdnController,
+            (0x02, 0x06) => DeviceKind::PicmgController,
+            (0x02, 0x80) => DeviceKind::OtherNetworkController,
+
+            (0x03, 0x00) => DeviceKind::VgaCompatibleController,
+            (0x03, 0x01) => DeviceKind::XgaController,
+            (0x03, 0x02) => DeviceKind::ThreeDController,
+            (0x03, 0x80) => DeviceKind::OtherDisplayController,
+
+            (0x04, 0x00) => DeviceKind::VideoDevice,
+            (0x04, 0x01) => DeviceKind::AudioDevice,
+            (0x04, 0x02) => DeviceKind::TelephonyDevice,
+            (0x04, 0x03) => DeviceKind::OtherMultimediaDevice,
+
+            (0x05, 0x00) => DeviceKind::RamController,
+            (0x05, 0x01) => DeviceKind::FlashController,
+            (0x05, 0x02) => DeviceKind::OtherMemoryController,
+
+            (0x06, 0x00) => DeviceKind::HostBridge,
+            (0x06, 0x01) => DeviceKind::IsaBridge,
+            (0x06, 0x02) => DeviceKind::EisaBridge,
+            (0x06, 0x03) => DeviceKind::McaBridge,
+            (0x06, 0x04) => DeviceKind::PciPciBridge,
+            (0x06, 0x05) => DeviceKind::PcmciaBridge,
+            (0x06, 0x06) => DeviceKind::NuBusBridge,
+            (0x06, 0x07) => DeviceKind::CardBusBridge,
+            (0x06, 0x08) => DeviceKind::RacewayBridge,
+            (0x06, 0x09) => DeviceKind::SemiTransparentPciPciBridge,
+            (0x06, 0x0a) => DeviceKind::InfinibandPciHostBridge,
+            (0x06, 0x80) => DeviceKind::OtherBridgeDevice,
+
+            (0x07, 0x00) => DeviceKind::SerialController,
+            (0x07, 0x01) => DeviceKind::ParallelPort,
+            (0x07, 0x02) => DeviceKind::MultiportSerialController,
+            (0x07, 0x03) => DeviceKind::Modem,
+            (0x07, 0x04) => DeviceKind::GpibController,
+            (0x07, 0x05) => DeviceKind::SmartCard,
+            (0x07, 0x80) => DeviceKind::OtherCommunicationsDevice,
+
+            (0x08, 0x00) => DeviceKind::InterruptController,
+            (0x08, 0x01) => DeviceKind::DmaController,
+            (0x08, 0x02) => DeviceKind::SystemTimer,
+            (0x08, 0x03) => DeviceKind::RtcController,
+            (0x08, 0x04) => DeviceKind::GenericPciHotPlugController,
+            (0x08, 0x05) => DeviceKind::SdHostController,
+            (0x08, 0x80) => DeviceKind::OtherSystemPeripheral,
+
+            (0x09, 0x00) => DeviceKind::KeyboardController,
+            (0x09, 0x01) => DeviceKind::Digitizer,
+            (0x09, 0x02) => DeviceKind::MouseController,
+            (0x09, 0x03) => DeviceKind::ScannerController,
+            (0x09, 0x04) => DeviceKind::GameportController,
+            (0x09, 0x80) => DeviceKind::OtherInputController,
+
+            (0x0a, 0x00) => DeviceKind::GenericDockingStation,
+            (0x0a, 0x80) => DeviceKind::OtherDockingStation,
+
+            (0x0b, 0x00) => DeviceKind::Processor386,
+            (0x0b, 0x01) => DeviceKind::Processor486,
+            (0x0b, 0x02) => DeviceKind::ProcessorPentium,
+            (0x0b, 0x10) => DeviceKind::ProcessorAlpha,
+            (0x0b, 0x20) => DeviceKind::ProcessorPowerPc,
+            (0x0b, 0x30) => DeviceKind::ProcessorMips,
+            (0x0b, 0x40) => DeviceKind::CoProcessor,
+
+            (0x0c, 0x00) => DeviceKind::FirewireController,
+            (0x0c, 0x01) => DeviceKind::AccessBusController,
+            (0x0c, 0x02) => DeviceKind::SsaBusController,
+            (0x0c, 0x03) => DeviceKind::UsbController,
+            (0x0c, 0x04) => DeviceKind::FibreChannelController,
+            (0x0c, 0x05) => DeviceKind::SmBusController,
+            (0x0c, 0x06) => DeviceKind::InfiniBandController,
+            (0x0c, 0x07) => DeviceKind::IpmiController,
+            (0x0c, 0x08) => DeviceKind::SercosController,
+            (0x0c, 0x09) => DeviceKind::CanBusController,
+
+            (0x0d, 0x00) => DeviceKind::IrdaController,
+            (0x0d, 0x01) => DeviceKind::ConsumerIrController,
+            (0x0d, 0x10) => DeviceKind::RfController,
+            (0x0d, 0x11) => DeviceKind::BluetoothController,
+            (0x0d, 0x12) => DeviceKind::BroadbandController,
+            (0x0d, 0x20) => DeviceKind::Ethernet5GHzController,
+            (0x0d, 0x21) => DeviceKind::Ethernet24GHzController,
+            (0x0d, 0x80) => DeviceKind::OtherWirelessController,
+
+            (0x0e, 0x00) => DeviceKind::IntelligentIoController,
+
+            (0x0f, 0x00) => DeviceKind::TvSatelliteCommunicationsController,
+            (0x0f, 0x01) => DeviceKind::AudioSatelliteCommunicationsController,
+            (0x0f, 0x02) => DeviceKind::VoiceSatelliteCommunicationsController,
+            (0x0f, 0x03) => DeviceKind::DataSatelliteCommunicationsController,
+
+            (0x10, 0x00) => DeviceKind::NetworkCryptionController,
+            (0x10, 0x10) => DeviceKind::EntertainmentCryptionController,
+            (0x10, 0x80) => DeviceKind::OtherCryptionController,
+
+            (0x11, 0x00) => DeviceKind::DpioModule,
+            (0x11, 0x01) => DeviceKind::PerformanceCounter,
+            (0x11, 0x10) => DeviceKind::CommunicationsSynchronizationController,
+            (0x11, 0x20) => DeviceKind::ManagementCard,
+            (0x11, 0x80) => DeviceKind::OtherSignalProcessingController,
+
+            _ => DeviceKind::Unknown,
+        }
+    }
+}
+
+/// Which functions a driver binds to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceMatch {
+    /// Every function of a class, for drivers of standardized interfaces like AHCI
+    ByClass(DeviceKind),
+    /// A specific device, by raw vendor and device ID
+    ById(u16, u16),
+    Any,
+}
+
+impl DeviceMatch {
+    /// Whether the rule covers a function of class `kind` with the given IDs
+    pub fn matches(&self, kind: DeviceKind, vendor_id: u16, device_id: u16) -> bool {
+        match *self {
+            Self::ByClass(class) => kind == class,
+            Self::ById(vendor, device) => vendor_id == vendor && device_id == device,
+            Self::Any => true,
+        }
+    }
+}
+
+/// Whether any of a driver's `rules` covers a function of class `kind` with the given IDs
+pub fn any_matches(
+    rules: &[DeviceMatch],
+    kind: DeviceKind,
+    vendor_id: u16,
+    device_id: u16,
+) -> bool {
+    rules
+        .iter()
+        .any(|rule| rule.matches(kind, vendor_id, device_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A driver for one specific NIC, the Intel 82540EM that QEMU emulates as e1000
+    const FAKE_E1000: &[DeviceMatch] = &[DeviceMatch::ById(0x8086, 0x100E)];
+
+    #[test]
+    fn by_id_binds_only_the_matching_device() {
+        let kind = DeviceKind::EthernetController;
+
+        assert!(any_matches(FAKE_E1000, kind, 0x8086, 0x100E));
+
+        // Realtek RTL8139, a NIC of the same class
+        assert!(!any_matches(FAKE_E1000, kind, 0x10EC, 0x8139));
+        // Same vendor, other device
+        assert!(!any_matches(FAKE_E1000, kind, 0x8086, 0x10D3));
+        // Same device ID, other vendor
+        assert!(!any_matches(FAKE_E1000, kind, 0x10EC, 0x100E));
+    }
+
+    #[test]
+    fn by_id_ignores_the_class() {
+        assert!(any_matches(FAKE_E1000, DeviceKind::Unknown, 0x8086, 0x100E));
+    }
+
+    #[test]
+    fn by_class_ignores_the_ids() {
+        let rules = [DeviceMatch::ByClass(DeviceKind::SataController)];
+
+        assert!(any_matches(
+            &rules,
+            DeviceKind::SataController,
+            0x8086,
+            0x2922
+        ));
+        assert!(any_matches(
+            &rules,
+            DeviceKind::SataController,
+            0x1B4B,
+            0x9230
+        ));
+        assert!(!any_matches(
+            &rules,
+            DeviceKind::RaidController,
+            0x8086,
+            0x2922
+        ));
+    }
+
+    #[test]
+    fn any_of_several_rules_binds() {
+        let rules = [
+            DeviceMatch::ByClass(DeviceKind::SataController),
+            DeviceMatch::ById(0x8086, 0x2822),
+        ];
+
+        assert!(any_matches(
+            &rules,
+            DeviceKind::RaidController,
+            0x8086,
+            0x2822
+        ));
+        assert!(!any_matches(
+            &rules,
+            DeviceKind::RaidController,
+            0x8086,
+            0x2826
+        ));
+        assert!(!any_matches(
+            &[],
+            DeviceKind::SataController,
+            0x8086,
+            0x2922
+        ));
+        assert!(any_matches(
+            &[DeviceMatch::Any],
+            DeviceKind::Unknown,
+            0xFFFF,
+            0xFFFF
+        ));
+    }
+
+    #[test]
+    fn class_codes_map_to_kinds() {
+        assert_eq!(DeviceKind::new(0x01, 0x06), DeviceKind::SataController);
+        assert_eq!(DeviceKind::new(0x01, 0x04), DeviceKind::RaidController);
+        assert_eq!(DeviceKind::new(0x0C, 0x03), DeviceKind::UsbController);
+        assert_eq!(DeviceKind::new(0xFF, 0x00), DeviceKind::Unknown);
+    }
+}
@@ -1,269 +1,615 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Partial port of https://github.com/Andy-Python-Programmer/aero/raw/master/src/aero_kernel/src/drivers/pci.rs
 
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 use x2apic::{ioapic::IrqMode, lapic::xapic_base};
 
-use core::{ops::Range, sync::atomic::AtomicUsize};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use acpi::AcpiTables;
+use acpi::{
+    platform::interrupt::{Polarity, TriggerMode},
+    AcpiTables,
+};
+use aml::{
+    pci_routing::Pin,
+    resource::{InterruptPolarity, InterruptTrigger},
+};
 use pcics::{
     capabilities::{msi_x::Bir, CapabilityKind},
     header::HeaderType,
     Capabilities, Header, DDR_OFFSET, ECS_OFFSET,
 };
 use x86_64::{
-    structures::{
-        idt::InterruptStackFrame,
-        paging::{Page, Size4KiB},
-    },
+    align_up,
+    structures::paging::{Page, Size4KiB},
     VirtAddr,
 };
 
 use crate::{
-    acpi_impl::{aml_init, aml_route, KernelAcpi},
-    ahci::ahci_init,
-    apic_impl::get_active_lapic,
-    get_mcfg, get_phys_offset,
-    interrupts::{irqalloc, register_handler},
+    acpi_impl::{aml_init, aml_route, osc_granted, KernelAcpi, OscControl},
+    ahci::{ahci_init, is_ahci},
+    apic_impl::{init_all_available_apics, local_apic_id, route_gsi, APIC_IS_INITIALIZED},
+    get_boot_info, get_mcfg, get_phys_offset,
+    interrupts::{ahci, irqalloc, irqalloc_contiguous, irqfree, register_irq, IrqHandler},
+    virtio,
     xhci::xhci_init,
 };
 
 use {
-    crate::{ahci::util::VolatileCell, map_page},
-    alloc::{alloc::Global, sync::Arc, vec::Vec},
+    crate::{
+        ahci::util::{Deadline, Stopwatch, VolatileCell},
+        map_page,
+    },
+    alloc::{collections::BTreeMap, sync::Arc, vec::Vec},
     bit_field::BitField,
     bitflags::bitflags,
-    core::{alloc::Allocator, arch::asm},
-    x86_64::structures::paging::PageTableFlags,
+    core::{arch::asm, ops::Range},
+    x86_64::{instructions::interrupts::without_interrupts, structures::paging::PageTableFlags},
 };
 
 use log::*;
 
-pub const BLOCK_BITS: usize = core::mem::size_of::<usize>() * 8;
+pub use super::pci_bar::{Bar, BarKind};
+pub use super::pci_ids::{any_matches, DeviceKind, DeviceMatch};
+pub use super::pci_scan::Bdf;
+
+use super::pci_bar::decode_bar_registers;
+use super::pci_scan::{BusScan, ScannedFunction};
 
 pub static PCI_TABLE: RwLock<PciTable> = RwLock::new(PciTable::new());
 pub static PCI_DRIVER_COUNT: AtomicUsize = AtomicUsize::new(0);
-
-fn mcfg_brute_force_inner(r: Range<u32>) -> impl Iterator<Item = Option<u64>> {
-    r.map(|i: u32| match get_mcfg() {
-        Some(mcfg) => mcfg.physical_address(
-            i.to_be_bytes()[0] as u16,
-            i.to_be_bytes()[1],
-            i.to_be_bytes()[2],
-            i.to_be_bytes()[3],
-        ),
-        None => None,
-    })
+/// Set once the boot-time walk of the bus is done
+static PCI_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Offset of the command register
+const COMMAND_OFFSET: usize = 0x04;
+/// Offset of the status register
+const STATUS_OFFSET: usize = 0x06;
+/// Status register: the function asserts its INTx pin
+const STATUS_INTERRUPT: usize = 3;
+/// Offset of the header type register, whose bit 7 marks multi-function devices
+const HEADER_TYPE_OFFSET: usize = 0x0E;
+/// Offset of the first base address register
+const BAR_OFFSET: usize = 0x10;
+/// Offset of the pointer to the first entry in the capability list
+const CAPABILITIES_POINTER_OFFSET: usize = 0x34;
+/// Capability ID of MSI
+const MSI_CAPABILITY_ID: u8 = 0x05;
+/// Capability ID of power management
+const PM_CAPABILITY_ID: u8 = 0x01;
+/// Capability ID of MSI-X
+const MSIX_CAPABILITY_ID: u8 = 0x11;
+/// Extended capability ID of Advanced Error Reporting
+const AER_CAPABILITY_ID: u16 = 0x0001;
+/// Offset of the interrupt pin register, 0 if the function doesn't use INTx
+const INTERRUPT_PIN_OFFSET: usize = 0x3D;
+/// Offset of a PCI-to-PCI bridge's secondary bus number register
+const SECONDARY_BUS_OFFSET: usize = 0x19;
+/// Offset of a PCI-to-PCI bridge's subordinate bus number register, the highest bus behind it
+const SUBORDINATE_BUS_OFFSET: usize = 0x1A;
+/// Base class code of bridges, host bridges included
+const BRIDGE_CLASS: u8 = 0x06;
+/// End of the window for unprogrammed BARs, where the I/O APIC and local APIC registers start
+const BAR_WINDOW_END: u64 = 0xFEC0_0000;
+/// Size of the configuration space of a function behind ECAM
+const CONFIG_SPACE_SIZE: usize = 0x1000;
+
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+
+/// Window of physical addresses for BARs firmware left unprogrammed, see
+/// [`reserve_bar_window`]
+static BAR_WINDOW: Mutex<Option<Range<u64>>> = Mutex::new(None);
+
+/// ECAM pages mapped so far, physical address to virtual address
+static ECAM_PAGES: RwLock<BTreeMap<u64, u64>> = RwLock::new(BTreeMap::new());
+
+/// Serializes the address/data port pair of the legacy configuration mechanism
+static PORT_IO_LOCK: Mutex<()> = Mutex::new(());
+/// Bridge leading to each secondary bus, keyed by `(segment, bus)`
+static BRIDGES: RwLock<BTreeMap<(u16, u8), Bdf>> = RwLock::new(BTreeMap::new());
+
+/// How configuration space is reached: through the memory-mapped ECAM regions listed in the
+/// MCFG table, or through the legacy 0xCF8/0xCFC port pair, which only reaches segment 0 and
+/// the first 256 bytes of each function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigAccess {
+    Ecam,
+    PortIo,
 }
 
-/// Iterates over all possible `Option<u64>` in the address space, then maps and unwraps them
-pub fn mcfg_brute_force() -> impl Iterator<Item = u64> {
-    let mut deduped_scan = Vec::new();
-    let mut deduped_kinds = Vec::new();
+impl ConfigAccess {
+    /// Returns the mechanism to use on this machine, preferring ECAM if there's an MCFG table
+    pub fn current() -> Self {
+        if get_mcfg().is_some() {
+            Self::Ecam
+        } else {
+            Self::PortIo
+        }
+    }
 
-    let pci_addr_iter = if cfg!(opt_level = "0") {
-        mcfg_brute_force_inner(0x0..0x1000)
-    } else {
-        mcfg_brute_force_inner(0x0..0xffff)
-    }
-    .flatten();
-
-    // Will figure out later how not to hardcode this
-    for addr in pci_addr_iter {
-        let test_page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
-        let virt = test_page.start_address().as_u64() + get_phys_offset();
-
-        map_page!(
-            addr,
-            virt,
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+    /// Returns the mechanism that reaches `bdf`: ECAM if the MCFG covers its bus, the legacy
+    /// ports otherwise, since AML may touch buses the MCFG leaves out.
+    pub fn for_bdf(bdf: Bdf) -> Self {
+        if ecam_address(bdf).is_some() {
+            Self::Ecam
+        } else {
+            Self::PortIo
+        }
+    }
 
-        let raw_header = unsafe { *(virt as *const [u8; ECS_OFFSET]) };
-        let header = Header::try_from(raw_header.as_slice()).unwrap();
+    /// Returns whether `segment` can hold any devices
+    fn has_segment(&self, segment: u16) -> bool {
+        match self {
+            Self::Ecam => ecam_address(Bdf::new(segment, 0, 0, 0)).is_some(),
+            Self::PortIo => segment == 0,
+        }
+    }
 
-        // don't push unknown devices
-        if let DeviceKind::Unknown =
-            DeviceKind::new(header.class_code.base as u32, header.class_code.sub as u32)
-        {
-            continue;
+    /// Maps the ECAM page of `bdf` and returns the virtual address of register `offset`
+    fn ecam_register(bdf: Bdf, offset: usize) -> Option<u64> {
+        let addr = ecam_address(bdf)?;
+        Some(map_config_page(addr) + offset as u64)
+    }
+
+    /// Selects register `offset` of `bdf` through the address port, returning the data port
+    /// to access it through, or `None` if the legacy mechanism can't reach it
+    ///
+    /// ### Safety
+    /// [`PORT_IO_LOCK`] has to be held until the access through the data port is done
+    unsafe fn select_port(bdf: Bdf, offset: usize) -> Option<u16> {
+        if bdf.segment != 0 || offset >= 0x100 {
+            return None;
         }
-        // don't push duplicates
-        else if deduped_kinds.contains(&DeviceKind::new(
-            header.class_code.base as u32,
-            header.class_code.sub as u32,
-        )) {
-            continue;
-        } else {
-            deduped_kinds.push(DeviceKind::new(
-                header.class_code.base as u32,
-                header.class_code.sub as u32,
-            ));
-            deduped_scan.push(addr);
+
+        let mut address = 0u32;
+        address.set_bit(31, true);
+        address.set_bits(16..24, bdf.bus as u32);
+        address.set_bits(11..16, bdf.device as u32);
+        address.set_bits(8..11, bdf.function as u32);
+        address.set_bits(2..8, offset as u32 >> 2);
+
+        outl(PCI_CONFIG_ADDRESS_PORT, address);
+
+        Some(PCI_CONFIG_DATA_PORT + (offset % 4) as u16)
+    }
+
+    /// Reads the byte at `offset` of the configuration space of `bdf`. Registers that can't be
+    /// reached read as all ones, like those of missing functions.
+    pub fn read8(&self, bdf: Bdf, offset: usize) -> u8 {
+        match self {
+            Self::Ecam => Self::ecam_register(bdf, offset)
+                .map(|reg| unsafe { core::ptr::read_volatile(reg as *const u8) }),
+            Self::PortIo => unsafe {
+                let _lock = PORT_IO_LOCK.lock();
+                Self::select_port(bdf, offset).map(|port| inb(port))
+            },
         }
+        .unwrap_or(u8::MAX)
     }
 
-    deduped_scan.into_iter()
-}
+    /// Reads the 16-bit register at `offset`, which has to be 2-byte aligned
+    pub fn read16(&self, bdf: Bdf, offset: usize) -> u16 {
+        match self {
+            Self::Ecam => Self::ecam_register(bdf, offset)
+                .map(|reg| unsafe { core::ptr::read_volatile(reg as *const u16) }),
+            Self::PortIo => unsafe {
+                let _lock = PORT_IO_LOCK.lock();
+                Self::select_port(bdf, offset).map(|port| inw(port))
+            },
+        }
+        .unwrap_or(u16::MAX)
+    }
 
-const fn calculate_blocks(bits: usize) -> usize {
-    if bits % BLOCK_BITS == 0 {
-        bits / BLOCK_BITS
-    } else {
-        bits / BLOCK_BITS + 1
+    /// Reads the 32-bit register at `offset`, which has to be 4-byte aligned
+    pub fn read32(&self, bdf: Bdf, offset: usize) -> u32 {
+        match self {
+            Self::Ecam => Self::ecam_register(bdf, offset)
+                .map(|reg| unsafe { core::ptr::read_volatile(reg as *const u32) }),
+            Self::PortIo => unsafe {
+                let _lock = PORT_IO_LOCK.lock();
+                Self::select_port(bdf, offset).map(|port| inl(port))
+            },
+        }
+        .unwrap_or(u32::MAX)
+    }
+
+    /// Writes the byte at `offset`. Writes to registers that can't be reached are dropped.
+    pub fn write8(&self, bdf: Bdf, offset: usize, value: u8) {
+        match self {
+            Self::Ecam => {
+                if let Some(reg) = Self::ecam_register(bdf, offset) {
+                    unsafe { core::ptr::write_volatile(reg as *mut u8, value) }
+                }
+            }
+            Self::PortIo => unsafe {
+                let _lock = PORT_IO_LOCK.lock();
+
+                if let Some(port) = Self::select_port(bdf, offset) {
+                    outb(port, value);
+                }
+            },
+        }
+    }
+
+    /// Writes the 16-bit register at `offset`, which has to be 2-byte aligned
+    pub fn write16(&self, bdf: Bdf, offset: usize, value: u16) {
+        match self {
+            Self::Ecam => {
+                if let Some(reg) = Self::ecam_register(bdf, offset) {
+                    unsafe { core::ptr::write_volatile(reg as *mut u16, value) }
+                }
+            }
+            Self::PortIo => unsafe {
+                let _lock = PORT_IO_LOCK.lock();
+
+                if let Some(port) = Self::select_port(bdf, offset) {
+                    outw(port, value);
+                }
+            },
+        }
+    }
+
+    /// Writes the 32-bit register at `offset`, which has to be 4-byte aligned
+    pub fn write32(&self, bdf: Bdf, offset: usize, value: u32) {
+        match self {
+            Self::Ecam => {
+                if let Some(reg) = Self::ecam_register(bdf, offset) {
+                    unsafe { core::ptr::write_volatile(reg as *mut u32, value) }
+                }
+            }
+            Self::PortIo => unsafe {
+                let _lock = PORT_IO_LOCK.lock();
+
+                if let Some(port) = Self::select_port(bdf, offset) {
+                    outl(port, value);
+                }
+            },
+        }
+    }
+
+    /// Reads the first [`ECS_OFFSET`] bytes of the configuration space of `bdf`
+    pub fn read_header(&self, bdf: Bdf) -> [u8; ECS_OFFSET] {
+        let mut raw = [0u8; ECS_OFFSET];
+
+        for (i, dword) in raw.chunks_exact_mut(4).enumerate() {
+            dword.copy_from_slice(&self.read32(bdf, i * 4).to_le_bytes());
+        }
+
+        raw
     }
 }
 
-// Needed for compatibility
-#[derive(Debug)]
-pub struct Bitmap<A: Allocator> {
-    bitmap: Vec<usize, A>,
+/// Widths a configuration space register can be accessed with
+pub trait ConfigWidth: Copy {
+    fn read(access: ConfigAccess, bdf: Bdf, offset: usize) -> Self;
+    fn write(self, access: ConfigAccess, bdf: Bdf, offset: usize);
 }
 
-impl Bitmap<Global> {
-    /// Shorthand for `Bitmap::new_in(Global, size)`
-    pub fn new(size: usize) -> Self {
-        Self::new_in(Global, size)
+macro_rules! impl_config_width {
+    ($ty:ty, $read:ident, $write:ident) => {
+        impl ConfigWidth for $ty {
+            fn read(access: ConfigAccess, bdf: Bdf, offset: usize) -> Self {
+                access.$read(bdf, offset)
+            }
+
+            fn write(self, access: ConfigAccess, bdf: Bdf, offset: usize) {
+                access.$write(bdf, offset, self)
+            }
+        }
+    };
+}
+
+impl_config_width!(u8, read8, write8);
+impl_config_width!(u16, read16, write16);
+impl_config_width!(u32, read32, write32);
+
+/// Reads the register at `offset` of the configuration space of `bdf`, which has to be
+/// aligned to its size
+pub fn read_config<T: ConfigWidth>(bdf: Bdf, offset: usize) -> T {
+    T::read(ConfigAccess::current(), bdf, offset)
+}
+
+/// Writes the register at `offset` of the configuration space of `bdf`, which has to be
+/// aligned to its size
+pub fn write_config<T: ConfigWidth>(bdf: Bdf, offset: usize, value: T) {
+    value.write(ConfigAccess::current(), bdf, offset)
+}
+
+bitflags! {
+    /// Command register of a function's configuration space
+    pub struct PciCommand: u16 {
+        const IO_SPACE                = 1 << 0;
+        const MEMORY_SPACE            = 1 << 1;
+        const BUS_MASTER              = 1 << 2;
+        const SPECIAL_CYCLES          = 1 << 3;
+        const MEMORY_WRITE_INVALIDATE = 1 << 4;
+        const VGA_PALETTE_SNOOP       = 1 << 5;
+        const PARITY_ERROR_RESPONSE   = 1 << 6;
+        const SERR_ENABLE             = 1 << 8;
+        const FAST_BACK_TO_BACK       = 1 << 9;
+        const INTERRUPT_DISABLE       = 1 << 10;
     }
 }
 
-impl<A: Allocator> Bitmap<A> {
-    /// Constructs a new bitmap with `size` bits and uses `alloc`
-    /// as the alloctor.
-    ///
-    /// ## Example
-    /// ```rust
-    /// use alloc::alloc::Global;
-    ///
-    /// let mut bitmap = Bitmap::new_in(Global, 4096);
-    /// ```
-    pub fn new_in(alloc: A, size: usize) -> Self {
-        let bitmap_blocks = calculate_blocks(size);
-        let mut bitmap = Vec::new_in(alloc);
+pub fn read_command(bdf: Bdf) -> PciCommand {
+    PciCommand::from_bits_truncate(read_config(bdf, COMMAND_OFFSET))
+}
+
+/// Writes the command register of `bdf`. It's written on its own, so that the
+/// write-one-to-clear bits of the status register next to it are left alone.
+pub fn write_command(bdf: Bdf, command: PciCommand) {
+    write_config(bdf, COMMAND_OFFSET, command.bits())
+}
 
-        bitmap.resize(bitmap_blocks, 0);
-        Self { bitmap }
+/// Maps the configuration space page at `phys` once and returns its virtual address. Later
+/// calls only look it up in [`ECAM_PAGES`], so they don't contend on the mapper.
+fn map_config_page(phys: u64) -> u64 {
+    if let Some(virt) = ECAM_PAGES.read().get(&phys) {
+        return *virt;
     }
 
-    /// Constructs a new, empty bitmap. This function does *not* perform
-    /// any allocations.
-    ///
-    /// ## Example
-    /// ```rust
-    /// let bitmap = Bitmap::new();
-    /// ```
-    pub fn empty_in(alloc: A) -> Self {
-        Self {
-            bitmap: Vec::new_in(alloc),
+    let virt = phys + get_phys_offset();
+
+    map_page!(
+        phys,
+        virt,
+        Size4KiB,
+        PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+    );
+
+    ECAM_PAGES.write().insert(phys, virt);
+    virt
+}
+
+/// Returns the physical ECAM address of the configuration space of `bdf`
+fn ecam_address(bdf: Bdf) -> Option<u64> {
+    get_mcfg()
+        .as_ref()?
+        .physical_address(bdf.segment, bdf.bus, bdf.device, bdf.function)
+}
+
+impl Bar {
+    /// Maps every page of a memory BAR at the physical memory offset and returns the virtual
+    /// address of its start. Returns `None` for I/O BARs.
+    pub fn map(&self) -> Option<VirtAddr> {
+        if self.kind == BarKind::Io {
+            return None;
+        }
+
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(self.address));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(
+            self.address + self.size.max(1) - 1,
+        ));
+
+        for page in Page::range_inclusive(first, last) {
+            let phys = page.start_address().as_u64();
+
+            map_page!(
+                phys,
+                phys + get_phys_offset(),
+                Size4KiB,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH
+            );
         }
+
+        Some(VirtAddr::new(self.address + get_phys_offset()))
     }
+}
 
-    /// Sets the bit at the provided `bit_idx` to `yes` (`true` or `false`).
-    ///
-    /// ## Example
-    /// ```rust
-    /// use alloc::alloc::Global;
-    ///
-    /// let mut bitmap = Bitmap::new_in(Global, 4096);
-    ///
-    /// assert!(!bitmap.is_set(69));
-    /// bitmap.set(69, true);
-    /// assert!(bitmap.is_set(69));
-    /// ```
-    pub fn set(&mut self, bit_idx: usize, yes: bool) {
-        let (block_idx, mod_bit_idx) = (bit_idx / BLOCK_BITS, bit_idx % BLOCK_BITS);
+/// Maps the whole memory BAR `index` of a registered function, uncached, and returns the
+/// virtual address of its start. Returns `None` if the BAR isn't implemented or is an I/O BAR.
+pub fn map_bar(bdf: Bdf, index: usize) -> Option<VirtAddr> {
+    let bar = PCI_TABLE
+        .read()
+        .get(bdf)?
+        .bars
+        .get(index)
+        .copied()
+        .flatten()?;
+    bar.map()
+}
+
+/// Writes all ones to the BAR at `offset` and returns what sticks, restoring `original` after
+fn probe_bar(access: ConfigAccess, bdf: Bdf, offset: usize, original: u32) -> u32 {
+    access.write32(bdf, offset, u32::MAX);
+    let mask = access.read32(bdf, offset);
+    access.write32(bdf, offset, original);
+
+    mask
+}
+
+/// Decodes the BARs of `bdf`, sizing each one by writing all ones to it. The upper half of a
+/// 64-bit BAR and unimplemented BARs are `None`.
+pub fn decode_bars(header: &Header, bdf: Bdf) -> [Option<Bar>; 6] {
+    let access = ConfigAccess::current();
+
+    let count = match header.header_type {
+        HeaderType::Normal(_) => 6,
+        HeaderType::Bridge(_) => 2,
+        _ => 0,
+    };
+
+    // Keep the device from decoding accesses to the sizing pattern
+    let command = access.read16(bdf, COMMAND_OFFSET);
+    access.write16(bdf, COMMAND_OFFSET, command & !0b11);
+
+    let bars = decode_bar_registers(
+        count,
+        |i| access.read32(bdf, BAR_OFFSET + i * 4),
+        |i, original| probe_bar(access, bdf, BAR_OFFSET + i * 4, original),
+    );
+
+    access.write16(bdf, COMMAND_OFFSET, command);
 
-        self.bitmap
-            .get_mut(block_idx)
-            .map(|n| n.set_bit(mod_bit_idx, yes));
+    bars
+}
+
+/// Reserves the window that BARs firmware left unprogrammed get their addresses from: above
+/// RAM and every BAR of the functions in `found`, below [`BAR_WINDOW_END`]
+fn reserve_bar_window(found: &[Bdf]) {
+    let access = ConfigAccess::current();
+
+    let ram_end = get_boot_info()
+        .memory_regions
+        .iter()
+        .map(|region| region.end)
+        .filter(|end| *end <= BAR_WINDOW_END)
+        .max()
+        .unwrap_or(0);
+
+    let bars_end = found
+        .iter()
+        .filter_map(|bdf| {
+            let header = Header::try_from(access.read_header(*bdf).as_slice()).ok()?;
+            Some(decode_bars(&header, *bdf))
+        })
+        .flatten()
+        .flatten()
+        .filter(|bar| bar.kind != BarKind::Io && bar.address != 0)
+        .map(|bar| bar.address + bar.size)
+        .filter(|end| *end <= BAR_WINDOW_END)
+        .max()
+        .unwrap_or(0);
+
+    let start = align_up(ram_end.max(bars_end), 0x10_0000);
+    debug!("PCI: BAR window {:#x}..{:#x}", start, BAR_WINDOW_END);
+
+    *BAR_WINDOW.lock() = Some(start..BAR_WINDOW_END.max(start));
+}
+
+/// Takes a naturally aligned region of `size` bytes from the BAR window
+fn allocate_bar(size: u64) -> Option<u64> {
+    let mut window = BAR_WINDOW.lock();
+    let window = window.as_mut()?;
+
+    let start = align_up(window.start, size.max(0x1000));
+    let end = start.checked_add(size)?;
+
+    if end > window.end {
+        return None;
     }
 
-    /// Returns weather the bit at the provided `bit_idx` is set.
-    ///
-    /// ## Example
-    /// ```rust
-    /// use alloc::alloc::Global;
-    ///
-    /// let bitmap = Bitmap::new_in(Global, 4096);
-    /// assert!(!bitmap.is_set(69));
-    /// ```
-    pub fn is_set(&self, bit_idx: usize) -> bool {
-        let (block_idx, mod_bit_idx) = (bit_idx / BLOCK_BITS, bit_idx % BLOCK_BITS);
-        let n = self.bitmap[block_idx];
+    window.start = end;
+    Some(start)
+}
+
+/// Gives every memory BAR of `bdf` that firmware left at 0 an address from the BAR window.
+/// This is normally firmware's job, so every assignment gets logged.
+fn assign_bars(bdf: Bdf, bars: &mut [Option<Bar>; 6]) {
+    let access = ConfigAccess::current();
+
+    for (i, bar) in bars.iter_mut().enumerate() {
+        let Some(bar) = bar
+            .as_mut()
+            .filter(|bar| bar.address == 0 && bar.kind != BarKind::Io)
+        else {
+            continue;
+        };
+
+        let Some(address) = allocate_bar(bar.size) else {
+            warn!(
+                "PCI: no room for the {:#x} bytes of unprogrammed BAR {} of {}",
+                bar.size, i, bdf
+            );
+            continue;
+        };
 
-        n.get_bit(mod_bit_idx)
+        let offset = BAR_OFFSET + i * 4;
+        let flags = access.read32(bdf, offset) & 0xF;
+        access.write32(bdf, offset, address as u32 | flags);
+
+        if bar.kind == BarKind::Memory64 {
+            access.write32(bdf, offset + 4, (address >> 32) as u32);
+        }
+
+        bar.address = address;
+        bar.assigned = true;
+
+        info!(
+            "PCI: firmware left BAR {} of {} unprogrammed, assigned {:#x}..{:#x}",
+            i,
+            bdf,
+            address,
+            address + bar.size
+        );
     }
+}
 
-    /// Returns the index of the first unset bit.
-    ///
-    /// ## Example
-    /// ```rust
-    /// use alloc::alloc::Global;
-    ///
-    /// let mut bitmap = Bitmap::new_in(Global, 4096);
-    ///
-    /// bitmap.set(69, true);
-    /// assert_eq!(bitmap.find_first_set(), Some(0));
-    /// ```
-    pub fn find_first_unset(&self) -> Option<usize> {
-        self.bitmap
-            .iter()
-            .enumerate()
-            .map(|(i, block)| {
-                let mut block_value = *block;
-
-                if block_value == 0 {
-                    i * BLOCK_BITS
-                } else {
-                    let mut bit = 0;
-
-                    while block_value.get_bit(0) {
-                        block_value >>= 1;
-                        bit += 1;
-                    }
+/// Walks every PCI segment, starting at bus 0 and following PCI-to-PCI bridges to the buses
+/// behind them, and returns the functions found
+pub fn enumerate_devices() -> impl Iterator<Item = Bdf> {
+    let access = ConfigAccess::current();
+    let mut scan = BusScan::new(|bdf| scan_header(access, bdf));
 
-                    (i * BLOCK_BITS) + bit
-                }
-            })
-            .nth(0)
+    for segment in (0..=u16::MAX).filter(|segment| access.has_segment(*segment)) {
+        scan.scan_bus(segment, 0);
     }
 
-    /// Returns the index of the first set bit.
-    ///
-    /// ## Example
-    /// ```rust
-    /// use alloc::alloc::Global;
-    ///
-    /// let mut bitmap = Bitmap::new_in(Global, 4096);
-    ///
-    /// bitmap.set(69, true);
-    /// assert_eq!(bitmap.find_first_set(), Some(69));
-    /// ```
-    pub fn find_first_set(&self) -> Option<usize> {
-        for (i, block) in self.bitmap.iter().enumerate() {
-            let mut block_value = *block;
-
-            if block_value != 0 {
-                let mut bit = 0;
-
-                // Loop through the bits in the block and find
-                // the first set bit.
-                while !block_value.get_bit(0) {
-                    block_value >>= 1;
-                    bit += 1;
-                }
+    for (bdf, buses) in scan.misconfigured {
+        warn!(
+            "PCI: bridge at {} forwards to buses {:#x}..={:#x}, skipping them",
+            bdf,
+            buses.start(),
+            buses.end()
+        );
+    }
 
-                return Some((i * BLOCK_BITS) + bit);
-            }
-        }
+    let mut bridges = BRIDGES.write();
+    for (bdf, secondary) in scan.bridges {
+        bridges.insert((bdf.segment, secondary), bdf);
+    }
 
-        None
+    scan.found.into_iter()
+}
+
+/// The PCI-to-PCI bridge whose secondary bus is `bus`, or `None` for root buses and buses
+/// that haven't been walked
+pub fn parent_bridge(segment: u16, bus: u8) -> Option<Bdf> {
+    BRIDGES.read().get(&(segment, bus)).copied()
+}
+
+/// Reads what the walk over the bus hierarchy needs to know about the function at `bdf`, or
+/// `None` if there's nothing there
+fn scan_header(access: ConfigAccess, bdf: Bdf) -> Option<ScannedFunction> {
+    // Reads of functions that don't exist return all ones
+    if access.read16(bdf, 0) == 0xFFFF {
+        return None;
     }
+
+    let raw_header = access.read_header(bdf);
+    let multi_function = raw_header[HEADER_TYPE_OFFSET].get_bit(7);
+
+    let Ok(header) = Header::try_from(raw_header.as_slice()) else {
+        warn!("PCI: unparseable header at {}", bdf);
+
+        return Some(ScannedFunction {
+            kind: DeviceKind::Unknown,
+            multi_function,
+            bridge: None,
+        });
+    };
+
+    let kind = DeviceKind::new(header.class_code.base as u32, header.class_code.sub as u32);
+
+    let bridge = matches!(header.header_type, HeaderType::Bridge(_)).then(|| {
+        (
+            raw_header[SECONDARY_BUS_OFFSET],
+            raw_header[SUBORDINATE_BUS_OFFSET],
+        )
+    });
+
+    Some(ScannedFunction {
+        kind,
+        multi_function,
+        bridge,
+    })
 }
 
 bitflags! {
@@ -360,9 +706,6 @@ pub unsafe fn inw(port: u16) -> u16 {
     ret
 }
 
-// const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
-// const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
-
 /// Struct representing a single MSI-X message
 #[repr(C)]
 pub struct Message {
@@ -385,35 +728,556 @@ impl Message {
     }
 
     pub fn route_irq(&mut self, irq: u8, delivery_mode: IrqMode) {
-        // Found out all of the below mainly from studying Aero's implementation
+        let (addr, data) = msi_message(irq, delivery_mode);
 
-        let mut data = 0;
-        data.set_bits(0..8, irq as u32);
-        data.set_bits(8..11, delivery_mode as u32);
-        data.set_bit(14, false);
-        data.set_bit(15, false);
+        self.data.write_volatile(data);
+        self.addr_low.write_volatile(addr);
+        self.addr_high.write_volatile(get_phys_offset() as u32);
+    }
+}
 
-        // reserved values
-        data.set_bits(16..32, 0);
+/// Returns the message address and data delivering `irq` to this CPU's local APIC, as used by
+/// both MSI and MSI-X
+fn msi_message(irq: u8, delivery_mode: IrqMode) -> (u32, u32) {
+    // Found out all of the below mainly from studying Aero's implementation
 
-        let mut addr = 0;
+    let mut data = 0;
+    data.set_bits(0..8, irq as u32);
+    data.set_bits(8..11, delivery_mode as u32);
+    data.set_bit(14, false);
+    data.set_bit(15, false);
 
-        // Since we're already sending IPIs in a cycle to schedule tasks,
-        // this always changes, so pointless to fix it to a specific ID
-        addr.set_bits(12..20, unsafe { get_active_lapic().id() });
+    // reserved values
+    data.set_bits(16..32, 0);
 
-        // Use the IA32_APIC_BASE MSR to ensure that these bits actually match the first 12 bits
-        // of the address of the APIC on the system instead of hardcoding them.
-        // This ensures compatibility with non-compliant hardware.
-        addr.set_bits(20..32, unsafe { xapic_base().get_bits(20..32) as u32 });
+    let mut addr = 0;
 
-        self.data.write_volatile(data);
-        self.addr_low.write_volatile(addr);
-        self.addr_high.write_volatile(get_phys_offset() as u32);
+    // The destination field only holds 8 bits, higher x2APIC IDs need interrupt remapping
+    addr.set_bits(12..20, local_apic_id().get_bits(0..8));
+
+    // Use the IA32_APIC_BASE MSR to ensure that these bits actually match the first 12 bits
+    // of the address of the APIC on the system instead of hardcoding them.
+    // This ensures compatibility with non-compliant hardware.
+    addr.set_bits(20..32, unsafe { xapic_base().get_bits(20..32) as u32 });
+
+    (addr, data)
+}
+
+/// Returns the offset of the capability with ID `id` in the configuration space `raw`
+fn find_capability(raw: &[u8; ECS_OFFSET], id: u8) -> Option<usize> {
+    let mut pointer = raw[CAPABILITIES_POINTER_OFFSET] as usize & !0x3;
+
+    // The list lives in the first 256 bytes, so a longer walk means it loops
+    for _ in 0..48 {
+        if pointer == 0 {
+            return None;
+        }
+
+        if raw[pointer] == id {
+            return Some(pointer);
+        }
+
+        pointer = raw[pointer + 1] as usize & !0x3;
     }
+
+    None
 }
 
-#[derive(Debug, PartialEq)]
+/// Programs and enables the MSI capability at offset `cap` of `bdf`, routing its messages to
+/// newly allocated vectors. `ahci_controller` picks the AHCI handler for them.
+fn enable_msi(bdf: Bdf, cap: usize, ahci_controller: bool) {
+    let access = ConfigAccess::current();
+    let mut control = access.read16(bdf, cap + 2);
+
+    let is_64bit = control.get_bit(7);
+    let capable = control.get_bits(1..4);
+
+    // Multiple messages share the address and differ in the low bits of the data, so they
+    // need a block of vectors aligned to its size
+    let (irq, enabled) = match irqalloc_contiguous(1 << capable) {
+        Some(irq) => (irq, capable),
+        None => match irqalloc() {
+            Some(irq) => (irq, 0),
+            None => {
+                warn!("MSI: out of vectors for {}", bdf);
+                return;
+            }
+        },
+    };
+
+    // TODO: split this into different interrupts depending on device functionality
+    let (handler, name): (IrqHandler, _) = if ahci_controller {
+        (ahci, "ahci-msi")
+    } else {
+        (msi, "msi")
+    };
+
+    for i in 0..1u8 << enabled {
+        register_irq(irq + i, handler, core::ptr::null_mut(), Some(name));
+    }
+
+    let (addr, data) = msi_message(irq, IrqMode::Fixed);
+
+    access.write32(bdf, cap + 4, addr);
+
+    if is_64bit {
+        access.write32(bdf, cap + 8, 0);
+        access.write16(bdf, cap + 0xC, data as u16);
+    } else {
+        access.write16(bdf, cap + 8, data as u16);
+    }
+
+    control.set_bits(4..7, enabled);
+    control.set_bit(0, true);
+    access.write16(bdf, cap + 2, control);
+
+    // Disable legacy interrupts
+    write_command(bdf, read_command(bdf) | PciCommand::INTERRUPT_DISABLE);
+
+    info!(
+        "MSI: {} vector(s) starting at {:#x} ({}-bit)",
+        1 << enabled,
+        irq,
+        if is_64bit { 64 } else { 32 }
+    );
+}
+
+/// Called for every interrupt on a function's INTx line. Lines can be shared, so it has to
+/// check whether its device actually raised the interrupt.
+pub type IntxHandler = fn(Bdf);
+
+/// A GSI that one or more functions raise INTx interrupts on
+struct IntxLine {
+    /// IRQ number as found in `_PRT`, before interrupt source overrides
+    irq: u32,
+    gsi: u32,
+    functions: Vec<Bdf>,
+    /// Set once the line fired without any of its functions asserting INTx
+    misrouted: AtomicBool,
+}
+
+/// Lines in use. The index of a line is the context its vector's handler is registered with.
+static INTX_LINES: RwLock<Vec<IntxLine>> = RwLock::new(Vec::new());
+/// Handlers drivers registered for the INTx line of their function
+static INTX_HANDLERS: RwLock<BTreeMap<Bdf, IntxHandler>> = RwLock::new(BTreeMap::new());
+
+/// Registers the handler called when the function at `bdf` raises its INTx line
+pub fn register_intx_handler(bdf: Bdf, handler: IntxHandler) {
+    without_interrupts(|| INTX_HANDLERS.write().insert(bdf, handler));
+}
+
+/// Calls the handler of every function on the INTx line whose index is `line`
+fn dispatch_intx(_: u8, line: *mut ()) {
+    if let Some(line) = INTX_LINES.read().get(line as usize) {
+        // If none of the functions routed here asserts INTx, the interrupt came from elsewhere
+        // and _PRT doesn't describe how the line is wired. Functions older than PCI 2.3 don't
+        // report it, so this is only worth a warning.
+        let asserted = line
+            .functions
+            .iter()
+            .any(|&bdf| read_config::<u16>(bdf, STATUS_OFFSET).get_bit(STATUS_INTERRUPT));
+
+        if !asserted && !line.misrouted.swap(true, Ordering::Relaxed) {
+            warn!(
+                "PCI: GSI {} fired, but none of the {} functions routed to it asserts INTx",
+                line.gsi,
+                line.functions.len()
+            );
+        }
+
+        let handlers = INTX_HANDLERS.read();
+
+        for bdf in &line.functions {
+            if let Some(handler) = handlers.get(bdf) {
+                handler(*bdf);
+            }
+        }
+    }
+}
+
+/// Routes INTx pin `pin` (1 for INTA# to 4 for INTD#) of the function at `bdf` through
+/// `_PRT` and the I/O APIC, sharing a vector with the other functions on the same GSI.
+/// Returns the GSI.
+fn route_intx(bdf: Bdf, pin: u8) -> Option<u32> {
+    let pin = match pin {
+        1 => Pin::IntA,
+        2 => Pin::IntB,
+        3 => Pin::IntC,
+        4 => Pin::IntD,
+        _ => return None,
+    };
+
+    let desc = aml_route(bdf, pin)?;
+    let trigger = match desc.trigger {
+        InterruptTrigger::Edge => TriggerMode::Edge,
+        InterruptTrigger::Level => TriggerMode::Level,
+    };
+    let polarity = match desc.polarity {
+        InterruptPolarity::ActiveHigh => Polarity::ActiveHigh,
+        InterruptPolarity::ActiveLow => Polarity::ActiveLow,
+    };
+
+    without_interrupts(|| {
+        let mut lines = INTX_LINES.write();
+
+        if let Some(line) = lines.iter_mut().find(|line| line.irq == desc.irq) {
+            line.functions.push(bdf);
+            return Some(line.gsi);
+        }
+
+        let vector = irqalloc()?;
+        register_irq(
+            vector,
+            dispatch_intx,
+            lines.len() as *mut (),
+            Some("pci-intx"),
+        );
+
+        let dest = local_apic_id();
+
+        let Some(gsi) = route_gsi(desc.irq, vector, dest, polarity, trigger) else {
+            irqfree(vector);
+            return None;
+        };
+
+        lines.push(IntxLine {
+            irq: desc.irq,
+            gsi,
+            functions: alloc::vec![bdf],
+            misrouted: AtomicBool::new(false),
+        });
+
+        Some(gsi)
+    })
+}
+
+/// Device power states that can be set through the power management capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0 = 0,
+    D3Hot = 3,
+}
+
+/// Moves the function at `bdf` into `state` through its power management capability, waiting
+/// the 10ms recovery time the spec requires. Returns `false` if the function has no such
+/// capability.
+pub fn set_power_state(bdf: Bdf, state: PowerState) -> bool {
+    let access = ConfigAccess::current();
+
+    let Some(cap) = find_capability(&access.read_header(bdf), PM_CAPABILITY_ID) else {
+        return false;
+    };
+
+    // PMCSR, whose PME_Status bit is write-one-to-clear
+    let mut pmcsr = access.read16(bdf, cap + 4);
+    let current = pmcsr.get_bits(0..2);
+
+    if current == state as u16 {
+        return true;
+    }
+
+    pmcsr.set_bit(15, false);
+    pmcsr.set_bits(0..2, state as u16);
+    access.write16(bdf, cap + 4, pmcsr);
+
+    let deadline = Deadline::after_millis(10);
+    while !deadline.expired() {
+        core::hint::spin_loop();
+    }
+
+    debug!("PCI: {} went from D{} to {:?}", bdf, current, state);
+    true
+}
+
+/// Puts every function but bridges into D3hot, so that nothing stays powered on through
+/// shutdown. Functions behind bridges stay reachable since the bridges stay in D0.
+pub fn power_down_all() {
+    let bdfs = PCI_TABLE
+        .read()
+        .functions
+        .values()
+        .filter(|info| info.header.class_code.base != BRIDGE_CLASS)
+        .map(|info| info.bdf)
+        .collect::<Vec<_>>();
+
+    for bdf in bdfs {
+        set_power_state(bdf, PowerState::D3Hot);
+    }
+}
+
+/// Configuration space of a function saved across a suspend, along with its MSI-X table
+struct SavedConfig {
+    bdf: Bdf,
+    raw: [u8; ECS_OFFSET],
+    msix_table: Vec<u32>,
+}
+
+static SAVED_CONFIG: Mutex<Vec<SavedConfig>> = Mutex::new(Vec::new());
+
+/// Returns the mapped MSI-X table described by the capability at `cap` and its entry count
+fn msix_table(bdf: Bdf, raw: &[u8; ECS_OFFSET], cap: usize) -> Option<(VirtAddr, usize)> {
+    let control = u16::from_le_bytes([raw[cap + 2], raw[cap + 3]]);
+    let table = u32::from_le_bytes(raw[cap + 4..cap + 8].try_into().unwrap());
+    let base = map_bar(bdf, (table & 0x7) as usize)?;
+
+    Some((base + (table & !0x7) as u64, (control & 0x7FF) as usize + 1))
+}
+
+/// Saves the configuration space and MSI-X table of every function, before a suspend cuts
+/// their power
+pub fn save_config_state() {
+    let access = ConfigAccess::current();
+    let bdfs = PCI_TABLE
+        .read()
+        .functions
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+
+    let saved = bdfs
+        .into_iter()
+        .map(|bdf| {
+            let raw = access.read_header(bdf);
+            let msix_table = find_capability(&raw, MSIX_CAPABILITY_ID)
+                .and_then(|cap| msix_table(bdf, &raw, cap))
+                .map(|(table, entries)| {
+                    // Four dwords per entry: address, upper address, data and vector control
+                    (0..entries * 4)
+                        .map(|i| unsafe {
+                            core::ptr::read_volatile((table.as_u64() + i as u64 * 4) as *const u32)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            SavedConfig {
+                bdf,
+                raw,
+                msix_table,
+            }
+        })
+        .collect();
+
+    *SAVED_CONFIG.lock() = saved;
+}
+
+/// Writes the state saved by [`save_config_state`] back after a resume. The BARs go before
+/// the command register, so nothing gets decoded or mastered at a stale address, and the
+/// interrupt capabilities come last since the MSI-X table is only reachable through a BAR.
+pub fn restore_config_state() {
+    let access = ConfigAccess::current();
+    let saved = core::mem::take(&mut *SAVED_CONFIG.lock());
+
+    for config in saved {
+        let bdf = config.bdf;
+        let raw = &config.raw;
+        let word = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let dword = |offset: usize| u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap());
+
+        set_power_state(bdf, PowerState::D0);
+
+        // BARs, bus numbers and windows of bridges, expansion ROM and interrupt line
+        for offset in (0x0C..0x40).step_by(4) {
+            access.write32(bdf, offset, dword(offset));
+        }
+
+        access.write16(bdf, COMMAND_OFFSET, word(COMMAND_OFFSET));
+
+        if let Some(cap) = find_capability(raw, MSI_CAPABILITY_ID) {
+            let control = word(cap + 2);
+            let is_64bit = control.get_bit(7);
+            let per_vector_mask = control.get_bit(8);
+
+            // Address, data and mask bits; the pending bits are read-only
+            let end =
+                cap + if is_64bit { 0x10 } else { 0x0C } + if per_vector_mask { 4 } else { 0 };
+
+            for offset in (cap + 4..end).step_by(4) {
+                access.write32(bdf, offset, dword(offset));
+            }
+
+            access.write16(bdf, cap + 2, control);
+        }
+
+        if let Some(cap) = find_capability(raw, MSIX_CAPABILITY_ID) {
+            if let Some((table, _)) = msix_table(bdf, raw, cap) {
+                for (i, value) in config.msix_table.iter().enumerate() {
+                    unsafe {
+                        core::ptr::write_volatile(
+                            (table.as_u64() + i as u64 * 4) as *mut u32,
+                            *value,
+                        )
+                    };
+                }
+            }
+
+            access.write16(bdf, cap + 2, word(cap + 2));
+        }
+    }
+}
+
+bitflags! {
+    /// Uncorrectable Error Status register of the AER capability
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AerUncorrectable: u32 {
+        const DATA_LINK_PROTOCOL           = 1 << 4;
+        const SURPRISE_DOWN                = 1 << 5;
+        const POISONED_TLP                 = 1 << 12;
+        const FLOW_CONTROL_PROTOCOL        = 1 << 13;
+        const COMPLETION_TIMEOUT           = 1 << 14;
+        const COMPLETER_ABORT              = 1 << 15;
+        const UNEXPECTED_COMPLETION        = 1 << 16;
+        const RECEIVER_OVERFLOW            = 1 << 17;
+        const MALFORMED_TLP                = 1 << 18;
+        const ECRC                         = 1 << 19;
+        const UNSUPPORTED_REQUEST          = 1 << 20;
+        const ACS_VIOLATION                = 1 << 21;
+        const UNCORRECTABLE_INTERNAL       = 1 << 22;
+        const MC_BLOCKED_TLP               = 1 << 23;
+        const ATOMIC_OP_EGRESS_BLOCKED     = 1 << 24;
+        const TLP_PREFIX_BLOCKED           = 1 << 25;
+        const POISONED_TLP_EGRESS_BLOCKED  = 1 << 26;
+    }
+}
+
+bitflags! {
+    /// Correctable Error Status register of the AER capability
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct AerCorrectable: u32 {
+        const RECEIVER_ERROR       = 1 << 0;
+        const BAD_TLP              = 1 << 6;
+        const BAD_DLLP             = 1 << 7;
+        const REPLAY_NUM_ROLLOVER  = 1 << 8;
+        const REPLAY_TIMER_TIMEOUT = 1 << 12;
+        const ADVISORY_NON_FATAL   = 1 << 13;
+        const CORRECTED_INTERNAL   = 1 << 14;
+        const HEADER_LOG_OVERFLOW  = 1 << 15;
+    }
+}
+
+/// Error state latched in a function's AER capability
+#[derive(Debug, Clone, Copy)]
+pub struct AerStatus {
+    pub uncorrectable: AerUncorrectable,
+    pub correctable: AerCorrectable,
+    /// Header of the TLP that caused the first uncorrectable error
+    pub header_log: [u32; 4],
+}
+
+impl AerStatus {
+    pub fn is_empty(&self) -> bool {
+        self.uncorrectable.is_empty() && self.correctable.is_empty()
+    }
+}
+
+/// Returns the offset of the extended capability with ID `id` of `bdf`. Only ECAM reaches
+/// the extended configuration space, so this is always `None` with port I/O.
+fn find_extended_capability(bdf: Bdf, id: u16) -> Option<usize> {
+    let access = ConfigAccess::current();
+
+    if access != ConfigAccess::Ecam {
+        return None;
+    }
+
+    let mut pointer = ECS_OFFSET;
+
+    // Every capability takes at least a dword, so a longer walk means it loops
+    for _ in 0..(CONFIG_SPACE_SIZE - ECS_OFFSET) / 4 {
+        let header = access.read32(bdf, pointer);
+
+        if header == 0 || header == u32::MAX {
+            return None;
+        }
+
+        if header.get_bits(0..16) as u16 == id {
+            return Some(pointer);
+        }
+
+        pointer = header.get_bits(20..32) as usize & !0x3;
+
+        if pointer < ECS_OFFSET {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// Reads the error status latched in the AER capability of `bdf`, or `None` if it has none
+pub fn read_aer_status(bdf: Bdf) -> Option<AerStatus> {
+    let access = ConfigAccess::current();
+    let cap = find_extended_capability(bdf, AER_CAPABILITY_ID)?;
+
+    let mut header_log = [0; 4];
+    for (i, dword) in header_log.iter_mut().enumerate() {
+        *dword = access.read32(bdf, cap + 0x1C + i * 4);
+    }
+
+    Some(AerStatus {
+        uncorrectable: AerUncorrectable::from_bits_truncate(access.read32(bdf, cap + 0x04)),
+        correctable: AerCorrectable::from_bits_truncate(access.read32(bdf, cap + 0x10)),
+        header_log,
+    })
+}
+
+/// Clears the errors in `status` from the AER capability of `bdf`, so new ones get latched
+pub fn clear_aer_status(bdf: Bdf, status: &AerStatus) {
+    let access = ConfigAccess::current();
+
+    if let Some(cap) = find_extended_capability(bdf, AER_CAPABILITY_ID) {
+        // Both status registers are write-one-to-clear
+        access.write32(bdf, cap + 0x04, status.uncorrectable.bits());
+        access.write32(bdf, cap + 0x10, status.correctable.bits());
+    }
+}
+
+/// Logs and clears the errors latched in the AER capability of `bdf`, returning them. Does
+/// nothing unless firmware granted AER control through `_OSC`.
+pub fn check_aer(bdf: Bdf) -> Option<AerStatus> {
+    // Firmware that kept AER for itself expects to find the error status untouched
+    if !osc_granted().contains(OscControl::AER) {
+        return None;
+    }
+
+    let status = read_aer_status(bdf).filter(|status| !status.is_empty())?;
+
+    if !status.uncorrectable.is_empty() {
+        error!(
+            "PCI: uncorrectable errors at {}: {:?} (TLP header {:08x?})",
+            bdf, status.uncorrectable, status.header_log
+        );
+    }
+
+    if !status.correctable.is_empty() {
+        warn!(
+            "PCI: correctable errors at {}: {:?}",
+            bdf, status.correctable
+        );
+    }
+
+    clear_aer_status(bdf, &status);
+    Some(status)
+}
+
+/// Timer ticks between two runs of `check_all_aer()` from the main loop
+pub const AER_CHECK_INTERVAL_TICKS: u64 = 1000;
+
+/// Checks every function for AER errors. Meant to be called periodically, since nothing
+/// routes AER interrupts yet.
+pub fn check_all_aer() {
+    let bdfs = PCI_TABLE
+        .read()
+        .functions
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+
+    for bdf in bdfs {
+        check_aer(bdf);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Vendor {
     Intel,
     AMD,
@@ -499,501 +1363,450 @@ impl Vendor {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum DeviceKind {
-    Unknown,
-
-    /*
-     * Base Class 0x00 - Devices that predate Class Codes
-     */
-    LegacyVgaCompatible,
-    LegacyNotVgaCompatible,
-
-    /*
-     * Base Class 0x01 - Mass Storage Controllers
-     */
-    ScsiBusController,
-    IdeController,
-    FloppyController,
-    IpiBusController,
-    RaidController,
-    AtaController,
-    SataController,
-    SasController,
-    NvmeController,
-    OtherMassStorageController,
-
-    /*
-     * Base Class 0x02 - Network Controllers
-     */
-    EthernetController,
-    TokenRingController,
-    FddiController,
-    AtmController,
-    IsdnController,
-    PicmgController,
-    OtherNetworkController,
-
-    /*
-     * Base Class 0x03 - Display Controllers
-     */
-    VgaCompatibleController,
-    XgaController,
-    ThreeDController,
-    OtherDisplayController,
-
-    /*
-     * Base Class 0x04 - Multimedia Devices
-     */
-    VideoDevice,
-    AudioDevice,
-    TelephonyDevice,
-    OtherMultimediaDevice,
-
-    /*
-     * Base Class 0x05 - Memory Controllers
-     */
-    RamController,
-    FlashController,
-    OtherMemoryController,
-
-    /*
-     * Base Class 0x06 - Bridge Devices
-     */
-    HostBridge,
-    IsaBridge,
-    EisaBridge,
-    McaBridge,
-    PciPciBridge,
-    PcmciaBridge,
-    NuBusBridge,
-    CardBusBridge,
-    RacewayBridge,
-    SemiTransparentPciPciBridge,
-    InfinibandPciHostBridge,
-    OtherBridgeDevice,
-
-    /*
-     * Base Class 0x07 - Simple Communications Controllers
-     */
-    SerialController,
-    ParallelPort,
-    MultiportSerialController,
-    Modem,
-    GpibController,
-    SmartCard,
-    OtherCommunicationsDevice,
-
-    /*
-     * Base Class 0x08 - Generic System Peripherals
-     */
-    InterruptController,
-    DmaController,
-    SystemTimer,
-    RtcController,
-    GenericPciHotPlugController,
-    SdHostController,
-    OtherSystemPeripheral,
-
-    /*
-     * Base Class 0x09 - Input Devices
-     */
-    KeyboardController,
-    Digitizer,
-    MouseController,
-    ScannerController,
-    GameportController,
-    OtherInputController,
-
-    /*
-     * Base Class 0x0a - Docking Stations
-     */
-    GenericDockingStation,
-    OtherDockingStation,
-
-    /*
-     * Base Class 0x0b - Processors
-     */
-    Processor386,
-    Processor486,
-    ProcessorPentium,
-    ProcessorAlpha,
-    ProcessorPowerPc,
-    ProcessorMips,
-    CoProcessor,
-
-    /*
-     * Base Class 0x0c - Serial Bus Controllers
-     */
-    FirewireController,
-    AccessBusController,
-    SsaBusController,
-    UsbController,
-    FibreChannelController,
-    SmBusController,
-    InfiniBandController,
-    IpmiController,
-    SercosController,
-    CanBusController,
-
-    /*
-     * Base Class 0x0d - Wireless Controllers
-     */
-    IrdaController,
-    ConsumerIrController,
-    RfController,
-    BluetoothController,
-    BroadbandController,
-    Ethernet5GHzController,
-    Ethernet24GHzController,
-    OtherWirelessController,
-
-    /*
-     * Base Class 0x0e - Intelligent IO Controllers
-     */
-    IntelligentIoController,
-
-    /*
-     * Base Class 0x0f - Satellite Communications Controllers
-     */
-    TvSatelliteCommunicationsController,
-    AudioSatelliteCommunicationsController,
-    VoiceSatelliteCommunicationsController,
-    DataSatelliteCommunicationsController,
-
-    /*
-     * Base Class 0x10 - Encryption and Decryption Controllers
-     */
-    NetworkCryptionController,
-    EntertainmentCryptionController,
-    OtherCryptionController,
-
-    /*
-     * Base Class 0x11 - Data Acquisition and Signal Processing Controllers
-     */
-    DpioModule,
-    PerformanceCounter,
-    CommunicationsSynchronizationController,
-    ManagementCard,
-    OtherSignalProcessingController,
-}
-
-impl DeviceKind {
-    pub fn new(base_class: u32, sub_class: u32) -> Self {
-        match (base_class, sub_class) {
-            (0x00, 0x00) => DeviceKind::LegacyNotVgaCompatible,
-            (0x00, 0x01) => DeviceKind::LegacyVgaCompatible,
-
-            (0x01, 0x00) => DeviceKind::ScsiBusController,
-            (0x01, 0x01) => DeviceKind::IdeController,
-            (0x01, 0x02) => DeviceKind::FloppyController,
-            (0x01, 0x03) => DeviceKind::IpiBusController,
-            (0x01, 0x04) => DeviceKind::RaidController,
-            (0x01, 0x05) => DeviceKind::AtaController,
-            (0x01, 0x06) => DeviceKind::SataController,
-            (0x01, 0x07) => DeviceKind::SasController,
-            (0x01, 0x08) => DeviceKind::NvmeController,
-            (0x01, 0x80) => DeviceKind::OtherMassStorageController,
-
-            (0x02, 0x00) => DeviceKind::EthernetController,
-            (0x02, 0x01) => DeviceKind::TokenRingController,
-            (0x02, 0x02) => DeviceKind::FddiController,
-            (0x02, 0x03) => DeviceKind::AtmController,
-            (0x02, 0x04) => DeviceKind::IsdnController,
-            (0x02, 0x06) => DeviceKind::PicmgController,
-            (0x02, 0x80) => DeviceKind::OtherNetworkController,
-
-            (0x03, 0x00) => DeviceKind::VgaCompatibleController,
-            (0x03, 0x01) => DeviceKind::XgaController,
-            (0x03, 0x02) => DeviceKind::ThreeDController,
-            (0x03, 0x80) => DeviceKind::OtherDisplayController,
-
-            (0x04, 0x00) => DeviceKind::VideoDevice,
-            (0x04, 0x01) => DeviceKind::AudioDevice,
-            (0x04, 0x02) => DeviceKind::TelephonyDevice,
-            (0x04, 0x03) => DeviceKind::OtherMultimediaDevice,
-
-            (0x05, 0x00) => DeviceKind::RamController,
-            (0x05, 0x01) => DeviceKind::FlashController,
-            (0x05, 0x02) => DeviceKind::OtherMemoryController,
-
-            (0x06, 0x00) => DeviceKind::HostBridge,
-            (0x06, 0x01) => DeviceKind::IsaBridge,
-            (0x06, 0x02) => DeviceKind::EisaBridge,
-            (0x06, 0x03) => DeviceKind::McaBridge,
-            (0x06, 0x04) => DeviceKind::PciPciBridge,
-            (0x06, 0x05) => DeviceKind::PcmciaBridge,
-            (0x06, 0x06) => DeviceKind::NuBusBridge,
-            (0x06, 0x07) => DeviceKind::CardBusBridge,
-            (0x06, 0x08) => DeviceKind::RacewayBridge,
-            (0x06, 0x09) => DeviceKind::SemiTransparentPciPciBridge,
-            (0x06, 0x0a) => DeviceKind::InfinibandPciHostBridge,
-            (0x06, 0x80) => DeviceKind::OtherBridgeDevice,
-
-            (0x07, 0x00) => DeviceKind::SerialController,
-            (0x07, 0x01) => DeviceKind::ParallelPort,
-            (0x07, 0x02) => DeviceKind::MultiportSerialController,
-            (0x07, 0x03) => DeviceKind::Modem,
-            (0x07, 0x04) => DeviceKind::GpibController,
-            (0x07, 0x05) => DeviceKind::SmartCard,
-            (0x07, 0x80) => DeviceKind::OtherCommunicationsDevice,
-
-            (0x08, 0x00) => DeviceKind::InterruptController,
-            (0x08, 0x01) => DeviceKind::DmaController,
-            (0x08, 0x02) => DeviceKind::SystemTimer,
-            (0x08, 0x03) => DeviceKind::RtcController,
-            (0x08, 0x04) => DeviceKind::GenericPciHotPlugController,
-            (0x08, 0x05) => DeviceKind::SdHostController,
-            (0x08, 0x80) => DeviceKind::OtherSystemPeripheral,
-
-            (0x09, 0x00) => DeviceKind::KeyboardController,
-            (0x09, 0x01) => DeviceKind::Digitizer,
-            (0x09, 0x02) => DeviceKind::MouseController,
-            (0x09, 0x03) => DeviceKind::ScannerController,
-            (0x09, 0x04) => DeviceKind::GameportController,
-            (0x09, 0x80) => DeviceKind::OtherInputController,
-
-            (0x0a, 0x00) => DeviceKind::GenericDockingStation,
-            (0x0a, 0x80) => DeviceKind::OtherDockingStation,
-
-            (0x0b, 0x00) => DeviceKind::Processor386,
-            (0x0b, 0x01) => DeviceKind::Processor486,
-            (0x0b, 0x02) => DeviceKind::ProcessorPentium,
-            (0x0b, 0x10) => DeviceKind::ProcessorAlpha,
-            (0x0b, 0x20) => DeviceKind::ProcessorPowerPc,
-            (0x0b, 0x30) => DeviceKind::ProcessorMips,
-            (0x0b, 0x40) => DeviceKind::CoProcessor,
-
-            (0x0c, 0x00) => DeviceKind::FirewireController,
-            (0x0c, 0x01) => DeviceKind::AccessBusController,
-            (0x0c, 0x02) => DeviceKind::SsaBusController,
-            (0x0c, 0x03) => DeviceKind::UsbController,
-            (0x0c, 0x04) => DeviceKind::FibreChannelController,
-            (0x0c, 0x05) => DeviceKind::SmBusController,
-            (0x0c, 0x06) => DeviceKind::InfiniBandController,
-            (0x0c, 0x07) => DeviceKind::IpmiController,
-            (0x0c, 0x08) => DeviceKind::SercosController,
-            (0x0c, 0x09) => DeviceKind::CanBusController,
-
-            (0x0d, 0x00) => DeviceKind::IrdaController,
-            (0x0d, 0x01) => DeviceKind::ConsumerIrController,
-            (0x0d, 0x10) => DeviceKind::RfController,
-            (0x0d, 0x11) => DeviceKind::BluetoothController,
-            (0x0d, 0x12) => DeviceKind::BroadbandController,
-            (0x0d, 0x20) => DeviceKind::Ethernet5GHzController,
-            (0x0d, 0x21) => DeviceKind::Ethernet24GHzController,
-            (0x0d, 0x80) => DeviceKind::OtherWirelessController,
-
-            (0x0e, 0x00) => DeviceKind::IntelligentIoController,
-
-            (0x0f, 0x00) => DeviceKind::TvSatelliteCommunicationsController,
-            (0x0f, 0x01) => DeviceKind::AudioSatelliteCommunicationsController,
-            (0x0f, 0x02) => DeviceKind::VoiceSatelliteCommunicationsController,
-            (0x0f, 0x03) => DeviceKind::DataSatelliteCommunicationsController,
-
-            (0x10, 0x00) => DeviceKind::NetworkCryptionController,
-            (0x10, 0x10) => DeviceKind::EntertainmentCryptionController,
-            (0x10, 0x80) => DeviceKind::OtherCryptionController,
-
-            (0x11, 0x00) => DeviceKind::DpioModule,
-            (0x11, 0x01) => DeviceKind::PerformanceCounter,
-            (0x11, 0x10) => DeviceKind::CommunicationsSynchronizationController,
-            (0x11, 0x20) => DeviceKind::ManagementCard,
-            (0x11, 0x80) => DeviceKind::OtherSignalProcessingController,
-
-            _ => DeviceKind::Unknown,
-        }
-    }
-}
-
 /// Device handle for open source drivers
 ///
 /// Proprietary drivers must use `redox_syscall` instead, since usermode isn't beholden to GPLv3 the way kernel mode is
 pub trait FOSSPciDeviceHandle: Send + Sync {
-    fn handles(&self, vendor_id: Vendor, device_id: DeviceKind) -> bool;
-    fn start(&self, header: &mut pcics::Header);
+    /// Functions this driver binds to
+    fn matches(&self) -> &[DeviceMatch];
+
+    fn handles(&self, device: &PciDeviceInfo) -> bool {
+        any_matches(
+            self.matches(),
+            device.kind,
+            device.header.vendor_id,
+            device.header.device_id,
+        )
+    }
+
+    /// Starts driving `device`. Changes to it are kept in [`PCI_TABLE`] afterwards.
+    fn start(&self, device: &mut PciDeviceInfo);
+
+    /// Returns the MSI-X table entries of the function at `bdf` this driver wants routed, out
+    /// of `table_len`, with the handler and its context for each. Entries nobody claims stay
+    /// masked.
+    fn msix_vectors(&self, _bdf: Bdf, _table_len: u16) -> Vec<(u16, IrqHandler, *mut ())> {
+        Vec::new()
+    }
 }
 
 pub struct PciDevice {
     pub handle: Arc<dyn FOSSPciDeviceHandle>,
 }
 
+/// A PCI function found during enumeration
+#[derive(Clone)]
+pub struct PciDeviceInfo {
+    pub bdf: Bdf,
+    /// Physical address of the configuration space, `None` when it's
+    /// reached through port I/O
+    pub address: Option<u64>,
+    /// Where the configuration space is mapped, if it's reached through ECAM
+    pub config_space: Option<VirtAddr>,
+    pub raw_header: [u8; ECS_OFFSET],
+    pub header: Header,
+    pub kind: DeviceKind,
+    pub vendor: Vendor,
+    /// BARs as decoded during enumeration
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDeviceInfo {
+    /// Iterates over the capabilities in the header read during enumeration, along with the
+    /// offset of each in configuration space, for use with [`read_config`] and
+    /// [`write_config`]. Capabilities that can't be parsed are skipped.
+    pub fn capabilities(&self) -> impl Iterator<Item = (usize, CapabilityKind<'_>)> + '_ {
+        let caps = (self.header.capabilities_pointer != 0).then(|| {
+            Capabilities::new(&self.raw_header[DDR_OFFSET..ECS_OFFSET], &self.header)
+                .filter_map(|cap| cap.ok())
+                .map(|cap| (cap.pointer as usize, cap.kind))
+        });
+
+        caps.into_iter().flatten()
+    }
+}
+
 pub struct PciTable {
     // TODO: BTreeMap
     pub devices: Vec<PciDevice>,
-    pub raw_headers: Vec<[u8; ECS_OFFSET]>,
-    pub headers: Vec<Header>,
+    /// Every function found on the bus, keyed by location
+    pub functions: BTreeMap<Bdf, PciDeviceInfo>,
 }
 
 impl PciTable {
     const fn new() -> Self {
         Self {
             devices: Vec::new(),
-            raw_headers: Vec::new(),
-            headers: Vec::new(),
+            functions: BTreeMap::new(),
         }
     }
 
-    pub fn register_headers(&mut self, raw: [u8; ECS_OFFSET], header: Header) {
-        self.raw_headers.push(raw);
-        self.headers.push(header);
+    pub fn register_function(&mut self, info: PciDeviceInfo) {
+        self.functions.insert(info.bdf, info);
+    }
+
+    pub fn get(&self, bdf: Bdf) -> Option<&PciDeviceInfo> {
+        self.functions.get(&bdf)
+    }
+
+    /// All functions of the given kind, in BDF order
+    pub fn find_by_kind(&self, kind: DeviceKind) -> impl Iterator<Item = &PciDeviceInfo> {
+        self.functions
+            .values()
+            .filter(move |info| info.kind == kind)
+    }
+
+    /// All functions matching the raw vendor and device IDs, in BDF order
+    pub fn find_by_vendor_device(
+        &self,
+        vendor_id: u16,
+        device_id: u16,
+    ) -> impl Iterator<Item = &PciDeviceInfo> {
+        self.functions.values().filter(move |info| {
+            info.header.vendor_id == vendor_id && info.header.device_id == device_id
+        })
+    }
+
+    /// Reads the 32-bit register at `offset` of a registered function, or `None` if there's
+    /// no function at `bdf`
+    pub fn config_read32(&self, bdf: Bdf, offset: usize) -> Option<u32> {
+        assert!(
+            offset % 4 == 0,
+            "PCI: unaligned config space read at {:#x}",
+            offset
+        );
+        let info = self.get(bdf)?;
+
+        Some(match info.config_space {
+            Some(base) if offset < CONFIG_SPACE_SIZE => unsafe {
+                core::ptr::read_volatile((base + offset as u64).as_ptr::<u32>())
+            },
+            Some(_) => u32::MAX,
+            None => ConfigAccess::PortIo.read32(bdf, offset),
+        })
+    }
+
+    /// Writes the 32-bit register at `offset` of a registered function. Returns `false` if
+    /// there's no function at `bdf`.
+    pub fn config_write32(&self, bdf: Bdf, offset: usize, value: u32) -> bool {
+        assert!(
+            offset % 4 == 0,
+            "PCI: unaligned config space write at {:#x}",
+            offset
+        );
+        let Some(info) = self.get(bdf) else {
+            return false;
+        };
+
+        match info.config_space {
+            Some(base) if offset < CONFIG_SPACE_SIZE => unsafe {
+                core::ptr::write_volatile((base + offset as u64).as_mut_ptr::<u32>(), value)
+            },
+            Some(_) => {}
+            None => ConfigAccess::PortIo.write32(bdf, offset, value),
+        }
+
+        true
     }
 }
 
 pub fn register_device_driver(handle: Arc<dyn FOSSPciDeviceHandle>) {
-    PCI_TABLE.write().devices.push(PciDevice { handle });
-    unsafe {
-        *(PCI_DRIVER_COUNT.as_ptr()) = PCI_TABLE.read().devices.len();
+    PCI_TABLE.write().devices.push(PciDevice {
+        handle: handle.clone(),
+    });
+    PCI_DRIVER_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    // Drivers registered during enumeration are matched as each function is probed, later
+    // ones have to be matched against everything found so far
+    if PCI_INITIALIZED.load(Ordering::Acquire) {
+        let bdfs = PCI_TABLE
+            .read()
+            .functions
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+
+        for bdf in bdfs {
+            start_drivers(bdf, core::slice::from_ref(&handle));
+        }
     }
 }
 
 /// Lookup and initialize all PCI devices.
 pub fn init(tables: &AcpiTables<KernelAcpi>) {
-    // Check if the MCFG table is avaliable.
-    if get_mcfg().is_some() {
-        // Initialize AML table only once, not multiple times
-        aml_init(tables);
-        /*
-         * Use the brute force method to go through each possible bus,
-         * device, function ID and check if we have a driver for it. If a driver
-         * for the PCI device is found then initialize it.
-         */
-        for dev in mcfg_brute_force() {
-            let test_page = Page::<Size4KiB>::containing_address(VirtAddr::new(dev));
-            let virt = test_page.start_address().as_u64() + get_phys_offset();
+    let access = ConfigAccess::current();
 
-            map_page!(
-                dev,
-                virt,
-                Size4KiB,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::NO_CACHE
-                    | PageTableFlags::WRITE_THROUGH
-            );
-
-            let raw_header = unsafe { *(virt as *const [u8; ECS_OFFSET]) };
-            let header_addr = virt;
-
-            // borrow checker
-            let raw_clone = raw_header;
+    if access == ConfigAccess::PortIo {
+        info!("PCI: no MCFG table, using port I/O configuration access");
+    }
 
-            let mut header = Header::try_from(raw_header.as_slice()).unwrap();
+    // Initialize AML table only once, not multiple times
+    aml_init(tables);
 
-            // borrow checker
-            let header_clone = header.clone();
+    // The functions found below get their interrupts routed through the I/O APICs
+    debug!("Loading IDT...");
+    crate::arch::x86_64::interrupts::init();
 
-            PCI_TABLE.write().register_headers(raw_clone, header_clone);
+    if !APIC_IS_INITIALIZED.load(Ordering::Relaxed) {
+        debug!("Setting up interrupts...");
+        init_all_available_apics();
 
-            let _ = aml_route(&header);
+        info!("Local APIC ID: {:#?}", local_apic_id());
+    }
+    /*
+     * Walk the bus hierarchy to find every function and check if we have
+     * a driver for it. If a driver for the PCI device is found then
+     * initialize it.
+     */
+    let stopwatch = Stopwatch::start();
 
-            let kind = DeviceKind::new(header.class_code.base as u32, header.class_code.sub as u32);
+    let found = enumerate_devices().collect::<Vec<_>>();
+    reserve_bar_window(&found);
 
-            info!(
-                "PCI device {:04x?}:{:04x?} (device={:?}, vendor={:?}) with capabilities pointer {:#x?}",
-                header.vendor_id,
-                header.device_id,
-                kind,
-                Vendor::new(header.vendor_id as u32),
-                header.capabilities_pointer
-            );
+    for dev in found {
+        probe_function(access, dev);
+    }
 
-            if let DeviceKind::SataController = kind {
-                ahci_init();
-            }
+    info!(
+        "PCI: enumeration took {}us, {} config space pages mapped",
+        stopwatch.elapsed_micros(),
+        ECAM_PAGES.read().len()
+    );
 
-            // borrow checker
-            let raw_clone_2 = raw_header;
-            let header_clone_2 = Header::try_from(raw_clone_2.as_slice()).unwrap();
+    PCI_INITIALIZED.store(true, Ordering::Release);
+}
 
-            debug!("Interrupt pin: {:#?}", header.interrupt_pin);
+/// Re-walks the bus after boot, probing functions that appeared since the last walk and
+/// forgetting the ones that are gone
+pub fn rescan() {
+    let access = ConfigAccess::current();
+    let found = enumerate_devices().collect::<Vec<_>>();
+    let known = PCI_TABLE
+        .read()
+        .functions
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+
+    for bdf in known.iter().filter(|bdf| !found.contains(bdf)) {
+        info!("PCI: function at {} was removed", bdf);
+        PCI_TABLE.write().functions.remove(bdf);
+    }
 
-            let caps = if header.capabilities_pointer != 0 {
-                Some(
-                    Capabilities::new(&raw_clone_2[DDR_OFFSET..ECS_OFFSET], &header_clone_2)
-                        .map(|cap| cap.ok()),
-                )
-            } else {
-                None
-            };
+    for dev in found.into_iter().filter(|bdf| !known.contains(bdf)) {
+        info!("PCI: found new function at {}", dev);
+        probe_function(access, dev);
+    }
+}
 
-            let msix = caps.and_then(|caps| {
-                caps.flatten()
-                    .find(|cap| matches!(cap.kind, CapabilityKind::MsiX(_)))
-            });
-
-            if let Some(msix) = msix {
-                // Most of this was learned from studying Aero's implementation:
-                // https://github.com/Andy-Python-Programmer/aero/blob/master/src/aero_kernel/src/drivers/pci.rs#L99
-                if let CapabilityKind::MsiX(mut msix) = msix.kind {
-                    let mut msg_control = msix.message_control.clone();
-
-                    let table = msix.clone().table;
-                    let table_len = msg_control.table_size as u64;
-
-                    let bir = if let HeaderType::Normal(ref header) = header.header_type {
-                        match msix.table.bir {
-                            Bir::Bar10h => header.base_addresses.orig()[0] as u64,
-                            Bir::Bar14h => header.base_addresses.orig()[1] as u64,
-                            Bir::Bar18h => header.base_addresses.orig()[2] as u64,
-                            Bir::Bar1Ch => header.base_addresses.orig()[3] as u64,
-                            Bir::Bar20h => header.base_addresses.orig()[4] as u64,
-                            Bir::Bar24h => header.base_addresses.orig()[5] as u64,
-                            Bir::Reserved(err) => panic!("Invalid BAR: {}", err),
-                        }
-                    } else {
-                        0
-                    };
+/// Registers the function at `dev`, sets up its interrupts and starts the drivers that
+/// handle it
+fn probe_function(access: ConfigAccess, dev: Bdf) {
+    let raw_header = access.read_header(dev);
+
+    let mut header = Header::try_from(raw_header.as_slice()).unwrap();
+
+    let kind = DeviceKind::new(header.class_code.base as u32, header.class_code.sub as u32);
+    let mut bars = decode_bars(&header, dev);
+    assign_bars(dev, &mut bars);
+
+    PCI_TABLE.write().register_function(PciDeviceInfo {
+        bdf: dev,
+        address: ecam_address(dev).filter(|_| access == ConfigAccess::Ecam),
+        config_space: ConfigAccess::ecam_register(dev, 0)
+            .filter(|_| access == ConfigAccess::Ecam)
+            .map(VirtAddr::new),
+        raw_header,
+        header: header.clone(),
+        kind,
+        vendor: Vendor::new(header.vendor_id as u32),
+        bars,
+    });
+
+    info!(
+        "PCI device {:04x?}:{:04x?} at {} (device={:?}, vendor={:?}) with capabilities pointer {:#x?}",
+        header.vendor_id,
+        header.device_id,
+        dev,
+        kind,
+        Vendor::new(header.vendor_id as u32),
+        header.capabilities_pointer
+    );
 
-                    let bar_offset = table.offset as u64;
+    let ahci_controller = is_ahci(kind, header.vendor_id, header.device_id);
 
-                    let msg_table = unsafe {
-                        core::slice::from_raw_parts_mut::<'static>(
-                            (header_addr + bir + bar_offset) as *mut Message,
-                            table_len as usize,
-                        )
-                    }
-                    .iter_mut();
+    if ahci_controller {
+        ahci_init();
+    }
 
-                    msg_control.msi_x_enable = true;
-                    msg_control.function_mask = false;
+    if let Some(virtio) = virtio::device_type(header.vendor_id, header.device_id) {
+        info!("virtio {:?} device at {}", virtio, dev);
+    }
 
-                    // Disable legacy interrupts
-                    header.command.interrupt_disable = true;
-                    msix.message_control = msg_control;
+    // borrow checker
+    let raw_clone_2 = raw_header;
+    let header_clone_2 = Header::try_from(raw_clone_2.as_slice()).unwrap();
 
-                    info!("MSI-X: {:#?}", msix);
+    debug!("Interrupt pin: {:#?}", header.interrupt_pin);
 
-                    for entry in msg_table {
-                        let irq = irqalloc();
-                        entry.route_irq(irq, IrqMode::Fixed);
+    let caps = if header.capabilities_pointer != 0 {
+        Some(
+            Capabilities::new(&raw_clone_2[DDR_OFFSET..ECS_OFFSET], &header_clone_2)
+                .map(|cap| cap.ok()),
+        )
+    } else {
+        None
+    };
+
+    let msix = caps.and_then(|caps| {
+        caps.flatten()
+            .find(|cap| matches!(cap.kind, CapabilityKind::MsiX(_)))
+    });
+
+    if let Some(msix) = msix {
+        // Most of this was learned from studying Aero's implementation:
+        // https://github.com/Andy-Python-Programmer/aero/blob/master/src/aero_kernel/src/drivers/pci.rs#L99
+        if let CapabilityKind::MsiX(mut msix) = msix.kind {
+            let mut msg_control = msix.message_control.clone();
+
+            let table = msix.clone().table;
+            // Table Size is encoded as N-1
+            let table_len = msg_control.table_size as u64 + 1;
+
+            let bir = match msix.table.bir {
+                Bir::Bar10h => 0,
+                Bir::Bar14h => 1,
+                Bir::Bar18h => 2,
+                Bir::Bar1Ch => 3,
+                Bir::Bar20h => 4,
+                Bir::Bar24h => 5,
+                Bir::Reserved(err) => panic!("Invalid BAR: {}", err),
+            };
 
-                        // TODO: split this into different interrupts depending on device functionality
-                        register_handler(irq, msi_x);
-                    }
+            let bar_offset = table.offset as u64;
+            let table_bytes = table_len * core::mem::size_of::<Message>() as u64;
+
+            // The table can't be reached while the function is in D3
+            set_power_state(dev, PowerState::D0);
+
+            // The table lives in one of the device's memory BARs, and has to fit inside it
+            let msg_table = bars[bir]
+                .filter(|bar| bar_offset + table_bytes <= bar.size)
+                .and_then(|bar| bar.map())
+                .map(|bar| unsafe {
+                    core::slice::from_raw_parts_mut::<'static>(
+                        (bar.as_u64() + bar_offset) as *mut Message,
+                        table_len as usize,
+                    )
+                });
+
+            // Register the driver first, so it can claim its entries
+            if let DeviceKind::UsbController = kind {
+                xhci_init();
+            }
 
-                    if let DeviceKind::UsbController = kind {
-                        xhci_init();
+            if let Some(msg_table) = msg_table {
+                let claimed = {
+                    let table = PCI_TABLE.read();
+                    let device = table.get(dev);
+
+                    table
+                        .devices
+                        .iter()
+                        .filter(|driver| device.is_some_and(|device| driver.handle.handles(device)))
+                        .flat_map(|driver| driver.handle.msix_vectors(dev, table_len as u16))
+                        .collect::<Vec<_>>()
+                };
+
+                for (i, entry) in msg_table.iter_mut().enumerate() {
+                    match claimed.iter().find(|(index, ..)| *index as usize == i) {
+                        Some(&(_, handler, ctx)) => {
+                            let Some(irq) = irqalloc() else {
+                                warn!("MSI-X: out of vectors for entry {} of {}", i, dev);
+                                entry.set_mask(true);
+                                continue;
+                            };
+
+                            register_irq(irq, handler, ctx, Some("msi-x"));
+
+                            entry.route_irq(irq, IrqMode::Fixed);
+                            entry.set_mask(false);
+                        }
+                        None => entry.set_mask(true),
                     }
                 }
-            }
 
-            for driver in &mut PCI_TABLE.write().devices {
-                // can't declare these earlier than this without pissing off the borrow checker
+                msg_control.msi_x_enable = true;
+                msg_control.function_mask = false;
+
+                // Disable legacy interrupts
+                header.command.interrupt_disable = true;
+                msix.message_control = msg_control;
+
+                if let Some(cap) = find_capability(&raw_header, MSIX_CAPABILITY_ID) {
+                    let mut control = access.read16(dev, cap + 2);
+                    control.set_bit(15, true);
+                    control.set_bit(14, false);
+                    access.write16(dev, cap + 2, control);
 
-                if driver.handle.handles(
-                    Vendor::new(header.vendor_id as u32),
-                    DeviceKind::new(header.class_code.base as u32, header.class_code.sub as u32),
-                ) {
-                    driver.handle.start(&mut header);
+                    write_command(dev, read_command(dev) | PciCommand::INTERRUPT_DISABLE);
                 }
+
+                info!(
+                    "MSI-X: {} of {} entries claimed for {}: {:#?}",
+                    claimed.len(),
+                    table_len,
+                    dev,
+                    msix
+                );
+            } else {
+                warn!(
+                    "MSI-X: table of {} ({} entries at {:#x}) doesn't fit in memory BAR {}",
+                    dev, table_len, bar_offset, bir
+                );
             }
         }
-    } else {
-        panic!("MCFG table not present");
+    } else if let Some(cap) = find_capability(&raw_header, MSI_CAPABILITY_ID) {
+        // MSI-X is preferred when a device has both
+        enable_msi(dev, cap, ahci_controller);
+    } else if raw_header[INTERRUPT_PIN_OFFSET] != 0 {
+        // Neither is supported, so fall back to the legacy pin
+        match route_intx(dev, raw_header[INTERRUPT_PIN_OFFSET]) {
+            Some(gsi) => info!("PCI: {} raises INTx on GSI {}", dev, gsi),
+            None => warn!("PCI: couldn't route the INTx pin of {}", dev),
+        }
+    }
+
+    if let Some(device) = PCI_TABLE.write().functions.get_mut(&dev) {
+        device.header = header;
+    }
+
+    let handles = PCI_TABLE
+        .read()
+        .devices
+        .iter()
+        .map(|driver| driver.handle.clone())
+        .collect::<Vec<_>>();
+
+    start_drivers(dev, &handles);
+}
+
+/// Starts every driver in `handles` that handles the function at `dev`
+fn start_drivers(dev: Bdf, handles: &[Arc<dyn FOSSPciDeviceHandle>]) {
+    // Drivers get a copy, so the table isn't locked while they start
+    let Some(mut device) = PCI_TABLE.read().get(dev).cloned() else {
+        return;
+    };
+
+    for handle in handles {
+        if handle.handles(&device) {
+            handle.start(&mut device);
+        }
     }
+
+    PCI_TABLE.write().register_function(device);
 }
 
-extern "x86-interrupt" fn msi_x(_: InterruptStackFrame) {
-    info!("MSI-X interrupt");
+fn msi(vector: u8, _: *mut ()) {
+    info!("MSI interrupt on vector {}", vector);
 }
@@ -0,0 +1,448 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! PCI function addresses and the walk over the bus hierarchy
+
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use super::pci_ids::DeviceKind;
+
+/// Segment, bus, device and function number of a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bdf {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Bdf {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl core::fmt::Display for Bdf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// What the walk needs to know about a function
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScannedFunction {
+    pub kind: DeviceKind,
+    /// Whether functions 1-7 of the device may exist, from bit 7 of the header type
+    pub multi_function: bool,
+    /// Secondary and subordinate bus number, if the function has a type 1 header
+    pub bridge: Option<(u8, u8)>,
+}
+
+/// State of a walk over the bus hierarchy. `probe` reads the function at an address, or
+/// returns `None` if there's nothing there.
+pub(crate) struct BusScan<P> {
+    probe: P,
+    /// Functions found, except those of unknown kind
+    pub found: Vec<Bdf>,
+    /// PCI-to-PCI bridges walked, along with their secondary bus
+    pub bridges: Vec<(Bdf, u8)>,
+    /// Bridges whose bus range doesn't lie below their own bus, along with that range
+    pub misconfigured: Vec<(Bdf, RangeInclusive<u8>)>,
+    /// Buses already walked, as `(segment, bus)`
+    buses: Vec<(u16, u8)>,
+}
+
+impl<P> BusScan<P>
+where
+    P: FnMut(Bdf) -> Option<ScannedFunction>,
+{
+    pub(crate) fn new(probe: P) -> Self {
+        Self {
+            probe,
+            found: Vec::new(),
+            bridges: Vec::new(),
+            misconfigured: Vec::new(),
+            buses: Vec::new(),
+        }
+    }
+
+    pub(crate) fn scan_bus(&mut self, segment: u16, bus: u8) {
+        if self.buses.contains(&(segment, bus)) {
+            return;
+        }
+
+        self.buses.push((segment, bus));
+
+        for device in 0..32 {
+            // Functions 1-7 only exist if function 0 says the device has several
+            if let Some(true) = self.scan_function(Bdf::new(segment, bus, device, 0)) {
+                for function in 1..8 {
+                    self.scan_function(Bdf::new(segment, bus, device, function));
+                }
+            }
+        }
+    }
+
+    /// Probes a single function, walking the buses behind it if it's a bridge. Returns
+    /// whether the function belongs to a multi-function device, or `None` if there's nothing
+    /// there.
+    fn scan_function(&mut self, bdf: Bdf) -> Option<bool> {
+        let function = (self.probe)(bdf)?;
+
+        // Identical devices at different addresses are separate hardware, but misconfigured
+        // bridges can lead us to the same function twice
+        if self.found.contains(&bdf) {
+            return Some(function.multi_function);
+        }
+
+        // don't push unknown devices
+        if function.kind != DeviceKind::Unknown {
+            self.found.push(bdf);
+        }
+
+        let is_bridge = matches!(
+            function.kind,
+            DeviceKind::PciPciBridge | DeviceKind::SemiTransparentPciPciBridge
+        );
+
+        if let (true, Some((secondary, subordinate))) = (is_bridge, function.bridge) {
+            // Bus numbers only grow downstream, anything else is a misconfigured bridge
+            if secondary <= bdf.bus || subordinate < secondary {
+                self.misconfigured.push((bdf, secondary..=subordinate));
+            } else {
+                self.bridges.push((bdf, secondary));
+
+                // Nested bridges are found on the secondary bus, but walk the whole range in
+                // case one of them couldn't be parsed
+                for bus in secondary..=subordinate {
+                    self.scan_bus(bdf.segment, bus);
+                }
+            }
+        }
+
+        Some(function.multi_function)
+    }
+}
+
+/// Follows INTx pin `pin` (0 for INTA) of the function at `bdf` up through the bridges `parent`
+/// leads to, until `table` finds the interrupt routing table (`_PRT`) of a bus on the way.
+/// Returns that table along with the function and pin to look up in it. Bridges don't decode
+/// function numbers, so only the device number rotates the pin on the way up.
+pub fn swizzle_to_table<T>(
+    bdf: Bdf,
+    pin: usize,
+    mut table: impl FnMut(Bdf) -> Option<T>,
+    mut parent: impl FnMut(Bdf) -> Option<Bdf>,
+) -> Option<(T, Bdf, usize)> {
+    let (mut at, mut pin) = (bdf, pin);
+
+    loop {
+        if let Some(table) = table(at) {
+            return Some((table, at, pin));
+        }
+
+        pin = (pin + at.device as usize) % 4;
+        at = parent(at)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    /// A machine's functions, by address
+    #[derive(Default)]
+    struct Topology(BTreeMap<Bdf, ScannedFunction>);
+
+    impl Topology {
+        fn add(&mut self, bdf: Bdf, kind: DeviceKind) -> &mut Self {
+            self.0.insert(
+                bdf,
+                ScannedFunction {
+                    kind,
+                    multi_function: false,
+                    bridge: None,
+                },
+            );
+            self
+        }
+
+        fn add_bridge(&mut self, bdf: Bdf, secondary: u8, subordinate: u8) -> &mut Self {
+            self.0.insert(
+                bdf,
+                ScannedFunction {
+                    kind: DeviceKind::PciPciBridge,
+                    multi_function: false,
+                    bridge: Some((secondary, subordinate)),
+                },
+            );
+            self
+        }
+
+        /// Walks segment 0 from bus 0, returning the functions found and the number of
+        /// times each address was probed
+        fn scan(&self) -> (Vec<Bdf>, BTreeMap<Bdf, usize>) {
+            let mut probes = BTreeMap::new();
+
+            let found = {
+                let mut scan = BusScan::new(|bdf| {
+                    *probes.entry(bdf).or_insert(0) += 1;
+                    self.0.get(&bdf).copied()
+                });
+
+                scan.scan_bus(0, 0);
+                scan.found
+            };
+
+            (found, probes)
+        }
+    }
+
+    #[test]
+    fn identical_devices_at_different_addresses_are_all_found() {
+        let mut topology = Topology::default();
+        topology
+            .add(Bdf::new(0, 0, 0x1F, 0), DeviceKind::SataController)
+            .add(Bdf::new(0, 0, 0x03, 0), DeviceKind::SataController)
+            .add(Bdf::new(0, 0, 0x04, 0), DeviceKind::UsbController);
+
+        let (found, _) = topology.scan();
+
+        assert_eq!(
+            found,
+            [
+                Bdf::new(0, 0, 0x03, 0),
+                Bdf::new(0, 0, 0x04, 0),
+                Bdf::new(0, 0, 0x1F, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_functions_are_left_out() {
+        let mut topology = Topology::default();
+        topology
+            .add(Bdf::new(0, 0, 1, 0), DeviceKind::Unknown)
+            .add(Bdf::new(0, 0, 2, 0), DeviceKind::SataController);
+
+        assert_eq!(topology.scan().0, [Bdf::new(0, 0, 2, 0)]);
+    }
+
+    #[test]
+    fn buses_reached_twice_are_walked_once() {
+        // Two bridges claiming the same bus
+        let mut topology = Topology::default();
+        topology
+            .add_bridge(Bdf::new(0, 0, 1, 0), 1, 1)
+            .add_bridge(Bdf::new(0, 0, 2, 0), 1, 1)
+            .add(Bdf::new(0, 1, 0, 0), DeviceKind::SataController);
+
+        let (found, probes) = topology.scan();
+
+        assert_eq!(
+            found,
+            [
+                Bdf::new(0, 0, 1, 0),
+                Bdf::new(0, 1, 0, 0),
+                Bdf::new(0, 0, 2, 0),
+            ]
+        );
+        assert_eq!(probes[&Bdf::new(0, 1, 0, 0)], 1);
+    }
+
+    #[test]
+    fn functions_1_to_7_need_a_multi_function_device() {
+        let mut topology = Topology::default();
+        topology
+            .add(Bdf::new(0, 0, 1, 0), DeviceKind::SataController)
+            .add(Bdf::new(0, 0, 1, 2), DeviceKind::UsbController);
+
+        let (found, probes) = topology.scan();
+
+        assert_eq!(found, [Bdf::new(0, 0, 1, 0)]);
+        assert!(!probes.contains_key(&Bdf::new(0, 0, 1, 1)));
+
+        topology
+            .0
+            .get_mut(&Bdf::new(0, 0, 1, 0))
+            .unwrap()
+            .multi_function = true;
+
+        assert_eq!(
+            topology.scan().0,
+            [Bdf::new(0, 0, 1, 0), Bdf::new(0, 0, 1, 2)]
+        );
+    }
+
+    #[test]
+    fn the_whole_bus_range_behind_a_bridge_is_walked() {
+        // A root port forwarding to buses 1..=3, where the switch on bus 1 couldn't be parsed
+        let mut topology = Topology::default();
+        topology
+            .add_bridge(Bdf::new(0, 0, 0x1C, 0), 1, 3)
+            .add(Bdf::new(0, 1, 0, 0), DeviceKind::Unknown)
+            .add(Bdf::new(0, 3, 0, 0), DeviceKind::NvmeController);
+
+        let mut scan = BusScan::new(|bdf| topology.0.get(&bdf).copied());
+        scan.scan_bus(0, 0);
+
+        assert_eq!(scan.found, [Bdf::new(0, 0, 0x1C, 0), Bdf::new(0, 3, 0, 0)]);
+        assert_eq!(scan.bridges, [(Bdf::new(0, 0, 0x1C, 0), 1)]);
+    }
+
+    #[test]
+    fn nested_bridges_are_followed() {
+        let mut topology = Topology::default();
+        topology
+            .add_bridge(Bdf::new(0, 0, 1, 0), 1, 4)
+            .add_bridge(Bdf::new(0, 1, 0, 0), 2, 4)
+            .add_bridge(Bdf::new(0, 2, 1, 0), 3, 3)
+            .add_bridge(Bdf::new(0, 2, 2, 0), 4, 4)
+            .add(Bdf::new(0, 3, 0, 0), DeviceKind::SataController)
+            .add(Bdf::new(0, 4, 0, 0), DeviceKind::SataController);
+
+        let (found, probes) = topology.scan();
+
+        assert!(found.contains(&Bdf::new(0, 3, 0, 0)));
+        assert!(found.contains(&Bdf::new(0, 4, 0, 0)));
+        assert_eq!(found.len(), 6);
+
+        // Every bus is walked once, even though each bridge covers the ones below it
+        assert_eq!(probes[&Bdf::new(0, 4, 0, 0)], 1);
+    }
+
+    #[test]
+    fn bridges_not_forwarding_downstream_are_skipped() {
+        let mut topology = Topology::default();
+        topology
+            .add_bridge(Bdf::new(0, 0, 1, 0), 2, 2)
+            // Points back up at bus 0
+            .add_bridge(Bdf::new(0, 2, 0, 0), 0, 0)
+            // Subordinate bus below the secondary one
+            .add_bridge(Bdf::new(0, 2, 1, 0), 5, 4)
+            .add(Bdf::new(0, 4, 0, 0), DeviceKind::SataController)
+            .add(Bdf::new(0, 5, 0, 0), DeviceKind::SataController);
+
+        let mut scan = BusScan::new(|bdf| topology.0.get(&bdf).copied());
+        scan.scan_bus(0, 0);
+
+        assert_eq!(
+            scan.misconfigured
+                .iter()
+                .map(|(bdf, buses)| (*bdf, *buses.start(), *buses.end()))
+                .collect::<Vec<_>>(),
+            [(Bdf::new(0, 2, 0, 0), 0, 0), (Bdf::new(0, 2, 1, 0), 5, 4)]
+        );
+        assert_eq!(
+            scan.found,
+            [
+                Bdf::new(0, 0, 1, 0),
+                Bdf::new(0, 2, 0, 0),
+                Bdf::new(0, 2, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn type_1_headers_of_other_kinds_are_not_followed() {
+        let mut topology = Topology::default();
+        topology.add(Bdf::new(0, 0, 1, 0), DeviceKind::SataController);
+        topology.add(Bdf::new(0, 1, 0, 0), DeviceKind::SataController);
+        topology.0.get_mut(&Bdf::new(0, 0, 1, 0)).unwrap().bridge = Some((1, 1));
+
+        assert_eq!(topology.scan().0, [Bdf::new(0, 0, 1, 0)]);
+    }
+
+    #[test]
+    fn bdfs_are_displayed_like_lspci() {
+        assert_eq!(
+            alloc::format!("{}", Bdf::new(0, 0x1F, 2, 1)),
+            "0000:1f:02.1"
+        );
+    }
+
+    /// Follows `pin` of `bdf` through the bridges leading to the buses in `bridges`, up to one
+    /// of the buses in `tables`. Returns the bus whose table is used, the function looked up
+    /// in it and the pin.
+    fn swizzle(
+        bdf: Bdf,
+        pin: usize,
+        tables: &[u8],
+        bridges: &[(u8, Bdf)],
+    ) -> Option<(u8, Bdf, usize)> {
+        swizzle_to_table(
+            bdf,
+            pin,
+            |at| tables.contains(&at.bus).then_some(at.bus),
+            |at| {
+                bridges
+                    .iter()
+                    .find(|(bus, _)| *bus == at.bus)
+                    .map(|(_, bridge)| *bridge)
+            },
+        )
+    }
+
+    #[test]
+    fn functions_on_a_bus_with_a_table_are_looked_up_as_they_are() {
+        let bdf = Bdf::new(0, 0, 0x1F, 2);
+
+        assert_eq!(swizzle(bdf, 1, &[0], &[]), Some((0, bdf, 1)));
+    }
+
+    #[test]
+    fn pins_rotate_by_the_device_number_behind_a_bridge() {
+        let root_port = Bdf::new(0, 0, 0x1C, 0);
+        let bridges = [(2, root_port)];
+
+        // INTB of device 3 arrives at the root port as INTA, whatever the function
+        for function in 0..8 {
+            assert_eq!(
+                swizzle(Bdf::new(0, 2, 3, function), 1, &[0], &bridges),
+                Some((0, root_port, 0))
+            );
+        }
+
+        assert_eq!(
+            swizzle(Bdf::new(0, 2, 0, 0), 3, &[0], &bridges),
+            Some((0, root_port, 3))
+        );
+    }
+
+    #[test]
+    fn nested_bridges_rotate_the_pin_at_every_level() {
+        let root_port = Bdf::new(0, 0, 0x1C, 0);
+        let switch = Bdf::new(0, 2, 1, 0);
+        let bridges = [(2, root_port), (3, switch)];
+
+        // INTD of device 2 on bus 3 is INTB at the switch and INTC at the root port
+        assert_eq!(
+            swizzle(Bdf::new(0, 3, 2, 0), 3, &[0], &bridges),
+            Some((0, root_port, 2))
+        );
+
+        // A bus with a table of its own ends the walk there
+        assert_eq!(
+            swizzle(Bdf::new(0, 3, 2, 0), 3, &[0, 2], &bridges),
+            Some((2, switch, 1))
+        );
+    }
+
+    #[test]
+    fn functions_without_a_table_above_are_not_routed() {
+        let bridges = [(2, Bdf::new(0, 0, 0x1C, 0))];
+
+        assert_eq!(swizzle(Bdf::new(0, 2, 0, 0), 0, &[], &bridges), None);
+        assert_eq!(swizzle(Bdf::new(0, 5, 0, 0), 0, &[0], &bridges), None);
+    }
+}
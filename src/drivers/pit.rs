@@ -0,0 +1,161 @@
+//! 8254 programmable interval timer
+//!
+//! The PIT counts down at a fixed 1.193182MHz, which makes it a reference for calibrating the
+//! other timers when there's neither an HPET nor an ACPI PM timer. Only channel 2 is used: its
+//! gate and output are wired to port 0x61 instead of an interrupt line, so it can be polled
+//! with the 8259 PICs disabled. Channel 0 is left as the firmware programmed it.
+
+use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+
+/// Frequency the channels count down at
+pub const PIT_HZ: u64 = 1_193_182;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// NMI status and control register, holding the gate and output of channel 2
+const PORT_B: u16 = 0x61;
+
+/// Port B: counting on channel 2 is enabled
+const PORT_B_GATE_2: u8 = 1 << 0;
+/// Port B: channel 2 drives the PC speaker
+const PORT_B_SPEAKER: u8 = 1 << 1;
+/// Port B: output of channel 2, high once a one-shot count ran out
+const PORT_B_OUT_2: u8 = 1 << 5;
+
+/// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary
+const COMMAND_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+/// Latches the count of channel 2, so both bytes read belong together
+const COMMAND_CHANNEL_2_LATCH: u8 = 0b1000_0000;
+
+/// Highest count a channel takes, about 54.9ms
+const MAX_COUNT: u16 = u16::MAX;
+
+/// Polls of the output before the PIT is given up on while probing, far longer than the
+/// count used for it takes
+const PROBE_POLLS: usize = 100_000;
+
+/// Serializes access to channel 2 and port B
+static PIT: Mutex<()> = Mutex::new(());
+
+static PRESENT: Once<bool> = Once::new();
+
+fn read_port_b() -> u8 {
+    unsafe { Port::<u8>::new(PORT_B).read() }
+}
+
+fn write_port_b(value: u8) {
+    unsafe { Port::<u8>::new(PORT_B).write(value) };
+}
+
+fn output_high() -> bool {
+    read_port_b() & PORT_B_OUT_2 != 0
+}
+
+/// Loads `count` into channel 2 in one-shot mode and opens its gate, with the speaker
+/// disconnected. The output goes low until the count runs out.
+fn start(count: u16) {
+    write_port_b(read_port_b() & !(PORT_B_GATE_2 | PORT_B_SPEAKER));
+
+    unsafe {
+        Port::<u8>::new(COMMAND).write(COMMAND_CHANNEL_2_ONE_SHOT);
+
+        let mut channel = Port::<u8>::new(CHANNEL_2);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+    }
+
+    write_port_b(read_port_b() | PORT_B_GATE_2);
+}
+
+fn stop() {
+    write_port_b(read_port_b() & !PORT_B_GATE_2);
+}
+
+/// Current count of channel 2
+fn read_count() -> u16 {
+    unsafe {
+        Port::<u8>::new(COMMAND).write(COMMAND_CHANNEL_2_LATCH);
+
+        let mut channel = Port::<u8>::new(CHANNEL_2);
+        let low = channel.read() as u16;
+        let high = channel.read() as u16;
+
+        high << 8 | low
+    }
+}
+
+fn ns_to_count(ns: u64) -> u64 {
+    ns * PIT_HZ / 1_000_000_000
+}
+
+fn count_to_ns(count: u64) -> u64 {
+    count * 1_000_000_000 / PIT_HZ
+}
+
+/// Whether channel 2 counts down. Machines without the legacy ports read port B as all ones,
+/// so the output never goes low after a count is loaded.
+fn probe() -> bool {
+    let _pit = PIT.lock();
+
+    // About 100µs
+    start(120);
+
+    let counting = !output_high()
+        && (0..PROBE_POLLS).any(|_| {
+            core::hint::spin_loop();
+            output_high()
+        });
+
+    stop();
+    counting
+}
+
+pub fn is_available() -> bool {
+    *PRESENT.call_once(probe)
+}
+
+/// Spins for at least `ms` milliseconds. Returns immediately without a PIT.
+pub fn wait_ms(ms: u64) {
+    if !is_available() {
+        return;
+    }
+
+    let _pit = PIT.lock();
+    let mut left = ns_to_count(ms * 1_000_000);
+
+    // One count lasts at most MAX_COUNT ticks
+    while left > 0 {
+        let count = left.min(MAX_COUNT as u64);
+
+        start(count as u16);
+
+        while !output_high() {
+            core::hint::spin_loop();
+        }
+
+        left -= count;
+    }
+
+    stop();
+}
+
+/// Runs `f` and measures how long it took, in nanoseconds. The time is `None` without a PIT
+/// or if `f` took longer than a single count lasts, about 54.9ms.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Option<u64>) {
+    if !is_available() {
+        return (f(), None);
+    }
+
+    let _pit = PIT.lock();
+
+    start(MAX_COUNT);
+    let result = f();
+    let remaining = read_count();
+    let overran = output_high();
+    stop();
+
+    let elapsed = (!overran).then(|| count_to_ns((MAX_COUNT - remaining) as u64));
+
+    (result, elapsed)
+}
@@ -0,0 +1,195 @@
+//! Battery status from the ACPI control method battery interface
+//!
+//! Batteries are devices with the `_HID` PNP0C0A. Their static information (`_BIF`) is read
+//! once when they're found, their status (`_BST`) every time it is asked for. Both usually
+//! go through the embedded controller, so any failure just means there's no battery.
+
+use alloc::{string::String, vec::Vec};
+use aml::{AmlContext, AmlName, AmlValue};
+use spin::RwLock;
+
+use crate::acpi_impl::{evaluate_in, find_devices_by_hid, AcpiDevice, AML_CONTEXT};
+
+use log::*;
+
+/// `_HID` of a control method battery
+const BATTERY_HID: &str = "PNP0C0A";
+
+/// `_STA` bit telling whether a battery is inserted
+const STA_BATTERY_PRESENT: u64 = 1 << 4;
+
+/// Value of `_BIF` and `_BST` fields the battery doesn't know
+const UNKNOWN: u64 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerUnit {
+    MilliwattHours,
+    MilliampHours,
+}
+
+/// Static information from `_BIF`
+#[derive(Debug, Clone)]
+pub struct BatteryInfo {
+    pub unit: PowerUnit,
+    pub design_capacity: Option<u64>,
+    pub last_full_capacity: Option<u64>,
+    /// Design voltage in millivolts
+    pub design_voltage: Option<u64>,
+    pub model: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeState {
+    Charging,
+    Discharging,
+    /// Neither charging nor discharging, e.g. full and on AC power
+    Idle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryStatus {
+    /// Remaining charge in percent of the last full charge
+    pub percentage: u8,
+    pub state: ChargeState,
+    /// Whether the battery reports a critical energy level
+    pub critical: bool,
+    /// Estimated minutes until empty while discharging, or until full while charging
+    pub minutes_remaining: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Battery {
+    name: AmlName,
+    info: BatteryInfo,
+}
+
+static BATTERIES: RwLock<Vec<Battery>> = RwLock::new(Vec::new());
+
+/// Whether the battery is inserted; devices without `_STA` are always present
+fn is_present(aml_ctx: &mut AmlContext, device: &AmlName) -> bool {
+    match evaluate_in(aml_ctx, device, "_STA") {
+        Some(value) => value
+            .as_integer(aml_ctx)
+            .is_ok_and(|sta| sta & STA_BATTERY_PRESENT != 0),
+        None => true,
+    }
+}
+
+/// Evaluates `object` of `device` to a package of at least `length` elements
+fn package(
+    aml_ctx: &mut AmlContext,
+    device: &AmlName,
+    object: &str,
+    length: usize,
+) -> Option<Vec<AmlValue>> {
+    match evaluate_in(aml_ctx, device, object)? {
+        AmlValue::Package(elements) if elements.len() >= length => Some(elements),
+        _ => None,
+    }
+}
+
+/// Integer element `index` of a `_BIF` or `_BST` package, `None` if the battery doesn't know it
+fn field(aml_ctx: &AmlContext, elements: &[AmlValue], index: usize) -> Option<u64> {
+    elements
+        .get(index)?
+        .as_integer(aml_ctx)
+        .ok()
+        .filter(|&value| value != UNKNOWN)
+}
+
+fn read_info(aml_ctx: &mut AmlContext, device: &AmlName) -> Option<BatteryInfo> {
+    let bif = package(aml_ctx, device, "_BIF", 13)?;
+
+    let unit = match field(aml_ctx, &bif, 0)? {
+        0 => PowerUnit::MilliwattHours,
+        _ => PowerUnit::MilliampHours,
+    };
+
+    let model = match &bif[9] {
+        AmlValue::String(model) => model.clone(),
+        _ => String::new(),
+    };
+
+    Some(BatteryInfo {
+        unit,
+        design_capacity: field(aml_ctx, &bif, 1),
+        last_full_capacity: field(aml_ctx, &bif, 2),
+        design_voltage: field(aml_ctx, &bif, 4),
+        model,
+    })
+}
+
+/// Finds the batteries in the namespace and reads their `_BIF`. Must run after `aml_init()`.
+pub fn init() {
+    let devices = find_devices_by_hid(BATTERY_HID);
+
+    let Some(aml) = AML_CONTEXT.get() else {
+        return;
+    };
+
+    let mut aml_ctx = aml.write();
+    let mut batteries = BATTERIES.write();
+
+    for AcpiDevice { name, .. } in devices {
+        if !is_present(&mut aml_ctx, &name) {
+            continue;
+        }
+
+        let Some(info) = read_info(&mut aml_ctx, &name) else {
+            warn!("ACPI: battery {} has no usable _BIF", name);
+            continue;
+        };
+
+        info!(
+            "ACPI: battery {} \"{}\", design capacity {:?} {:?}",
+            name, info.model, info.design_capacity, info.unit
+        );
+
+        batteries.push(Battery { name, info });
+    }
+}
+
+/// Static information of the first battery, or `None` if there is none
+pub fn battery_info() -> Option<BatteryInfo> {
+    BATTERIES.read().first().map(|battery| battery.info.clone())
+}
+
+/// Evaluates `_BST` of the first battery. Returns `None` if there is no battery or its status
+/// can't be read.
+pub fn battery_status() -> Option<BatteryStatus> {
+    let batteries = BATTERIES.read();
+    let battery = batteries.first()?;
+
+    let mut aml_ctx = AML_CONTEXT.get()?.write();
+    let bst = package(&mut aml_ctx, &battery.name, "_BST", 4)?;
+
+    let flags = field(&aml_ctx, &bst, 0)?;
+    let rate = field(&aml_ctx, &bst, 1).filter(|&rate| rate > 0);
+    let remaining = field(&aml_ctx, &bst, 2)?;
+    let full = battery
+        .info
+        .last_full_capacity
+        .or(battery.info.design_capacity)
+        .filter(|&full| full > 0)?;
+
+    let state = if flags & 1 != 0 {
+        ChargeState::Discharging
+    } else if flags & 2 != 0 {
+        ChargeState::Charging
+    } else {
+        ChargeState::Idle
+    };
+
+    let minutes_remaining = rate.and_then(|rate| match state {
+        ChargeState::Discharging => Some(remaining * 60 / rate),
+        ChargeState::Charging => Some(full.saturating_sub(remaining) * 60 / rate),
+        ChargeState::Idle => None,
+    });
+
+    Some(BatteryStatus {
+        percentage: (remaining * 100 / full).min(100) as u8,
+        state,
+        critical: flags & 4 != 0,
+        minutes_remaining,
+    })
+}
@@ -0,0 +1,153 @@
+//! CMOS real-time clock
+//!
+//! The RTC keeps the wall-clock time in CMOS registers, in BCD or binary and with a 12- or
+//! 24-hour clock depending on status register B. The firmware may point the FADT at a
+//! register holding the century, otherwise the year is assumed to be in the 2000s.
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{acpi_impl::FADT, fs::hmfs::time_t};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status register A: the RTC is updating its registers
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status register B: hours run from 0 to 23 instead of 1 to 12
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status register B: values are binary instead of BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hours register for PM times in 12-hour mode
+const HOURS_PM: u8 = 1 << 7;
+
+/// Serializes access to the CMOS index port
+static CMOS: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        // Bit 7 of the index port masks NMIs, keep it clear
+        Port::<u8>::new(CMOS_ADDRESS).write(register & 0x7F);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn read_raw(century_register: Option<u8>) -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    RawTime {
+        seconds: read_register(REG_SECONDS),
+        minutes: read_register(REG_MINUTES),
+        hours: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: century_register.map(read_register).unwrap_or(0),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Days between 1970-01-01 and the given date of the proleptic Gregorian calendar
+fn days_since_epoch(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March, so the leap day is the last day of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Reads the wall-clock time as seconds since the Unix epoch, taking the RTC to run on UTC
+pub fn now() -> time_t {
+    let century_register = FADT
+        .get()
+        .map(|fadt| fadt.read().century)
+        .filter(|&register| register != 0);
+
+    let (raw, status_b) = without_interrupts(|| {
+        let _cmos = CMOS.lock();
+
+        // The registers may change between reading two of them, so read until two agree
+        let mut raw = read_raw(century_register);
+
+        loop {
+            let again = read_raw(century_register);
+
+            if again == raw {
+                break;
+            }
+
+            raw = again;
+        }
+
+        (raw, read_register(REG_STATUS_B))
+    });
+
+    let pm = raw.hours & HOURS_PM != 0;
+    let mut raw = RawTime {
+        hours: raw.hours & !HOURS_PM,
+        ..raw
+    };
+
+    if status_b & STATUS_B_BINARY == 0 {
+        raw = RawTime {
+            seconds: from_bcd(raw.seconds),
+            minutes: from_bcd(raw.minutes),
+            hours: from_bcd(raw.hours),
+            day: from_bcd(raw.day),
+            month: from_bcd(raw.month),
+            year: from_bcd(raw.year),
+            century: from_bcd(raw.century),
+        };
+    }
+
+    // 12 AM is midnight, 12 PM is noon
+    if status_b & STATUS_B_24_HOUR == 0 {
+        raw.hours = match (raw.hours, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (hours, false) => hours,
+            (hours, true) => hours + 12,
+        };
+    }
+
+    let century = match raw.century {
+        0 => 20,
+        century => century as i64,
+    };
+
+    let year = century * 100 + raw.year as i64;
+    let days = days_since_epoch(year, raw.month as i64, raw.day as i64);
+
+    let seconds =
+        days * 86_400 + raw.hours as i64 * 3600 + raw.minutes as i64 * 60 + raw.seconds as i64;
+
+    seconds as time_t
+}
@@ -0,0 +1,84 @@
+//! COM1 serial port as an emergency output
+//!
+//! Code that may have interrupted a holder of the logger's locks, like the NMI handler,
+//! can't log the usual way. It writes straight to the UART here instead, which takes no
+//! locks at all, so output of two CPUs writing at once may interleave.
+
+use core::fmt::{self, Write};
+
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+/// Line control: the first two registers hold the baud rate divisor
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+/// Line control: 8 data bits, no parity, one stop bit
+const LINE_CONTROL_8N1: u8 = 0x03;
+/// Line status: the transmitter can take another byte
+const LINE_STATUS_THR_EMPTY: u8 = 1 << 5;
+
+/// Divisor of the 115200 baud base rate
+const BAUD_DIVISOR: u16 = 1;
+
+/// Polls of the line status before a byte is dropped, so a missing UART can't hang the caller
+const TX_SPINS: usize = 100_000;
+
+fn port(register: u16) -> Port<u8> {
+    Port::new(COM1 + register)
+}
+
+/// Sets COM1 up for 115200 baud, 8N1, with interrupts off
+pub fn init() {
+    unsafe {
+        port(REG_INTERRUPT_ENABLE).write(0);
+
+        port(REG_LINE_CONTROL).write(LINE_CONTROL_DLAB);
+        port(0).write(BAUD_DIVISOR as u8);
+        port(1).write((BAUD_DIVISOR >> 8) as u8);
+        port(REG_LINE_CONTROL).write(LINE_CONTROL_8N1);
+
+        // Enable and clear the FIFOs
+        port(REG_FIFO_CONTROL).write(0xC7);
+        // DTR and RTS
+        port(REG_MODEM_CONTROL).write(0x03);
+    }
+}
+
+fn write_byte(byte: u8) {
+    for _ in 0..TX_SPINS {
+        if unsafe { port(REG_LINE_STATUS).read() } & LINE_STATUS_THR_EMPTY != 0 {
+            unsafe { port(0).write(byte) };
+            return;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// Writes straight to COM1, without taking any locks
+pub struct EmergencyWriter;
+
+impl Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                write_byte(b'\r');
+            }
+
+            write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats `args` onto COM1, without taking any locks
+pub fn emergency_print(args: fmt::Arguments) {
+    let _ = EmergencyWriter.write_fmt(args);
+}
@@ -0,0 +1,281 @@
+//! Kernel time base
+//!
+//! Every CPU's local APIC timer interrupts it [`HZ`] times a second, which also drives
+//! preemption. Only the BSP's ticks count towards `TICK_COUNT`. How fast the APIC timer counts
+//! depends on the machine's bus clock, so it is measured once against the HPET, the ACPI PM
+//! timer or the PIT before being programmed.
+//!
+//! CPUs that support it run the APIC timer in TSC-deadline mode instead of periodically. The
+//! timer is then armed for whichever comes first, the next tick or the earliest deadline asked
+//! for through [`set_deadline_ns`].
+
+use core::{
+    arch::x86_64::{_mm_mfence, _rdtsc},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x2apic::lapic::{LocalApic, TimerDivide, TimerMode};
+use x86_64::{instructions::interrupts::without_interrupts, registers::model_specific::Msr};
+
+use crate::{
+    acpi_impl::{pm_timer_read, pm_timer_stall},
+    arch::x86_64::interrupts::TICK_COUNT,
+    hpet, percpu, pit,
+};
+
+use log::*;
+
+/// Timer interrupts per second
+pub const HZ: u64 = 1000;
+
+/// Length of the window the APIC timer is measured over
+const CALIBRATION_MS: u64 = 10;
+
+/// Rate assumed when there's nothing to calibrate against, that of QEMU's 1GHz APIC timer
+/// divided by 16
+const FALLBACK_TICKS_PER_MS: u32 = 62_500;
+
+/// APIC timer ticks per millisecond with a divisor of 16, 0 until calibrated
+static LAPIC_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Writing a TSC value arms the APIC timer in TSC-deadline mode, writing 0 disarms it
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// TSC frequency in Hz, 0 until calibrated
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Whether the APIC timer runs in TSC-deadline mode
+static DEADLINE_MODE: AtomicBool = AtomicBool::new(false);
+/// Deadlines asked for through `set_deadline_ns()` that haven't passed yet, as TSC values
+static PENDING_DEADLINES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// Spins for the calibration window. Returns `false` if there's no timer to measure it with.
+fn wait_calibration_window() -> bool {
+    if hpet::is_available() {
+        hpet::busy_wait_ns(CALIBRATION_MS * 1_000_000);
+    } else if pm_timer_read().is_some() {
+        pm_timer_stall(CALIBRATION_MS * 1000);
+    } else if pit::is_available() {
+        pit::wait_ms(CALIBRATION_MS);
+    } else {
+        return false;
+    }
+
+    true
+}
+
+/// How far the APIC timer and the TSC got in the calibration window
+struct Calibration {
+    lapic_ticks_per_ms: u32,
+    tsc_hz: u64,
+}
+
+/// Measures the APIC timer and the TSC over the calibration window, leaving the APIC timer
+/// stopped
+fn calibrate(lapic: &mut LocalApic) -> Option<Calibration> {
+    // The PIT is probed the first time it is asked for, which mustn't count towards the window
+    pit::is_available();
+
+    let (remaining, tsc_ticks) = without_interrupts(|| unsafe {
+        lapic.set_timer_divide(TimerDivide::Div16);
+        lapic.set_timer_mode(TimerMode::OneShot);
+        lapic.set_timer_initial(u32::MAX);
+
+        let start = _rdtsc();
+        let waited = wait_calibration_window();
+        let remaining = lapic.timer_current();
+        let tsc_ticks = _rdtsc() - start;
+
+        lapic.set_timer_initial(0);
+        waited.then_some((remaining, tsc_ticks))
+    })?;
+
+    let lapic_ticks_per_ms = (u32::MAX - remaining) as u64 / CALIBRATION_MS;
+
+    Some(Calibration {
+        lapic_ticks_per_ms: u32::try_from(lapic_ticks_per_ms)
+            .ok()
+            .filter(|&ticks| ticks > 0)?,
+        tsc_hz: tsc_ticks * 1000 / CALIBRATION_MS,
+    })
+}
+
+/// TSC frequency as reported by CPUID leaf 0x15, or the base frequency from leaf 0x16
+fn tsc_hz_from_cpuid() -> Option<u64> {
+    let cpuid = CpuId::new();
+
+    cpuid
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
+        .or_else(|| {
+            cpuid
+                .get_processor_frequency_info()
+                .map(|info| info.processor_base_frequency() as u64 * 1_000_000)
+        })
+        .filter(|&hz| hz > 0)
+}
+
+/// Whether the APIC timer supports TSC-deadline mode, CPUID.01H:ECX bit 24
+fn has_tsc_deadline() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_tsc_deadline())
+}
+
+/// Converts nanoseconds to TSC ticks
+fn ns_to_tsc(ns: u64) -> u64 {
+    (ns as u128 * TSC_HZ.load(Ordering::Relaxed) as u128 / 1_000_000_000) as u64
+}
+
+/// TSC ticks between two timer interrupts
+fn tsc_per_tick() -> u64 {
+    (TSC_HZ.load(Ordering::Relaxed) / HZ).max(1)
+}
+
+fn arm_deadline(tsc: u64) {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+}
+
+/// Starts the APIC timer of this CPU at [`HZ`], calibrating it and the TSC the first time.
+/// Uses TSC-deadline mode if the CPU supports it and the TSC frequency is known. Every CPU
+/// ends up in the mode the BSP picked, as the APs copy its CPUID-visible features.
+pub(crate) fn start_lapic_timer(lapic: &mut LocalApic) {
+    let mut ticks_per_ms = LAPIC_TICKS_PER_MS.load(Ordering::Relaxed);
+
+    if ticks_per_ms == 0 {
+        let calibration = calibrate(lapic);
+
+        ticks_per_ms = match &calibration {
+            Some(calibration) => calibration.lapic_ticks_per_ms,
+            None => {
+                warn!(
+                    "APIC: no HPET, PM timer or PIT to calibrate the timer against, guessing its rate"
+                );
+                FALLBACK_TICKS_PER_MS
+            }
+        };
+
+        let tsc_hz = tsc_hz_from_cpuid()
+            .or(calibration.map(|calibration| calibration.tsc_hz))
+            .unwrap_or(0);
+
+        info!(
+            "APIC: timer counts {} ticks per ms, TSC runs at {}MHz",
+            ticks_per_ms,
+            tsc_hz / 1_000_000
+        );
+
+        LAPIC_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+        TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    }
+
+    if has_tsc_deadline() && TSC_HZ.load(Ordering::Relaxed) != 0 {
+        without_interrupts(|| unsafe {
+            lapic.set_timer_mode(TimerMode::TscDeadline);
+
+            // The mode change has to land before the deadline is written, or the write is
+            // ignored
+            _mm_mfence();
+
+            let next = _rdtsc() + tsc_per_tick();
+            percpu::current().set_next_tick(next);
+            DEADLINE_MODE.store(true, Ordering::Relaxed);
+            arm_deadline(next);
+        });
+
+        return;
+    }
+
+    DEADLINE_MODE.store(false, Ordering::Relaxed);
+
+    let initial = (ticks_per_ms as u64 * 1000 / HZ).clamp(1, u32::MAX as u64) as u32;
+
+    unsafe {
+        lapic.set_timer_divide(TimerDivide::Div16);
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial(initial);
+    }
+}
+
+/// Makes the timer interrupt this CPU `ns` nanoseconds from now, on top of the regular ticks.
+/// Without TSC-deadline mode the timer only fires periodically, so the interrupt comes with
+/// the first tick after the deadline instead.
+pub fn set_deadline_ns(ns: u64) {
+    if !DEADLINE_MODE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let deadline = unsafe { _rdtsc() } + ns_to_tsc(ns);
+
+    without_interrupts(|| {
+        let mut pending = PENDING_DEADLINES.lock();
+        pending.push(deadline);
+
+        let next = pending
+            .iter()
+            .copied()
+            .fold(percpu::current().next_tick(), u64::min);
+        arm_deadline(next);
+    });
+}
+
+/// Counts the ticks that passed and, in TSC-deadline mode, arms the timer for the next tick
+/// or pending deadline. Called from the timer interrupt, without signalling its end.
+pub(crate) fn timer_service() {
+    let cpu = percpu::current();
+    let is_bsp = cpu.cpu_id() == 0;
+
+    cpu.count_tick();
+
+    if !DEADLINE_MODE.load(Ordering::Relaxed) {
+        if is_bsp {
+            TICK_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+
+        return;
+    }
+
+    let now = unsafe { _rdtsc() };
+    let period = tsc_per_tick();
+    let mut next = cpu.next_tick();
+
+    // Interrupts may have been off for longer than a tick
+    if now >= next {
+        let ticks = (now - next) / period + 1;
+
+        if is_bsp {
+            TICK_COUNT.fetch_add(ticks, Ordering::Relaxed);
+        }
+
+        next += ticks * period;
+        cpu.set_next_tick(next);
+    }
+
+    let mut pending = PENDING_DEADLINES.lock();
+    pending.retain(|&deadline| deadline > now);
+
+    arm_deadline(pending.iter().copied().fold(next, u64::min));
+}
+
+/// TSC frequency in Hz, as calibrated the first time an APIC timer was started. 0 before that,
+/// or if neither CPUID nor a timer to measure against gave it away.
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Milliseconds `ticks` timer interrupts take
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / HZ
+}
+
+/// Timer interrupts in `ms` milliseconds, at least one
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * HZ / 1000).max(1)
+}
+
+/// Milliseconds since the APIC timer was started
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(TICK_COUNT.load(Ordering::Relaxed))
+}
@@ -0,0 +1,562 @@
+//! Common transport for virtio devices attached over PCI.
+//!
+//! Only the modern (virtio 1.0+) PCI transport is supported. The device exposes its
+//! configuration structures through vendor-specific capabilities that point into its memory
+//! BARs; [`VirtioTransport`] locates them, negotiates features and hands out split
+//! [`Virtqueue`]s that device drivers (block, net, ...) build on.
+
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{
+    ahci::{pmm_alloc_contiguous, util::VolatileCell},
+    get_phys_offset,
+    pci_impl::{Bdf, PciDeviceInfo},
+};
+
+use log::*;
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+/// Device IDs 0x1000-0x103f are transitional devices, 0x1040-0x107f are modern ones
+pub const VIRTIO_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x107f;
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+const VENDOR_CAPABILITY_ID: u8 = 0x09;
+const CAPABILITIES_POINTER_OFFSET: usize = 0x34;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+/// The device complies with virtio 1.0 or later; required by the modern transport
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Upper bound for the number of descriptors in a queue we allocate
+const MAX_QUEUE_SIZE: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioDeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    NineP,
+    Gpu,
+    Input,
+    Other(u16),
+}
+
+impl VirtioDeviceType {
+    fn from_id(id: u16) -> Self {
+        match id {
+            1 => Self::Network,
+            2 => Self::Block,
+            3 => Self::Console,
+            4 => Self::Entropy,
+            5 => Self::Balloon,
+            8 => Self::Scsi,
+            9 => Self::NineP,
+            16 => Self::Gpu,
+            18 => Self::Input,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Returns the kind of virtio device behind the given PCI IDs, or `None` if it is not one
+pub fn device_type(vendor_id: u16, device_id: u16) -> Option<VirtioDeviceType> {
+    if vendor_id != VIRTIO_VENDOR_ID || !VIRTIO_DEVICE_IDS.contains(&device_id) {
+        return None;
+    }
+
+    let id = match device_id {
+        id if id >= MODERN_DEVICE_ID_BASE => id - MODERN_DEVICE_ID_BASE,
+        0x1000 => 1,
+        0x1001 => 2,
+        0x1002 => 5,
+        0x1003 => 3,
+        0x1004 => 8,
+        0x1005 => 4,
+        0x1009 => 9,
+        other => return Some(VirtioDeviceType::Other(other)),
+    };
+
+    Some(VirtioDeviceType::from_id(id))
+}
+
+pub fn is_virtio(device: &PciDeviceInfo) -> bool {
+    device_type(device.header.vendor_id, device.header.device_id).is_some()
+}
+
+bitflags! {
+    pub struct DeviceStatus: u8 {
+        const ACKNOWLEDGE        = 1;
+        const DRIVER             = 2;
+        const DRIVER_OK          = 4;
+        const FEATURES_OK        = 8;
+        const DEVICE_NEEDS_RESET = 64;
+        const FAILED             = 128;
+    }
+}
+
+/// `virtio_pci_common_cfg`, 64-bit queue addresses are split so they can be written with
+/// 32-bit accesses
+#[repr(C)]
+struct CommonConfig {
+    device_feature_select: VolatileCell<u32>,
+    device_feature: VolatileCell<u32>,
+    driver_feature_select: VolatileCell<u32>,
+    driver_feature: VolatileCell<u32>,
+    config_msix_vector: VolatileCell<u16>,
+    num_queues: VolatileCell<u16>,
+    device_status: VolatileCell<u8>,
+    config_generation: VolatileCell<u8>,
+    queue_select: VolatileCell<u16>,
+    queue_size: VolatileCell<u16>,
+    queue_msix_vector: VolatileCell<u16>,
+    queue_enable: VolatileCell<u16>,
+    queue_notify_off: VolatileCell<u16>,
+    queue_desc_lo: VolatileCell<u32>,
+    queue_desc_hi: VolatileCell<u32>,
+    queue_driver_lo: VolatileCell<u32>,
+    queue_driver_hi: VolatileCell<u32>,
+    queue_device_lo: VolatileCell<u32>,
+    queue_device_hi: VolatileCell<u32>,
+}
+
+const _: () = assert!(core::mem::size_of::<CommonConfig>() == 0x38);
+
+/// A `virtio_pci_cap` structure found in the capability list
+#[derive(Debug, Clone, Copy)]
+struct VirtioCapability {
+    cfg_type: u8,
+    bar: u8,
+    offset: u32,
+    length: u32,
+    /// Only present for the notification structure
+    notify_off_multiplier: u32,
+}
+
+fn read_u32(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap())
+}
+
+/// Walks the standard capability list and collects every virtio vendor capability
+fn virtio_capabilities(raw: &[u8]) -> Vec<VirtioCapability> {
+    let mut found = Vec::new();
+    let mut pointer = raw[CAPABILITIES_POINTER_OFFSET] as usize & !0x3;
+    let mut remaining = 48;
+
+    while pointer != 0 && pointer + 16 <= raw.len() && remaining > 0 {
+        if raw[pointer] == VENDOR_CAPABILITY_ID {
+            let cfg_type = raw[pointer + 3];
+
+            found.push(VirtioCapability {
+                cfg_type,
+                bar: raw[pointer + 4],
+                offset: read_u32(raw, pointer + 8),
+                length: read_u32(raw, pointer + 12),
+                notify_off_multiplier: if cfg_type == CFG_TYPE_NOTIFY && pointer + 20 <= raw.len() {
+                    read_u32(raw, pointer + 16)
+                } else {
+                    0
+                },
+            });
+        }
+
+        pointer = raw[pointer + 1] as usize & !0x3;
+        remaining -= 1;
+    }
+
+    found
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer handed to the device as part of a descriptor chain
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// The device writes into this buffer rather than reading from it
+    pub device_writable: bool,
+}
+
+/// A split virtqueue living in physically contiguous, uncached memory
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: VirtAddr,
+    avail: VirtAddr,
+    used: VirtAddr,
+    doorbell: VirtAddr,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn layout(size: u16) -> (u64, u64, u64) {
+        let size = size as u64;
+        let avail = 16 * size;
+        let used = (avail + 6 + 2 * size + 3) & !3;
+
+        (avail, used, used + 6 + 8 * size)
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        (self.descriptors.as_u64() as *mut Descriptor).wrapping_add(index as usize)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Chains `buffers` into free descriptors and publishes the chain in the available ring.
+    /// Returns the head descriptor, which identifies the request in [`Virtqueue::pop_used`].
+    /// The device is not notified, see [`Virtqueue::notify`].
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut current = head;
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = unsafe { &mut *self.descriptor(current) };
+            let next = descriptor.next;
+
+            descriptor.addr = buffer.address.as_u64();
+            descriptor.len = buffer.length;
+            descriptor.flags = if buffer.device_writable {
+                DESC_F_WRITE
+            } else {
+                0
+            };
+
+            if i + 1 < buffers.len() {
+                descriptor.flags |= DESC_F_NEXT;
+                current = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+
+        self.free_count -= buffers.len() as u16;
+
+        let avail_idx = unsafe { &*((self.avail.as_u64() + 2) as *const VolatileCell<u16>) };
+        let idx = avail_idx.get();
+        let slot = (self.avail.as_u64() + 4 + 2 * (idx % self.size) as u64) as *mut u16;
+
+        unsafe { slot.write_volatile(head) };
+
+        // the ring entry has to be visible before the index that publishes it
+        fence(Ordering::SeqCst);
+        avail_idx.set(idx.wrapping_add(1));
+
+        Some(head)
+    }
+
+    /// Rings the doorbell for this queue
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { (self.doorbell.as_u64() as *mut u16).write_volatile(self.index) };
+    }
+
+    /// Takes the next completed chain off the used ring and returns its head descriptor and the
+    /// number of bytes the device wrote. The descriptors are returned to the free list.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { &*((self.used.as_u64() + 2) as *const VolatileCell<u16>) };
+
+        if used_idx.get() == self.last_used {
+            return None;
+        }
+
+        fence(Ordering::SeqCst);
+
+        let element = self.used.as_u64() + 4 + 8 * (self.last_used % self.size) as u64;
+        let (head, length) = unsafe {
+            (
+                (element as *const u32).read_volatile() as u16,
+                ((element + 4) as *const u32).read_volatile(),
+            )
+        };
+
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut tail = head;
+        let mut freed = 1;
+
+        while unsafe { (*self.descriptor(tail)).flags } & DESC_F_NEXT != 0 {
+            tail = unsafe { (*self.descriptor(tail)).next };
+            freed += 1;
+        }
+
+        unsafe { (*self.descriptor(tail)).next = self.free_head };
+        self.free_head = head;
+        self.free_count += freed;
+
+        Some((head, length))
+    }
+}
+
+/// The modern virtio PCI transport of a single function
+pub struct VirtioTransport {
+    bdf: Bdf,
+    common: &'static CommonConfig,
+    notify_base: VirtAddr,
+    notify_off_multiplier: u32,
+    isr: VirtAddr,
+    device_config: Option<VirtAddr>,
+}
+
+impl VirtioTransport {
+    /// Locates the configuration structures of a virtio device and maps the BARs they live in.
+    /// Returns `None` for devices without the modern transport.
+    pub fn new(device: &PciDeviceInfo) -> Option<Self> {
+        if !is_virtio(device) {
+            return None;
+        }
+
+        let capabilities = virtio_capabilities(&device.raw_header);
+
+        let locate = |cap: &VirtioCapability| -> Option<VirtAddr> {
+            let bar = device.bars.get(cap.bar as usize).copied().flatten()?;
+
+            if cap.offset as u64 + cap.length as u64 > bar.size {
+                warn!(
+                    "virtio structure type {} at {} exceeds BAR{}",
+                    cap.cfg_type, device.bdf, cap.bar
+                );
+                return None;
+            }
+
+            Some(bar.map()? + cap.offset as u64)
+        };
+
+        // the first capability of each type is the preferred one
+        let find = |cfg_type: u8| capabilities.iter().find(|cap| cap.cfg_type == cfg_type);
+
+        let common = locate(find(CFG_TYPE_COMMON)?)?;
+        let notify_cap = find(CFG_TYPE_NOTIFY)?;
+        let notify_base = locate(notify_cap)?;
+        let isr = locate(find(CFG_TYPE_ISR)?)?;
+        let device_config = find(CFG_TYPE_DEVICE).and_then(|cap| locate(cap));
+
+        Some(Self {
+            bdf: device.bdf,
+            common: unsafe { &*(common.as_u64() as *const CommonConfig) },
+            notify_base,
+            notify_off_multiplier: notify_cap.notify_off_multiplier,
+            isr,
+            device_config,
+        })
+    }
+
+    pub fn bdf(&self) -> Bdf {
+        self.bdf
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.common.device_status.get())
+    }
+
+    fn add_status(&self, status: DeviceStatus) {
+        self.common
+            .device_status
+            .set((self.status() | status).bits());
+    }
+
+    /// Resets the device and waits until it reports the reset as complete
+    pub fn reset(&self) {
+        self.common.device_status.set(0);
+
+        while self.common.device_status.get() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tells the device something went wrong and it should give up on this driver
+    pub fn fail(&self) {
+        self.add_status(DeviceStatus::FAILED);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.common.device_feature_select.set(0);
+        let low = self.common.device_feature.get() as u64;
+        self.common.device_feature_select.set(1);
+        let high = self.common.device_feature.get() as u64;
+
+        low | high << 32
+    }
+
+    /// Runs the initialization sequence up to FEATURES_OK, accepting the subset of `wanted`
+    /// the device offers. [`VIRTIO_F_VERSION_1`] is always requested. Returns the accepted
+    /// features, or `None` if the device refused them.
+    pub fn negotiate_features(&self, wanted: u64) -> Option<u64> {
+        self.reset();
+        self.add_status(DeviceStatus::ACKNOWLEDGE);
+        self.add_status(DeviceStatus::DRIVER);
+
+        let offered = self.device_features();
+
+        if offered & VIRTIO_F_VERSION_1 == 0 {
+            warn!("virtio device at {} does not offer VERSION_1", self.bdf);
+            self.fail();
+            return None;
+        }
+
+        let accepted = offered & (wanted | VIRTIO_F_VERSION_1);
+
+        self.common.driver_feature_select.set(0);
+        self.common.driver_feature.set(accepted as u32);
+        self.common.driver_feature_select.set(1);
+        self.common.driver_feature.set((accepted >> 32) as u32);
+
+        self.add_status(DeviceStatus::FEATURES_OK);
+
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            warn!(
+                "virtio device at {} rejected features {:#x}",
+                self.bdf, accepted
+            );
+            self.fail();
+            return None;
+        }
+
+        Some(accepted)
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        self.common.num_queues.get()
+    }
+
+    /// Allocates the rings for queue `index` and enables it. Must be called after
+    /// [`VirtioTransport::negotiate_features`] and before [`VirtioTransport::driver_ok`].
+    pub fn setup_queue(&self, index: u16) -> Option<Virtqueue> {
+        if index >= self.num_queues() {
+            return None;
+        }
+
+        self.common.queue_select.set(index);
+
+        let size = self.common.queue_size.get().min(MAX_QUEUE_SIZE);
+
+        if size == 0 || self.common.queue_enable.get() != 0 {
+            return None;
+        }
+
+        self.common.queue_size.set(size);
+
+        let (avail_offset, used_offset, total) = Virtqueue::layout(size);
+        let phys = pmm_alloc_contiguous(total.div_ceil(4096) as usize);
+        let virt = VirtAddr::new(phys.as_u64() + get_phys_offset());
+
+        let descriptors = virt.as_u64() as *mut Descriptor;
+
+        for i in 0..size {
+            unsafe { (*descriptors.add(i as usize)).next = (i + 1) % size };
+        }
+
+        let set_split = |lo: &VolatileCell<u32>, hi: &VolatileCell<u32>, value: u64| {
+            lo.set(value as u32);
+            hi.set((value >> 32) as u32);
+        };
+
+        set_split(
+            &self.common.queue_desc_lo,
+            &self.common.queue_desc_hi,
+            phys.as_u64(),
+        );
+        set_split(
+            &self.common.queue_driver_lo,
+            &self.common.queue_driver_hi,
+            phys.as_u64() + avail_offset,
+        );
+        set_split(
+            &self.common.queue_device_lo,
+            &self.common.queue_device_hi,
+            phys.as_u64() + used_offset,
+        );
+
+        let doorbell = self.notify_base
+            + self.common.queue_notify_off.get() as u64 * self.notify_off_multiplier as u64;
+
+        self.common.queue_enable.set(1);
+
+        Some(Virtqueue {
+            index,
+            size,
+            descriptors: virt,
+            avail: virt + avail_offset,
+            used: virt + used_offset,
+            doorbell,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        })
+    }
+
+    /// Routes configuration change and queue `index` interrupts to MSI-X table entries.
+    /// Returns `false` if the device could not allocate the vectors.
+    pub fn set_msix_vectors(&self, index: u16, config: u16, queue: u16) -> bool {
+        self.common.config_msix_vector.set(config);
+        self.common.queue_select.set(index);
+        self.common.queue_msix_vector.set(queue);
+
+        self.common.config_msix_vector.get() == config
+            && self.common.queue_msix_vector.get() == queue
+    }
+
+    /// Finishes initialization, the device may use its queues from now on
+    pub fn driver_ok(&self) {
+        self.add_status(DeviceStatus::DRIVER_OK);
+    }
+
+    /// Reads and thereby acknowledges the interrupt status. Bit 0 signals a queue interrupt,
+    /// bit 1 a configuration change.
+    pub fn read_isr(&self) -> u8 {
+        unsafe { (self.isr.as_u64() as *const u8).read_volatile() }
+    }
+
+    /// Device-specific configuration structure, if the device has one
+    pub fn device_config(&self) -> Option<VirtAddr> {
+        self.device_config
+    }
+
+    /// Reads the device-specific configuration consistently by retrying until the
+    /// configuration generation is stable across the read
+    pub fn read_device_config<T: Copy>(&self, offset: usize) -> Option<T> {
+        let base = self.device_config?.as_u64() + offset as u64;
+
+        loop {
+            let generation = self.common.config_generation.get();
+            let value = unsafe { (base as *const T).read_volatile() };
+
+            if generation == self.common.config_generation.get() {
+                return Some(value);
+            }
+        }
+    }
+}
@@ -1,22 +1,24 @@
 use alloc::sync::Arc;
 use bit_field::BitField;
 use conquer_once::spin::OnceCell;
-use core::ptr::addr_of;
-use x86_64::{
-    structures::paging::{Page, Size4KiB},
-    VirtAddr,
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, Ordering},
 };
+use x86_64::structures::paging::{Page, Size4KiB};
 
 use crate::{
     common::addralloc,
     common::XhciMapper,
-    pci_impl::{register_device_driver, DeviceKind, FOSSPciDeviceHandle, PCI_TABLE},
+    pci_impl::{
+        read_command, register_device_driver, set_power_state, write_command, DeviceKind,
+        DeviceMatch, FOSSPciDeviceHandle, PciCommand, PciDeviceInfo, PowerState, PCI_TABLE,
+    },
     xhci::mass_storage::UsbDeviceKind,
 };
-use pcics::{header::HeaderType, Header};
 use spin::{Once, RwLock};
 use xhci::{
-    accessor::array::ReadWrite,
+    accessor::{array::ReadWrite, Mapper},
     context::Device,
     extended_capabilities::List,
     registers::{
@@ -372,36 +374,26 @@ pub struct XhciImpl {
 }
 
 impl XhciImpl {
-    pub fn new(header: &Header) -> Self {
+    pub fn new(device: &PciDeviceInfo) -> Self {
+        let bdf = device.bdf;
         let offset_full_bar_outer = OnceCell::<usize>::uninit();
         let regs = {
-            if let DeviceKind::UsbController =
-                DeviceKind::new(header.class_code.base as u32, header.class_code.sub as u32)
-            {
-                if let HeaderType::Normal(header) = header.header_type.clone() {
-                    let bar0 = header.base_addresses.orig()[0];
-                    let bar1 = header.base_addresses.orig()[1];
-
-                    // Align this properly
-                    let full_bar = bar0 as u64 | ((bar1 as u64) << 32);
-
-                    let offset_full_bar = {
-                        let test =
-                            Page::<Size4KiB>::containing_address(VirtAddr::new(full_bar as u64));
-                        test.start_address().as_u64()
-                    } as usize;
-                    offset_full_bar_outer.get_or_init(move || offset_full_bar);
-
-                    let regs = unsafe {
-                        Registers::new(
-                            offset_full_bar_outer.get().cloned().unwrap(),
-                            MAPPER.read().clone(),
-                        )
-                    };
-                    Some(regs)
-                } else {
-                    None
-                }
+            if let DeviceKind::UsbController = device.kind {
+                set_power_state(bdf, PowerState::D0);
+                write_command(
+                    bdf,
+                    read_command(bdf) | PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER,
+                );
+
+                // The registers live in BAR0, which is usually a 64-bit BAR
+                device.bars[0].map(|bar| {
+                    offset_full_bar_outer.get_or_init(|| bar.address as usize);
+
+                    let mut mapper = MAPPER.read().clone();
+                    unsafe { mapper.map(bar.address as usize, bar.size as usize) };
+
+                    unsafe { Registers::new(bar.address as usize, mapper) }
+                })
             } else {
                 None
             }
@@ -764,27 +756,30 @@ pub(crate) fn get_xhci<'a>() -> &'a Arc<XhciProtected> {
 
 pub struct XhciProtected {
     inner: RwLock<XhciImpl>,
+    /// Only the first controller is driven for now
+    started: AtomicBool,
 }
 
 impl XhciProtected {
-    pub fn new(header: &Header) -> Self {
+    pub fn new(device: &PciDeviceInfo) -> Self {
         Self {
-            inner: RwLock::new(XhciImpl::new(header)),
+            inner: RwLock::new(XhciImpl::new(device)),
+            started: AtomicBool::new(false),
         }
     }
 }
 
 pub fn xhci_init() {
+    // Every USB controller calls this, but the driver only gets registered once
+    if DRIVER.is_completed() {
+        return;
+    }
+
     DRIVER.call_once(|| {
         let guard = PCI_TABLE.read();
-        let header = guard.headers.iter().find(|h| {
-            matches!(
-                DeviceKind::new(h.class_code.base as u32, h.class_code.sub as u32),
-                DeviceKind::UsbController
-            )
-        });
-
-        let out = header.map(|h| Arc::new(XhciProtected::new(h)));
+        let info = guard.find_by_kind(DeviceKind::UsbController).next();
+
+        let out = info.map(|info| Arc::new(XhciProtected::new(info)));
         out.expect("XHCI device not in the table")
     });
 
@@ -792,11 +787,19 @@ pub fn xhci_init() {
 }
 
 impl FOSSPciDeviceHandle for XhciProtected {
-    fn handles(&self, _: crate::pci_impl::Vendor, device_id: DeviceKind) -> bool {
-        matches!(device_id, DeviceKind::UsbController)
+    fn matches(&self) -> &[DeviceMatch] {
+        &[DeviceMatch::ByClass(DeviceKind::UsbController)]
     }
 
-    fn start(&self, _: &mut pcics::Header) {
+    fn start(&self, device: &mut PciDeviceInfo) {
+        if self.started.swap(true, Ordering::AcqRel) {
+            log::warn!(
+                "XHCI: only one controller is supported, ignoring the one at {}",
+                device.bdf
+            );
+            return;
+        }
+
         self.inner.write().init();
     }
 }
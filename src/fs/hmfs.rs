@@ -1,3 +1,4 @@
+use crate::rtc;
 use alloc::borrow::ToOwned;
 use alloc::string::String;
 use alloc::sync::Arc;
@@ -108,7 +109,9 @@ impl<'a> Entry<'a> {
     pub fn parent(&self) -> Option<EntryKind> {
         self.parent.clone()
     }
-    pub fn mkdir(&self, name: String, timestamp: time_t) -> syscall::Result<Self> {
+    pub fn mkdir(&self, name: String) -> syscall::Result<Self> {
+        let timestamp = rtc::now();
+
         match self.kind.clone() {
             EntryKind::Directory(mut dir) => {
                 let parent = Some(EntryKind::Directory(dir.clone()));
@@ -186,9 +189,10 @@ impl<'a> Entry<'a> {
         &self,
         mime: Mime<'a>,
         name: String,
-        timestamp: time_t,
         data: FileData,
     ) -> syscall::Result<Self> {
+        let timestamp = rtc::now();
+
         match self.kind.clone() {
             EntryKind::Directory(ref mut dir) => {
                 let parent = EntryKind::Directory(dir.clone());
@@ -44,13 +44,15 @@ pub mod drm;
 pub mod fs;
 pub mod process;
 pub mod scheme;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 
 use crate::{
     acpi_impl::{system_shutdown, KernelAcpi},
     cralloc::heap_init,
     drm::COMPOSITING_TABLE,
 };
-use acpi::{AcpiTables, InterruptModel, PciConfigRegions, PlatformInfo};
+use acpi::{platform::ProcessorInfo, AcpiTables, InterruptModel, PciConfigRegions, PlatformInfo};
 use alloc::{alloc::Global, boxed::Box};
 use bootloader_api::{
     config::{Mapping, Mappings},
@@ -165,6 +167,7 @@ pub fn get_phys_offset() -> u64 {
 }
 
 pub static INTERRUPT_MODEL: OnceCell<InterruptModel<Global>> = OnceCell::uninit();
+pub static PROCESSOR_INFO: OnceCell<Option<ProcessorInfo<Global>>> = OnceCell::uninit();
 pub static PCI_CONFIG: OnceCell<Option<PciConfigRegions<Global>>> = OnceCell::uninit();
 
 pub fn get_mcfg<'a>() -> &'a Option<PciConfigRegions<'a, Global>> {
@@ -198,6 +201,18 @@ pub fn maink(boot_info: &'static mut BootInfo) -> ! {
     // load the GDT early because repeated GDT loads cause a #GP
     crate::arch::x86_64::exceptions::init();
 
+    // Has to follow the GDT, loading GS clears its base
+    percpu::init(0, exceptions::TSS.privilege_stack_table[0]);
+
+    // Emergency output, for when the logger can't be used
+    serial::init();
+
+    // Errors left over from before the reset go to the serial port
+    mce::init();
+
+    // Needs the per-CPU block for its stack
+    crate::arch::x86_64::syscall::init();
+
     // map the TLS template onto the heap to ensure proper memory safety
     TLS_TEMPLATE_ADDR.store(Box::into_raw(Box::new(0)) as usize as u64, Ordering::SeqCst);
 
@@ -236,7 +251,13 @@ pub fn maink(boot_info: &'static mut BootInfo) -> ! {
 
     match unsafe { AcpiTables::from_rsdp(KernelAcpi, rsdp as usize) } {
         Ok(tables) => {
-            USER_ACPI.call_once(|| UserAcpi::new(&tables));
+            match UserAcpi::new(&tables) {
+                Ok(user_acpi) => {
+                    USER_ACPI.call_once(|| user_acpi);
+                }
+                Err(e) => error!("ACPI tables required by usermode are missing: {:?}", e),
+            }
+            acpi_impl::pm_timer_init(&tables);
 
             let mcfg = match PciConfigRegions::new_in(&tables, Global) {
                 Ok(mcfg) => Some(mcfg),
@@ -245,21 +266,59 @@ pub fn maink(boot_info: &'static mut BootInfo) -> ! {
 
             if let Ok(platform_info) = PlatformInfo::new_in(&tables, Global) {
                 let interrupts = platform_info.interrupt_model;
+                let processors = platform_info.processor_info;
 
                 INTERRUPT_MODEL.get_or_init(move || interrupts);
+                PROCESSOR_INFO.get_or_init(move || processors);
                 PCI_CONFIG.get_or_init(move || mcfg);
 
                 debug!("Interrupt model: {:#?}", INTERRUPT_MODEL.get().unwrap());
 
                 debug!("TLS template: {:#x?}", boot_info.tls_template);
                 pci_impl::init(&tables);
+                hpet::init(&tables);
+
+                if let Some(processors) = PROCESSOR_INFO.get().unwrap() {
+                    smp::start_aps(processors);
+                }
+
+                acpi_impl::sci_init();
+                power::init();
+                partitions::scan_all();
+
+                #[cfg(feature = "selftest")]
+                selftest::run();
             }
         }
         Err(e) => error!("Failed to parse the ACPI tables: {:?}", e),
     }
 
+    let mut next_aer_check = 0;
+    let mut next_thermal_poll = 0;
+
     // Use the loop at the end of main as the rendering loop
     loop {
+        // Nothing routes AER interrupts, so poll for PCIe errors every once in a while
+        let ticks = interrupts::TICK_COUNT.load(Ordering::Relaxed);
+        if ticks >= next_aer_check {
+            pci_impl::check_all_aer();
+            next_aer_check = ticks + pci_impl::AER_CHECK_INTERVAL_TICKS;
+        }
+
+        if ticks >= next_thermal_poll {
+            acpi_impl::poll_thermal_zones();
+            next_thermal_poll = ticks + acpi_impl::THERMAL_POLL_INTERVAL_TICKS;
+        }
+
+        acpi_impl::process_gpes();
+        ahci::process_hotplug();
+        ahci::process_resets();
+
+        if acpi_impl::POWER_BUTTON_PRESSED.load(Ordering::SeqCst) {
+            info!("Shutting down");
+            unsafe { system_shutdown() };
+        }
+
         if !(COMPOSITING_TABLE.read().is_empty()) {
             for canvas in COMPOSITING_TABLE.read().iter() {
                 canvas.merge_down(get_boot_info().framebuffer.as_mut().unwrap());
@@ -26,10 +26,22 @@ use crate::{
 pub use self::signal::Signal;
 pub mod signal;
 
+pub(crate) use self::run_queue::RunQueue;
+mod run_queue;
+pub(crate) mod sched;
+
 use signal::abort;
 
 int_like!(Pid, AtomicPid, usize, AtomicUsize);
 
+/// How long a process runs before it is preempted, in milliseconds
+pub const TIME_SLICE_MS: u64 = 10;
+
+/// Length of a time slice in timer interrupts
+pub fn time_slice_ticks() -> u64 {
+    crate::time::ms_to_ticks(TIME_SLICE_MS)
+}
+
 /// State that the context is left in
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum State {
@@ -69,10 +81,11 @@ impl From<(u8, u64)> for State {
     }
 }
 
-pub(crate) static PTABLE: RwLock<BTreeMap<usize, Arc<RwLock<Process>>>> =
-    RwLock::new(BTreeMap::new());
+/// Processes by their key
+pub(crate) type ProcessTable = RwLock<BTreeMap<usize, Arc<RwLock<Process<'static>>>>>;
 
-pub(crate) static PTABLE_IDX: AtomicUsize = AtomicUsize::new(0);
+/// Every process, whichever CPU it runs on. Each CPU schedules its own from its per-CPU block.
+pub(crate) static PTABLE: ProcessTable = RwLock::new(BTreeMap::new());
 
 /// Enum of `main()` fn signatures for the kernel to accept
 ///
@@ -176,12 +189,14 @@ impl<'a> Process<'a> {
         }
     }
 
-    /// Creates a new process using and automatically adds it to `PTABLE`
+    /// Creates a new process, adds it to `PTABLE` and has the executing CPU run it
     pub fn create(exec: ElfFile<'static>) {
-        PTABLE.write().insert(
-            PTABLE.read().len() - 1,
-            Arc::new(RwLock::new(Process::<'static>::from(exec))),
-        );
+        let key = PTABLE.read().len() - 1;
+        let process = Arc::new(RwLock::new(Process::<'static>::from(exec)));
+
+        PTABLE.write().insert(key, Arc::clone(&process));
+        crate::percpu::current()
+            .with_run_queue(|queue| queue.insert(key, Box::new(sched::Task::new(process))));
     }
 
     /// Runs this process
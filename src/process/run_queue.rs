@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Tasks one CPU schedules, handed out in turns
+
+use alloc::collections::BTreeMap;
+
+/// Tasks of one CPU by their key, along with where the next turn starts
+#[derive(Debug)]
+pub(crate) struct RunQueue<T> {
+    tasks: BTreeMap<usize, T>,
+    /// Key the search for the next task starts at
+    cursor: usize,
+}
+
+impl<T> RunQueue<T> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            cursor: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: usize, task: T) {
+        self.tasks.insert(key, task);
+    }
+
+    pub(crate) fn remove(&mut self, key: usize) -> Option<T> {
+        self.tasks.remove(&key)
+    }
+
+    pub(crate) fn get(&self, key: usize) -> Option<&T> {
+        self.tasks.get(&key)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        self.tasks.get_mut(&key)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Removes the tasks `keep` rejects, without changing whose turn it is
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(usize, &T) -> bool) {
+        self.tasks.retain(|&key, task| keep(key, task));
+    }
+
+    /// Picks the task whose turn it is, skipping those `ready` rejects. Turns go by ascending
+    /// key, starting after the task picked last and wrapping around at the end.
+    pub(crate) fn next(&mut self, mut ready: impl FnMut(&T) -> bool) -> Option<usize> {
+        let key = self
+            .tasks
+            .range(self.cursor..)
+            .chain(self.tasks.range(..self.cursor))
+            .find(|(_, task)| ready(task))
+            .map(|(&key, _)| key)?;
+
+        self.cursor = key.wrapping_add(1);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn turns(queue: &mut RunQueue<bool>, count: usize) -> Vec<usize> {
+        (0..count)
+            .filter_map(|_| queue.next(|ready| *ready))
+            .collect()
+    }
+
+    #[test]
+    fn tasks_take_turns_in_key_order() {
+        let mut queue = RunQueue::new();
+
+        for key in [7, 2, 4] {
+            queue.insert(key, true);
+        }
+
+        assert_eq!(turns(&mut queue, 7), [2, 4, 7, 2, 4, 7, 2]);
+    }
+
+    #[test]
+    fn tasks_that_are_not_ready_are_skipped() {
+        let mut queue = RunQueue::new();
+
+        for key in 0..4 {
+            queue.insert(key, key != 1);
+        }
+
+        assert_eq!(turns(&mut queue, 4), [0, 2, 3, 0]);
+
+        *queue.get_mut(1).unwrap() = true;
+        assert_eq!(turns(&mut queue, 2), [1, 2]);
+    }
+
+    #[test]
+    fn turns_go_on_after_tasks_come_and_go() {
+        let mut queue = RunQueue::new();
+
+        queue.insert(1, true);
+        queue.insert(5, true);
+        assert_eq!(turns(&mut queue, 1), [1]);
+
+        // The task after the cursor left, the next one in line gets the turn
+        queue.remove(5);
+        queue.insert(3, true);
+        queue.insert(9, true);
+        assert_eq!(turns(&mut queue, 4), [3, 9, 1, 3]);
+    }
+
+    #[test]
+    fn removing_tasks_keeps_the_turns() {
+        let mut queue = RunQueue::new();
+
+        for key in 0..5 {
+            queue.insert(key, true);
+        }
+
+        assert_eq!(turns(&mut queue, 2), [0, 1]);
+
+        queue.retain(|key, _| ![1, 3].contains(&key));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(turns(&mut queue, 3), [2, 4, 0]);
+    }
+
+    #[test]
+    fn an_empty_queue_has_nothing_to_run() {
+        let mut queue = RunQueue::<bool>::new();
+
+        assert_eq!(queue.next(|_| true), None);
+
+        queue.insert(0, false);
+        assert_eq!(queue.next(|ready| *ready), None);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn every_queue_keeps_its_own_turns() {
+        let (mut first, mut second) = (RunQueue::new(), RunQueue::new());
+
+        for key in 0..3 {
+            first.insert(key, true);
+            second.insert(key, true);
+        }
+
+        assert_eq!(turns(&mut first, 2), [0, 1]);
+        assert_eq!(turns(&mut second, 1), [0]);
+        assert_eq!(turns(&mut first, 1), [2]);
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Preemptive round-robin scheduling of the processes on each CPU's run queue
+//!
+//! Every process runs on a kernel stack of its own. Switching to another one saves the
+//! callee-saved registers and the stack pointer of the current task and picks up the next
+//! one where it left off, see [`Context`]. A task preempted by the timer is switched away
+//! from at the end of the timer handler, so it continues by returning from that handler.
+//! Tasks stay on the CPU whose run queue they are on.
+
+use alloc::{boxed::Box, sync::Arc, vec};
+
+use log::warn;
+use spin::RwLock;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use super::{time_slice_ticks, Process, State};
+use crate::{context::Context, percpu};
+
+/// Size of the kernel stack every task runs on
+const TASK_STACK_SIZE: usize = 64 * 1024;
+
+/// A process along with the stack it runs on
+pub(crate) struct Task {
+    pub(crate) process: Arc<RwLock<Process<'static>>>,
+    context: Context,
+    /// Only referred to through `context` while the task isn't running
+    _stack: Box<[u8]>,
+    /// Whether the process returned, after which the task is only waiting to be removed
+    done: bool,
+}
+
+impl Task {
+    pub(crate) fn new(process: Arc<RwLock<Process<'static>>>) -> Self {
+        let stack = vec![0u8; TASK_STACK_SIZE].into_boxed_slice();
+        let top = VirtAddr::from_ptr(stack.as_ptr()) + TASK_STACK_SIZE as u64;
+
+        Self {
+            process,
+            context: Context::new(top, task_entry),
+            _stack: stack,
+            done: false,
+        }
+    }
+}
+
+/// Where every task starts, with interrupts disabled as `schedule` leaves them
+extern "C" fn task_entry() -> ! {
+    let cpu = percpu::current();
+    let key = cpu
+        .current_task()
+        .expect("task started without being current");
+
+    let process = cpu
+        .with_run_queue(|queue| queue.get(key).map(|task| Arc::clone(&task.process)))
+        .expect("task started without being on the run queue");
+
+    interrupts::enable();
+
+    let result = {
+        let mut process = process.write();
+
+        process.set_state(State::Runnable);
+        process.run()
+    };
+
+    // TODO: hand the error to whoever waits for the process
+    if let Err(e) = result {
+        warn!("Process {} exited with {:?}", key, e);
+    }
+
+    interrupts::disable();
+
+    cpu.with_run_queue(|queue| {
+        if let Some(task) = queue.get_mut(key) {
+            task.done = true;
+        }
+    });
+
+    schedule();
+    unreachable!("finished task {} was switched back to", key);
+}
+
+/// Switches to the next task in turn on the executing CPU, or back to the code running on the
+/// CPU's own stack once no task is left. Returns once the calling task is switched back to,
+/// right away if it's the only one. Interrupts have to be disabled.
+pub(crate) fn schedule() {
+    debug_assert!(!interrupts::are_enabled());
+
+    let cpu = percpu::current();
+    let current = cpu.current_task();
+
+    let switch = cpu.with_run_queue(|queue| {
+        // A finished task's stack can go once something else runs
+        queue.retain(|key, task| !task.done || Some(key) == current);
+
+        let (next, to) = match queue.next(|task| !task.done) {
+            Some(key) if Some(key) == current => return None,
+            Some(key) => (Some(key), &queue.get(key)?.context as *const Context),
+            None if current.is_some() => (None, cpu.own_context() as *const Context),
+            None => return None,
+        };
+
+        let from = match current {
+            Some(key) => &mut queue.get_mut(key)?.context as *mut Context,
+            None => cpu.own_context(),
+        };
+
+        Some((next, from, to))
+    });
+
+    if let Some((next, from, to)) = switch {
+        cpu.set_current_task(next);
+        cpu.refill_slice(time_slice_ticks());
+        cpu.count_context_switch();
+
+        // Tasks are boxed, so their contexts stay put while the run queue changes
+        unsafe { Context::switch(from, to) };
+    }
+}
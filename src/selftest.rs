@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Checks of the running kernel that need the hardware, run once at boot with the `selftest`
+//! feature. A failing check panics.
+
+use alloc::{sync::Arc, vec};
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use log::{info, warn};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    ahci::util::Stopwatch,
+    disk::{Disk, DiskLocation, ALL_DISKS},
+    interrupts::{irqalloc, irqfree, register_irq, unregister_irq},
+    percpu,
+    time::{tsc_hz, HZ},
+};
+
+/// How long the disk is kept busy while counting timer interrupts
+const IO_TICKS_MS: u64 = 500;
+/// Share of the expected timer interrupts, in percent, that has to arrive during disk I/O
+const IO_TICKS_MIN_PERCENT: u64 = 90;
+
+pub fn run() {
+    fresh_vectors_reach_their_handlers();
+    ticks_during_io();
+}
+
+// `int` only takes its vector as an immediate, so every vector from 32 up gets a stub raising it
+global_asm!(
+    r#"
+    .section .text.int_stubs, "ax"
+    .balign 4
+    .global int_stubs
+int_stubs:
+    int_vector = 32
+    .rept 224
+    .balign 4
+    int $int_vector
+    ret
+    int_vector = int_vector + 1
+    .endr
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static int_stubs: u8;
+}
+
+/// Distance between two stubs in `int_stubs`
+const INT_STUB_SIZE: u64 = 4;
+
+/// Raises `vector` on the executing CPU, like a device interrupt would
+fn raise(vector: u8) {
+    assert!(vector >= 32, "vector {} has no stub", vector);
+
+    unsafe {
+        let stub = &int_stubs as *const u8 as u64 + (vector - 32) as u64 * INT_STUB_SIZE;
+        let stub: extern "C" fn() = core::mem::transmute(stub as usize);
+
+        stub();
+    }
+}
+
+/// Counts its calls in the `AtomicUsize` behind `ctx`
+fn count_raised(_vector: u8, ctx: *mut ()) {
+    unsafe { &*(ctx as *const AtomicUsize) }.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A vector allocated and registered after boot has to get an IDT entry that reaches its
+/// handler
+fn fresh_vectors_reach_their_handlers() {
+    let vector = irqalloc().expect("selftest: no free vector");
+    let raised = AtomicUsize::new(0);
+    let ctx = &raised as *const AtomicUsize as *mut ();
+
+    register_irq(vector, count_raised, ctx, Some("selftest"));
+
+    // Software interrupts ignore IF, which keeps device interrupts from coming in between
+    without_interrupts(|| {
+        raise(vector);
+        raise(vector);
+    });
+
+    unregister_irq(vector, count_raised, ctx);
+    irqfree(vector);
+
+    let raised = raised.load(Ordering::Relaxed);
+    info!(
+        "selftest: vector {} reached its handler {} times",
+        vector, raised
+    );
+
+    assert_eq!(
+        raised, 2,
+        "selftest: vector {} reached its handler {} of 2 times",
+        vector, raised
+    );
+}
+
+/// Timer interrupts have to keep arriving at `HZ` while the executing CPU waits for AHCI reads,
+/// so neither the driver nor the interrupt handlers may keep interrupts disabled while waiting
+fn ticks_during_io() {
+    if tsc_hz() == 0 {
+        warn!("selftest: no TSC frequency to time disk I/O against, skipping");
+        return;
+    }
+
+    let disk: Option<Arc<dyn Disk>> = ALL_DISKS
+        .read()
+        .iter()
+        .find(|entry| matches!(entry.location, DiskLocation::Ahci { .. }))
+        .map(|entry| Arc::clone(&entry.disk));
+
+    let Some(disk) = disk else {
+        warn!("selftest: no AHCI disk to read from, skipping");
+        return;
+    };
+
+    let sectors = disk.sectors().unwrap_or(1).max(1);
+    let mut buffer = vec![0u8; disk.block_size() * 128];
+
+    let cpu = percpu::current();
+    let ticks = cpu.ticks();
+    let stopwatch = Stopwatch::start();
+
+    let mut sector = 0;
+    let mut reads = 0;
+
+    while stopwatch.elapsed_micros() < IO_TICKS_MS * 1000 {
+        assert!(
+            disk.read(sector, &mut buffer).is_some(),
+            "selftest: reading sector {} failed",
+            sector
+        );
+
+        sector = (sector + 128) % sectors;
+        reads += 1;
+    }
+
+    let elapsed_ms = stopwatch.elapsed_micros() / 1000;
+    let ticks = cpu.ticks() - ticks;
+    let expected = elapsed_ms * HZ / 1000;
+
+    info!(
+        "selftest: {} timer interrupts in {} ms over {} reads, expected {}",
+        ticks, elapsed_ms, reads, expected
+    );
+
+    assert!(
+        ticks * 100 >= expected * IO_TICKS_MIN_PERCENT,
+        "selftest: only {} of {} timer interrupts arrived during disk I/O",
+        ticks,
+        expected
+    );
+}